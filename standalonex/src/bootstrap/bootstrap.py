@@ -21,6 +21,11 @@ try:
 except ImportError:
     lzma = None
 
+try:
+    from urllib.request import urlopen
+except ImportError:
+    from urllib2 import urlopen
+
 def platform_is_win32():
     return sys.platform == 'win32'
 
@@ -47,7 +52,7 @@ def eprint(*args, **kwargs):
     print(*args, **kwargs)
 
 
-def get(base, url, path, checksums, verbose=False):
+def get(base, url, path, checksums, verbose=False, use_curl=False):
     with tempfile.NamedTemporaryFile(delete=False) as temp_file:
         temp_path = temp_file.name
 
@@ -69,7 +74,7 @@ def get(base, url, path, checksums, verbose=False):
                     eprint("ignoring already-download file",
                         path, "due to failed verification")
                 os.unlink(path)
-        download(temp_path, "{}/{}".format(base, url), True, verbose)
+        download(temp_path, "{}/{}".format(base, url), True, verbose, use_curl)
         if not verify(temp_path, sha256, verbose):
             raise RuntimeError("failed verification")
         if verbose:
@@ -87,17 +92,38 @@ def curl_version():
         return (0, 0)
     return (int(m[1]), int(m[2]))
 
-def download(path, url, probably_big, verbose):
+def download(path, url, probably_big, verbose, use_curl=False):
+    fetch = _download_curl if use_curl else _download
     for _ in range(4):
         try:
-            _download(path, url, probably_big, verbose, True)
+            fetch(path, url, probably_big, verbose, True)
             return
         except RuntimeError:
             eprint("\nspurious failure, trying again")
-    _download(path, url, probably_big, verbose, False)
+    fetch(path, url, probably_big, verbose, False)
 
 
 def _download(path, url, probably_big, verbose, exception):
+    # Use Python's own HTTP client, like bootstrap uses its built-in one (see
+    # src/bootstrap/src/utils/http.rs), so that neither needs curl unless
+    # `build.use-curl` is set. It also handles the `file://` URLs of
+    # `build.download-mirror`.
+    if probably_big or verbose:
+        eprint("downloading {}".format(url))
+
+    try:
+        # timeout if cannot connect, or nothing is received, within 30 seconds
+        with contextlib.closing(urlopen(url, timeout=30)) as response:
+            with open(path, "wb") as output:
+                shutil.copyfileobj(response, output)
+    except (IOError, OSError, ValueError) as e:
+        # Check if the error should be silenced
+        if verbose or exception:
+            raise RuntimeError("failed to download {}: {}".format(url, e))
+        eprint("failed to download {}: {}".format(url, e))
+
+
+def _download_curl(path, url, probably_big, verbose, exception):
     # Try to use curl (potentially available on win32
     #    https://devblogs.microsoft.com/commandline/tar-and-curl-come-to-windows/)
     # If an error occurs:
@@ -454,6 +480,7 @@ class DownloadInfo:
         stage0_data,
         pattern,
         verbose,
+        use_curl,
     ):
         self.base_download_url = base_download_url
         self.download_path = download_path
@@ -463,6 +490,7 @@ class DownloadInfo:
         self.stage0_data = stage0_data
         self.pattern = pattern
         self.verbose = verbose
+        self.use_curl = use_curl
 
 def download_component(download_info):
     if not os.path.exists(download_info.tarball_path):
@@ -472,6 +500,7 @@ def download_component(download_info):
             download_info.tarball_path,
             download_info.stage0_data,
             verbose=download_info.verbose,
+            use_curl=download_info.use_curl,
         )
 
 def unpack_component(download_info):
@@ -502,6 +531,7 @@ class RustBuild(object):
         self.git_version = None
         self.nix_deps_dir = None
         self._should_fix_bins_and_dylibs = None
+        self._nix_toolchain = None
         self.rust_root = os.path.abspath(os.path.join(__file__, '../../..'))
 
         self.config_toml = config_toml
@@ -532,6 +562,24 @@ class RustBuild(object):
         self.build = args.build or self.build_triple()
 
 
+    def mirrored_download_url(self):
+        """Return the URL to download the stage0 toolchain from, which is the local
+        `build.download-mirror` if it's set, like `mirrored_url` in src/core/download.rs
+
+        >>> rb = RustBuild()
+        >>> rb.download_url = "https://static.rust-lang.org"
+        >>> rb.config_toml = '[build]\\ndownload-mirror = "/srv/mirror"'
+        >>> rb.mirrored_download_url()
+        'file:///srv/mirror'
+        >>> rb.download_url = "file:///srv/dist"
+        >>> rb.mirrored_download_url()
+        'file:///srv/dist'
+        """
+        mirror = self.get_toml('download-mirror', 'build')
+        if mirror is None or not re.match("https?://", self.download_url):
+            return self.download_url
+        return "file://" + os.path.abspath(mirror)
+
     def download_toolchain(self):
         """Fetch the build system for Rust, written in Rust
 
@@ -542,22 +590,12 @@ class RustBuild(object):
         Each downloaded tarball is extracted, after that, the script
         will move all the content to the right place.
         """
-        # Check if Nix-provided rustc and cargo exist
-        nix_rustc_exists = os.path.exists(self.rustc())
-        nix_cargo_exists = os.path.exists(self.cargo())
-
-        # If Nix-provided tools exist, we don't need to download
-        need_rustc = not nix_rustc_exists
-        need_cargo = not nix_cargo_exists
-
-        if need_rustc or need_cargo:
-            eprint("INFO: Nix-provided rustc or cargo not found. Proceeding with downloads.")
-            # For now, we will raise an exception if downloads are attempted
-            raise Exception("Downloads are disabled in Nix environment. Ensure rustc and cargo are provided via config.toml.")
-        else:
-            eprint("INFO: Nix-provided rustc and cargo found. Skipping downloads.")
-            return # Skip the rest of the download_toolchain method
+        if self.nix_toolchain() is not None:
+            # `Config::parse` checks the toolchain, there's nothing to download.
+            return
 
+        # A rustc or cargo configured outside of `bin_root`, e.g. from the Nix store, isn't
+        # downloaded. Otherwise it is, from `build.download-mirror` if that's set.
         rustc_channel = self.stage0_compiler.version
         bin_root = self.bin_root()
 
@@ -613,7 +651,7 @@ class RustBuild(object):
 
             tarballs_download_info = [
                 DownloadInfo(
-                    base_download_url=self.download_url,
+                    base_download_url=self.mirrored_download_url(),
                     download_path="dist/{}/{}".format(self.stage0_compiler.date, filename),
                     bin_root=self.bin_root(),
                     tarball_path=os.path.join(rustc_cache, filename),
@@ -621,6 +659,7 @@ class RustBuild(object):
                     stage0_data=self.stage0_data,
                     pattern=pattern,
                     verbose=self.verbose,
+                    use_curl=self.get_toml('use-curl', 'build') == 'true',
                 )
                 for filename, pattern in tarballs_to_download
             ]
@@ -840,7 +879,20 @@ class RustBuild(object):
         >>> rb.config_toml = 'key1 = true'
         >>> rb.get_toml("key1")
         'true'
+
+        Keys of a section are overridden by their environment variable, see
+        `env_var_name`
+
+        >>> os.environ["RUST_BOOTSTRAP__A__KEY"] = "value3"
+        >>> rb.config_toml = '[a]\\nkey = "value1"'
+        >>> rb.get_toml('key', 'a')
+        'value3'
+        >>> del os.environ["RUST_BOOTSTRAP__A__KEY"]
         """
+        if section is not None:
+            value = os.environ.get(env_var_name(section.split('.', 1) + [key]))
+            if value is not None:
+                return RustBuild.get_string(value) or value.strip()
         return RustBuild.get_toml_static(self.config_toml, key, section)
 
     @staticmethod
@@ -866,6 +918,39 @@ class RustBuild(object):
         """Return config path for rustc"""
         return self.program_config('rustc')
 
+    def nix_toolchain(self):
+        """Return the prefix of the stage0 toolchain given by `build.nix-toolchain`, if any,
+        like `resolve_nix_toolchain` in src/core/config/config.rs
+
+        Store paths (or any other existing directory) are used as-is, anything else is
+        built as a flake reference.
+
+        >>> rb = RustBuild()
+        >>> rb.nix_toolchain() is None
+        True
+        >>> prefix = tempfile.mkdtemp()
+        >>> rb.config_toml = '[build]\\nnix-toolchain = "{}"'.format(prefix)
+        >>> rb.nix_toolchain() == prefix
+        True
+        >>> rb.program_config('rustc') == os.path.join(prefix, "bin", "rustc" + EXE_SUFFIX)
+        True
+        >>> os.rmdir(prefix)
+        """
+        toolchain = self.get_toml('nix-toolchain', 'build')
+        if toolchain is None or os.path.isdir(toolchain):
+            return toolchain
+        if self._nix_toolchain is None:
+            # `--no-link` keeps `nix build` from creating a `result` symlink in the source tree.
+            paths = require(["nix", "--extra-experimental-features", "nix-command flakes",
+                             "build", "--no-link", "--print-out-paths", toolchain])
+            paths = paths.decode(sys.getdefaultencoding()).splitlines()
+            if not paths:
+                eprint("ERROR: `nix build {}` did not print any output path.".format(toolchain))
+                sys.exit(2)
+            # Multi-output toolchains print the default output first.
+            self._nix_toolchain = paths[0].strip()
+        return self._nix_toolchain
+
     def program_config(self, program):
         """Return config path for the given program at the given stage
 
@@ -879,6 +964,9 @@ class RustBuild(object):
         ... "bin", "cargo")
         True
         """
+        nix_toolchain = self.nix_toolchain()
+        if nix_toolchain is not None:
+            return os.path.join(nix_toolchain, "bin", "{}{}".format(program, EXE_SUFFIX))
         config = self.get_toml(program)
         if config:
             return os.path.expanduser(config)
@@ -1070,7 +1158,7 @@ class RustBuild(object):
         Note that `default_build_triple` is moderately expensive,
         so use `self.build` where possible.
         """
-        config = self.get_toml('build')
+        config = self.get_toml('build', 'build')
         return config or default_build_triple(self.verbose)
 
     def check_vendored_status(self):
@@ -1111,6 +1199,8 @@ def parse_args(args):
     parser = argparse.ArgumentParser(add_help=False)
     parser.add_argument('-h', '--help', action='store_true')
     parser.add_argument('--config')
+    parser.add_argument('--config-json')
+    parser.add_argument('--migrate', action='store_true')
     parser.add_argument('--build-dir')
     parser.add_argument('--build')
     parser.add_argument('--color', choices=['always', 'never', 'auto'])
@@ -1137,6 +1227,131 @@ def parse_stage0_file(path):
                 result[key.strip()] = value.strip()
     return result
 
+def env_var_name(key):
+    """The environment variable overriding the config.toml key with the path `key`, keep in
+    sync with `src/core/config/env_overrides.rs`
+
+    >>> env_var_name(["llvm", "download-ci-llvm"])
+    'RUST_BOOTSTRAP__LLVM__DOWNLOAD_CI_LLVM'
+    >>> env_var_name(["target", "x86_64-unknown-linux-gnu", "cc"])
+    'RUST_BOOTSTRAP__TARGET__X86_64_UNKNOWN_LINUX_GNU__CC'
+    """
+    parts = [part.upper().replace('-', '_').replace('.', '_') for part in key]
+    return "RUST_BOOTSTRAP__" + "__".join(parts)
+
+def json_config_to_toml(config, section=None):
+    """Write a configuration given as JSON, see `--config-json`, as TOML
+
+    >>> print(json_config_to_toml({"profile": "dist", "build": {"jobs": 2, "vendor": True}}))
+    profile = "dist"
+    [build]
+    jobs = 2
+    vendor = true
+
+    Keys set to `null` are left unset, like in `Config::parse_json`:
+
+    >>> print(json_config_to_toml({"build": {"jobs": None, "vendor": True}, "llvm": None}))
+    [build]
+    vendor = true
+    """
+    def key(name):
+        return name if re.match(r'^[A-Za-z0-9_-]+$', name) else json.dumps(name)
+
+    def value(v):
+        if isinstance(v, bool):
+            return 'true' if v else 'false'
+        if isinstance(v, list):
+            return '[' + ', '.join(value(item) for item in v) + ']'
+        return json.dumps(v)
+
+    lines, tables = [], []
+    for name, v in config.items():
+        if v is None:
+            continue
+        if isinstance(v, dict):
+            tables.append((name, v))
+        else:
+            lines.append('{} = {}'.format(key(name), value(v)))
+    for name, v in tables:
+        name = key(name) if section is None else '{}.{}'.format(section, key(name))
+        lines.append('[{}]'.format(name))
+        lines.append(json_config_to_toml(v, name))
+    return '\n'.join(line for line in lines if line)
+
+def include_paths(config_toml):
+    """The paths in the top-level `include = [...]` array of `config_toml`, which may span lines
+
+    >>> include_paths('include = ["a.toml", "b.toml"]')
+    ['a.toml', 'b.toml']
+    >>> include_paths('include = [\\n  "a.toml", # first\\n  "b.toml",\\n]\\n[build]')
+    ['a.toml', 'b.toml']
+    >>> include_paths('[build]\\ninclude = ["a.toml"]')
+    []
+
+    Anything but basic strings is rejected rather than ignored:
+
+    >>> include_paths("include = ['a.toml']")
+    Traceback (most recent call last):
+    ...
+    Exception: unsupported `include` array, only "double-quoted" paths are supported: ['a.toml']
+    >>> include_paths('include = "a.toml"')
+    Traceback (most recent call last):
+    ...
+    Exception: unsupported `include` array, only "double-quoted" paths are supported: "a.toml"
+    >>> include_paths('include = [\\n  "a.toml",\\n')
+    Traceback (most recent call last):
+    ...
+    Exception: `include` array isn't closed
+    """
+    lines = config_toml.splitlines()
+    for i, line in enumerate(lines):
+        if re.match(r'^\s*\[(.*)\]\s*$', line) is not None:
+            break
+        match = re.match(r'^include\s*=(.*)$', line)
+        if match is None:
+            continue
+        value = ""
+        for line in [match.group(1)] + lines[i + 1:]:
+            # Drop the comment, if any, outside of strings.
+            value += re.match(r'^((?:[^"#]|"[^"]*")*)', line).group(1) + " "
+            if not value.lstrip().startswith('[') or \
+                    re.match(r'^\s*\[(?:[^"\]]|"[^"]*")*\]', value):
+                break
+        else:
+            raise Exception("`include` array isn't closed")
+        value = value.strip()
+        paths = re.findall(r'"([^"]*)"', value)
+        if re.sub(r'"[^"]*"|[\s,]', '', value) != "[]":
+            raise Exception('unsupported `include` array, only "double-quoted" paths are '
+                            'supported: {}'.format(value))
+        return paths
+    return []
+
+def append_includes(config_toml, toml_path, stack):
+    """Append the files listed in `include = [...]` to `config_toml`, later ones first
+
+    Paths are relative to the including file, or the working directory for inline JSON, where
+    `toml_path` is empty. Like the profile defaults below, this relies on `get_toml()` returning
+    the first match, so that each file overrides the ones it includes, and later includes
+    override earlier ones. Keep in sync with `merge_includes` in config.rs.
+    """
+    paths = include_paths(config_toml)
+    if not paths:
+        return config_toml
+
+    if toml_path:
+        toml_path = os.path.realpath(toml_path)
+        stack = stack + [toml_path]
+    directory = os.path.dirname(toml_path) if toml_path else os.getcwd()
+    for path in reversed(paths):
+        path = os.path.realpath(os.path.join(directory, path))
+        if path in stack:
+            raise Exception("config include cycle: {}".format(" -> ".join(stack + [path])))
+        with open(path) as included_toml:
+            included = included_toml.read()
+        config_toml += os.linesep + append_includes(included, path, stack)
+    return config_toml
+
 def bootstrap(args):
     """Configure, fetch, build and run the initial bootstrap"""
     rust_root = os.path.abspath(os.path.join(__file__, '../../..'))
@@ -1156,14 +1371,30 @@ def bootstrap(args):
         if not os.path.exists(toml_path):
             toml_path = os.path.join(rust_root, toml_path)
 
+    # `--config-json` and `RUST_BOOTSTRAP_CONFIG_JSON` take the place of the TOML file, see
+    # `Config::parse_inner`.
+    config_json = args.config_json
+    if config_json is None and using_default_path:
+        config_json = os.getenv('RUST_BOOTSTRAP_CONFIG_JSON')
+
+    if config_json is not None:
+        if not config_json.lstrip().startswith('{'):
+            toml_path = config_json
+            with open(config_json) as config:
+                config_json = config.read()
+        else:
+            toml_path = ''
+        config_toml = json_config_to_toml(json.loads(config_json))
     # Give a hard error if `--config` or `RUST_BOOTSTRAP_CONFIG` are set to a missing path,
     # but not if `config.toml` hasn't been created.
-    if not using_default_path or os.path.exists(toml_path):
+    elif not using_default_path or os.path.exists(toml_path):
         with open(toml_path) as config:
             config_toml = config.read()
     else:
         config_toml = ''
 
+    config_toml = append_includes(config_toml, toml_path, [])
+
     profile = RustBuild.get_toml_static(config_toml, "profile")
     is_non_git_source = not os.path.exists(os.path.join(rust_root, ".git"))
 
@@ -1182,13 +1413,15 @@ def bootstrap(args):
         include_path = os.path.join(include_dir, include_file)
 
         if not os.path.exists(include_path):
-            raise Exception("Unrecognized config profile '{}'. Check src/bootstrap/defaults"
-            " for available options.".format(profile))
-
-        # HACK: This works because `self.get_toml()` returns the first match it finds for a
-        # specific key, so appending our defaults at the end allows the user to override them
-        with open(include_path) as included_toml:
-            config_toml += os.linesep + included_toml.read()
+            # `x setup --migrate` renames removed profiles.
+            if not args.migrate:
+                raise Exception("Unrecognized config profile '{}'. Check src/bootstrap/defaults"
+                " for available options.".format(profile))
+        else:
+            # HACK: This works because `self.get_toml()` returns the first match it finds for a
+            # specific key, so appending our defaults at the end allows the user to override them
+            with open(include_path) as included_toml:
+                config_toml += os.linesep + included_toml.read()
 
     # Configure initial bootstrap
     build = RustBuild(config_toml, args)
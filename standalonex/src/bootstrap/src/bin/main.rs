@@ -11,8 +11,8 @@ use std::str::FromStr;
 use std::{env, process};
 
 use bootstrap::{
-    Build, CONFIG_CHANGE_HISTORY, Config, Flags, Subcommand, find_recent_config_change_ids,
-    human_readable_changes, t,
+    Build, CONFIG_CHANGE_HISTORY, Config, ConfigCommand, Flags, Subcommand,
+    find_recent_config_change_ids, human_readable_changes, migrate_config_toml, t,
 };
 use build_helper::ci::CiEnv;
 
@@ -24,8 +24,50 @@ fn main() {
     }
 
     let flags = Flags::parse(&args);
+
+    // Describes what a configuration may contain, so it mustn't require a valid one.
+    if let Subcommand::Config { cmd: ConfigCommand::Schema } = &flags.cmd {
+        print!("{}", Config::schema());
+        return;
+    }
+    // Makes an outdated configuration valid again.
+    if let Subcommand::Setup { migrate: true, .. } = &flags.cmd {
+        migrate_config_toml(&flags);
+        return;
+    }
+
     let config = Config::parse(flags);
 
+    // Doesn't need the build directory, and its output is meant to be read by other tools, so it
+    // must not be mixed with the warnings below.
+    match &config.cmd {
+        Subcommand::Config { cmd: ConfigCommand::Dump { format } } => {
+            print!("{}", config.dump(*format));
+            return;
+        }
+        Subcommand::Config { cmd: ConfigCommand::Export { format } } => {
+            print!("{}", config.export(*format));
+            return;
+        }
+        Subcommand::CheckConfig => {
+            if !config.check_config() {
+                process::exit(1);
+            }
+            return;
+        }
+        Subcommand::Fetch { list } => {
+            config.fetch(*list);
+            return;
+        }
+        Subcommand::Cache { cmd } => {
+            if !config.cache(cmd) {
+                process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
     let mut build_lock;
     let _build_lock_guard;
 
@@ -68,7 +110,9 @@ fn main() {
 
     // NOTE: Since `./configure` generates a `config.toml`, distro maintainers will see the
     // changelog warning, not the `x.py setup` message.
-    let suggest_setup = config.config.is_none() && !matches!(config.cmd, Subcommand::Setup { .. });
+    let suggest_setup = config.config.is_none()
+        && !config.config_json
+        && !matches!(config.cmd, Subcommand::Setup { .. });
     if suggest_setup {
         println!("WARNING: you have not made a `config.toml`");
         println!(
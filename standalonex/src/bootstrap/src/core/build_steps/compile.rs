@@ -9,10 +9,7 @@
 use std::borrow::Cow;
 use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::io::BufReader;
-use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::process::Stdio;
 use std::{env, fs, str};
 
 use serde_derive::Deserialize;
@@ -21,14 +18,16 @@ use crate::core::build_steps::tool::SourceType;
 use crate::core::build_steps::{dist, llvm};
 use crate::core::builder;
 use crate::core::builder::{
-    Builder, Cargo, Kind, PathSet, RunConfig, ShouldRun, Step, TaskPath, crate_description,
+    Builder, Cargo, Kind, PathSet, RunConfig, ShouldRun, Step, StepIo, TaskPath,
+    crate_description,
 };
 use crate::core::config::{DebuginfoLevel, LlvmLibunwind, RustcLto, TargetSelection};
 use crate::utils::exec::command;
 use crate::utils::helpers::{
     exe, get_clang_cl_resource_dir, is_debug_info, is_dylib, symlink_dir, t, up_to_date,
 };
-use crate::{CLang, Compiler, DependencyType, GitRepo, LLVM_TOOLS, Mode};
+use crate::utils::step_cache::{PersistentOutput, StepInputs};
+use crate::{CLang, Compiler, DependencyType, GitRepo, LLVM_TOOLS, Mode, parse_stamp_file};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Std {
@@ -107,6 +106,29 @@ impl Std {
         }
         deps
     }
+
+    /// Copies the self-contained linkers of the stage0 sysroot into the sysroot, when building
+    /// for the build triple with the stage0 compiler.
+    fn copy_stage0_linkers(&self, builder: &Builder<'_>) {
+        let (compiler, target) = (self.compiler, self.target);
+        // The LLD wrappers and `rust-lld` are self-contained linking components that can be
+        // necessary to link the stdlib on some targets. We'll also need to copy these binaries to
+        // the `stage0-sysroot` to ensure the linker is found when bootstrapping on such a target.
+        if compiler.stage == 0 && compiler.host == builder.config.build {
+            // We want to copy the host `bin` folder within the `rustlib` folder in the sysroot.
+            let src_sysroot_bin = builder
+                .rustc_snapshot_sysroot()
+                .join("lib")
+                .join("rustlib")
+                .join(compiler.host)
+                .join("bin");
+            if src_sysroot_bin.exists() {
+                let target_sysroot_bin = builder.sysroot_target_bindir(compiler, target);
+                t!(fs::create_dir_all(&target_sysroot_bin));
+                builder.cp_link_r(&src_sysroot_bin, &target_sysroot_bin);
+            }
+        }
+    }
 }
 
 impl Step for Std {
@@ -117,6 +139,14 @@ impl Step for Std {
         run.crate_or_deps("sysroot").path("library")
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
     fn make_run(run: RunConfig<'_>) {
         let crates = std_crates_for_run_make(&run);
         let builder = run.builder;
@@ -138,6 +168,56 @@ impl Step for Std {
         });
     }
 
+    fn persistent_output(&self, builder: &Builder<'_>) -> Option<PersistentOutput<()>> {
+        let (compiler, target) = (self.compiler, self.target);
+        // Only the plain build is cached, `run` reuses existing artifacts in the other cases.
+        if (builder.download_rustc() && target == builder.build.build && compiler.stage != 0)
+            || builder.config.keep_stage.contains(&compiler.stage)
+            || builder.config.keep_stage_std.contains(&compiler.stage)
+            || builder.compiler_for(compiler.stage, compiler.host, target) != compiler
+        {
+            return None;
+        }
+
+        let mut inputs = StepInputs::new(self);
+        inputs
+            .compiler(builder, compiler)
+            .whole_config(builder)
+            .flags(builder, target)
+            .config("MACOSX_STD_DEPLOYMENT_TARGET", env::var_os("MACOSX_STD_DEPLOYMENT_TARGET"))
+            .source(&builder.src.join("library"))
+            .source(&builder.src.join("Cargo.lock"));
+        if builder.config.optimized_compiler_builtins || builder.config.profiler_enabled(target) {
+            inputs.source(&builder.src.join("src/llvm-project/compiler-rt"));
+        }
+        if let Some(path) = builder.config.profiler_path(target) {
+            inputs.source(Path::new(path));
+        }
+        Some(PersistentOutput::new(inputs, stamped_files(libstd_stamp(builder, compiler, target))))
+    }
+
+    fn restored(&self, builder: &Builder<'_>, _output: &()) {
+        let (compiler, target) = (self.compiler, self.target);
+        builder.ensure(StartupObjects { compiler, target });
+        self.copy_extra_objects(builder, &compiler, target);
+        self.copy_stage0_linkers(builder);
+        builder.ensure(StdLink::from_std(
+            self.clone(),
+            builder.compiler(compiler.stage, builder.config.build),
+        ));
+    }
+
+    fn declared_io(&self, builder: &Builder<'_>) -> StepIo {
+        StepIo {
+            inputs: vec![builder.src.join("library"), builder.src.join("Cargo.lock")],
+            outputs: vec![
+                builder.cargo_out(self.compiler, Mode::Std, self.target),
+                libstd_stamp(builder, self.compiler, self.target),
+            ],
+            tools: vec![builder.initial_cargo.clone(), Sysroot::rustc(builder, self.compiler)],
+        }
+    }
+
     /// Builds the standard library.
     ///
     /// This will build the standard library for a particular stage of the build
@@ -206,24 +286,7 @@ impl Step for Std {
         }
 
         target_deps.extend(self.copy_extra_objects(builder, &compiler, target));
-
-        // The LLD wrappers and `rust-lld` are self-contained linking components that can be
-        // necessary to link the stdlib on some targets. We'll also need to copy these binaries to
-        // the `stage0-sysroot` to ensure the linker is found when bootstrapping on such a target.
-        if compiler.stage == 0 && compiler.host == builder.config.build {
-            // We want to copy the host `bin` folder within the `rustlib` folder in the sysroot.
-            let src_sysroot_bin = builder
-                .rustc_snapshot_sysroot()
-                .join("lib")
-                .join("rustlib")
-                .join(compiler.host)
-                .join("bin");
-            if src_sysroot_bin.exists() {
-                let target_sysroot_bin = builder.sysroot_target_bindir(compiler, target);
-                t!(fs::create_dir_all(&target_sysroot_bin));
-                builder.cp_link_r(&src_sysroot_bin, &target_sysroot_bin);
-            }
-        }
+        self.copy_stage0_linkers(builder);
 
         // We build a sysroot for mir-opt tests using the same trick that Miri does: A check build
         // with -Zalways-encode-mir. This frees us from the need to have a target linker, and the
@@ -630,6 +693,14 @@ impl Step for StdLink {
         run.never()
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
     /// Link all libstd rlibs/dylibs into the sysroot location.
     ///
     /// Links those artifacts generated by `compiler` to the `stage` compiler's
@@ -664,25 +735,20 @@ impl Step for StdLink {
 
         // Special case for stage0, to make `rustup toolchain link` and `x dist --stage 0`
         // work for stage0-sysroot. We only do this if the stage0 compiler comes from beta,
-        // and is not set to a custom path.
-        if compiler.stage == 0
-            && builder
-                .build
-                .config
-                .initial_rustc
-                .starts_with(builder.out.join(compiler.host).join("stage0/bin"))
-        {
+        // and is not set to a custom path. The stage0 toolchain is shared by all variants.
+        let stage0_dir = builder.config.base_out.join(compiler.host).join("stage0");
+        if compiler.stage == 0 && builder.config.initial_rustc.starts_with(stage0_dir.join("bin")) {
             // Copy bin files from stage0/bin to stage0-sysroot/bin
             let sysroot = builder.out.join(compiler.host).join("stage0-sysroot");
 
             let host = compiler.host;
-            let stage0_bin_dir = builder.out.join(host).join("stage0/bin");
+            let stage0_bin_dir = stage0_dir.join("bin");
             let sysroot_bin_dir = sysroot.join("bin");
             t!(fs::create_dir_all(&sysroot_bin_dir));
             builder.cp_link_r(&stage0_bin_dir, &sysroot_bin_dir);
 
             // Copy all files from stage0/lib to stage0-sysroot/lib
-            let stage0_lib_dir = builder.out.join(host).join("stage0/lib");
+            let stage0_lib_dir = stage0_dir.join("lib");
             if let Ok(files) = fs::read_dir(stage0_lib_dir) {
                 for file in files {
                     let file = t!(file);
@@ -697,12 +763,8 @@ impl Step for StdLink {
             // Copy codegen-backends from stage0
             let sysroot_codegen_backends = builder.sysroot_codegen_backends(compiler);
             t!(fs::create_dir_all(&sysroot_codegen_backends));
-            let stage0_codegen_backends = builder
-                .out
-                .join(host)
-                .join("stage0/lib/rustlib")
-                .join(host)
-                .join("codegen-backends");
+            let stage0_codegen_backends =
+                stage0_dir.join("lib/rustlib").join(host).join("codegen-backends");
             if stage0_codegen_backends.exists() {
                 builder.cp_link_r(&stage0_codegen_backends, &sysroot_codegen_backends);
             }
@@ -718,7 +780,7 @@ fn copy_sanitizers(
 ) -> Vec<PathBuf> {
     let runtimes: Vec<llvm::SanitizerRuntime> = builder.ensure(llvm::Sanitizers { target });
 
-    if builder.config.dry_run() {
+    if builder.dry_run() {
         return Vec::new();
     }
 
@@ -890,6 +952,14 @@ impl Step for Rustc {
         run.crates(crates)
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
     fn make_run(run: RunConfig<'_>) {
         let crates = run.cargo_crates_in_set();
         run.builder.ensure(Rustc {
@@ -899,6 +969,57 @@ impl Step for Rustc {
         });
     }
 
+    fn persistent_output(&self, builder: &Builder<'_>) -> Option<PersistentOutput<u32>> {
+        let (compiler, target) = (self.compiler, self.target);
+        // Only the plain build is cached, `run` reuses existing artifacts in the other cases.
+        if (builder.download_rustc() && compiler.stage != 0)
+            || builder.config.keep_stage.contains(&compiler.stage)
+            || builder.compiler_for(compiler.stage, compiler.host, target) != compiler
+        {
+            return None;
+        }
+
+        let mut inputs = StepInputs::new(self);
+        inputs
+            .compiler(builder, compiler)
+            .whole_config(builder)
+            .flags(builder, target)
+            .config("enable-bolt-settings", builder.config.enable_bolt_settings)
+            // The compiler links against the standard library it builds first.
+            .source(&builder.src.join("library"))
+            .source(&builder.src.join("compiler"))
+            .source(&builder.src.join("Cargo.lock"));
+        match builder.config.target_config.get(&target).and_then(|c| c.llvm_config.as_ref()) {
+            Some(llvm_config) => inputs.source(llvm_config),
+            None => inputs.config("llvm", llvm::in_tree_llvm_hash(builder)),
+        };
+        if let Some(profile) = &builder.config.rust_profile_use {
+            inputs.source(Path::new(profile));
+        }
+        let stamp = librustc_stamp(builder, compiler, target);
+        Some(PersistentOutput::new(inputs, stamped_files(stamp)))
+    }
+
+    fn restored(&self, builder: &Builder<'_>, _output: &u32) {
+        let compiler = self.compiler;
+        builder.ensure(Std::new(compiler, self.target));
+        builder.ensure(RustcLink::from_rustc(
+            self.clone(),
+            builder.compiler(compiler.stage, builder.config.build),
+        ));
+    }
+
+    fn declared_io(&self, builder: &Builder<'_>) -> StepIo {
+        StepIo {
+            inputs: vec![builder.src.join("compiler"), builder.src.join("Cargo.lock")],
+            outputs: vec![
+                builder.cargo_out(self.compiler, Mode::Rustc, self.target),
+                librustc_stamp(builder, self.compiler, self.target),
+            ],
+            tools: vec![builder.initial_cargo.clone(), Sysroot::rustc(builder, self.compiler)],
+        }
+    }
+
     /// Builds the compiler.
     ///
     /// This will build the compiler for a particular stage of the build using
@@ -1321,6 +1442,14 @@ impl Step for RustcLink {
         run.never()
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
     /// Same as `std_link`, only for librustc
     fn run(self, builder: &Builder<'_>) {
         let compiler = self.compiler;
@@ -1452,7 +1581,7 @@ impl Step for CodegenBackend {
 
         let _guard = builder.msg_build(compiler, format_args!("codegen backend {backend}"), target);
         let files = run_cargo(builder, cargo, vec![], &tmp_stamp, vec![], false, false);
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             return;
         }
         let mut files = files.into_iter().filter(|f| {
@@ -1500,7 +1629,7 @@ fn copy_codegen_backends_to_sysroot(
     let dst = builder.sysroot_codegen_backends(target_compiler);
     t!(fs::create_dir_all(&dst), dst);
 
-    if builder.config.dry_run() {
+    if builder.dry_run() {
         return;
     }
 
@@ -1530,6 +1659,16 @@ pub fn libstd_stamp(builder: &Builder<'_>, compiler: Compiler, target: TargetSel
     builder.cargo_out(compiler, Mode::Std, target).join(".libstd.stamp")
 }
 
+/// The files of a step whose output is the cargo build recorded in `stamp`: the stamp and the
+/// files listed in it.
+fn stamped_files<T>(stamp: PathBuf) -> impl Fn(&T) -> Vec<PathBuf> {
+    move |_| {
+        let mut files = vec![stamp.clone()];
+        files.extend(parse_stamp_file(&t!(fs::read(&stamp))).into_iter().map(|(path, _)| path));
+        files
+    }
+}
+
 /// Cargo's output path for librustc in a given stage, compiled by a particular
 /// compiler for the specified target.
 pub fn librustc_stamp(
@@ -1560,7 +1699,7 @@ pub fn compiler_file(
     c: CLang,
     file: &str,
 ) -> PathBuf {
-    if builder.config.dry_run() {
+    if builder.dry_run() {
         return PathBuf::new();
     }
     let mut cmd = command(compiler);
@@ -1581,6 +1720,30 @@ impl Sysroot {
     pub(crate) fn new(compiler: Compiler) -> Self {
         Sysroot { compiler, force_recompile: false }
     }
+
+    /// The rustc of `compiler`, like [`Builder::rustc`], but without ensuring its sysroot.
+    pub(crate) fn rustc(builder: &Builder<'_>, compiler: Compiler) -> PathBuf {
+        if compiler.is_snapshot(builder) {
+            return builder.initial_rustc.clone();
+        }
+        let sysroot = Sysroot::new(compiler).dir(builder, compiler.stage);
+        sysroot.join("bin").join(exe("rustc", compiler.host))
+    }
+
+    /// The directory of the sysroot of the `stage` compiler, on the host of `self.compiler`.
+    fn dir(&self, builder: &Builder<'_>, stage: u32) -> PathBuf {
+        let compiler = self.compiler;
+        let host_dir = builder.out.join(compiler.host);
+        if stage == 0 {
+            host_dir.join("stage0-sysroot")
+        } else if self.force_recompile && stage == compiler.stage {
+            host_dir.join(format!("stage{stage}-test-sysroot"))
+        } else if builder.download_rustc() && compiler.stage != builder.top_stage {
+            host_dir.join("ci-rustc-sysroot")
+        } else {
+            host_dir.join(format!("stage{}", stage))
+        }
+    }
 }
 
 impl Step for Sysroot {
@@ -1590,24 +1753,24 @@ impl Step for Sysroot {
         run.never()
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
+    fn declared_io(&self, builder: &Builder<'_>) -> StepIo {
+        StepIo { outputs: vec![self.dir(builder, self.compiler.stage)], ..Default::default() }
+    }
+
     /// Returns the sysroot that `compiler` is supposed to use.
     /// For the stage0 compiler, this is stage0-sysroot (because of the initial std build).
     /// For all other stages, it's the same stage directory that the compiler lives in.
     fn run(self, builder: &Builder<'_>) -> PathBuf {
         let compiler = self.compiler;
-        let host_dir = builder.out.join(compiler.host);
-
-        let sysroot_dir = |stage| {
-            if stage == 0 {
-                host_dir.join("stage0-sysroot")
-            } else if self.force_recompile && stage == compiler.stage {
-                host_dir.join(format!("stage{stage}-test-sysroot"))
-            } else if builder.download_rustc() && compiler.stage != builder.top_stage {
-                host_dir.join("ci-rustc-sysroot")
-            } else {
-                host_dir.join(format!("stage{}", stage))
-            }
-        };
+        let sysroot_dir = |stage| self.dir(builder, stage);
         let sysroot = sysroot_dir(compiler.stage);
 
         builder
@@ -1752,12 +1915,38 @@ impl Step for Assemble {
         run.path("compiler/rustc").path("compiler")
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.target_compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target_compiler.host)
+    }
+
     fn make_run(run: RunConfig<'_>) {
         run.builder.ensure(Assemble {
             target_compiler: run.builder.compiler(run.builder.top_stage + 1, run.target),
         });
     }
 
+    fn declared_io(&self, builder: &Builder<'_>) -> StepIo {
+        let target_compiler = self.target_compiler;
+        // The stage 0 compiler is downloaded, not assembled.
+        if target_compiler.stage == 0 {
+            return StepIo::default();
+        }
+        let sysroot = Sysroot::new(target_compiler).dir(builder, target_compiler.stage);
+        let libdir = sysroot
+            .join(builder.sysroot_libdir_relative(target_compiler))
+            .join("rustlib")
+            .join(target_compiler.host)
+            .join("lib");
+        StepIo {
+            outputs: vec![Sysroot::rustc(builder, target_compiler), libdir],
+            ..Default::default()
+        }
+    }
+
     /// Prepare a new compiler from the artifacts in `stage`
     ///
     /// This will assemble a compiler in `build/$host/stage$stage`. The compiler
@@ -1784,7 +1973,7 @@ impl Step for Assemble {
         if builder.config.llvm_enabled(target_compiler.host) {
             let llvm::LlvmResult { llvm_config, .. } =
                 builder.ensure(llvm::Llvm { target: target_compiler.host });
-            if !builder.config.dry_run() && builder.config.llvm_tools_enabled {
+            if !builder.dry_run() && builder.config.llvm_tools_enabled {
                 let llvm_bin_dir =
                     command(llvm_config).arg("--bindir").run_capture_stdout(builder).stdout();
                 let llvm_bin_dir = Path::new(llvm_bin_dir.trim());
@@ -2169,7 +2358,7 @@ pub fn run_cargo(
         crate::exit!(1);
     }
 
-    if builder.config.dry_run() {
+    if builder.dry_run() {
         return Vec::new();
     }
 
@@ -2227,8 +2416,8 @@ pub fn stream_cargo(
     tail_args: Vec<String>,
     cb: &mut dyn FnMut(CargoMessage<'_>),
 ) -> bool {
-    let mut cmd = cargo.into_cmd();
-    let cargo = cmd.as_command_mut();
+    // The callers decide what a failure means, e.g. for the toolstate.
+    let mut cmd = cargo.into_cmd().allow_failure();
     // Instruct Cargo to give us json messages on stdout, critically leaving
     // stderr as piped so we can get those pretty colors.
     let mut message_format = if builder.config.json_output {
@@ -2240,30 +2429,17 @@ pub fn stream_cargo(
         message_format.push_str(",json-diagnostic-");
         message_format.push_str(s);
     }
-    cargo.arg("--message-format").arg(message_format).stdout(Stdio::piped());
+    cmd.arg("--message-format").arg(message_format);
 
     for arg in tail_args {
-        cargo.arg(arg);
-    }
-
-    builder.verbose(|| println!("running: {cargo:?}"));
-
-    if builder.config.dry_run() {
-        return true;
+        cmd.arg(arg);
     }
 
-    let mut child = match cargo.spawn() {
-        Ok(child) => child,
-        Err(e) => panic!("failed to execute command: {cargo:?}\nERROR: {e}"),
-    };
-
-    // Spawn Cargo slurping up its JSON output. We'll start building up the
-    // `deps` array of all files it generated along with a `toplevel` array of
-    // files we need to probe for later.
-    let stdout = BufReader::new(child.stdout.take().unwrap());
-    for line in stdout.lines() {
-        let line = t!(line);
-        match serde_json::from_str::<CargoMessage<'_>>(&line) {
+    // Slurp up Cargo's JSON output. The callers build up the `deps` array of
+    // all files it generated along with a `toplevel` array of files we need to
+    // probe for later. In dry runs, there's no output to slurp.
+    let success = cmd.stream(builder, &mut |line| {
+        match serde_json::from_str::<CargoMessage<'_>>(line) {
             Ok(msg) => {
                 if builder.config.json_output {
                     // Forward JSON to stdout.
@@ -2274,17 +2450,12 @@ pub fn stream_cargo(
             // If this was informational, just print it out and continue
             Err(_) => println!("{line}"),
         }
-    }
+    });
 
-    // Make sure Cargo actually succeeded after we read all of its stdout.
-    let status = t!(child.wait());
-    if builder.is_verbose() && !status.success() {
-        eprintln!(
-            "command did not execute successfully: {cargo:?}\n\
-                  expected success, got: {status}"
-        );
+    if builder.is_verbose() && !success {
+        eprintln!("command did not execute successfully: {cmd:?}");
     }
-    status.success()
+    success
 }
 
 #[derive(Deserialize)]
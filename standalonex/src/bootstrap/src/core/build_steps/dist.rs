@@ -4,9 +4,8 @@
 //! compiler, and documentation. This ends up being what we distribute to
 //! everyone as well.
 //!
-//! No tarball is actually created literally in this file, but rather we shell
-//! out to `rust-installer` still. This may one day be replaced with bits and
-//! pieces of `rustup.rs`!
+//! No tarball is actually created literally in this file, the steps here fill
+//! the image of each one and leave generating it to `utils::tarball`.
 
 use std::collections::HashSet;
 use std::ffi::OsStr;
@@ -173,7 +172,7 @@ fn make_win_dist(
     target: TargetSelection,
     builder: &Builder<'_>,
 ) {
-    if builder.config.dry_run() {
+    if builder.dry_run() {
         return;
     }
 
@@ -365,6 +364,14 @@ impl Step for Rustc {
         run.alias("rustc")
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.compiler.host)
+    }
+
     fn make_run(run: RunConfig<'_>) {
         run.builder
             .ensure(Rustc { compiler: run.builder.compiler(run.builder.top_stage, run.target) });
@@ -643,6 +650,14 @@ impl Step for Std {
         run.alias("rust-std")
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
     fn make_run(run: RunConfig<'_>) {
         run.builder.ensure(Std {
             compiler: run.builder.compiler_for(
@@ -912,7 +927,7 @@ impl Step for Src {
 
     /// Creates the `rust-src` installer component
     fn run(self, builder: &Builder<'_>) -> GeneratedTarball {
-        if !builder.config.dry_run() {
+        if !builder.dry_run() {
             builder.require_submodule("src/llvm-project", None);
         }
 
@@ -1333,7 +1348,7 @@ impl Step for CodegenBackend {
     }
 
     fn run(self, builder: &Builder<'_>) -> Option<GeneratedTarball> {
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             return None;
         }
 
@@ -1511,7 +1526,7 @@ impl Step for Extended {
         let etc = builder.src.join("src/etc/installer");
 
         // Avoid producing tarballs during a dry run.
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             return;
         }
 
@@ -1929,7 +1944,7 @@ impl Step for Extended {
             let _time = timeit(builder);
             cmd.run(builder);
 
-            if !builder.config.dry_run() {
+            if !builder.dry_run() {
                 t!(move_file(exe.join(&filename), distdir(builder).join(&filename)));
             }
         }
@@ -1965,7 +1980,7 @@ fn install_llvm_file(
     destination: &Path,
     install_symlink: bool,
 ) {
-    if builder.config.dry_run() {
+    if builder.dry_run() {
         return;
     }
 
@@ -2039,7 +2054,7 @@ fn maybe_install_llvm(
         if llvm_dylib_path.exists() {
             builder.install(&llvm_dylib_path, dst_libdir, 0o644);
         }
-        !builder.config.dry_run()
+        !builder.dry_run()
     } else if let llvm::LlvmBuildStatus::AlreadyBuilt(llvm::LlvmResult { llvm_config, .. }) =
         llvm::prebuilt_llvm_config(builder, target, true)
     {
@@ -2058,7 +2073,7 @@ fn maybe_install_llvm(
             };
             install_llvm_file(builder, &file, dst_libdir, install_symlink);
         }
-        !builder.config.dry_run()
+        !builder.dry_run()
     } else {
         false
     }
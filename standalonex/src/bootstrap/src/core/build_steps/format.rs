@@ -1,21 +1,22 @@
 //! Runs rustfmt on the repository.
 
-use std::collections::VecDeque;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::sync::Mutex;
-use std::sync::mpsc::SyncSender;
 
 use build_helper::ci::CiEnv;
 use build_helper::git::get_git_modified_files;
 use ignore::WalkBuilder;
 
 use crate::core::builder::Builder;
-use crate::utils::exec::command;
+use crate::utils::exec::{BootstrapCommand, command};
 use crate::utils::helpers::{self, program_out_of_date, t};
 
-fn rustfmt(src: &Path, rustfmt: &Path, paths: &[PathBuf], check: bool) -> impl FnMut(bool) -> bool {
-    let mut cmd = Command::new(rustfmt);
+/// Paths formatted by one rustfmt process, to amortize the overhead of spawning processes.
+const PATHS_PER_RUSTFMT: usize = 64;
+
+fn rustfmt(src: &Path, rustfmt: &Path, paths: &[PathBuf], check: bool) -> BootstrapCommand {
+    // `format` reports the failure.
+    let mut cmd = command(rustfmt).allow_failure();
     // Avoid the submodule config paths from coming into play. We only allow a single global config
     // for the workspace for now.
     cmd.arg("--config-path").arg(src.canonicalize().unwrap());
@@ -26,31 +27,7 @@ fn rustfmt(src: &Path, rustfmt: &Path, paths: &[PathBuf], check: bool) -> impl F
         cmd.arg("--check");
     }
     cmd.args(paths);
-    let cmd_debug = format!("{cmd:?}");
-    let mut cmd = cmd.spawn().expect("running rustfmt");
-    // Poor man's async: return a closure that might wait for rustfmt's completion (depending on
-    // the value of the `block` argument).
-    move |block: bool| -> bool {
-        let status = if !block {
-            match cmd.try_wait() {
-                Ok(Some(status)) => Ok(status),
-                Ok(None) => return false,
-                Err(err) => Err(err),
-            }
-        } else {
-            cmd.wait()
-        };
-        if !status.unwrap().success() {
-            eprintln!(
-                "fmt error: Running `{}` failed.\nIf you're running `tidy`, \
-                try again with `--bless`. Or, if you just want to format \
-                code, run `./x.py fmt` instead.",
-                cmd_debug,
-            );
-            crate::exit!(1);
-        }
-        true
-    }
+    cmd
 }
 
 fn get_rustfmt_version(build: &Builder<'_>) -> Option<(String, PathBuf)> {
@@ -124,7 +101,7 @@ pub fn format(build: &Builder<'_>, check: bool, all: bool, paths: &[PathBuf]) {
         );
         crate::exit!(1);
     };
-    if build.config.dry_run() {
+    if build.dry_run() {
         return;
     }
 
@@ -233,76 +210,51 @@ pub fn format(build: &Builder<'_>, check: bool, all: bool, paths: &[PathBuf]) {
     });
     assert!(rustfmt_path.exists(), "{}", rustfmt_path.display());
     let src = build.src.clone();
-    let (tx, rx): (SyncSender<PathBuf>, _) = std::sync::mpsc::sync_channel(128);
     let walker = WalkBuilder::new(src.clone()).types(matcher).overrides(override_).build_parallel();
 
-    // There is a lot of blocking involved in spawning a child process and reading files to format.
-    // Spawn more processes than available concurrency to keep the CPU busy.
-    let max_processes = build.jobs() as usize * 2;
-
-    // Spawn child processes on a separate thread so we can batch entries we have received from
-    // ignore.
-    let thread = std::thread::spawn(move || {
-        let mut children = VecDeque::new();
-        while let Ok(path) = rx.recv() {
-            // Try getting more paths from the channel to amortize the overhead of spawning
-            // processes.
-            let paths: Vec<_> = rx.try_iter().take(63).chain(std::iter::once(path)).collect();
-
-            let child = rustfmt(&src, &rustfmt_path, paths.as_slice(), check);
-            children.push_back(child);
-
-            // Poll completion before waiting.
-            for i in (0..children.len()).rev() {
-                if children[i](false) {
-                    children.swap_remove_back(i);
-                    break;
-                }
-            }
-
-            if children.len() >= max_processes {
-                // Await oldest child.
-                children.pop_front().unwrap()(true);
-            }
-        }
-
-        // Await remaining children.
-        for mut child in children {
-            child(true);
-        }
-    });
-
-    let formatted_paths = Mutex::new(Vec::new());
-    let formatted_paths_ref = &formatted_paths;
+    let found_paths = Mutex::new(Vec::new());
+    let found_paths_ref = &found_paths;
     walker.run(|| {
-        let tx = tx.clone();
         Box::new(move |entry| {
-            let cwd = std::env::current_dir();
             let entry = t!(entry);
             if entry.file_type().map_or(false, |t| t.is_file()) {
-                formatted_paths_ref.lock().unwrap().push({
-                    // `into_path` produces an absolute path. Try to strip `cwd` to get a shorter
-                    // relative path.
-                    let mut path = entry.clone().into_path();
-                    if let Ok(cwd) = cwd {
-                        if let Ok(path2) = path.strip_prefix(cwd) {
-                            path = path2.to_path_buf();
-                        }
-                    }
-                    path.display().to_string()
-                });
-                t!(tx.send(entry.into_path()));
+                found_paths_ref.lock().unwrap().push(entry.into_path());
             }
             ignore::WalkState::Continue
         })
     });
-    let mut paths = formatted_paths.into_inner().unwrap();
-    paths.sort();
+    let mut found_paths = found_paths.into_inner().unwrap();
+    found_paths.sort();
+
+    let cwd = std::env::current_dir();
+    let paths: Vec<_> = found_paths
+        .iter()
+        .map(|path| {
+            // The paths are absolute. Try to strip `cwd` to get a shorter relative path.
+            let path =
+                cwd.as_ref().ok().and_then(|cwd| path.strip_prefix(cwd).ok()).unwrap_or(path);
+            path.display().to_string()
+        })
+        .collect();
     print_paths(if check { "checked" } else { "formatted" }, adjective, &paths);
 
-    drop(tx);
+    // There is a lot of blocking involved in spawning a child process and reading files to format.
+    // Run more processes than available concurrency to keep the CPU busy.
+    let max_processes = build.jobs() as usize * 2;
+    let mut commands: Vec<_> = found_paths
+        .chunks(PATHS_PER_RUSTFMT)
+        .map(|paths| rustfmt(&src, &rustfmt_path, paths, check))
+        .collect();
+    let outputs = build.run_all(&mut commands, max_processes);
+    if let Some((cmd, _)) = commands.iter().zip(outputs).find(|(_, output)| !output.is_success()) {
+        eprintln!(
+            "fmt error: Running `{cmd:?}` failed.\nIf you're running `tidy`, \
+            try again with `--bless`. Or, if you just want to format \
+            code, run `./x.py fmt` instead.",
+        );
+        crate::exit!(1);
+    }
 
-    thread.join().unwrap();
     if !check {
         update_rustfmt_version(build);
     }
@@ -3,17 +3,26 @@
 //! This module is responsible for installing the standard library,
 //! compiler, and documentation.
 
+use std::collections::BTreeSet;
+use std::io::{self, Read};
 use std::path::{Component, Path, PathBuf};
 use std::{env, fs};
 
+use object::{FileKind, Object, ObjectKind};
+
 use crate::core::build_steps::dist;
 use crate::core::builder::{Builder, RunConfig, ShouldRun, Step};
+use crate::core::config::flags::{InstallLayout, Subcommand};
 use crate::core::config::{Config, TargetSelection};
+use crate::utils::elf;
 use crate::utils::exec::command;
 use crate::utils::helpers::t;
 use crate::utils::tarball::GeneratedTarball;
 use crate::{Compiler, Kind};
 
+#[cfg(test)]
+mod tests;
+
 #[cfg(target_os = "illumos")]
 const SHELL: &str = "bash";
 #[cfg(not(target_os = "illumos"))]
@@ -59,7 +68,7 @@ fn is_dir_writable_for_user(dir: &Path) -> bool {
     }
 }
 
-fn install_sh(
+fn install_tarball(
     builder: &Builder<'_>,
     package: &str,
     stage: u32,
@@ -67,7 +76,20 @@ fn install_sh(
     tarball: &GeneratedTarball,
 ) {
     let _guard = builder.msg(Kind::Install, stage, package, host, host);
+    match &builder.config.cmd {
+        Subcommand::Install { layout: InstallLayout::Nix, out: Some(out) } => {
+            if builder.dry_run() {
+                return;
+            }
+            let out = t!(env::current_dir()).join(out);
+            builder.config.assert_writable(&out);
+            t!(install_nix(tarball.decompressed_output(), package, &out));
+        }
+        _ => install_sh(builder, package, tarball),
+    }
+}
 
+fn install_sh(builder: &Builder<'_>, package: &str, tarball: &GeneratedTarball) {
     let prefix = default_path(&builder.config.prefix, "/usr/local");
     let sysconfdir = prefix.join(default_path(&builder.config.sysconfdir, "/etc"));
     let destdir_env = env::var_os("DESTDIR").map(PathBuf::from);
@@ -116,6 +138,115 @@ fn install_sh(
     t!(fs::remove_dir_all(&empty_dir));
 }
 
+/// Installs the components of the unpacked tarball `package_dir` into the Nix output directory
+/// `out`, where `install.sh --prefix=$out` would put them.
+///
+/// Instead of relying on the environment of the derivation to find the libraries in `$out/lib`,
+/// every ELF executable and library gets an RPATH relative to `$ORIGIN` pointing there. The
+/// installed components are listed in `$out/nix-support/rust-components`.
+pub(crate) fn install_nix(package_dir: &Path, package: &str, out: &Path) -> io::Result<()> {
+    let components = fs::read_to_string(package_dir.join("components"))?;
+    for component in components.lines() {
+        let component_dir = package_dir.join(component);
+        let manifest = fs::read_to_string(component_dir.join("manifest.in"))?;
+        for line in manifest.lines() {
+            // Like `install.sh`, which sets the permissions of single files but not of
+            // directories.
+            let (files, mode) = match line.split_once(':') {
+                Some(("file", path)) => {
+                    let mode = if path.starts_with("bin/") { 0o755 } else { 0o644 };
+                    (vec![PathBuf::from(path)], Some(mode))
+                }
+                Some(("dir", path)) => {
+                    let mut files = vec![];
+                    for entry in walkdir::WalkDir::new(component_dir.join(path)) {
+                        let entry = entry?;
+                        if !entry.file_type().is_dir() {
+                            let file = entry.path().strip_prefix(&component_dir).unwrap();
+                            files.push(file.to_path_buf());
+                        }
+                    }
+                    (files, None)
+                }
+                _ => {
+                    let message = format!("invalid line in the manifest of {component}: {line}");
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+            };
+            for file in files {
+                let dst = out.join(nix_install_path(&file, package));
+                install_nix_file(&component_dir.join(&file), &dst, out, mode)?;
+            }
+        }
+    }
+
+    let support = out.join("nix-support");
+    fs::create_dir_all(&support)?;
+    let list = support.join("rust-components");
+    let mut installed: BTreeSet<String> =
+        fs::read_to_string(&list).unwrap_or_default().lines().map(str::to_owned).collect();
+    installed.extend(components.lines().map(str::to_owned));
+    fs::write(list, installed.into_iter().map(|component| component + "\n").collect::<String>())
+}
+
+/// Where `install.sh` installs the file `path` of a component of `package`, relative to the
+/// prefix. The documentation of each package goes to its own directory, like the `docdir` of
+/// `install_sh`.
+fn nix_install_path(path: &Path, package: &str) -> PathBuf {
+    match path.strip_prefix("share/doc/rust") {
+        Ok(doc) => Path::new("share/doc").join(package).join(doc),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+fn install_nix_file(src: &Path, dst: &Path, out: &Path, mode: Option<u32>) -> io::Result<()> {
+    fs::create_dir_all(dst.parent().unwrap())?;
+    // Files from an earlier installation may be read-only, like everything in the Nix store.
+    if let Err(e) = fs::remove_file(dst) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    if src.is_symlink() {
+        return copy_symlink(src, dst);
+    }
+    // A copy rather than a link, since it may be patched below.
+    fs::copy(src, dst)?;
+    if let Some(mode) = mode {
+        crate::chmod(dst, mode);
+    }
+
+    let mut magic = [0; 4];
+    if fs::File::open(dst)?.read_exact(&mut magic).is_err() || magic != *b"\x7fELF" {
+        return Ok(());
+    }
+    let data = fs::read(dst)?;
+    let is_dynamic_elf = FileKind::parse(&*data).is_ok_and(|kind| kind == FileKind::Elf64)
+        && object::File::parse(&*data)
+            .is_ok_and(|file| matches!(file.kind(), ObjectKind::Executable | ObjectKind::Dynamic));
+    if is_dynamic_elf {
+        // `bin/rustc` gets `$ORIGIN/../lib`, `lib/rustlib/<target>/bin/rust-lld`
+        // `$ORIGIN/../../../../lib`.
+        let depth = dst.parent().unwrap().strip_prefix(out).unwrap().components().count();
+        let rpath = format!("$ORIGIN/{}lib", "../".repeat(depth));
+        if let Some(patched) = elf::patch(&data, None, Some(&rpath))
+            .map_err(|e| io::Error::other(format!("{}: {e}", dst.display())))?
+        {
+            fs::write(dst, patched)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(src)?, dst)
+}
+#[cfg(windows)]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::copy(src, dst).map(drop)
+}
+
 fn default_path(config: &Option<PathBuf>, default: &str) -> PathBuf {
     config.as_ref().cloned().unwrap_or_else(|| PathBuf::from(default))
 }
@@ -199,7 +330,7 @@ macro_rules! install {
 install!((self, builder, _config),
     Docs, path = "src/doc", _config.docs, only_hosts: false, {
         let tarball = builder.ensure(dist::Docs { host: self.target }).expect("missing docs");
-        install_sh(builder, "docs", self.compiler.stage, Some(self.target), &tarball);
+        install_tarball(builder, "docs", self.compiler.stage, Some(self.target), &tarball);
     };
     Std, path = "library/std", true, only_hosts: false, {
         // `expect` should be safe, only None when host != build, but this
@@ -208,19 +339,19 @@ install!((self, builder, _config),
             compiler: self.compiler,
             target: self.target
         }).expect("missing std");
-        install_sh(builder, "std", self.compiler.stage, Some(self.target), &tarball);
+        install_tarball(builder, "std", self.compiler.stage, Some(self.target), &tarball);
     };
     Cargo, alias = "cargo", Self::should_build(_config), only_hosts: true, {
         let tarball = builder
             .ensure(dist::Cargo { compiler: self.compiler, target: self.target })
             .expect("missing cargo");
-        install_sh(builder, "cargo", self.compiler.stage, Some(self.target), &tarball);
+        install_tarball(builder, "cargo", self.compiler.stage, Some(self.target), &tarball);
     };
     RustAnalyzer, alias = "rust-analyzer", Self::should_build(_config), only_hosts: true, {
         if let Some(tarball) =
             builder.ensure(dist::RustAnalyzer { compiler: self.compiler, target: self.target })
         {
-            install_sh(builder, "rust-analyzer", self.compiler.stage, Some(self.target), &tarball);
+            install_tarball(builder, "rust-analyzer", self.compiler.stage, Some(self.target), &tarball);
         } else {
             builder.info(
                 &format!("skipping Install rust-analyzer stage{} ({})", self.compiler.stage, self.target),
@@ -231,11 +362,11 @@ install!((self, builder, _config),
         let tarball = builder
             .ensure(dist::Clippy { compiler: self.compiler, target: self.target })
             .expect("missing clippy");
-        install_sh(builder, "clippy", self.compiler.stage, Some(self.target), &tarball);
+        install_tarball(builder, "clippy", self.compiler.stage, Some(self.target), &tarball);
     };
     Miri, alias = "miri", Self::should_build(_config), only_hosts: true, {
         if let Some(tarball) = builder.ensure(dist::Miri { compiler: self.compiler, target: self.target }) {
-            install_sh(builder, "miri", self.compiler.stage, Some(self.target), &tarball);
+            install_tarball(builder, "miri", self.compiler.stage, Some(self.target), &tarball);
         } else {
             // Miri is only available on nightly
             builder.info(
@@ -245,7 +376,7 @@ install!((self, builder, _config),
     };
     LlvmTools, alias = "llvm-tools", Self::should_build(_config), only_hosts: true, {
         if let Some(tarball) = builder.ensure(dist::LlvmTools { target: self.target }) {
-            install_sh(builder, "llvm-tools", self.compiler.stage, Some(self.target), &tarball);
+            install_tarball(builder, "llvm-tools", self.compiler.stage, Some(self.target), &tarball);
         } else {
             builder.info(
                 &format!("skipping llvm-tools stage{} ({}): external LLVM", self.compiler.stage, self.target),
@@ -257,7 +388,7 @@ install!((self, builder, _config),
             compiler: self.compiler,
             target: self.target
         }) {
-            install_sh(builder, "rustfmt", self.compiler.stage, Some(self.target), &tarball);
+            install_tarball(builder, "rustfmt", self.compiler.stage, Some(self.target), &tarball);
         } else {
             builder.info(
                 &format!("skipping Install Rustfmt stage{} ({})", self.compiler.stage, self.target),
@@ -268,14 +399,14 @@ install!((self, builder, _config),
         let tarball = builder.ensure(dist::Rustc {
             compiler: builder.compiler(builder.top_stage, self.target),
         });
-        install_sh(builder, "rustc", self.compiler.stage, Some(self.target), &tarball);
+        install_tarball(builder, "rustc", self.compiler.stage, Some(self.target), &tarball);
     };
     RustcCodegenCranelift, alias = "rustc-codegen-cranelift", Self::should_build(_config), only_hosts: true, {
         if let Some(tarball) = builder.ensure(dist::CodegenBackend {
             compiler: self.compiler,
             backend: "cranelift".to_string(),
         }) {
-            install_sh(builder, "rustc-codegen-cranelift", self.compiler.stage, Some(self.target), &tarball);
+            install_tarball(builder, "rustc-codegen-cranelift", self.compiler.stage, Some(self.target), &tarball);
         } else {
             builder.info(
                 &format!("skipping Install CodegenBackend(\"cranelift\") stage{} ({})",
@@ -285,7 +416,7 @@ install!((self, builder, _config),
     };
     LlvmBitcodeLinker, alias = "llvm-bitcode-linker", Self::should_build(_config), only_hosts: true, {
         if let Some(tarball) = builder.ensure(dist::LlvmBitcodeLinker { compiler: self.compiler, target: self.target }) {
-            install_sh(builder, "llvm-bitcode-linker", self.compiler.stage, Some(self.target), &tarball);
+            install_tarball(builder, "llvm-bitcode-linker", self.compiler.stage, Some(self.target), &tarball);
         } else {
             builder.info(
                 &format!("skipping llvm-bitcode-linker stage{} ({})", self.compiler.stage, self.target),
@@ -316,6 +447,6 @@ impl Step for Src {
 
     fn run(self, builder: &Builder<'_>) {
         let tarball = builder.ensure(dist::Src);
-        install_sh(builder, "src", self.stage, None, &tarball);
+        install_tarball(builder, "src", self.stage, None, &tarball);
     }
 }
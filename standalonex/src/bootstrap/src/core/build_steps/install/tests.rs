@@ -0,0 +1,71 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::core::build_steps::install::install_nix;
+use crate::core::config::tests::test_dir;
+
+/// An unpacked tarball with the component `rustc`, with `files` in its image.
+fn package(dir: &Path, files: &[(&str, &[u8])], manifest: &str) -> PathBuf {
+    let package = dir.join("rustc-nightly-x86_64-unknown-linux-gnu");
+    for (file, contents) in files {
+        let path = package.join("rustc").join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    fs::write(package.join("rustc/manifest.in"), manifest).unwrap();
+    fs::write(package.join("components"), "rustc\n").unwrap();
+    package
+}
+
+#[test]
+fn test_install_nix() {
+    let dir = test_dir("install-nix");
+    let files: &[(&str, &[u8])] = &[
+        ("bin/rustc", b"#!/bin/sh\n"),
+        ("lib/rustlib/x86_64-unknown-linux-gnu/lib/libstd.rlib", b""),
+        ("share/doc/rust/README.md", b""),
+    ];
+    let manifest = "dir:lib/rustlib/x86_64-unknown-linux-gnu/lib\n\
+        file:bin/rustc\nfile:share/doc/rust/README.md\n";
+    let package = package(&dir, files, manifest);
+    let out = dir.join("out");
+    install_nix(&package, "rustc", &out).unwrap();
+    install_nix(&package, "rustc", &out).unwrap();
+
+    assert!(out.join("lib/rustlib/x86_64-unknown-linux-gnu/lib/libstd.rlib").is_file());
+    assert!(out.join("share/doc/rustc/README.md").is_file());
+    assert!(!out.join("manifest.in").exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = |path| fs::metadata(out.join(path)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("bin/rustc"), 0o755);
+        assert_eq!(mode("share/doc/rustc/README.md"), 0o644);
+    }
+    assert_eq!(fs::read_to_string(out.join("nix-support/rust-components")).unwrap(), "rustc\n");
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+#[test]
+fn test_install_nix_rpath() {
+    let dir = test_dir("install-nix-rpath");
+    let true_ = fs::read("/bin/true").unwrap();
+    let files: &[(&str, &[u8])] =
+        &[("bin/true", &true_), ("lib/rustlib/x86_64-unknown-linux-gnu/bin/true", &true_)];
+    let manifest = "file:bin/true\nfile:lib/rustlib/x86_64-unknown-linux-gnu/bin/true\n";
+    let package = package(&dir, files, manifest);
+    let out = dir.join("out");
+    install_nix(&package, "rustc", &out).unwrap();
+
+    let has_rpath = |path: &str, rpath: &str| {
+        let data = fs::read(out.join(path)).unwrap();
+        data.windows(rpath.len()).any(|window| window == rpath.as_bytes())
+    };
+    assert!(has_rpath("bin/true", "$ORIGIN/../lib"));
+    assert!(has_rpath("lib/rustlib/x86_64-unknown-linux-gnu/bin/true", "$ORIGIN/../../../../lib"));
+    // The unpacked tarball is left alone.
+    assert_eq!(fs::read(package.join("rustc/bin/true")).unwrap(), true_);
+    let status = std::process::Command::new(out.join("bin/true")).status().unwrap();
+    assert!(status.success());
+}
@@ -126,17 +126,8 @@ pub fn prebuilt_llvm_config(
     let llvm_cmake_dir = out_dir.join("lib/cmake/llvm");
     let res = LlvmResult { llvm_config: build_llvm_config, llvm_cmake_dir };
 
-    static STAMP_HASH_MEMO: OnceLock<String> = OnceLock::new();
-    let smart_stamp_hash = STAMP_HASH_MEMO.get_or_init(|| {
-        generate_smart_stamp_hash(
-            builder,
-            &builder.config.src.join("src/llvm-project"),
-            builder.in_tree_llvm_info.sha().unwrap_or_default(),
-        )
-    });
-
     let stamp = out_dir.join("llvm-finished-building");
-    let stamp = HashStamp::new(stamp, Some(smart_stamp_hash));
+    let stamp = HashStamp::new(stamp, Some(in_tree_llvm_hash(builder)));
 
     if stamp.is_done() {
         if stamp.hash.is_none() {
@@ -155,6 +146,19 @@ pub fn prebuilt_llvm_config(
     LlvmBuildStatus::ShouldBuild(Meta { stamp, res, out_dir, root: root.into() })
 }
 
+/// Identifies the state of the in-tree LLVM sources, including local changes. LLVM is rebuilt
+/// when this changes.
+pub(crate) fn in_tree_llvm_hash(builder: &Builder<'_>) -> &'static str {
+    static STAMP_HASH_MEMO: OnceLock<String> = OnceLock::new();
+    STAMP_HASH_MEMO.get_or_init(|| {
+        generate_smart_stamp_hash(
+            builder,
+            &builder.config.src.join("src/llvm-project"),
+            builder.in_tree_llvm_info.sha().unwrap_or_default(),
+        )
+    })
+}
+
 /// This retrieves the LLVM sha we *want* to use, according to git history.
 pub(crate) fn detect_llvm_sha(config: &Config, is_git: bool) -> String {
     let llvm_sha = if is_git {
@@ -280,6 +284,10 @@ impl Step for Llvm {
         run.path("src/llvm-project").path("src/llvm-project/llvm")
     }
 
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
     fn make_run(run: RunConfig<'_>) {
         run.builder.ensure(Llvm { target: run.target });
     }
@@ -509,7 +517,7 @@ impl Step for Llvm {
         if target != builder.config.build {
             let LlvmResult { llvm_config, .. } =
                 builder.ensure(Llvm { target: builder.config.build });
-            if !builder.config.dry_run() {
+            if !builder.dry_run() {
                 let llvm_bindir =
                     command(&llvm_config).arg("--bindir").run_capture_stdout(builder).stdout();
                 let host_bin = Path::new(llvm_bindir.trim());
@@ -524,7 +532,7 @@ impl Step for Llvm {
             if builder.config.llvm_clang {
                 let build_bin = builder.llvm_out(builder.config.build).join("build").join("bin");
                 let clang_tblgen = build_bin.join("clang-tblgen").with_extension(EXE_EXTENSION);
-                if !builder.config.dry_run() && !clang_tblgen.exists() {
+                if !builder.dry_run() && !clang_tblgen.exists() {
                     panic!("unable to find {}", clang_tblgen.display());
                 }
                 cfg.define("CLANG_TABLEGEN", clang_tblgen);
@@ -553,7 +561,7 @@ impl Step for Llvm {
             cfg.define(key, val);
         }
 
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             return res;
         }
 
@@ -615,7 +623,7 @@ impl Step for Llvm {
 }
 
 fn check_llvm_version(builder: &Builder<'_>, llvm_config: &Path) {
-    if builder.config.dry_run() {
+    if builder.dry_run() {
         return;
     }
 
@@ -915,7 +923,7 @@ impl Step for Enzyme {
             "src/tools/enzyme",
             Some("The Enzyme sources are required for autodiff."),
         );
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             let out_dir = builder.enzyme_out(self.target);
             return out_dir;
         }
@@ -1005,7 +1013,7 @@ impl Step for Lld {
 
     /// Compile LLD for `target`.
     fn run(self, builder: &Builder<'_>) -> PathBuf {
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             return PathBuf::from("lld-out-dir-test-gen");
         }
         let target = self.target;
@@ -1138,7 +1146,7 @@ impl Step for Sanitizers {
         }
 
         let LlvmResult { llvm_config, .. } = builder.ensure(Llvm { target: builder.config.build });
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             return runtimes;
         }
 
@@ -1312,7 +1320,7 @@ impl Step for CrtBeginEnd {
 
         let out_dir = builder.native_dir(self.target).join("crt");
 
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             return out_dir;
         }
 
@@ -1386,7 +1394,7 @@ impl Step for Libunwind {
             Some("The LLVM sources are required for libunwind."),
         );
 
-        if builder.config.dry_run() {
+        if builder.dry_run() {
             return PathBuf::new();
         }
 
@@ -9,6 +9,7 @@ pub(crate) mod gcc;
 pub(crate) mod install;
 pub(crate) mod llvm;
 pub(crate) mod perf;
+pub(crate) mod replay;
 pub(crate) mod run;
 pub(crate) mod setup;
 pub(crate) mod suggest;
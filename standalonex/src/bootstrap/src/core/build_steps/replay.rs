@@ -0,0 +1,242 @@
+//! Implementation of `x replay`.
+//!
+//! This re-executes the commands recorded with `--invocation-log`, without going through any of
+//! bootstrap's steps, and checks that each of them exits the same way as during the recording.
+//!
+//! Commands are started in the recorded order. When the step graph of the same build is given,
+//! a command only waits for the earlier commands of steps it is related to (i.e. one of the steps
+//! transitively ensures the other), so commands of unrelated steps can run in parallel.
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc;
+use std::{fs, thread};
+
+use build_helper::exit;
+use serde_derive::Deserialize;
+
+use crate::core::builder::Builder;
+use crate::utils::helpers::t;
+use crate::utils::invocation_log::{self, Invocation};
+
+#[cfg(test)]
+mod tests;
+
+/// The parts of the graph written by `--graph-out` that are needed to order commands.
+#[derive(Deserialize)]
+struct StepGraph {
+    edges: Vec<StepEdge>,
+}
+
+#[derive(Deserialize)]
+struct StepEdge {
+    parent: usize,
+    child: usize,
+}
+
+/// Decides which earlier commands a command has to wait for.
+enum Ordering {
+    /// Every command waits for all the previous ones.
+    Sequential,
+    /// `reachable[a][b]` is whether step `a` transitively ensures step `b`.
+    Graph { reachable: Vec<Vec<bool>> },
+}
+
+impl Ordering {
+    fn from_graph(graph: &StepGraph) -> Ordering {
+        let len = graph.edges.iter().map(|e| e.parent.max(e.child) + 1).max().unwrap_or(0);
+        let mut children = vec![vec![]; len];
+        for edge in &graph.edges {
+            children[edge.parent].push(edge.child);
+        }
+
+        let mut reachable = vec![vec![false; len]; len];
+        for (start, reachable) in reachable.iter_mut().enumerate() {
+            let mut stack = vec![start];
+            while let Some(node) = stack.pop() {
+                for &child in &children[node] {
+                    if !reachable[child] {
+                        reachable[child] = true;
+                        stack.push(child);
+                    }
+                }
+            }
+        }
+        Ordering::Graph { reachable }
+    }
+
+    /// Whether a command run by step `later` has to wait for an earlier command run by `earlier`.
+    fn must_wait(&self, earlier: Option<usize>, later: Option<usize>) -> bool {
+        let Ordering::Graph { reachable } = self else { return true };
+        // Commands run outside of any step, or by steps the graph doesn't know about, could
+        // depend on anything.
+        let (Some(a), Some(b)) = (earlier, later) else { return true };
+        if a >= reachable.len() || b >= reachable.len() {
+            return true;
+        }
+        a == b || reachable[a][b] || reachable[b][a]
+    }
+}
+
+/// How a command exited, either during the recording or during the replay.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    started: bool,
+    success: bool,
+    exit_code: Option<i32>,
+}
+
+impl Outcome {
+    fn recorded(invocation: &Invocation) -> Outcome {
+        Outcome {
+            started: invocation.started,
+            success: invocation.success,
+            exit_code: invocation.exit_code,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Outcome { started: false, .. } => "did not start".to_owned(),
+            Outcome { exit_code: Some(code), .. } => format!("exit code {code}"),
+            Outcome { success: true, .. } => "success".to_owned(),
+            Outcome { success: false, .. } => "terminated by a signal".to_owned(),
+        }
+    }
+}
+
+pub fn replay(builder: &Builder<'_>, log: &Path, graph: Option<&Path>) {
+    // Commands skipped by `--dry-run` weren't run during the recording either.
+    let invocations: Vec<(usize, Invocation)> = invocation_log::read(log)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, invocation)| !invocation.dry_run_skipped)
+        .collect();
+
+    let (ordering, jobs) = match graph {
+        Some(graph) => {
+            let contents = t!(fs::read_to_string(graph), graph.display().to_string());
+            let graph: StepGraph = t!(serde_json::from_str(&contents), graph.display().to_string());
+            (Ordering::from_graph(&graph), builder.jobs() as usize)
+        }
+        None => (Ordering::Sequential, 1),
+    };
+
+    builder.info(&format!("Replaying {} commands from {}", invocations.len(), log.display()));
+    if builder.dry_run() {
+        for (index, invocation) in &invocations {
+            println!("#{index}: {:?}", command(invocation));
+        }
+        return;
+    }
+
+    let Some(divergence) = run_all(&invocations, &ordering, jobs, builder.is_verbose()) else {
+        println!("All {} commands exited as recorded.", invocations.len());
+        return;
+    };
+
+    let (index, invocation) = &invocations[divergence.position];
+    eprintln!("ERROR: replay diverged from the recording at command #{index}:");
+    eprintln!("    {:?}", command(invocation));
+    if let Some(step) = &invocation.step {
+        eprintln!("Step: {step}");
+    }
+    eprintln!("Created at: {}", invocation.created_at);
+    eprintln!("Executed at: {}", invocation.executed_at);
+    eprintln!("Recorded: {}", Outcome::recorded(invocation).describe());
+    eprintln!("Replayed: {}", divergence.outcome.describe());
+    exit!(1);
+}
+
+struct Divergence {
+    /// Position of the command in the list of replayed invocations.
+    position: usize,
+    outcome: Outcome,
+}
+
+/// Runs `invocations` with up to `jobs` commands at a time, and returns the earliest command that
+/// didn't exit as recorded. No new commands are started once a divergence has been found.
+fn run_all(
+    invocations: &[(usize, Invocation)],
+    ordering: &Ordering,
+    jobs: usize,
+    verbose: bool,
+) -> Option<Divergence> {
+    let (tx, rx) = mpsc::channel();
+    let mut finished = vec![false; invocations.len()];
+    // All commands before this position have finished.
+    let mut finished_prefix = 0;
+    let mut running = 0;
+    let mut divergence: Option<Divergence> = None;
+
+    let wait_for_one = |finished: &mut Vec<bool>, divergence: &mut Option<Divergence>| {
+        let (position, outcome): (usize, Outcome) = rx.recv().unwrap();
+        finished[position] = true;
+        if outcome != Outcome::recorded(&invocations[position].1)
+            && divergence.as_ref().is_none_or(|d| position < d.position)
+        {
+            *divergence = Some(Divergence { position, outcome });
+        }
+    };
+
+    thread::scope(|s| {
+        for (position, (_, invocation)) in invocations.iter().enumerate() {
+            loop {
+                while finished_prefix < position && finished[finished_prefix] {
+                    finished_prefix += 1;
+                }
+                let blocked = running >= jobs
+                    || (finished_prefix..position).any(|i| {
+                        !finished[i]
+                            && ordering.must_wait(invocations[i].1.step_id, invocation.step_id)
+                    });
+                if !blocked || divergence.is_some() {
+                    break;
+                }
+                wait_for_one(&mut finished, &mut divergence);
+                running -= 1;
+            }
+            if divergence.is_some() {
+                break;
+            }
+
+            running += 1;
+            let tx = tx.clone();
+            s.spawn(move || {
+                let mut cmd = command(invocation);
+                if verbose {
+                    println!("replaying: {cmd:?}");
+                }
+                let outcome = match cmd.status() {
+                    Ok(status) => Outcome {
+                        started: true,
+                        success: status.success(),
+                        exit_code: status.code(),
+                    },
+                    Err(_) => Outcome { started: false, success: false, exit_code: None },
+                };
+                tx.send((position, outcome)).unwrap();
+            });
+        }
+
+        while running > 0 {
+            wait_for_one(&mut finished, &mut divergence);
+            running -= 1;
+        }
+    });
+
+    divergence
+}
+
+/// Rebuilds the recorded command, on top of the environment of the current process.
+fn command(invocation: &Invocation) -> Command {
+    let mut cmd = Command::new(&invocation.program);
+    cmd.args(&invocation.args).envs(&invocation.env);
+    for key in &invocation.env_remove {
+        cmd.env_remove(key);
+    }
+    if let Some(cwd) = &invocation.cwd {
+        cmd.current_dir(cwd);
+    }
+    cmd
+}
@@ -0,0 +1,75 @@
+use std::panic::Location;
+
+use super::{Ordering, Outcome, StepEdge, StepGraph, run_all};
+use crate::utils::exec::command;
+use crate::utils::invocation_log::Invocation;
+
+fn invocation(program: &str, step_id: Option<usize>, exit_code: i32) -> Invocation {
+    let mut cmd = command(program);
+    cmd.mark_as_executed();
+    let mut invocation =
+        Invocation::new(&cmd, cmd.get_created_location(), Location::caller(), None);
+    invocation.step_id = step_id;
+    invocation.started = true;
+    invocation.success = exit_code == 0;
+    invocation.exit_code = Some(exit_code);
+    invocation
+}
+
+#[test]
+fn test_ordering() {
+    // 0 -> 1 -> 2, 0 -> 3
+    let graph = StepGraph {
+        edges: vec![
+            StepEdge { parent: 0, child: 1 },
+            StepEdge { parent: 1, child: 2 },
+            StepEdge { parent: 0, child: 3 },
+        ],
+    };
+    let ordering = Ordering::from_graph(&graph);
+    assert!(ordering.must_wait(Some(2), Some(0)));
+    assert!(ordering.must_wait(Some(0), Some(2)));
+    assert!(ordering.must_wait(Some(1), Some(1)));
+    assert!(!ordering.must_wait(Some(2), Some(3)));
+    assert!(!ordering.must_wait(Some(3), Some(1)));
+    assert!(ordering.must_wait(None, Some(3)));
+    assert!(ordering.must_wait(Some(7), Some(3)));
+
+    assert!(Ordering::Sequential.must_wait(Some(2), Some(3)));
+}
+
+#[test]
+fn test_outcome_describe() {
+    let outcome = |started, success, exit_code| Outcome { started, success, exit_code };
+    assert_eq!(outcome(false, false, None).describe(), "did not start");
+    assert_eq!(outcome(true, false, Some(101)).describe(), "exit code 101");
+    assert_eq!(outcome(true, false, None).describe(), "terminated by a signal");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_all() {
+    let ordering =
+        Ordering::from_graph(&StepGraph { edges: vec![StepEdge { parent: 0, child: 1 }] });
+    let invocations = vec![
+        (0, invocation("true", Some(0), 0)),
+        (1, invocation("false", Some(1), 1)),
+        (2, invocation("true", Some(2), 0)),
+    ];
+    for jobs in [1, 4] {
+        assert!(run_all(&invocations, &ordering, jobs, false).is_none());
+    }
+
+    let diverging = vec![
+        (0, invocation("true", None, 0)),
+        (1, invocation("false", None, 0)),
+        (2, invocation("true", None, 0)),
+    ];
+    let divergence = run_all(&diverging, &Ordering::Sequential, 1, false).unwrap();
+    assert_eq!(divergence.position, 1);
+    assert_eq!(divergence.outcome, Outcome { started: true, success: false, exit_code: Some(1) });
+
+    let missing = vec![(0, invocation("/nonexistent/bootstrap-replay-test", None, 0))];
+    let divergence = run_all(&missing, &Ordering::Sequential, 1, false).unwrap();
+    assert!(!divergence.outcome.started);
+}
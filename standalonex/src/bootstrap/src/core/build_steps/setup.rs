@@ -11,12 +11,15 @@ use std::fs::File;
 use std::io::Write;
 use std::path::{MAIN_SEPARATOR_STR, Path, PathBuf};
 use std::str::FromStr;
-use std::{fmt, fs, io};
+use std::{env, fmt, fs, io};
 
 use sha2::Digest;
 
 use crate::core::builder::{Builder, RunConfig, ShouldRun, Step};
-use crate::utils::change_tracker::CONFIG_CHANGE_HISTORY;
+use crate::core::config::flags::Flags;
+use crate::utils::change_tracker::{
+    CONFIG_CHANGE_HISTORY, ConfigEdit, human_readable_changes, migrate_config,
+};
 use crate::utils::exec::command;
 use crate::utils::helpers::{self, hex_encode};
 use crate::{Config, t};
@@ -221,12 +224,93 @@ fn setup_config_toml(path: &PathBuf, profile: Profile, config: &Config) {
     change-id = {latest_change_id}\n"
     );
 
+    config.assert_writable(path);
     t!(fs::write(path, settings));
 
     let include_path = profile.include_path(&config.src);
     println!("`x.py` will now use the configuration at {}", include_path.display());
 }
 
+/// Implements `x setup --migrate`: shows the edits the changes since the `change-id` of
+/// `config.toml` call for, and applies them if the user accepts.
+///
+/// This runs before the configuration is parsed, which the keys being migrated can prevent.
+pub fn migrate_config_toml(flags: &Flags) {
+    if flags.config_json.is_some() {
+        eprintln!("ERROR: `x setup --migrate` can only update a TOML configuration");
+        crate::exit!(1);
+    }
+    // The same lookup as `Config::parse_inner`.
+    let path =
+        flags.config.clone().or_else(|| env::var_os("RUST_BOOTSTRAP_CONFIG").map(PathBuf::from));
+    let path = &path.unwrap_or_else(|| {
+        let path = PathBuf::from("config.toml");
+        if path.exists() { path } else { Config::default_opts().src.join(path) }
+    });
+    if !path.exists() {
+        eprintln!(
+            "ERROR: there is no `{}` to migrate, run `x setup` to create one",
+            path.display()
+        );
+        crate::exit!(1);
+    }
+    let contents = t!(fs::read_to_string(path));
+    // Not a `TomlConfig`, the keys being migrated may not exist anymore.
+    let toml: toml::Value = t!(toml::from_str(&contents));
+    let current_id = toml.get("change-id").and_then(|id| id.as_integer()).map(|id| id as usize);
+
+    let (migrated, edits) = migrate_config(&contents, current_id);
+    if edits.is_empty() {
+        println!("`{}` is up to date.", path.display());
+        return;
+    }
+    let changes: Vec<_> = CONFIG_CHANGE_HISTORY
+        .iter()
+        .filter(|change| edits.iter().any(|edit| edit.change_id == Some(change.change_id)))
+        .cloned()
+        .collect();
+    if !changes.is_empty() {
+        println!("The following changes call for edits to `{}`:", path.display());
+        println!("{}", human_readable_changes(&changes));
+    }
+    println!("{}", migration_diff(path, &edits));
+
+    match prompt_user("Apply these edits? [y/N]") {
+        Ok(Some(PromptResult::Yes)) => {
+            t!(fs::write(path, migrated));
+            println!("Updated `{}`.", path.display());
+        }
+        _ => println!("Leaving `{}` unchanged.", path.display()),
+    }
+}
+
+/// Renders the edits to `path` like a diff, with the change requiring each of them.
+fn migration_diff(path: &Path, edits: &[ConfigEdit]) -> String {
+    let mut diff = String::new();
+    let mut previous: Option<&ConfigEdit> = None;
+    for edit in edits {
+        // Consecutive lines removed together, like a multi-line array, are a single hunk.
+        let continued = previous.is_some_and(|previous| {
+            previous.line + 1 == edit.line && previous.change_id == edit.change_id
+        });
+        if !continued {
+            let reason = match edit.change_id {
+                Some(id) => format!("for #{id}"),
+                None => "latest change-id".to_owned(),
+            };
+            writeln!(diff, "@@ {}:{} ({reason}) @@", path.display(), edit.line + 1).unwrap();
+        }
+        previous = Some(edit);
+        if let Some(old) = &edit.old {
+            writeln!(diff, "-{old}").unwrap();
+        }
+        if let Some(new) = &edit.new {
+            writeln!(diff, "+{new}").unwrap();
+        }
+    }
+    diff
+}
+
 /// Creates a toolchain link for stage1 using `rustup`
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Link;
@@ -499,6 +583,7 @@ undesirable, simply delete the `pre-push` file from .git/hooks."
         println!("Ok, skipping installation!");
         return Ok(());
     }
+    config.assert_writable(&dst);
     if !hooks_dir.exists() {
         // We need to (try to) create the hooks directory first.
         let _ = fs::create_dir(hooks_dir);
@@ -710,6 +795,7 @@ fn create_editor_settings_maybe(config: &Config, editor: EditorKind) -> io::Resu
         }
     };
     if should_create {
+        config.assert_writable(&settings_path);
         let settings_folder_path = config.src.join(editor.settings_folder());
         if !settings_folder_path.exists() {
             fs::create_dir(settings_folder_path)?;
@@ -51,7 +51,7 @@ fn create_synthetic_target(
     let path = builder.out.join("synthetic-target-specs").join(format!("{name}.json"));
     std::fs::create_dir_all(path.parent().unwrap()).unwrap();
 
-    if builder.config.dry_run() {
+    if builder.dry_run() {
         std::fs::write(&path, b"dry run\n").unwrap();
         return TargetSelection::create_synthetic(&name, path.to_str().unwrap());
     }
@@ -1,14 +1,15 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 use crate::core::build_steps::compile;
 use crate::core::build_steps::toolstate::ToolState;
 use crate::core::builder;
-use crate::core::builder::{Builder, Cargo as CargoCommand, RunConfig, ShouldRun, Step};
+use crate::core::builder::{Builder, Cargo as CargoCommand, RunConfig, ShouldRun, Step, StepIo};
 use crate::core::config::TargetSelection;
 use crate::utils::channel::GitInfo;
 use crate::utils::exec::{BootstrapCommand, command};
 use crate::utils::helpers::{add_dylib_path, exe, t};
+use crate::utils::step_cache::{PersistentOutput, StepInputs};
 use crate::{Compiler, Kind, Mode, gha};
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -65,6 +66,50 @@ impl Step for ToolBuild {
         run.never()
     }
 
+    fn stage(&self) -> Option<u32> {
+        Some(self.compiler.stage)
+    }
+
+    fn target(&self) -> Option<TargetSelection> {
+        Some(self.target)
+    }
+
+    fn persistent_output(&self, builder: &Builder<'_>) -> Option<PersistentOutput<PathBuf>> {
+        // Only tools built by the stage0 compiler don't depend on the in-tree compiler or library.
+        if self.mode != Mode::ToolBootstrap {
+            return None;
+        }
+        // Tools outside of the workspace have path dependencies bootstrap doesn't know about.
+        let krate = builder.crate_paths.get(Path::new(self.path))?;
+
+        let mut inputs = StepInputs::new(self);
+        inputs
+            .toolchain(builder)
+            .config("channel", &builder.config.channel)
+            .config("rust.optimize", &builder.config.rust_optimize)
+            .config("rust.debuginfo-level-tools", builder.config.rust_debuginfo_level_tools)
+            .config("rust.codegen-units", builder.config.rust_codegen_units)
+            .flags(builder, self.target)
+            .source(&builder.src.join("Cargo.lock"));
+        for krate in builder.in_tree_crates(krate, None) {
+            inputs.source(&krate.path);
+        }
+        Some(PersistentOutput::new(inputs, |bin: &PathBuf| vec![bin.clone()]))
+    }
+
+    fn declared_io(&self, builder: &Builder<'_>) -> StepIo {
+        // See the `tidy` HACK in `run`.
+        let tool = if self.tool == "tidy" { "rust-tidy" } else { self.tool };
+        StepIo {
+            inputs: vec![builder.src.join(self.path), builder.src.join("Cargo.lock")],
+            outputs: vec![builder.tools_dir_path(self.compiler).join(exe(tool, self.target))],
+            tools: vec![
+                builder.initial_cargo.clone(),
+                compile::Sysroot::rustc(builder, self.compiler),
+            ],
+        }
+    }
+
     /// Builds a tool in `src/tools`
     ///
     /// This will build the specified tool with the specified `host` compiler in
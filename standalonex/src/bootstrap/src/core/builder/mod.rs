@@ -23,6 +23,7 @@ use crate::core::config::{DryRun, TargetSelection};
 use crate::utils::cache::Cache;
 use crate::utils::exec::{BootstrapCommand, command};
 use crate::utils::helpers::{self, LldThreads, add_dylib_path, exe, libdir, linker_args, t};
+use crate::utils::step_cache::PersistentOutput;
 use crate::{Build, Crate};
 
 #[cfg(test)]
@@ -67,6 +68,23 @@ impl Deref for Builder<'_> {
     }
 }
 
+/// The inputs and outputs of a step, as returned by [`Step::declared_io`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StepIo {
+    /// Files and directories the step reads, other than the outputs of the steps it depends on.
+    pub inputs: Vec<PathBuf>,
+    /// Files and directories the step produces.
+    pub outputs: Vec<PathBuf>,
+    /// Programs the step runs.
+    pub tools: Vec<PathBuf>,
+}
+
+impl StepIo {
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty() && self.outputs.is_empty() && self.tools.is_empty()
+    }
+}
+
 pub trait Step: 'static + Clone + Debug + PartialEq + Eq + Hash {
     /// Result type of `Step::run`.
     type Output: Clone;
@@ -99,6 +117,40 @@ pub trait Step: 'static + Clone + Debug + PartialEq + Eq + Hash {
     /// Determines if this `Step` should be run when given specific paths (e.g., `x build $path`).
     fn should_run(run: ShouldRun<'_>) -> ShouldRun<'_>;
 
+    /// Opts this step into the on-disk step cache (`build.step-cache`), by describing everything
+    /// its output depends on. Only steps whose output is fully determined by these inputs, and
+    /// that have no side effects other than producing the output files, should do this.
+    // The step cache is internal to bootstrap, even though `Step` isn't.
+    #[allow(private_interfaces)]
+    fn persistent_output(&self, _builder: &Builder<'_>) -> Option<PersistentOutput<Self::Output>> {
+        None
+    }
+
+    /// Called instead of [`Step::run`] when the output was restored from the step cache, to do
+    /// what `run` does other than producing the output files, e.g. linking them into a sysroot.
+    fn restored(&self, _builder: &Builder<'_>, _output: &Self::Output) {}
+
+    /// The stage of the compiler this step uses, if it uses one. This only describes the step,
+    /// e.g. in the graph written with `--graph-out`.
+    fn stage(&self) -> Option<u32> {
+        None
+    }
+
+    /// The target this step builds for, if any. Like [`Step::stage`], this only describes the step.
+    fn target(&self) -> Option<TargetSelection> {
+        None
+    }
+
+    /// Declares the files this step reads and produces, and the programs it runs, so that the
+    /// step can be described on its own to an external build system (see `--emit=nix`).
+    ///
+    /// This is called once the step has run, and must not have side effects: in particular, it
+    /// must not ensure any step. Steps that don't implement it are folded into the step that
+    /// ensured them.
+    fn declared_io(&self, _builder: &Builder<'_>) -> StepIo {
+        StepIo::default()
+    }
+
     /// Called directly by the bootstrap `Step` handler when not triggered indirectly by other `Step`s using [`Builder::ensure`].
     /// For example, `./x.py test bootstrap` runs this for `test::Bootstrap`. Similarly, `./x.py test` runs it for every step
     /// that is listed by the `describe` macro in [`Builder::get_step_descriptions`].
@@ -720,6 +772,11 @@ pub enum Kind {
     Suggest,
     Vendor,
     Perf,
+    Replay,
+    Config,
+    CheckConfig,
+    Fetch,
+    Cache,
 }
 
 impl Kind {
@@ -744,6 +801,11 @@ impl Kind {
             Kind::Suggest => "suggest",
             Kind::Vendor => "vendor",
             Kind::Perf => "perf",
+            Kind::Replay => "replay",
+            Kind::Config => "config",
+            Kind::CheckConfig => "check-config",
+            Kind::Fetch => "fetch",
+            Kind::Cache => "cache",
         }
     }
 
@@ -1015,7 +1077,14 @@ impl<'a> Builder<'a> {
             Kind::Clean => describe!(clean::CleanAll, clean::Rustc, clean::Std),
             Kind::Vendor => describe!(vendor::Vendor),
             // special-cased in Build::build()
-            Kind::Format | Kind::Suggest | Kind::Perf => vec![],
+            Kind::Format
+            | Kind::Suggest
+            | Kind::Perf
+            | Kind::Replay
+            | Kind::Config
+            | Kind::CheckConfig
+            | Kind::Fetch
+            | Kind::Cache => vec![],
             Kind::MiriTest | Kind::MiriSetup => unreachable!(),
         }
     }
@@ -1077,18 +1146,23 @@ impl<'a> Builder<'a> {
             Subcommand::Test { .. } => (Kind::Test, &paths[..]),
             Subcommand::Miri { .. } => (Kind::Miri, &paths[..]),
             Subcommand::Bench { .. } => (Kind::Bench, &paths[..]),
-            Subcommand::Dist => (Kind::Dist, &paths[..]),
-            Subcommand::Install => (Kind::Install, &paths[..]),
+            Subcommand::Dist { .. } => (Kind::Dist, &paths[..]),
+            Subcommand::Install { .. } => (Kind::Install, &paths[..]),
             Subcommand::Run { .. } => (Kind::Run, &paths[..]),
             Subcommand::Clean { .. } => (Kind::Clean, &paths[..]),
             Subcommand::Format { .. } => (Kind::Format, &[][..]),
             Subcommand::Suggest { .. } => (Kind::Suggest, &[][..]),
-            Subcommand::Setup { profile: ref path } => (
+            Subcommand::Setup { profile: ref path, .. } => (
                 Kind::Setup,
                 path.as_ref().map_or([].as_slice(), |path| std::slice::from_ref(path)),
             ),
             Subcommand::Vendor { .. } => (Kind::Vendor, &paths[..]),
             Subcommand::Perf { .. } => (Kind::Perf, &paths[..]),
+            Subcommand::Replay { .. } => (Kind::Replay, &[][..]),
+            Subcommand::Config { .. } => (Kind::Config, &[][..]),
+            Subcommand::CheckConfig => (Kind::CheckConfig, &[][..]),
+            Subcommand::Fetch { .. } => (Kind::Fetch, &[][..]),
+            Subcommand::Cache { .. } => (Kind::Cache, &[][..]),
         };
 
         Self::new_internal(build, kind, paths.to_owned())
@@ -1403,7 +1477,7 @@ impl<'a> Builder<'a> {
     /// Note that this returns `None` if LLVM is disabled, or if we're in a
     /// check build or dry-run, where there's no need to build all of LLVM.
     fn llvm_config(&self, target: TargetSelection) -> Option<PathBuf> {
-        if self.config.llvm_enabled(target) && self.kind != Kind::Check && !self.config.dry_run() {
+        if self.config.llvm_enabled(target) && self.kind != Kind::Check && !self.dry_run() {
             let llvm::LlvmResult { llvm_config, .. } = self.ensure(llvm::Llvm { target });
             if llvm_config.is_file() {
                 return Some(llvm_config);
@@ -1432,6 +1506,10 @@ impl<'a> Builder<'a> {
             }
             if let Some(out) = self.cache.get(&step) {
                 self.verbose_than(1, || println!("{}c {:?}", "  ".repeat(stack.len()), step));
+                self.step_graph.record_cached(&step, self);
+                if let Some(nix_executor) = &self.nix_executor {
+                    nix_executor.reuse_step(type_name::<S>(), &step);
+                }
 
                 return out;
             }
@@ -1439,6 +1517,11 @@ impl<'a> Builder<'a> {
             stack.push(Box::new(step.clone()));
         }
 
+        self.step_graph.enter_step(&step, self);
+        if let Some(nix_executor) = &self.nix_executor {
+            nix_executor.enter_step();
+        }
+
         #[cfg(feature = "build-metrics")]
         self.metrics.enter_step(&step, self);
 
@@ -1446,13 +1529,16 @@ impl<'a> Builder<'a> {
             let start = Instant::now();
             let zero = Duration::new(0, 0);
             let parent = self.time_spent_on_dependencies.replace(zero);
-            let out = step.clone().run(self);
+            let out = match &self.step_cache {
+                Some(step_cache) if !self.dry_run() => step_cache.run(self, step.clone()),
+                _ => step.clone().run(self),
+            };
             let dur = start.elapsed();
             let deps = self.time_spent_on_dependencies.replace(parent + dur);
             (out, dur - deps)
         };
 
-        if self.config.print_step_timings && !self.config.dry_run() {
+        if self.config.print_step_timings && !self.dry_run() {
             let step_string = format!("{step:?}");
             let brace_index = step_string.find('{').unwrap_or(0);
             let type_string = type_name::<S>();
@@ -1468,13 +1554,18 @@ impl<'a> Builder<'a> {
         #[cfg(feature = "build-metrics")]
         self.metrics.exit_step(self);
 
+        self.step_graph.exit_step(self);
+
         {
             let mut stack = self.stack.borrow_mut();
             let cur_step = stack.pop().expect("step stack empty");
             assert_eq!(cur_step.downcast_ref(), Some(&step));
         }
         self.verbose_than(1, || println!("{}< {:?}", "  ".repeat(self.stack.borrow().len()), step));
-        self.cache.put(step, out.clone());
+        self.cache.put(step.clone(), out.clone());
+        if let Some(nix_executor) = &self.nix_executor {
+            t!(nix_executor.exit_step(type_name::<S>(), &step, || step.declared_io(self)));
+        }
         out
     }
 
@@ -1526,7 +1617,7 @@ impl<'a> Builder<'a> {
     }
 
     pub(crate) fn open_in_browser(&self, path: impl AsRef<Path>) {
-        if self.config.dry_run() || !self.config.cmd.open() {
+        if self.dry_run() || !self.config.cmd.open() {
             return;
         }
 
@@ -245,6 +245,30 @@ fn ci_rustc_if_unchanged_logic() {
     }
 }
 
+/// Test runners go through `Build::run` like any other command, so that `x replay` can re-run
+/// them from the invocation log.
+#[test]
+fn test_runs_are_logged() {
+    use crate::utils::invocation_log::{self, INVOCATION_LOG_FILENAME};
+    use crate::utils::render_tests::try_run_tests;
+
+    let mut config = configure("test", &["A-A"], &["A-A"]);
+    config.dry_run = DryRun::UserSelected;
+    config.invocation_log = true;
+    let build = Build::new(config);
+    let builder = Builder::new(&build);
+
+    let mut cmd = command("test-runner");
+    cmd.arg("--format=json");
+    assert!(try_run_tests(&builder, &mut cmd, false));
+
+    let log = invocation_log::read(&build.out.join(INVOCATION_LOG_FILENAME));
+    let invocation = log.iter().find(|invocation| invocation.program == "test-runner").unwrap();
+    assert_eq!(invocation.args, ["--format=json"]);
+    assert_eq!(invocation.failure_behavior, "ignore");
+    assert!(invocation.dry_run_skipped);
+}
+
 mod defaults {
     use pretty_assertions::assert_eq;
 
@@ -6,23 +6,26 @@
 use std::cell::{Cell, RefCell};
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::{self, Display};
-use std::io::IsTerminal;
+use std::io::{self, IsTerminal};
 use std::path::{Path, PathBuf, absolute};
 use std::process::Command;
 use std::str::FromStr;
 use std::sync::OnceLock;
-use std::{cmp, env, fs};
+use std::{cmp, env, fs, mem};
 
 use build_helper::ci::CiEnv;
 use build_helper::exit;
 use build_helper::git::{GitConfig, get_closest_merge_commit, output_result};
-use serde::{Deserialize, Deserializer};
-use serde_derive::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
 
 use crate::core::build_steps::compile::CODEGEN_BACKEND_PREFIX;
 use crate::core::build_steps::llvm;
 pub use crate::core::config::flags::Subcommand;
-use crate::core::config::flags::{Color, Flags, Warnings};
+use crate::core::config::dump::{ConfigSource, ConfigSources};
+use crate::core::config::env_overrides::{self, EnvOverride};
+use crate::core::config::flags::{Color, EmitMode, Flags, Warnings};
+use crate::core::config::schema::{self, ConfigSchema};
 use crate::core::download::is_download_ci_available;
 use crate::utils::cache::{INTERNER, Interned};
 use crate::utils::channel::{self, GitInfo};
@@ -120,6 +123,22 @@ impl<'de> Deserialize<'de> for DebuginfoLevel {
     }
 }
 
+impl Serialize for DebuginfoLevel {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        use DebuginfoLevel::*;
+        serializer.serialize_str(match self {
+            None => "none",
+            LineDirectivesOnly => "line-directives-only",
+            LineTablesOnly => "line-tables-only",
+            Limited => "limited",
+            Full => "full",
+        })
+    }
+}
+
 /// Suitable for passing to `-C debuginfo`
 impl Display for DebuginfoLevel {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -189,10 +208,16 @@ pub struct Config {
     pub docs_minification: bool,
     pub docs: bool,
     pub locked_deps: bool,
+    /// Whether bootstrap must not write anything into `src`, see `build.read-only-src`.
+    pub read_only_src: bool,
     pub vendor: bool,
     pub target_config: HashMap<TargetSelection, Target>,
     pub full_bootstrap: bool,
     pub bootstrap_cache_path: Option<PathBuf>,
+    /// A directory with the layout of the download servers, see `build.download-mirror`.
+    pub download_mirror: Option<PathBuf>,
+    /// Whether to download with `curl` instead of bootstrap's HTTP client, see `build.use-curl`.
+    pub use_curl: bool,
     pub extended: bool,
     pub tools: Option<HashSet<String>>,
     pub sanitizers: bool,
@@ -205,6 +230,10 @@ pub struct Config {
     pub test_compare_mode: bool,
     pub color: Color,
     pub patch_binaries_for_nix: Option<bool>,
+    /// Dynamic linker to set on downloaded executables when patching them for Nix.
+    pub patch_binaries_interpreter: Option<String>,
+    /// Directory to add to the RPATH of downloaded binaries when patching them for Nix.
+    pub patch_binaries_rpath: Option<String>,
     pub stage0_metadata: build_helper::stage0_parser::Stage0,
     pub android_ndk: Option<PathBuf>,
     /// Whether to use the `c` feature of the `compiler_builtins` crate.
@@ -220,18 +249,29 @@ pub struct Config {
     pub src: PathBuf,
     /// defaults to `config.toml`
     pub config: Option<PathBuf>,
+    /// Whether the configuration was given as JSON, with `--config-json` or
+    /// `RUST_BOOTSTRAP_CONFIG_JSON`, instead of a TOML file.
+    pub config_json: bool,
+    /// The `[variant.<name>]` selected with `--variant`, built in `build/variants/<name>`.
+    pub variant: Option<String>,
     pub jobs: Option<u32>,
     pub cmd: Subcommand,
     pub incremental: bool,
     pub dry_run: DryRun,
+    /// What to do with the commands bootstrap would run, see [`EmitMode`].
+    pub emit: EmitMode,
     pub dump_bootstrap_shims: bool,
+    /// Where to write the step dependency graph, see `utils::step_graph`.
+    pub graph_out: Option<PathBuf>,
+    /// Whether to log every command to `invocations.jsonl`, see `utils::invocation_log`.
+    pub invocation_log: bool,
     /// Arguments appearing after `--` to be forwarded to tools,
     /// e.g. `--fix-broken` or test arguments.
     pub free_args: Vec<String>,
 
     /// `None` if we shouldn't download CI compiler artifacts, or the commit to download if we should.
     #[cfg(not(test))]
-    download_rustc_commit: Option<String>,
+    pub(crate) download_rustc_commit: Option<String>,
     #[cfg(test)]
     pub download_rustc_commit: Option<String>,
 
@@ -365,6 +405,8 @@ pub struct Config {
     pub cargo_native_static: bool,
     pub configure_args: Vec<String>,
     pub out: PathBuf,
+    /// `out` without `--variant`, where the variants share the stage0 toolchain and tools.
+    pub base_out: PathBuf,
     pub rust_info: channel::GitInfo,
 
     pub cargo_info: channel::GitInfo,
@@ -382,7 +424,7 @@ pub struct Config {
     pub initial_cargo_clippy: Option<PathBuf>,
 
     #[cfg(not(test))]
-    initial_rustfmt: RefCell<RustfmtState>,
+    pub(crate) initial_rustfmt: RefCell<RustfmtState>,
     #[cfg(test)]
     pub initial_rustfmt: RefCell<RustfmtState>,
 
@@ -392,6 +434,19 @@ pub struct Config {
 
     /// Command for visual diff display, e.g. `diff-tool --color=always`.
     pub compiletest_diff_tool: Option<String>,
+
+    /// Program that runs the commands of bootstrap instead of bootstrap itself,
+    /// see `utils::executor::ExternalExecutor`.
+    pub command_executor: Option<PathBuf>,
+
+    /// Directory of the on-disk step cache, see `utils::step_cache`.
+    pub step_cache: Option<PathBuf>,
+
+    /// Prefix of the stage0 toolchain given by `build.nix-toolchain`, which is also its sysroot.
+    pub nix_toolchain: Option<PathBuf>,
+
+    /// Where each configuration value comes from, for `x config dump`.
+    pub(crate) sources: ConfigSources,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -647,27 +702,95 @@ impl Target {
 /// This structure uses `Decodable` to automatically decode a TOML configuration
 /// file into this format, and then this is traversed and written into the above
 /// `Config` structure.
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Serialize, Default)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case")]
 pub(crate) struct TomlConfig {
     #[serde(flatten)]
     change_id: ChangeIdWrapper,
+    #[serde(skip_serializing_if = "Option::is_none")]
     build: Option<Build>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     install: Option<Install>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     llvm: Option<Llvm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     rust: Option<Rust>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     target: Option<HashMap<String, TomlTarget>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     dist: Option<Dist>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     profile: Option<String>,
+    /// Other config files to merge into this one, see `Config::merge_includes`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include: Option<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    variant: Option<HashMap<String, TomlVariant>>,
+}
+
+/// A named overlay of the configuration in `[variant.<name>]`, merged over it with
+/// `--variant <name>`.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TomlVariant {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build: Option<Build>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    install: Option<Install>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    llvm: Option<Llvm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rust: Option<Rust>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<HashMap<String, TomlTarget>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dist: Option<Dist>,
+}
+
+impl From<TomlVariant> for TomlConfig {
+    fn from(TomlVariant { build, install, llvm, rust, target, dist }: TomlVariant) -> Self {
+        TomlConfig { build, install, llvm, rust, target, dist, ..Default::default() }
+    }
+}
+
+impl ConfigSchema for TomlConfig {
+    fn schema() -> serde_json::Value {
+        schema::table([
+            ("change-id", usize::schema()),
+            ("build", Build::schema()),
+            ("install", Install::schema()),
+            ("llvm", Llvm::schema()),
+            ("rust", Rust::schema()),
+            ("target", HashMap::<String, TomlTarget>::schema()),
+            ("dist", Dist::schema()),
+            ("profile", String::schema()),
+            ("include", Vec::<PathBuf>::schema()),
+            ("variant", HashMap::<String, TomlVariant>::schema()),
+        ])
+    }
+}
+
+impl ConfigSchema for TomlVariant {
+    fn schema() -> serde_json::Value {
+        schema::table([
+            ("build", Build::schema()),
+            ("install", Install::schema()),
+            ("llvm", Llvm::schema()),
+            ("rust", Rust::schema()),
+            ("target", HashMap::<String, TomlTarget>::schema()),
+            ("dist", Dist::schema()),
+        ])
+    }
 }
 
 /// Since we use `#[serde(deny_unknown_fields)]` on `TomlConfig`, we need a wrapper type
 /// for the "change-id" field to parse it even if other fields are invalid. This ensures
 /// that if deserialization fails due to other fields, we can still provide the changelogs
 /// to allow developers to potentially find the reason for the failure in the logs..
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Serialize, Default)]
 pub(crate) struct ChangeIdWrapper {
-    #[serde(alias = "change-id")]
+    #[serde(alias = "change-id", rename(serialize = "change-id"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) inner: Option<usize>,
 }
 
@@ -689,7 +812,18 @@ trait Merge {
 impl Merge for TomlConfig {
     fn merge(
         &mut self,
-        TomlConfig { build, install, llvm, rust, dist, target, profile: _, change_id }: Self,
+        TomlConfig {
+            build,
+            install,
+            llvm,
+            rust,
+            dist,
+            target,
+            profile,
+            include: _,
+            change_id,
+            variant,
+        }: Self,
         replace: ReplaceOpt,
     ) {
         fn do_merge<T: Merge>(x: &mut Option<T>, y: Option<T>, replace: ReplaceOpt) {
@@ -702,23 +836,35 @@ impl Merge for TomlConfig {
             }
         }
         self.change_id.inner.merge(change_id.inner, replace);
+        // Profiles don't set a profile themselves, but included files may.
+        self.profile.merge(profile, replace);
         do_merge(&mut self.build, build, replace);
         do_merge(&mut self.install, install, replace);
         do_merge(&mut self.llvm, llvm, replace);
         do_merge(&mut self.rust, rust, replace);
         do_merge(&mut self.dist, dist, replace);
+        do_merge(&mut self.target, target, replace);
+        do_merge(&mut self.variant, variant, replace);
+    }
+}
 
-        match (self.target.as_mut(), target) {
-            (_, None) => {}
-            (None, Some(target)) => self.target = Some(target),
-            (Some(original_target), Some(new_target)) => {
-                for (triple, new) in new_target {
-                    if let Some(original) = original_target.get_mut(&triple) {
-                        original.merge(new, replace);
-                    } else {
-                        original_target.insert(triple, new);
-                    }
-                }
+impl Merge for TomlVariant {
+    fn merge(&mut self, other: Self, replace: ReplaceOpt) {
+        let mut config = TomlConfig::from(mem::take(self));
+        config.merge(other.into(), replace);
+        let TomlConfig { build, install, llvm, rust, target, dist, .. } = config;
+        *self = TomlVariant { build, install, llvm, rust, target, dist };
+    }
+}
+
+/// Merges the tables of `[target.<triple>]` or `[variant.<name>]`.
+impl<T: Merge> Merge for HashMap<String, T> {
+    fn merge(&mut self, other: Self, replace: ReplaceOpt) {
+        for (name, new) in other {
+            if let Some(original) = self.get_mut(&name) {
+                original.merge(new, replace);
+            } else {
+                self.insert(name, new);
             }
         }
     }
@@ -767,6 +913,30 @@ macro_rules! define_config {
             }
         }
 
+        impl ConfigSchema for $name {
+            fn schema() -> serde_json::Value {
+                schema::table([$(($field_key, <$field_ty as ConfigSchema>::schema()),)*])
+            }
+        }
+
+        // Only the fields that are set are serialized, under their TOML keys.
+        impl Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                use serde::ser::SerializeMap;
+
+                let mut map = serializer.serialize_map(None)?;
+                $(
+                    if let Some(value) = &self.$field {
+                        map.serialize_entry($field_key, value)?;
+                    }
+                )*
+                map.end()
+            }
+        }
+
         // The following is a trimmed version of what serde_derive generates. All parts not relevant
         // for toml deserialization have been removed. This reduces the binary size and improves
         // compile time of bootstrap.
@@ -877,6 +1047,7 @@ define_config! {
         rustc: Option<PathBuf> = "rustc",
         rustfmt: Option<PathBuf> = "rustfmt",
         cargo_clippy: Option<PathBuf> = "cargo-clippy",
+        nix_toolchain: Option<String> = "nix-toolchain",
         docs: Option<bool> = "docs",
         compiler_docs: Option<bool> = "compiler-docs",
         library_docs_private_items: Option<bool> = "library-docs-private-items",
@@ -889,9 +1060,12 @@ define_config! {
         python: Option<String> = "python",
         reuse: Option<String> = "reuse",
         locked_deps: Option<bool> = "locked-deps",
+        read_only_src: Option<bool> = "read-only-src",
         vendor: Option<bool> = "vendor",
         full_bootstrap: Option<bool> = "full-bootstrap",
         bootstrap_cache_path: Option<PathBuf> = "bootstrap-cache-path",
+        download_mirror: Option<PathBuf> = "download-mirror",
+        use_curl: Option<bool> = "use-curl",
         extended: Option<bool> = "extended",
         tools: Option<HashSet<String>> = "tools",
         verbose: Option<usize> = "verbose",
@@ -911,12 +1085,16 @@ define_config! {
         dist_stage: Option<u32> = "dist-stage",
         bench_stage: Option<u32> = "bench-stage",
         patch_binaries_for_nix: Option<bool> = "patch-binaries-for-nix",
+        patch_binaries_interpreter: Option<String> = "patch-binaries-interpreter",
+        patch_binaries_rpath: Option<String> = "patch-binaries-rpath",
         // NOTE: only parsed by bootstrap.py, `--feature build-metrics` enables metrics unconditionally
         metrics: Option<bool> = "metrics",
         android_ndk: Option<PathBuf> = "android-ndk",
         optimized_compiler_builtins: Option<bool> = "optimized-compiler-builtins",
         jobs: Option<u32> = "jobs",
         compiletest_diff_tool: Option<String> = "compiletest-diff-tool",
+        command_executor: Option<PathBuf> = "command-executor",
+        step_cache: Option<PathBuf> = "step-cache",
     }
 }
 
@@ -981,7 +1159,7 @@ define_config! {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum StringOrBool {
     String(String),
@@ -1061,6 +1239,19 @@ impl serde::de::Visitor<'_> for OptimizeVisitor {
     }
 }
 
+impl Serialize for RustOptimize {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            RustOptimize::String(s) => serializer.serialize_str(s),
+            RustOptimize::Int(i) => serializer.serialize_u8(*i),
+            RustOptimize::Bool(b) => serializer.serialize_bool(*b),
+        }
+    }
+}
+
 fn format_optimize_error_msg(v: impl std::fmt::Display) -> String {
     format!(
         r#"unrecognized option for rust optimize: "{v}", expected one of 0, 1, 2, 3, "s", "z", true, false"#
@@ -1129,6 +1320,19 @@ impl<'de> Deserialize<'de> for LldMode {
     }
 }
 
+impl Serialize for LldMode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            LldMode::Unused => serializer.serialize_bool(false),
+            LldMode::SelfContained => serializer.serialize_str("self-contained"),
+            LldMode::External => serializer.serialize_str("external"),
+        }
+    }
+}
+
 define_config! {
     /// TOML representation of how the Rust build is configured.
     struct Rust {
@@ -1256,6 +1460,7 @@ impl Config {
                 manifest_dir.parent().unwrap().parent().unwrap().to_owned()
             },
             out: PathBuf::from("build"),
+            base_out: PathBuf::from("build"),
 
             // This is needed by codegen_ssa on macOS to ship `llvm-objcopy` aliased to
             // `rust-objcopy` to workaround bad `strip`s on macOS.
@@ -1275,6 +1480,27 @@ impl Config {
         Self::get_toml(&builder_config_path)
     }
 
+    /// Parses a configuration given as JSON instead of TOML, see `--config-json`. Keys set to
+    /// `null` are left unset, since TOML has no such value.
+    pub(crate) fn parse_json(json: &str) -> Result<TomlConfig, String> {
+        fn remove_nulls(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Object(map) => {
+                    map.retain(|_, value| !value.is_null());
+                    map.values_mut().for_each(remove_nulls);
+                }
+                serde_json::Value::Array(values) => values.iter_mut().for_each(remove_nulls),
+                _ => {}
+            }
+        }
+
+        let mut json: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        remove_nulls(&mut json);
+        // Through `toml::Value`, so that numbers are deserialized the same way as from TOML.
+        let value = toml::Value::deserialize(json).map_err(|e| e.to_string())?;
+        TomlConfig::deserialize(value).map_err(|e| e.to_string())
+    }
+
     #[cfg(test)]
     pub(crate) fn get_toml(_: &Path) -> Result<TomlConfig, toml::de::Error> {
         Ok(TomlConfig::default())
@@ -1303,6 +1529,46 @@ impl Config {
             })
     }
 
+    /// Merges the files listed in `include` of `toml`, which was read from `path`, into it.
+    ///
+    /// Included paths are relative to the including file. The including file takes precedence
+    /// over everything it includes, and later includes over earlier ones. Included files may
+    /// include other files in turn, but not one that is already being included.
+    fn merge_includes(
+        &mut self,
+        toml: &mut TomlConfig,
+        path: &Path,
+        get_toml: &impl Fn(&Path) -> Result<TomlConfig, toml::de::Error>,
+        stack: &mut Vec<PathBuf>,
+    ) {
+        let Some(includes) = toml.include.take() else { return };
+        let canonicalize = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        stack.push(canonicalize(path));
+        for include in includes.iter().rev() {
+            let include_path = path.parent().unwrap_or(Path::new("")).join(include);
+            let canonical = canonicalize(&include_path);
+            if stack.contains(&canonical) {
+                let cycle: Vec<_> =
+                    stack.iter().chain([&canonical]).map(|p| p.display().to_string()).collect();
+                eprintln!("ERROR: config include cycle: {}", cycle.join(" -> "));
+                exit!(2);
+            }
+            let mut included = get_toml(&include_path).unwrap_or_else(|e| {
+                eprintln!(
+                    "ERROR: Failed to parse '{}', included from '{}': {e}",
+                    include_path.display(),
+                    path.display()
+                );
+                exit!(2);
+            });
+            let source = ConfigSource::File { path: include_path.clone(), env: None };
+            self.sources.record(&included, source, false);
+            self.merge_includes(&mut included, &include_path, get_toml, stack);
+            toml.merge(included, ReplaceOpt::IgnoreDuplicate);
+        }
+        stack.pop();
+    }
+
     pub fn parse(flags: Flags) -> Config {
         Self::parse_inner(flags, Self::get_toml)
     }
@@ -1322,8 +1588,11 @@ impl Config {
         config.on_fail = flags.on_fail;
         config.cmd = flags.cmd;
         config.incremental = flags.incremental;
+        config.emit = flags.emit;
         config.dry_run = if flags.dry_run { DryRun::UserSelected } else { DryRun::Disabled };
         config.dump_bootstrap_shims = flags.dump_bootstrap_shims;
+        config.graph_out = flags.graph_out;
+        config.invocation_log = flags.invocation_log;
         config.keep_stage = flags.keep_stage;
         config.keep_stage_std = flags.keep_stage_std;
         config.color = flags.color;
@@ -1395,32 +1664,72 @@ impl Config {
 
         config.stage0_metadata = build_helper::stage0_parser::parse_stage0_file();
 
+        // `--config-json` takes the place of `--config`, and `RUST_BOOTSTRAP_CONFIG_JSON` the one of
+        // `RUST_BOOTSTRAP_CONFIG`, which wins if both are set.
+        let json = match (&flags.config_json, &flags.config, env::var_os("RUST_BOOTSTRAP_CONFIG")) {
+            (Some(json), ..) => Some((json.clone(), None)),
+            (None, None, None) => env::var("RUST_BOOTSTRAP_CONFIG_JSON")
+                .ok()
+                .map(|json| (json, Some("RUST_BOOTSTRAP_CONFIG_JSON"))),
+            _ => None,
+        };
+
         // Read from `--config`, then `RUST_BOOTSTRAP_CONFIG`, then `./config.toml`, then `config.toml` in the root directory.
-        let toml_path = flags
-            .config
-            .clone()
-            .or_else(|| env::var_os("RUST_BOOTSTRAP_CONFIG").map(PathBuf::from));
+        let env_toml_path = env::var_os("RUST_BOOTSTRAP_CONFIG").map(PathBuf::from);
+        let toml_path_env = flags.config.is_none() && env_toml_path.is_some();
+        let toml_path = flags.config.clone().or(env_toml_path);
         let using_default_path = toml_path.is_none();
         let mut toml_path = toml_path.unwrap_or_else(|| PathBuf::from("config.toml"));
         if using_default_path && !toml_path.exists() {
             toml_path = config.src.join(toml_path);
         }
 
-        let file_content = t!(fs::read_to_string(config.src.join("src/ci/channel")));
-        let ci_channel = file_content.trim_end();
+        // Trimmed down source trees may not have the channel file, use the default channel then.
+        let ci_channel = match fs::read_to_string(config.src.join("src/ci/channel")) {
+            Ok(file_content) => file_content.trim_end().to_owned(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => config.channel.clone(),
+            Err(e) => panic!("failed to read {}: {e}", config.src.join("src/ci/channel").display()),
+        };
 
         // Give a hard error if `--config` or `RUST_BOOTSTRAP_CONFIG` are set to a missing path,
         // but not if `config.toml` hasn't been created.
-        let mut toml = if !using_default_path || toml_path.exists() {
+        let mut toml = if let Some((json, env)) = json {
+            // Anything but an object names a JSON file.
+            let (json, path) = if json.trim_start().starts_with('{') {
+                (json, None)
+            } else {
+                let path = PathBuf::from(json);
+                (t!(fs::read_to_string(&path), format!("{} not found", path.display())), Some(path))
+            };
+            let mut toml = Self::parse_json(&json).unwrap_or_else(|e| {
+                match &path {
+                    Some(path) => eprintln!("ERROR: Failed to parse '{}': {e}", path.display()),
+                    None => eprintln!("ERROR: Failed to parse the JSON configuration: {e}"),
+                }
+                exit!(2);
+            });
+            // Not a TOML file `x setup` could edit, or dist tarballs could embed.
+            config.config = None;
+            config.config_json = true;
+            config.sources.record(&toml, ConfigSource::Json { path: path.clone(), env }, true);
+            // Includes of inline JSON are relative to the working directory.
+            let path = path.unwrap_or_default();
+            config.merge_includes(&mut toml, &path, &get_toml, &mut vec![]);
+            toml
+        } else if !using_default_path || toml_path.exists() {
             config.config = Some(if cfg!(not(feature = "bootstrap-self-test")) {
                 toml_path.canonicalize().unwrap()
             } else {
                 toml_path.clone()
             });
-            get_toml(&toml_path).unwrap_or_else(|e| {
+            let mut toml = get_toml(&toml_path).unwrap_or_else(|e| {
                 eprintln!("ERROR: Failed to parse '{}': {e}", toml_path.display());
                 exit!(2);
-            })
+            });
+            let env = toml_path_env.then_some("RUST_BOOTSTRAP_CONFIG");
+            config.sources.record(&toml, ConfigSource::File { path: toml_path.clone(), env }, true);
+            config.merge_includes(&mut toml, &toml_path, &get_toml, &mut vec![]);
+            toml
         } else {
             config.config = None;
             TomlConfig::default()
@@ -1431,10 +1740,12 @@ impl Config {
             // same ones used to call the tests (if custom ones are not defined in the toml). If we
             // don't do that, bootstrap will use its own detection logic to find a suitable rustc
             // and Cargo, which doesn't work when the caller is specìfying a custom local rustc or
-            // Cargo in their config.toml.
+            // Cargo in their config.toml. `build.nix-toolchain` provides both of them as well.
             let build = toml.build.get_or_insert_with(Default::default);
-            build.rustc = build.rustc.take().or(std::env::var_os("RUSTC").map(|p| p.into()));
-            build.cargo = build.cargo.take().or(std::env::var_os("CARGO").map(|p| p.into()));
+            if build.nix_toolchain.is_none() {
+                build.rustc = build.rustc.take().or(std::env::var_os("RUSTC").map(|p| p.into()));
+                build.cargo = build.cargo.take().or(std::env::var_os("CARGO").map(|p| p.into()));
+            }
         }
 
         if let Some(include) = &toml.profile {
@@ -1458,38 +1769,46 @@ impl Config {
                 );
                 exit!(2);
             });
+            config.sources.record(&included_toml, ConfigSource::Profile(include_path), false);
             toml.merge(included_toml, ReplaceOpt::IgnoreDuplicate);
         }
 
+        if let Some(name) = &flags.variant {
+            let Some(variant) = toml.variant.as_mut().and_then(|variants| variants.remove(name))
+            else {
+                eprintln!("ERROR: no `[variant.{name}]` in the configuration");
+                exit!(2);
+            };
+            let variant = TomlConfig::from(variant);
+            config.sources.record(&variant, ConfigSource::Variant(name.clone()), true);
+            toml.merge(variant, ReplaceOpt::Override);
+            config.variant = Some(name.clone());
+        }
+
+        // Environment variables override the configuration files, and `--set` overrides them.
+        let mut triples: Vec<String> = toml.target.iter().flat_map(|t| t.keys().cloned()).collect();
+        triples.push(config.build.triple.to_string());
+        if let Some(build) = &toml.build {
+            triples.extend(build.build.iter().chain(build.host.iter().flatten()).cloned());
+            triples.extend(build.target.iter().flatten().cloned());
+        }
+        let env_overrides = env_overrides::parse(env::vars_os(), &triples).unwrap_or_else(|e| {
+            eprintln!("ERROR: {e}");
+            exit!(2);
+        });
+        let mut env_toml = TomlConfig::default();
+        for env_override in &env_overrides {
+            let option = parse_override(&env_override.option(), &env_override.var);
+            config.sources.record(&option, ConfigSource::Env(env_override.var.clone()), true);
+            env_toml.merge(option, ReplaceOpt::ErrorOnDuplicate);
+        }
+        toml.merge(env_toml, ReplaceOpt::Override);
+
         let mut override_toml = TomlConfig::default();
         for option in flags.set.iter() {
-            fn get_table(option: &str) -> Result<TomlConfig, toml::de::Error> {
-                toml::from_str(option).and_then(|table: toml::Value| TomlConfig::deserialize(table))
-            }
-
-            let mut err = match get_table(option) {
-                Ok(v) => {
-                    override_toml.merge(v, ReplaceOpt::ErrorOnDuplicate);
-                    continue;
-                }
-                Err(e) => e,
-            };
-            // We want to be able to set string values without quotes,
-            // like in `configure.py`. Try adding quotes around the right hand side
-            if let Some((key, value)) = option.split_once('=') {
-                if !value.contains('"') {
-                    match get_table(&format!(r#"{key}="{value}""#)) {
-                        Ok(v) => {
-                            override_toml.merge(v, ReplaceOpt::ErrorOnDuplicate);
-                            continue;
-                        }
-                        Err(e) => err = e,
-                    }
-                }
-            }
-            eprintln!("failed to parse override `{option}`: `{err}");
-            exit!(2)
+            override_toml.merge(parse_override(option, option), ReplaceOpt::ErrorOnDuplicate);
         }
+        config.sources.record(&override_toml, ConfigSource::Set, true);
         toml.merge(override_toml, ReplaceOpt::Override);
 
         config.change_id = toml.change_id.inner;
@@ -1503,6 +1822,7 @@ impl Config {
             rustc,
             rustfmt,
             cargo_clippy,
+            nix_toolchain,
             docs,
             compiler_docs,
             library_docs_private_items,
@@ -1515,9 +1835,12 @@ impl Config {
             python,
             reuse,
             locked_deps,
+            read_only_src,
             vendor,
             full_bootstrap,
             bootstrap_cache_path,
+            download_mirror,
+            use_curl,
             extended,
             tools,
             verbose,
@@ -1537,20 +1860,30 @@ impl Config {
             dist_stage,
             bench_stage,
             patch_binaries_for_nix,
+            patch_binaries_interpreter,
+            patch_binaries_rpath,
             // This field is only used by bootstrap.py
             metrics: _,
             android_ndk,
             optimized_compiler_builtins,
             jobs,
             compiletest_diff_tool,
+            command_executor,
+            step_cache,
         } = toml.build.unwrap_or_default();
 
+        if flags.jobs.is_some() {
+            config.sources.set("build.jobs", ConfigSource::Flag("--jobs"));
+        }
         config.jobs = Some(threads_from_config(flags.jobs.unwrap_or(jobs.unwrap_or(0))));
 
         if let Some(file_build) = build {
             config.build = TargetSelection::from_user(&file_build);
         };
 
+        if flags.build_dir.is_some() {
+            config.sources.set("build.build-dir", ConfigSource::Flag("--build-dir"));
+        }
         set(&mut config.out, flags.build_dir.or_else(|| build_dir.map(PathBuf::from)));
         // NOTE: Bootstrap spawns various commands with different working directories.
         // To avoid writing to random places on the file system, `config.out` needs to be an absolute path.
@@ -1559,6 +1892,43 @@ impl Config {
             config.out = absolute(&config.out).expect("can't make empty path absolute");
         }
 
+        // Source trees unpacked into e.g. the Nix store can't be written to anyway.
+        config.read_only_src = read_only_src.unwrap_or_else(|| {
+            fs::metadata(&config.src).is_ok_and(|metadata| metadata.permissions().readonly())
+        });
+
+        let (rustc, cargo, rustfmt, cargo_clippy) = if let Some(toolchain) = nix_toolchain {
+            if rustc.is_some() || cargo.is_some() || rustfmt.is_some() || cargo_clippy.is_some() {
+                eprintln!(
+                    "ERROR: `build.nix-toolchain` can't be combined with `build.rustc`, `build.cargo`, `build.rustfmt` or `build.cargo-clippy`."
+                );
+                exit!(2);
+            }
+            let prefix = resolve_nix_toolchain(&toolchain);
+            let bin = |name| prefix.join("bin").join(exe(name, config.build));
+            for program in [bin("rustc"), bin("cargo")] {
+                if !program.exists() {
+                    eprintln!(
+                        "ERROR: `build.nix-toolchain = {toolchain:?}` has no `{}`.",
+                        program.display()
+                    );
+                    exit!(2);
+                }
+            }
+            // rustfmt and clippy are optional, see `download_clippy` and the `RustfmtState` below.
+            let optional = |name| Some(bin(name)).filter(|program| program.exists());
+            let toolchain = (
+                Some(bin("rustc")),
+                Some(bin("cargo")),
+                optional("rustfmt"),
+                optional("cargo-clippy"),
+            );
+            config.nix_toolchain = Some(prefix);
+            toolchain
+        } else {
+            (rustc, cargo, rustfmt, cargo_clippy)
+        };
+
         if cargo_clippy.is_some() && rustc.is_none() {
             println!(
                 "WARNING: Using `build.cargo-clippy` without `build.rustc` usually fails due to toolchain conflict."
@@ -1566,6 +1936,12 @@ impl Config {
         }
 
         config.initial_cargo_clippy = cargo_clippy;
+        config.command_executor = command_executor;
+        config.step_cache = step_cache;
+        config.bootstrap_cache_path = bootstrap_cache_path;
+        config.download_mirror =
+            download_mirror.map(|mirror| absolute(mirror).expect("can't make empty path absolute"));
+        set(&mut config.use_curl, use_curl);
 
         config.initial_rustc = if let Some(rustc) = rustc {
             if !flags.skip_stage0_validation {
@@ -1597,6 +1973,14 @@ impl Config {
                 .join(exe("cargo", config.build))
         };
 
+        // Variants share the stage0 toolchain and the download cache of the build directory, and
+        // build everything else in their own.
+        config.base_out = config.out.clone();
+        if let Some(variant) = &config.variant {
+            config.bootstrap_cache_path.get_or_insert_with(|| config.out.join("cache"));
+            config.out = config.out.join("variants").join(variant);
+        }
+
         // NOTE: it's important this comes *after* we set `initial_rustc` just above.
         if config.dry_run() {
             let dir = config.out.join("tmp-dry-run");
@@ -1605,6 +1989,7 @@ impl Config {
         }
 
         config.hosts = if let Some(TargetSelectionList(arg_host)) = flags.host {
+            config.sources.set("build.host", ConfigSource::Flag("--host"));
             arg_host
         } else if let Some(file_host) = host {
             file_host.iter().map(|h| TargetSelection::from_user(h)).collect()
@@ -1612,6 +1997,7 @@ impl Config {
             vec![config.build]
         };
         config.targets = if let Some(TargetSelectionList(arg_target)) = flags.target {
+            config.sources.set("build.target", ConfigSource::Flag("--target"));
             arg_target
         } else if let Some(file_target) = target {
             file_target.iter().map(|h| TargetSelection::from_user(h)).collect()
@@ -1629,13 +2015,13 @@ impl Config {
         config.reuse = reuse.map(PathBuf::from);
         config.submodules = submodules;
         config.android_ndk = android_ndk;
-        config.bootstrap_cache_path = bootstrap_cache_path;
         set(&mut config.low_priority, low_priority);
         set(&mut config.compiler_docs, compiler_docs);
         set(&mut config.library_docs_private_items, library_docs_private_items);
         set(&mut config.docs_minification, docs_minification);
         set(&mut config.docs, docs);
-        set(&mut config.locked_deps, locked_deps);
+        // Cargo can't update `Cargo.lock` in a read-only source tree, so make it fail early.
+        set(&mut config.locked_deps, locked_deps.or(config.read_only_src.then_some(true)));
         set(&mut config.vendor, vendor);
         set(&mut config.full_bootstrap, full_bootstrap);
         set(&mut config.extended, extended);
@@ -1649,8 +2035,16 @@ impl Config {
         set(&mut config.print_step_timings, print_step_timings);
         set(&mut config.print_step_rusage, print_step_rusage);
         config.patch_binaries_for_nix = patch_binaries_for_nix;
+        config.patch_binaries_interpreter = patch_binaries_interpreter;
+        config.patch_binaries_rpath = patch_binaries_rpath;
 
+        if flags.verbose as usize > config.verbose {
+            config.sources.set("build.verbose", ConfigSource::Flag("--verbose"));
+        }
         config.verbose = cmp::max(config.verbose, flags.verbose as usize);
+        for EnvOverride { var, key, value } in &env_overrides {
+            config.verbose(|| println!("config: {} = {value} (from {var})", key.join(".")));
+        }
 
         // Verbose flag is a good default for `rust.verbose-tests`.
         config.verbose_tests = config.is_verbose();
@@ -1715,6 +2109,13 @@ impl Config {
         config.in_tree_llvm_info = GitInfo::new(false, &config.src.join("src/llvm-project"));
         config.in_tree_gcc_info = GitInfo::new(false, &config.src.join("src/gcc"));
 
+        if flags.rust_profile_use.is_some() {
+            config.sources.set("rust.profile-use", ConfigSource::Flag("--rust-profile-use"));
+        }
+        if flags.rust_profile_generate.is_some() {
+            let flag = ConfigSource::Flag("--rust-profile-generate");
+            config.sources.set("rust.profile-generate", flag);
+        }
         if let Some(rust) = toml.rust {
             let Rust {
                 optimize: optimize_toml,
@@ -1826,6 +2227,9 @@ impl Config {
             config.rustc_default_linker = default_linker;
             config.musl_root = musl_root.map(PathBuf::from);
             config.save_toolstates = save_toolstates.map(PathBuf::from);
+            if !matches!(flags.warnings, Warnings::Default) {
+                config.sources.set("rust.deny-warnings", ConfigSource::Flag("--warnings"));
+            }
             set(&mut config.deny_warnings, match flags.warnings {
                 Warnings::Deny => Some(true),
                 Warnings::Warn => Some(false),
@@ -2129,6 +2533,9 @@ impl Config {
             } else {
                 RustfmtState::Unavailable
             };
+        } else if config.nix_toolchain.is_some() {
+            // A Nix toolchain without rustfmt doesn't get one downloaded next to it.
+            *config.initial_rustfmt.borrow_mut() = RustfmtState::Unavailable;
         }
 
         // Now that we've reached the end of our configuration, infer the
@@ -2231,7 +2638,12 @@ impl Config {
             | Subcommand::Setup { .. }
             | Subcommand::Format { .. }
             | Subcommand::Suggest { .. }
-            | Subcommand::Vendor { .. } => flags.stage.unwrap_or(0),
+            | Subcommand::Vendor { .. }
+            | Subcommand::Replay { .. }
+            | Subcommand::Config { .. }
+            | Subcommand::CheckConfig
+            | Subcommand::Fetch { .. }
+            | Subcommand::Cache { .. } => flags.stage.unwrap_or(0),
         };
 
         // CI should always run stage 2 builds, unless it specifically states otherwise
@@ -2260,13 +2672,74 @@ impl Config {
                 | Subcommand::Format { .. }
                 | Subcommand::Suggest { .. }
                 | Subcommand::Vendor { .. }
-                | Subcommand::Perf { .. } => {}
+                | Subcommand::Perf { .. }
+                | Subcommand::Replay { .. }
+                | Subcommand::Config { .. }
+                | Subcommand::CheckConfig
+                | Subcommand::Fetch { .. }
+                | Subcommand::Cache { .. } => {}
             }
         }
 
+        if config.read_only_src {
+            config.check_read_only_src();
+        }
+
         config
     }
 
+    /// Makes sure that nothing bootstrap is asked to do requires writing into `src`.
+    fn check_read_only_src(&self) {
+        let error = |message: &str| {
+            eprintln!("ERROR: {message}");
+            eprintln!(
+                "HELP: `build.read-only-src` is enabled because {} is read-only, or because it is set in config.toml",
+                self.src.display()
+            );
+            exit!(2);
+        };
+
+        if self.out.starts_with(&self.src) {
+            error(&format!(
+                "the build directory {} is inside the read-only source tree, set `build.build-dir` or `--build-dir` to a writable directory elsewhere",
+                self.out.display()
+            ));
+        }
+        match &self.cmd {
+            Subcommand::Setup { .. } => {
+                error("`x setup` writes its configuration into the read-only source tree")
+            }
+            Subcommand::Vendor { .. } => {
+                error("`x vendor` writes the vendored crates into the read-only source tree")
+            }
+            Subcommand::Format { check: false, .. } => {
+                error("`x fmt` rewrites files in the read-only source tree, use `x fmt --check`")
+            }
+            Subcommand::Test { bless: true, .. } => {
+                error("`--bless` updates expected outputs in the read-only source tree")
+            }
+            _ => {}
+        }
+    }
+
+    /// Panics if `path` is inside the source tree while it is read-only, so that a step writing
+    /// into `src` fails clearly instead of with a permission error, or worse, succeeds.
+    ///
+    /// This is checked by the filesystem helpers of `Build` and `Config` (`copy_link`, `install`,
+    /// `create_dir`, `remove_dir`, `symlink_file`, `create` and `remove`). Code writing with
+    /// `std::fs` directly, like `x setup` and `x install --layout nix`, checks its destination
+    /// itself.
+    #[track_caller]
+    pub(crate) fn assert_writable(&self, path: &Path) {
+        if self.read_only_src && path.starts_with(&self.src) {
+            panic!(
+                "attempted to write to {} inside the read-only source tree {}",
+                path.display(),
+                self.src.display()
+            );
+        }
+    }
+
     pub fn dry_run(&self) -> bool {
         match self.dry_run {
             DryRun::Disabled => false,
@@ -2274,6 +2747,13 @@ impl Config {
         }
     }
 
+    /// Whether the commands of the build only pretend to run, see [`Build::dry_run`].
+    ///
+    /// [`Build::dry_run`]: crate::Build::dry_run
+    pub fn build_dry_run(&self) -> bool {
+        self.dry_run() || self.emit == EmitMode::Nix
+    }
+
     /// Runs a command, printing out nice contextual information if it fails.
     /// Exits if the command failed to execute at all, otherwise returns its
     /// `status.success()`.
@@ -2596,6 +3076,10 @@ impl Config {
 
     /// Returns whether or not submodules should be managed by bootstrap.
     pub fn submodules(&self) -> bool {
+        // Submodules are checked out into the source tree.
+        if self.read_only_src {
+            return false;
+        }
         // If not specified in config, the default is to only manage
         // submodules if we're currently inside a git repository.
         self.submodules.unwrap_or(self.rust_info.is_managed_git_subrepository())
@@ -3192,6 +3676,30 @@ fn check_incompatible_options_for_ci_rustc(
     Ok(())
 }
 
+/// Parses a `--set` option, or the one an environment variable stands for.
+fn parse_override(option: &str, origin: &str) -> TomlConfig {
+    fn get_table(option: &str) -> Result<TomlConfig, toml::de::Error> {
+        toml::from_str(option).and_then(|table: toml::Value| TomlConfig::deserialize(table))
+    }
+
+    let mut err = match get_table(option) {
+        Ok(v) => return v,
+        Err(e) => e,
+    };
+    // We want to be able to set string values without quotes,
+    // like in `configure.py`. Try adding quotes around the right hand side
+    if let Some((key, value)) = option.split_once('=') {
+        if !value.contains('"') {
+            match get_table(&format!(r#"{key}="{value}""#)) {
+                Ok(v) => return v,
+                Err(e) => err = e,
+            }
+        }
+    }
+    eprintln!("failed to parse override `{origin}`: `{err}");
+    exit!(2)
+}
+
 fn set<T>(field: &mut T, val: Option<T>) {
     if let Some(v) = val {
         *field = v;
@@ -3204,3 +3712,26 @@ fn threads_from_config(v: u32) -> u32 {
         n => n,
     }
 }
+
+/// Resolves `build.nix-toolchain` to the prefix containing `bin/rustc`. Store paths (or any other
+/// existing directory) are used as-is, anything else is built as a flake reference. `--no-link`
+/// keeps `nix build` from creating a `result` symlink in the source tree.
+fn resolve_nix_toolchain(toolchain: &str) -> PathBuf {
+    if Path::new(toolchain).is_dir() {
+        return PathBuf::from(toolchain);
+    }
+    let paths = output(
+        Command::new("nix")
+            .args(["--extra-experimental-features", "nix-command flakes"])
+            .args(["build", "--no-link", "--print-out-paths"])
+            .arg(toolchain),
+    );
+    // Multi-output toolchains print the default output first.
+    match paths.lines().next() {
+        Some(path) => PathBuf::from(path.trim()),
+        None => {
+            eprintln!("ERROR: `nix build {toolchain}` did not print any output path.");
+            exit!(2);
+        }
+    }
+}
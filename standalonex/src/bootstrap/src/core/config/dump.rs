@@ -0,0 +1,299 @@
+//! Tracks where each configuration value comes from, and prints the resolved configuration
+//! annotated with it for `x config dump`.
+//!
+//! `Config::parse_inner` records every layer it merges into the TOML configuration, and every
+//! flag that overrides a key. The dump shows those values, except that keys bootstrap interprets
+//! (like `llvm.download-ci-llvm = "if-unchanged"`) are shown with the value bootstrap settled on.
+//!
+//! `x config export` prints just the merged layers, as a configuration bootstrap can read back.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde_json::json;
+use toml::Value;
+
+use crate::core::config::flags::ConfigFormat;
+use crate::core::config::{Config, TargetSelection};
+use crate::utils::helpers::t;
+
+/// Where a configuration value comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ConfigSource {
+    /// Nothing sets it, bootstrap's default applies.
+    Default,
+    /// The default config profile selected with `profile = "..."`.
+    Profile(PathBuf),
+    /// `config.toml`, or the file passed with `--config`, or the one named by the environment
+    /// variable `env`.
+    File { path: PathBuf, env: Option<&'static str> },
+    /// JSON passed with `--config-json`, or the environment variable `env`, either inline or as
+    /// the file `path`.
+    Json { path: Option<PathBuf>, env: Option<&'static str> },
+    /// The `[variant.<name>]` selected with `--variant`.
+    Variant(String),
+    /// An override from the environment variable, see the `env_overrides` module.
+    Env(String),
+    /// A `--set` override.
+    Set,
+    /// A command-line flag, e.g. `--jobs`.
+    Flag(&'static str),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => f.write_str("default"),
+            ConfigSource::Profile(path) => write!(f, "profile {}", path.display()),
+            ConfigSource::File { path, env: None } => write!(f, "file {}", path.display()),
+            ConfigSource::File { path, env: Some(env) } => {
+                write!(f, "file {} (from {env})", path.display())
+            }
+            ConfigSource::Json { path: Some(path), env } => {
+                write!(f, "JSON file {} (from {})", path.display(), env.unwrap_or("--config-json"))
+            }
+            ConfigSource::Json { path: None, env } => {
+                write!(f, "JSON from {}", env.unwrap_or("--config-json"))
+            }
+            ConfigSource::Variant(name) => write!(f, "variant {name}"),
+            ConfigSource::Env(var) => write!(f, "environment variable {var}"),
+            ConfigSource::Set => f.write_str("--set"),
+            ConfigSource::Flag(flag) => write!(f, "flag {flag}"),
+        }
+    }
+}
+
+/// The path of a value in the TOML configuration, e.g. `["llvm", "download-ci-llvm"]`.
+pub(crate) type ConfigKey = Vec<String>;
+
+/// The values of the TOML configuration, and where each one was set.
+#[derive(Clone, Default)]
+pub(crate) struct ConfigSources {
+    values: BTreeMap<ConfigKey, Value>,
+    sources: BTreeMap<ConfigKey, ConfigSource>,
+}
+
+impl ConfigSources {
+    /// Records the values set by `layer`, a `TomlConfig` or one of its sections. With `replace`,
+    /// they take precedence over the values recorded so far, otherwise only unset keys are taken
+    /// from it, like `ReplaceOpt::IgnoreDuplicate` does.
+    pub(crate) fn record(&mut self, layer: &impl Serialize, source: ConfigSource, replace: bool) {
+        let mut values = vec![];
+        flatten(vec![], t!(Value::try_from(layer)), &mut values);
+        for (key, value) in values {
+            if replace || !self.values.contains_key(&key) {
+                self.sources.insert(key.clone(), source.clone());
+                self.values.insert(key, value);
+            }
+        }
+    }
+
+    /// Records that `key`, e.g. `build.jobs`, was overridden from somewhere other than a TOML
+    /// layer, such as a command-line flag.
+    pub(crate) fn set(&mut self, key: &str, source: ConfigSource) {
+        self.sources.insert(key_path(key), source);
+    }
+
+    /// The keys the configuration sets itself, rather than through its profile.
+    pub(crate) fn written_keys(&self) -> impl Iterator<Item = &ConfigKey> {
+        self.values
+            .keys()
+            .filter(|key| !matches!(self.sources.get(*key), Some(ConfigSource::Profile(_))))
+    }
+
+    /// Where `key`, or the first key set in the section `key`, comes from.
+    pub(crate) fn source(&self, key: &[String]) -> Option<&ConfigSource> {
+        self.sources.iter().find(|(path, _)| path.starts_with(key)).map(|(_, source)| source)
+    }
+}
+
+/// A value in the dump.
+struct Entry {
+    value: Value,
+    source: ConfigSource,
+    /// What the configuration says, if bootstrap interpreted it as something else.
+    written: Option<Value>,
+}
+
+impl Config {
+    /// Renders the resolved configuration, see the module documentation.
+    pub fn dump(&self, format: ConfigFormat) -> String {
+        let entries = self.dump_entries();
+        match format {
+            ConfigFormat::Toml => render_toml(&entries),
+            ConfigFormat::Json => render_json(&entries),
+        }
+    }
+
+    /// Renders the configuration merged from all layers, without the flags, as TOML or as JSON
+    /// for `--config-json`.
+    pub fn export(&self, format: ConfigFormat) -> String {
+        let mut root = toml::value::Table::new();
+        // The included files are merged in already.
+        for (key, value) in self.sources.values.iter().filter(|(key, _)| **key != ["include"]) {
+            let (name, section) = key.split_last().unwrap();
+            let mut table = &mut root;
+            for part in section {
+                table = table
+                    .entry(part.clone())
+                    .or_insert_with(|| Value::Table(Default::default()))
+                    .as_table_mut()
+                    .expect("a value can't also be a table");
+            }
+            table.insert(name.clone(), value.clone());
+        }
+        match format {
+            ConfigFormat::Toml => t!(toml::to_string(&Value::Table(root))),
+            ConfigFormat::Json => t!(serde_json::to_string_pretty(&root)) + "\n",
+        }
+    }
+
+    /// The value of every key in the dump, without where it comes from.
+    pub(crate) fn resolved_config(&self) -> impl Iterator<Item = (ConfigKey, Value)> {
+        self.dump_entries().into_iter().map(|(key, entry)| (key, entry.value))
+    }
+
+    fn dump_entries(&self) -> BTreeMap<ConfigKey, Entry> {
+        let resolved: BTreeMap<_, _> =
+            self.resolved_values().into_iter().map(|(key, value)| (key_path(key), value)).collect();
+        let mut entries = BTreeMap::new();
+        for key in self.sources.values.keys().chain(resolved.keys()) {
+            let written = self.sources.values.get(key);
+            let value = resolved.get(key).or(written).unwrap().clone();
+            let source = self.sources.sources.get(key).cloned().unwrap_or(ConfigSource::Default);
+            let written = written.filter(|&written| *written != value).cloned();
+            entries.insert(key.clone(), Entry { value, source, written });
+        }
+        entries
+    }
+
+    /// The values of the keys that bootstrap doesn't take as-is from the configuration, because
+    /// they have defaults that depend on the environment or are overridden by flags.
+    fn resolved_values(&self) -> Vec<(&'static str, Value)> {
+        let path = |path: &Path| Value::String(path.to_string_lossy().into_owned());
+        let targets = |targets: &[TargetSelection]| {
+            Value::Array(targets.iter().map(|t| Value::String(t.triple.to_string())).collect())
+        };
+        let mut values = vec![
+            ("build.build", Value::String(self.build.triple.to_string())),
+            ("build.host", targets(&self.hosts)),
+            ("build.target", targets(&self.targets)),
+            ("build.build-dir", path(&self.out)),
+            ("build.rustc", path(&self.initial_rustc)),
+            ("build.cargo", path(&self.initial_cargo)),
+            ("build.jobs", Value::Integer(self.jobs.unwrap_or(0).into())),
+            ("build.verbose", Value::Integer(self.verbose as i64)),
+            ("build.submodules", Value::Boolean(self.submodules())),
+            ("build.locked-deps", Value::Boolean(self.locked_deps)),
+            ("build.vendor", Value::Boolean(self.vendor)),
+            ("build.read-only-src", Value::Boolean(self.read_only_src)),
+            ("llvm.download-ci-llvm", Value::Boolean(self.llvm_from_ci)),
+            ("llvm.assertions", Value::Boolean(self.llvm_assertions)),
+            ("rust.channel", Value::String(self.channel.clone())),
+            (
+                "rust.download-rustc",
+                self.download_rustc_commit()
+                    .map_or(Value::Boolean(false), |commit| Value::String(commit.to_owned())),
+            ),
+            ("rust.debug-assertions", Value::Boolean(self.rustc_debug_assertions)),
+            ("rust.deny-warnings", Value::Boolean(self.deny_warnings)),
+            ("rust.lld", Value::Boolean(self.lld_enabled)),
+            (
+                "rust.codegen-backends",
+                Value::Array(
+                    self.rust_codegen_backends.iter().cloned().map(Value::String).collect(),
+                ),
+            ),
+        ];
+        if let Some(profile) = &self.rust_profile_use {
+            values.push(("rust.profile-use", Value::String(profile.clone())));
+        }
+        if let Some(profile) = &self.rust_profile_generate {
+            values.push(("rust.profile-generate", Value::String(profile.clone())));
+        }
+        values
+    }
+}
+
+fn key_path(key: &str) -> ConfigKey {
+    key.split('.').map(str::to_owned).collect()
+}
+
+/// Collects the leaves of `value`, i.e. everything but tables, with their paths.
+fn flatten(key: ConfigKey, value: Value, out: &mut Vec<(ConfigKey, Value)>) {
+    match value {
+        Value::Table(table) => {
+            for (name, value) in table {
+                let mut key = key.clone();
+                key.push(name);
+                flatten(key, value, out);
+            }
+        }
+        value => out.push((key, value)),
+    }
+}
+
+fn render_toml(entries: &BTreeMap<ConfigKey, Entry>) -> String {
+    let mut out = String::from(
+        "# The configuration bootstrap resolved. The comment after each value says where it\n\
+         # comes from.\n",
+    );
+    // Top-level keys first, they'd end up in the preceding table otherwise.
+    let mut sections: BTreeMap<&[String], Vec<(&String, &Entry)>> = BTreeMap::new();
+    for (key, entry) in entries {
+        let (name, section) = key.split_last().unwrap();
+        sections.entry(section).or_default().push((name, entry));
+    }
+    for (section, values) in sections {
+        if !section.is_empty() {
+            let section: Vec<_> = section.iter().map(|s| toml_key(s)).collect();
+            writeln!(out, "\n[{}]", section.join(".")).unwrap();
+        }
+        for (name, entry) in values {
+            write!(out, "{} = {}  # {}", toml_key(name), entry.value, entry.source).unwrap();
+            match (&entry.written, &entry.source) {
+                (Some(written), ConfigSource::Flag(_)) => write!(out, ", overriding {written}"),
+                (Some(written), _) => write!(out, ", resolved from {written}"),
+                (None, _) => Ok(()),
+            }
+            .unwrap();
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn render_json(entries: &BTreeMap<ConfigKey, Entry>) -> String {
+    let mut root = serde_json::Map::new();
+    for (key, entry) in entries {
+        let (name, section) = key.split_last().unwrap();
+        let mut table = &mut root;
+        for part in section {
+            table = table
+                .entry(part.clone())
+                .or_insert_with(|| json!({}))
+                .as_object_mut()
+                .expect("a value can't also be a table");
+        }
+        let mut value = json!({
+            "value": t!(serde_json::to_value(&entry.value)),
+            "source": entry.source.to_string(),
+        });
+        if let Some(written) = &entry.written {
+            value["written"] = t!(serde_json::to_value(written));
+        }
+        table.insert(name.clone(), value);
+    }
+    t!(serde_json::to_string_pretty(&root)) + "\n"
+}
+
+/// Quotes `key` if it can't be a bare TOML key.
+fn toml_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        key.to_owned()
+    } else {
+        Value::String(key.to_owned()).to_string()
+    }
+}
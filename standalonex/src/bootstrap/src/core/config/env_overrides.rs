@@ -0,0 +1,86 @@
+//! Overrides of configuration keys from environment variables, for build systems where setting
+//! a variable is easier than passing `--set`, such as a Nix derivation.
+//!
+//! The variable of a key is `RUST_BOOTSTRAP__` followed by its path, each part upper-cased, with
+//! `-` and `.` replaced by `_` and separated by `__`. For example `llvm.download-ci-llvm` is set
+//! by `RUST_BOOTSTRAP__LLVM__DOWNLOAD_CI_LLVM=false`, and `target.x86_64-unknown-linux-gnu.cc` by
+//! `RUST_BOOTSTRAP__TARGET__X86_64_UNKNOWN_LINUX_GNU__CC=clang`. Since a triple can't be told
+//! from its variable name, only the targets the configuration names can be configured this way.
+//!
+//! The variables are derived from the schema, so every key `define_config!` declares has one.
+//! Values are read like the ones of `--set`. Keep in sync with `RustBuild.get_toml` in
+//! bootstrap.py.
+
+use std::ffi::OsString;
+
+use serde_json::Value;
+
+use crate::core::config::TomlConfig;
+use crate::core::config::dump::ConfigKey;
+use crate::core::config::schema::ConfigSchema;
+
+const PREFIX: &str = "RUST_BOOTSTRAP__";
+
+/// A key set by an environment variable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct EnvOverride {
+    pub(crate) var: String,
+    pub(crate) key: ConfigKey,
+    pub(crate) value: String,
+}
+
+impl EnvOverride {
+    /// The override as a `--set` option.
+    pub(crate) fn option(&self) -> String {
+        let key: Vec<_> = self.key.iter().map(|part| format!("{part:?}")).collect();
+        format!("{}={}", key.join("."), self.value)
+    }
+}
+
+fn normalize(part: &str) -> String {
+    part.to_uppercase().replace(['-', '.'], "_")
+}
+
+/// Finds the overrides among the environment variables `vars`. The sections of `target` are
+/// matched against `triples`, the targets the configuration names.
+pub(crate) fn parse(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+    triples: &[String],
+) -> Result<Vec<EnvOverride>, String> {
+    let schema = TomlConfig::schema();
+    let mut overrides = vec![];
+    for (var, value) in vars {
+        let Some(path) = var.to_str().and_then(|var| var.strip_prefix(PREFIX)) else { continue };
+        let var = var.to_string_lossy().into_owned();
+        let parts: Vec<_> = path.split("__").collect();
+        let Some(key) = resolve(&schema, &parts, triples) else {
+            return Err(format!("`{var}` doesn't name a configuration key"));
+        };
+        let Ok(value) = value.into_string() else {
+            return Err(format!("the value of `{var}` isn't valid unicode"));
+        };
+        overrides.push(EnvOverride { var, key, value });
+    }
+    // Independent of the order of the environment.
+    overrides.sort_by(|a, b| a.var.cmp(&b.var));
+    Ok(overrides)
+}
+
+/// The key `parts` names in a table of `schema`. Whole sections can't be overridden.
+fn resolve(schema: &Value, parts: &[&str], triples: &[String]) -> Option<ConfigKey> {
+    let Some((part, rest)) = parts.split_first() else {
+        return (!schema["properties"].is_object()).then(Vec::new);
+    };
+    let (name, schema) = match (&schema["properties"], &schema["additionalProperties"]) {
+        (Value::Object(properties), _) => {
+            properties.iter().find(|(name, _)| normalize(name) == *part)?
+        }
+        (_, schema @ Value::Object(_)) => {
+            (triples.iter().find(|triple| normalize(triple) == *part)?, schema)
+        }
+        _ => return None,
+    };
+    let mut key = vec![name.clone()];
+    key.extend(resolve(schema, rest, triples)?);
+    Some(key)
+}
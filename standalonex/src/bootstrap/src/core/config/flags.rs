@@ -3,13 +3,14 @@
 //! This module implements the command-line parsing of the build system which
 //! has various flags to configure how it's run.
 
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use clap::{CommandFactory, Parser, ValueEnum};
 
 use crate::core::build_steps::setup::Profile;
 use crate::core::builder::{Builder, Kind};
 use crate::core::config::{Config, TargetSelectionList, target_selection_list};
+use crate::core::download_cache::parse_size;
 use crate::{Build, DocTests};
 
 #[derive(Copy, Clone, Default, Debug, ValueEnum)]
@@ -29,6 +30,17 @@ pub enum Warnings {
     Default,
 }
 
+/// What bootstrap should do with the commands it would otherwise run.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EmitMode {
+    /// Run the commands.
+    #[default]
+    Run,
+    /// Don't build anything; write a Nix derivation for each command instead. The stage 0
+    /// toolchain is still downloaded, since the commands refer to it.
+    Nix,
+}
+
 /// Deserialized version of all flags for this compile.
 #[derive(Debug, Parser)]
 #[command(
@@ -50,9 +62,17 @@ pub struct Flags {
     #[arg(global = true, long, value_hint = clap::ValueHint::FilePath, value_name = "FILE")]
     /// TOML configuration file for build
     pub config: Option<PathBuf>,
+    #[arg(global = true, long, value_name = "JSON", conflicts_with = "config")]
+    /// Configuration as JSON, e.g. from Nix's `builtins.toJSON`, or a JSON file, used instead of
+    /// `config.toml`
+    pub config_json: Option<String>,
     #[arg(global = true, long, value_hint = clap::ValueHint::DirPath, value_name = "DIR")]
     /// Build directory, overrides `build.build-dir` in `config.toml`
     pub build_dir: Option<PathBuf>,
+    #[arg(global = true, long, value_name = "NAME", value_parser = parse_variant)]
+    /// Merge `[variant.NAME]` of `config.toml` over the configuration, and build in
+    /// `<build-dir>/variants/NAME`
+    pub variant: Option<String>,
 
     #[arg(global = true, long, value_hint = clap::ValueHint::Other, value_name = "BUILD")]
     /// build target of the stage0 compiler
@@ -85,6 +105,17 @@ pub struct Flags {
     #[arg(global = true, long)]
     /// dry run; don't build anything
     pub dry_run: bool,
+    #[arg(global = true, long, value_name = "MODE")]
+    #[arg(value_enum, default_value_t = EmitMode::Run)]
+    /// if value is nix, doesn't build anything and writes a Nix derivation for every
+    /// command bootstrap would have run into `<build-dir>/nix/`
+    pub emit: EmitMode,
+    #[arg(global = true, long, value_hint = clap::ValueHint::FilePath, value_name = "FILE")]
+    /// write the graph of steps bootstrap ran to FILE.json and FILE.dot
+    pub graph_out: Option<PathBuf>,
+    #[arg(global = true, long)]
+    /// log every command bootstrap runs, as JSON lines, to `<build-dir>/invocations.jsonl`
+    pub invocation_log: bool,
     /// Indicates whether to dump the work done from bootstrap shims
     #[arg(global = true, long)]
     pub dump_bootstrap_shims: bool,
@@ -222,6 +253,15 @@ fn normalize_args(args: &[String]) -> Vec<String> {
     it.collect()
 }
 
+/// Parses `--variant`, whose name is also the name of its build directory, so it has to be a
+/// single plain path component.
+fn parse_variant(name: &str) -> Result<String, String> {
+    match Path::new(name).components().collect::<Vec<_>>()[..] {
+        [Component::Normal(component)] if component == name => Ok(name.to_owned()),
+        _ => Err(format!("`{name}` can't be the name of a directory in `variants`")),
+    }
+}
+
 #[derive(Debug, Clone, Default, clap::Subcommand)]
 pub enum Subcommand {
     #[command(aliases = ["b"], long_about = "\n
@@ -420,9 +460,29 @@ pub enum Subcommand {
         stage: Option<u32>,
     },
     /// Build distribution artifacts
-    Dist,
+    Dist {
+        #[arg(long)]
+        /// build the selected components twice, in different build directories and environments,
+        /// and check that their tarballs are the same
+        verify_reproducible: bool,
+    },
+    #[command(long_about = "\n
+    Arguments:
+        This subcommand accepts a number of paths to components to install. By default,
+        they're installed into `install.prefix` with the `install.sh` of each one.
+        With `--layout nix`, they're unpacked into a single Nix output directory instead,
+        without running any script. For example:
+
+        ./x.py install --layout nix --out $out")]
     /// Install distribution artifacts
-    Install,
+    Install {
+        #[arg(long, value_enum, default_value_t = InstallLayout::Prefix)]
+        /// where to install the components
+        layout: InstallLayout,
+        #[arg(long, value_name = "DIR", required_if_eq("layout", "nix"))]
+        /// the output directory of `--layout nix`
+        out: Option<PathBuf>,
+    },
     #[command(aliases = ["r"], long_about = "\n
     Arguments:
         This subcommand accepts a number of paths to tools to build and run. For
@@ -449,12 +509,17 @@ Arguments:
     To only set up the git hook, editor config or toolchain link, you may use
         ./x.py setup hook
         ./x.py setup editor
-        ./x.py setup link", Profile::all_for_help("        ").trim_end()))]
+        ./x.py setup link
+    To update an existing `config.toml` for the changes since its `change-id`, use
+        ./x.py setup --migrate", Profile::all_for_help("        ").trim_end()))]
     Setup {
         /// Either the profile for `config.toml` or another setup action.
         /// May be omitted to set up interactively
         #[arg(value_name = "<PROFILE>|hook|editor|link")]
         profile: Option<PathBuf>,
+        /// Apply the automatic migrations for the changes since `change-id` to `config.toml`
+        #[arg(long, conflicts_with = "profile")]
+        migrate: bool,
     },
     /// Suggest a subset of tests to run, based on modified files
     #[command(long_about = "\n")]
@@ -477,6 +542,143 @@ Arguments:
     ///
     /// You need to pass arguments after `--`, e.g.`x perf -- cachegrind`.
     Perf {},
+    /// Re-run the commands recorded with `--invocation-log`, without running any steps
+    #[command(long_about = "\n
+    Arguments:
+        This subcommand accepts the path to a log written by `--invocation-log`. For example:
+            ./x.py replay build/invocations.jsonl
+        The commands are run in the recorded order, and replay stops at the first command
+        whose exit status differs from the recording. If the step graph of the same build is
+        passed with `--graph`, commands of unrelated steps are run in parallel, up to `-j`.")]
+    Replay {
+        /// the invocation log to replay
+        #[arg(value_name = "LOG", value_hint = clap::ValueHint::FilePath)]
+        log: PathBuf,
+        /// the `.json` step graph written by `--graph-out` for the same build
+        #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        graph: Option<PathBuf>,
+    },
+    /// Inspect the configuration bootstrap resolves from `config.toml`, `--set` and flags
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCommand,
+    },
+    /// Report options in the configuration that have no effect or contradict each other
+    #[command(long_about = "\n
+    Checks the configuration for options that are ignored because of other options, like
+    `llvm.*` while `llvm.download-ci-llvm` is enabled, for options that contradict each other,
+    like `rust.lld` without the LLVM tools, and for `[target.*]` sections of targets rustc doesn't
+    know about. Warnings are printed for ignored options, and errors make it exit with a non-zero
+    status. For example:
+        ./x.py check-config
+        ./x.py check-config --config ci.toml")]
+    CheckConfig,
+    /// Download everything the build would download, up front
+    #[command(long_about = "\n
+    Downloads the stage0 toolchain, and the CI rustc and LLVM, rustfmt and clippy if the
+    configuration uses them, into the download cache, so the build itself needs no network.
+    With `--list`, nothing is downloaded, and the URL, cache path and SHA-256 checksum of each
+    file are printed as JSON, in a form that maps to Nix `fetchurl`. Artifacts of CI builds have
+    no known checksum, and `null` is printed for it. For example:
+        ./x.py fetch
+        ./x.py fetch --list --set rust.download-rustc=true")]
+    Fetch {
+        /// print the files as JSON instead of downloading them
+        #[arg(long)]
+        list: bool,
+    },
+    /// Inspect and clean up the cache of downloaded tarballs
+    #[command(long_about = "\n
+    The download cache is `build.bootstrap-cache-path`, or `build/cache` by default. It has an
+    entry for each stage0 release, CI rustc and CI LLVM that was downloaded. For example:
+        ./x.py cache ls
+        ./x.py cache verify
+        ./x.py cache pin 2024-11-28
+        ./x.py cache gc --max-age 30 --max-size 10G")]
+    Cache {
+        #[command(subcommand)]
+        cmd: CacheCommand,
+    },
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum CacheCommand {
+    /// List the entries of the download cache, with their size and age
+    Ls,
+    /// Check the downloaded tarballs against `src/stage0`, or the checksums recorded on download
+    Verify,
+    /// Remove the entries that weren't used for a while, or don't fit in a size
+    #[command(long_about = "\n
+    Removes the entries that weren't used for more than `--max-age` days, and then the least
+    recently used ones until the cache is at most `--max-size`. Pinned entries are kept, but
+    count towards the size. For example:
+        ./x.py cache gc --max-age 30
+        ./x.py cache gc --max-size 10G")]
+    Gc {
+        /// remove the entries not used for more than this many days
+        #[arg(long, value_name = "DAYS", required_unless_present = "max_size")]
+        max_age: Option<u64>,
+        /// remove the least recently used entries until the cache is at most this large, e.g. 10G
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
+    /// Protect an entry from `x cache gc`
+    Pin {
+        /// stop protecting the entry instead
+        #[arg(long)]
+        remove: bool,
+        /// the entry, as listed by `x cache ls`
+        entry: String,
+    },
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum ConfigCommand {
+    /// Print the resolved configuration, with where each value comes from
+    #[command(long_about = "\n
+    Prints every value set by `config.toml`, its `profile`, `--set` or a flag, and the values
+    bootstrap derives from them, each annotated with where it comes from. For example:
+        ./x.py config dump --set rust.lld=true
+        ./x.py config dump --format json")]
+    Dump {
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+    /// Print the configuration merged from `config.toml`, its includes and profile, and `--set`
+    #[command(long_about = "\n
+    Prints the configuration bootstrap merged from all its layers, without the command-line
+    flags, in a form it can read back with `--config` or `--config-json`. For example:
+        ./x.py config export --format json > config.json
+        ./x.py build --config-json config.json")]
+    Export {
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+    /// Print the JSON Schema of `config.toml`
+    #[command(long_about = "\n
+    Prints a JSON Schema describing every key `config.toml` accepts, for tools that want to
+    validate or complete a configuration without running bootstrap. For example:
+        ./x.py config schema > config.schema.json")]
+    Schema,
+}
+
+/// Output format of `x config dump` and `x config export`.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    #[default]
+    Toml,
+    Json,
+}
+
+/// How `x install` lays out what it installs.
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InstallLayout {
+    /// Into `install.prefix` and the other `install` directories, with `install.sh`.
+    #[default]
+    Prefix,
+    /// Into a single directory like the `$out` of a Nix derivation, with `bin`, `lib`, `share`
+    /// and `etc` in it.
+    Nix,
 }
 
 impl Subcommand {
@@ -499,6 +701,11 @@ impl Subcommand {
             Subcommand::Suggest { .. } => Kind::Suggest,
             Subcommand::Vendor { .. } => Kind::Vendor,
             Subcommand::Perf { .. } => Kind::Perf,
+            Subcommand::Replay { .. } => Kind::Replay,
+            Subcommand::Config { .. } => Kind::Config,
+            Subcommand::CheckConfig => Kind::CheckConfig,
+            Subcommand::Fetch { .. } => Kind::Fetch,
+            Subcommand::Cache { .. } => Kind::Cache,
         }
     }
 
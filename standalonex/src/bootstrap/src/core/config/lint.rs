@@ -0,0 +1,170 @@
+//! `x check-config`: reports options that have no effect under the rest of the configuration,
+//! options that contradict each other, and `[target.*]` sections for unknown targets.
+//!
+//! Parsing the configuration already rejects some combinations outright; these are the ones it
+//! accepts but that are most likely mistakes. Keys set by the config profile aren't reported,
+//! since the profile applies to every configuration using it.
+
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+
+use crate::core::config::dump::ConfigKey;
+use crate::core::config::{Config, Target, TargetSelection};
+use crate::core::sanity::STAGE0_MISSING_TARGETS;
+use crate::utils::exec::{OutputMode, command};
+use crate::utils::executor::{CommandExecutor, LocalExecutor};
+
+/// The `llvm.*` keys that still matter when LLVM is downloaded from CI.
+const LLVM_KEYS_USED_WITH_CI_LLVM: &[&str] =
+    &["download-ci-llvm", "assertions", "link-shared", "static-libstdcpp"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LintLevel {
+    /// The option has no effect.
+    Warning,
+    /// The configuration can't work as written.
+    Error,
+}
+
+/// A problem `x check-config` found with the configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ConfigLint {
+    pub(crate) level: LintLevel,
+    /// The key or section the problem is about, e.g. `["llvm", "optimize"]`.
+    pub(crate) key: ConfigKey,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for ConfigLint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            LintLevel::Warning => "WARNING",
+            LintLevel::Error => "ERROR",
+        };
+        write!(f, "{level}: `{}` {}", self.key.join("."), self.message)
+    }
+}
+
+impl Config {
+    /// Prints the problems with the configuration, and returns whether none of them is an error.
+    pub fn check_config(&self) -> bool {
+        let known_targets = self.known_targets();
+        if known_targets.is_none() {
+            eprintln!(
+                "WARNING: couldn't get the target list from {}, not checking `[target.*]` \
+                 sections for unknown targets",
+                self.initial_rustc.display()
+            );
+        }
+
+        let lints = self.lints(known_targets.as_ref());
+        for lint in &lints {
+            match self.sources.source(&lint.key) {
+                Some(source) => println!("{lint} (set by {source})"),
+                None => println!("{lint}"),
+            }
+        }
+        if lints.is_empty() {
+            println!("No problems found in the configuration.");
+        }
+        lints.iter().all(|lint| lint.level != LintLevel::Error)
+    }
+
+    /// The targets the stage0 rustc knows about, or `None` if it can't be run.
+    fn known_targets(&self) -> Option<HashSet<String>> {
+        let mut rustc = command(&self.initial_rustc);
+        rustc.args(["--print", "target-list"]);
+        // There's no `Build` yet to run the command with.
+        let output = LocalExecutor.execute(&mut rustc, OutputMode::Capture, OutputMode::Capture);
+        rustc.mark_as_executed();
+        let target_list = output.ok()?.stdout_if_ok()?;
+        Some(
+            target_list
+                .lines()
+                .chain(STAGE0_MISSING_TARGETS.iter().copied())
+                .map(str::to_owned)
+                .collect(),
+        )
+    }
+
+    /// Finds the problems with the configuration, see the module documentation. `known_targets`
+    /// are the targets rustc knows about; without them, targets aren't checked for being known.
+    pub(crate) fn lints(&self, known_targets: Option<&HashSet<String>>) -> Vec<ConfigLint> {
+        let mut lints = vec![];
+        let mut lint = |level, key: &[&str], message: String| {
+            let key = key.iter().map(|&part| part.to_owned()).collect();
+            lints.push(ConfigLint { level, key, message });
+        };
+        let written: Vec<_> = self.sources.written_keys().collect();
+        let is_written = |key: &[&str]| written.iter().any(|written| *written == key);
+        let all_targets: Vec<TargetSelection> = [self.build]
+            .into_iter()
+            .chain(self.hosts.iter().copied())
+            .chain(self.targets.iter().copied())
+            .collect();
+
+        // Options without effect.
+        let llvm_used = all_targets.iter().any(|&target| self.llvm_enabled(target));
+        for key in written.iter().filter(|key| key[0] == "llvm" && key.len() == 2) {
+            if !llvm_used {
+                let message = "has no effect, none of the targets is built with the LLVM codegen \
+                    backend (`rust.codegen-backends`)";
+                lint(LintLevel::Warning, &["llvm", &key[1]], message.to_owned());
+            } else if self.llvm_from_ci && !LLVM_KEYS_USED_WITH_CI_LLVM.contains(&&*key[1]) {
+                let message = "has no effect, LLVM is downloaded from CI (`llvm.download-ci-llvm`)";
+                lint(LintLevel::Warning, &["llvm", &key[1]], message.to_owned());
+            }
+        }
+        let configured_targets: BTreeSet<&str> = written
+            .iter()
+            .filter(|key| key[0] == "target" && key.len() > 1)
+            .map(|key| &*key[1])
+            .collect();
+        for triple in configured_targets {
+            let known = known_targets.is_none_or(|targets| targets.contains(triple))
+                || TargetSelection::from_user(triple).filepath().is_some_and(|path| path.exists());
+            if !known {
+                let message = "is not a target rustc knows about, nor a custom target file";
+                lint(LintLevel::Error, &["target", triple], message.to_owned());
+            } else if !all_targets.iter().any(|target| target.triple == triple) {
+                let message =
+                    "has no effect, the target isn't built (`build.host`, `build.target`)";
+                lint(LintLevel::Warning, &["target", triple], message.to_owned());
+            }
+        }
+
+        // Contradictions.
+        if self.lld_enabled && !self.llvm_tools_enabled {
+            let message = "is enabled, but `rust-lld` is built with the LLVM tools, which \
+                `rust.llvm-tools = false` disables";
+            lint(LintLevel::Error, &["rust", "lld"], message.to_owned());
+        }
+        for &target in &all_targets {
+            let triple = &*target.triple;
+            if self.codegen_backends(target).is_empty() {
+                let key = if is_written(&["target", triple, "codegen-backends"]) {
+                    vec!["target", triple, "codegen-backends"]
+                } else {
+                    vec!["rust", "codegen-backends"]
+                };
+                lint(LintLevel::Error, &key, format!("leaves no codegen backend for {target}"));
+            }
+            let no_std = self
+                .target_config
+                .get(&target)
+                .map_or_else(|| Target::from_triple(triple).no_std, |config| config.no_std);
+            if no_std && self.profiler_enabled(target) {
+                let key = if is_written(&["target", triple, "profiler"]) {
+                    vec!["target", triple, "profiler"]
+                } else {
+                    vec!["build", "profiler"]
+                };
+                let message = format!(
+                    "is enabled for {target}, which has no `std` to build the profiler runtime with"
+                );
+                lint(LintLevel::Error, &key, message);
+            }
+        }
+        lints
+    }
+}
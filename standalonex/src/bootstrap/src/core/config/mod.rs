@@ -1,7 +1,11 @@
 #[allow(clippy::module_inception)]
 mod config;
+mod dump;
+mod env_overrides;
 pub mod flags;
+mod lint;
+mod schema;
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
 
 pub use config::*;
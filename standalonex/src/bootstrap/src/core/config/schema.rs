@@ -0,0 +1,119 @@
+//! A JSON Schema for `config.toml`, printed by `x config schema`.
+//!
+//! The schema of each section is generated by `define_config!` from the same field list the
+//! deserializer uses, so it can't go out of date. Tools can use it to validate a configuration
+//! before bootstrap runs, e.g. the Nix module at evaluation time, or editors to complete keys.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+
+use serde_json::{Value, json};
+
+use crate::core::config::{Config, DebuginfoLevel, LldMode, RustOptimize, StringOrBool};
+use crate::utils::helpers::t;
+
+/// A type that can be written in `config.toml`, and the JSON Schema of the values it accepts.
+pub(crate) trait ConfigSchema {
+    fn schema() -> Value;
+}
+
+impl Config {
+    /// Renders the JSON Schema of `config.toml`, see the module documentation.
+    pub fn schema() -> String {
+        let mut schema = super::TomlConfig::schema();
+        let root = schema.as_object_mut().unwrap();
+        root.insert("$schema".into(), json!("https://json-schema.org/draft/2020-12/schema"));
+        root.insert("title".into(), json!("bootstrap config.toml"));
+        t!(serde_json::to_string_pretty(&schema)) + "\n"
+    }
+}
+
+/// The schema of a table that only allows `properties`, like `#[serde(deny_unknown_fields)]`.
+pub(crate) fn table(properties: impl IntoIterator<Item = (&'static str, Value)>) -> Value {
+    let properties: serde_json::Map<_, _> =
+        properties.into_iter().map(|(key, schema)| (key.to_owned(), schema)).collect();
+    json!({ "type": "object", "properties": properties, "additionalProperties": false })
+}
+
+impl ConfigSchema for bool {
+    fn schema() -> Value {
+        json!({ "type": "boolean" })
+    }
+}
+
+impl ConfigSchema for String {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl ConfigSchema for PathBuf {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+macro_rules! unsigned_schema {
+    ($($ty:ty),*) => {$(
+        impl ConfigSchema for $ty {
+            fn schema() -> Value {
+                json!({ "type": "integer", "minimum": 0, "maximum": <$ty>::MAX })
+            }
+        }
+    )*};
+}
+
+unsigned_schema!(u32, usize);
+
+impl<T: ConfigSchema> ConfigSchema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: ConfigSchema> ConfigSchema for HashSet<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema(), "uniqueItems": true })
+    }
+}
+
+impl<T: ConfigSchema> ConfigSchema for BTreeSet<T> {
+    fn schema() -> Value {
+        HashSet::<T>::schema()
+    }
+}
+
+impl<T: ConfigSchema> ConfigSchema for HashMap<String, T> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": T::schema() })
+    }
+}
+
+impl ConfigSchema for StringOrBool {
+    fn schema() -> Value {
+        json!({ "type": ["string", "boolean"] })
+    }
+}
+
+impl ConfigSchema for DebuginfoLevel {
+    fn schema() -> Value {
+        json!({
+            "oneOf": [
+                { "enum": ["none", "line-directives-only", "line-tables-only", "limited", "full"] },
+                { "enum": [0, 1, 2] },
+            ]
+        })
+    }
+}
+
+impl ConfigSchema for RustOptimize {
+    fn schema() -> Value {
+        json!({ "enum": [0, 1, 2, 3, "s", "z", true, false] })
+    }
+}
+
+impl ConfigSchema for LldMode {
+    fn schema() -> Value {
+        json!({ "enum": [true, false, "self-contained", "external"] })
+    }
+}
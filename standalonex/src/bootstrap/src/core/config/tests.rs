@@ -1,17 +1,18 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env;
-use std::fs::{File, remove_file};
+use std::fs::{self, File, remove_file};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use clap::CommandFactory;
+use clap::{CommandFactory, Parser};
 use serde::Deserialize;
 
-use super::flags::Flags;
-use super::{ChangeIdWrapper, Config, RUSTC_IF_UNCHANGED_ALLOWED_PATHS};
+use super::flags::{ConfigFormat, EmitMode, Flags};
+use super::lint::LintLevel;
+use super::{ChangeIdWrapper, Config, RUSTC_IF_UNCHANGED_ALLOWED_PATHS, env_overrides};
 use crate::core::build_steps::clippy::{LintConfig, get_clippy_rules_in_order};
 use crate::core::build_steps::llvm;
-use crate::core::config::{LldMode, Target, TargetSelection, TomlConfig};
+use crate::core::config::{LldMode, RustOptimize, Target, TargetSelection, TomlConfig};
 
 pub(crate) fn parse(config: &str) -> Config {
     Config::parse_inner(
@@ -20,6 +21,15 @@ pub(crate) fn parse(config: &str) -> Config {
     )
 }
 
+/// Returns an empty directory named `name` inside the build's temporary directory, for tests
+/// that need to write files.
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = parse("").tempdir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
 #[test]
 #[ignore]
 fn download_ci_llvm() {
@@ -381,6 +391,23 @@ fn parse_jobs() {
     assert_eq!(parse("build.jobs = 1").jobs, Some(1));
 }
 
+#[test]
+fn emit_nix_is_not_a_dry_run() {
+    let config = Config::parse_inner(
+        Flags::parse(&[
+            "check".to_owned(),
+            "--config=/does/not/exist".to_owned(),
+            "--emit=nix".to_owned(),
+        ]),
+        |&_| toml::from_str(""),
+    );
+    assert_eq!(config.emit, EmitMode::Nix);
+    assert!(!config.dry_run());
+    assert!(config.build_dry_run());
+    assert!(!config.out.ends_with("tmp-dry-run"));
+    assert!(!parse("").build_dry_run());
+}
+
 #[test]
 fn jobs_precedence() {
     // `--jobs` should take precedence over using `--set build.jobs`.
@@ -448,3 +475,390 @@ fn check_rustc_if_unchanged_paths() {
         assert!(config.src.join(p).exists(), "{p} doesn't exist.");
     }
 }
+
+#[test]
+fn nix_toolchain() {
+    let prefix = test_dir("nix-toolchain");
+    std::fs::create_dir_all(prefix.join("bin")).unwrap();
+    for program in ["rustc", "cargo"] {
+        File::create(prefix.join("bin").join(program)).unwrap();
+    }
+
+    let config = parse(&format!("build.nix-toolchain = {:?}", prefix.display().to_string()));
+    assert_eq!(config.nix_toolchain.as_deref(), Some(prefix.as_path()));
+    assert_eq!(config.initial_rustc, prefix.join("bin/rustc"));
+    assert_eq!(config.initial_cargo, prefix.join("bin/cargo"));
+    // The toolchain has no rustfmt and no clippy, which must not be downloaded instead.
+    assert_eq!(config.initial_cargo_clippy, None);
+    assert_eq!(config.initial_rustfmt(), None);
+
+    // `x fetch` has nothing of the stage0 toolchain to download either.
+    let config = parse(&format!(
+        "build.nix-toolchain = {:?}\nllvm.download-ci-llvm = false\nrust.download-rustc = false",
+        prefix.display().to_string()
+    ));
+    let names: Vec<_> =
+        config.download_manifest().into_iter().map(|artifact| artifact.name).collect();
+    assert!(names.is_empty(), "{names:?}");
+
+    File::create(prefix.join("bin/cargo-clippy")).unwrap();
+    let config = parse(&format!("build.nix-toolchain = {:?}", prefix.display().to_string()));
+    assert_eq!(config.initial_cargo_clippy, Some(prefix.join("bin/cargo-clippy")));
+
+    std::fs::remove_dir_all(prefix).unwrap();
+}
+
+fn read_only_src_config() -> Config {
+    let mut config = Config::default_opts();
+    config.src = "/nix/store/rust-src".into();
+    config.read_only_src = true;
+    config
+}
+
+#[test]
+fn read_only_src() {
+    let mut config = read_only_src_config();
+    config.assert_writable(Path::new("/build/host/stage1"));
+
+    config.read_only_src = false;
+    config.assert_writable(Path::new("/nix/store/rust-src/Cargo.lock"));
+}
+
+#[test]
+#[should_panic(expected = "inside the read-only source tree")]
+fn read_only_src_file() {
+    read_only_src_config().assert_writable(Path::new("/nix/store/rust-src/Cargo.lock"));
+}
+
+#[test]
+#[should_panic(expected = "inside the read-only source tree")]
+fn read_only_src_root() {
+    read_only_src_config().assert_writable(Path::new("/nix/store/rust-src"));
+}
+
+#[test]
+fn config_dump() {
+    let config = Config::parse_inner(
+        Flags::parse(&[
+            "check".to_owned(),
+            "--config=/does/not/exist".to_owned(),
+            "--set=rust.deny-warnings=false".to_owned(),
+            "--jobs=3".to_owned(),
+        ]),
+        |&_| toml::from_str("[build]\njobs = 1\ngdb = \"foo\"\n[llvm]\ndownload-ci-llvm = false"),
+    );
+    let dump = config.dump(ConfigFormat::Toml);
+    let file = "file /does/not/exist";
+    assert!(dump.contains(&format!("gdb = \"foo\"  # {file}\n")), "{dump}");
+    assert!(dump.contains("jobs = 3  # flag --jobs, overriding 1\n"), "{dump}");
+    assert!(dump.contains(&format!("download-ci-llvm = false  # {file}\n")), "{dump}");
+    assert!(dump.contains("deny-warnings = false  # --set\n"), "{dump}");
+    assert!(dump.contains("lld = false  # default\n"), "{dump}");
+
+    let dump: serde_json::Value = serde_json::from_str(&config.dump(ConfigFormat::Json)).unwrap();
+    assert_eq!(dump["build"]["jobs"]["value"], 3);
+    assert_eq!(dump["build"]["jobs"]["source"], "flag --jobs");
+    assert_eq!(dump["build"]["jobs"]["written"], 1);
+}
+
+fn parse_with_includes(files: &[(&str, &str)]) -> Config {
+    let files: HashMap<_, _> =
+        files.iter().map(|&(path, contents)| (PathBuf::from(path), contents)).collect();
+    Config::parse_inner(
+        Flags::parse(&["check".to_owned(), "--config=/cfg/config.toml".to_owned()]),
+        |path| toml::from_str(files.get(path).unwrap_or_else(|| panic!("no {}", path.display()))),
+    )
+}
+
+#[test]
+fn config_includes() {
+    let config = parse_with_includes(&[
+        (
+            "/cfg/config.toml",
+            "include = [\"team.toml\", \"local/machine.toml\"]\n[build]\njobs = 3",
+        ),
+        ("/cfg/team.toml", "include = [\"../shared.toml\"]\n[build]\njobs = 1\ngdb = \"team\""),
+        ("/cfg/local/machine.toml", "[build]\ngdb = \"machine\""),
+        ("/cfg/../shared.toml", "[build]\nnodejs = \"shared\"\ngdb = \"shared\""),
+    ]);
+    assert_eq!(config.jobs, Some(3));
+    assert_eq!(config.gdb, Some(PathBuf::from("machine")));
+    assert_eq!(config.nodejs, Some(PathBuf::from("shared")));
+
+    let dump = config.dump(ConfigFormat::Toml);
+    assert!(dump.contains("gdb = \"machine\"  # file /cfg/local/machine.toml\n"), "{dump}");
+    assert!(dump.contains("nodejs = \"shared\"  # file /cfg/../shared.toml\n"), "{dump}");
+}
+
+#[test]
+#[should_panic(expected = "status code: 2")]
+fn config_include_cycle() {
+    parse_with_includes(&[
+        ("/cfg/config.toml", "include = [\"a.toml\"]"),
+        ("/cfg/a.toml", "include = [\"b.toml\"]"),
+        ("/cfg/b.toml", "include = [\"a.toml\"]"),
+    ]);
+}
+
+#[test]
+fn config_schema() {
+    let schema: serde_json::Value = serde_json::from_str(&Config::schema()).unwrap();
+    let build = &schema["properties"]["build"];
+    assert_eq!(build["additionalProperties"], false);
+    assert_eq!(build["properties"]["jobs"]["type"], "integer");
+    assert_eq!(build["properties"]["gdb"]["type"], "string");
+    let target = &schema["properties"]["target"]["additionalProperties"];
+    assert_eq!(target["properties"]["linker"]["type"], "string");
+
+    // Every key the default profiles set must be described.
+    fn check(value: &toml::Value, schema: &serde_json::Value, key: &str) {
+        let toml::Value::Table(table) = value else { return };
+        for (name, value) in table {
+            let properties = &schema["properties"];
+            let schema = properties.get(name).unwrap_or_else(|| panic!("{key}.{name} is missing"));
+            check(value, schema, &format!("{key}.{name}"));
+        }
+    }
+    let defaults = Path::new(env!("CARGO_MANIFEST_DIR")).join("defaults");
+    for entry in std::fs::read_dir(defaults).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            let value = toml::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            check(&value, &schema, &path.display().to_string());
+        }
+    }
+}
+
+#[test]
+fn config_json() {
+    let config = Config::parse_inner(
+        Flags::parse(&[
+            "check".to_owned(),
+            r#"--config-json={"build": {"jobs": 2, "gdb": "foo"}, "rust": {"optimize": 3}}"#
+                .to_owned(),
+            "--set=rust.lld=true".to_owned(),
+        ]),
+        |&_| unreachable!("the configuration is inline JSON"),
+    );
+    assert_eq!(config.jobs, Some(2));
+    assert_eq!(config.gdb, Some(PathBuf::from("foo")));
+    assert_eq!(config.rust_optimize, RustOptimize::Int(3));
+    assert!(config.config_json && config.config.is_none());
+    let dump = config.dump(ConfigFormat::Toml);
+    assert!(dump.contains("gdb = \"foo\"  # JSON from --config-json\n"), "{dump}");
+
+    // What's exported reads back as the same configuration.
+    let json = config.export(ConfigFormat::Json);
+    let config = Config::parse_inner(
+        Flags::parse(&["check".to_owned(), format!("--config-json={json}")]),
+        |&_| unreachable!("the configuration is inline JSON"),
+    );
+    assert!(config.lld_enabled);
+    assert_eq!(config.export(ConfigFormat::Json), json);
+    let toml = config.export(ConfigFormat::Toml);
+    let config = Config::parse_inner(
+        Flags::parse(&["check".to_owned(), "--config=/does/not/exist".to_owned()]),
+        |&_| toml::from_str(&toml),
+    );
+    assert_eq!(config.export(ConfigFormat::Json), json);
+
+    assert!(Config::parse_json(r#"{"build": {"jobz": 2}}"#).is_err());
+    assert!(Flags::try_parse_from(["x.py", "check", "--config=a", "--config-json={}"]).is_err());
+}
+
+#[test]
+fn config_json_null_is_unset() {
+    let config = Config::parse_inner(
+        Flags::parse(&[
+            "check".to_owned(),
+            r#"--config-json={"build": {"jobs": null, "gdb": "foo"}, "llvm": null}"#.to_owned(),
+        ]),
+        |&_| unreachable!("the configuration is inline JSON"),
+    );
+    assert_eq!(config.jobs, parse("").jobs);
+    assert_eq!(config.gdb, Some(PathBuf::from("foo")));
+}
+
+#[test]
+fn config_lints() {
+    let known_targets: HashSet<_> =
+        ["x86_64-unknown-linux-gnu", "thumbv7em-none-eabi", "aarch64-unknown-linux-gnu"]
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+    let lints_with = |config: &str, known_targets: Option<&HashSet<String>>| {
+        let config = parse(config);
+        let mut lints: Vec<_> = config
+            .lints(known_targets)
+            .into_iter()
+            .map(|lint| (lint.level, lint.key.join(".")))
+            .collect();
+        lints.sort_by(|a, b| a.1.cmp(&b.1));
+        lints
+    };
+    let lints = |config: &str| lints_with(config, Some(&known_targets));
+
+    assert_eq!(lints("build.build = \"x86_64-unknown-linux-gnu\""), vec![]);
+    assert_eq!(
+        lints(
+            "build.build = \"x86_64-unknown-linux-gnu\"
+            llvm.download-ci-llvm = false
+            llvm.optimize = false
+            rust.codegen-backends = [\"cranelift\"]"
+        ),
+        vec![
+            (LintLevel::Warning, "llvm.download-ci-llvm".to_owned()),
+            (LintLevel::Warning, "llvm.optimize".to_owned())
+        ],
+    );
+    assert_eq!(
+        lints(
+            "[build]
+            build = \"x86_64-unknown-linux-gnu\"
+            target = [\"thumbv7em-none-eabi\"]
+            profiler = true
+            [rust]
+            lld = true
+            llvm-tools = false
+            [target.aarch64-unknown-linux-gnu]
+            cc = \"cc\"
+            [target.x86_64-unknown-linux-gnx]
+            cc = \"cc\"
+            [target.thumbv7em-none-eabi]
+            codegen-backends = []"
+        ),
+        vec![
+            (LintLevel::Error, "build.profiler".to_owned()),
+            (LintLevel::Error, "rust.lld".to_owned()),
+            (LintLevel::Warning, "target.aarch64-unknown-linux-gnu".to_owned()),
+            (LintLevel::Error, "target.thumbv7em-none-eabi.codegen-backends".to_owned()),
+            (LintLevel::Error, "target.x86_64-unknown-linux-gnx".to_owned()),
+        ],
+    );
+    // Without the target list, no target is unknown.
+    assert_eq!(
+        lints_with(
+            "build.build = \"x86_64-unknown-linux-gnu\"
+            [target.x86_64-unknown-linux-gnx]
+            cc = \"cc\"",
+            None
+        ),
+        vec![(LintLevel::Warning, "target.x86_64-unknown-linux-gnx".to_owned())],
+    );
+}
+
+#[test]
+fn config_env_overrides() {
+    let triples = ["x86_64-unknown-linux-gnu".to_owned(), "custom.json".to_owned()];
+    let overrides = |vars: &[(&str, &str)]| {
+        let vars = vars.iter().map(|&(var, value)| (var.into(), value.into()));
+        env_overrides::parse(vars, &triples)
+    };
+
+    let found = overrides(&[
+        ("RUST_BOOTSTRAP__LLVM__DOWNLOAD_CI_LLVM", "false"),
+        ("RUST_BOOTSTRAP__TARGET__CUSTOM_JSON__CC", "clang"),
+        ("RUST_BOOTSTRAP__CHANGE_ID", "1"),
+        ("RUST_BOOTSTRAP_CONFIG", "config.toml"),
+        ("PATH", "/bin"),
+    ])
+    .unwrap();
+    let keys: Vec<_> = found.iter().map(|o| o.key.join(".")).collect();
+    assert_eq!(keys, ["change-id", "llvm.download-ci-llvm", "target.custom.json.cc"]);
+    let options: Vec<_> = found.iter().map(|o| o.option()).collect();
+    assert_eq!(options[2], r#""target"."custom.json"."cc"=clang"#);
+    assert!(!parse(&options[1]).llvm_from_ci);
+
+    assert!(overrides(&[("RUST_BOOTSTRAP__LLVM__DOWNLOAD_CI", "false")]).is_err());
+    assert!(overrides(&[("RUST_BOOTSTRAP__LLVM", "false")]).is_err());
+    assert!(overrides(&[("RUST_BOOTSTRAP__TARGET__AARCH64_UNKNOWN_LINUX_GNU__CC", "cc")]).is_err());
+}
+
+#[test]
+fn config_variant() {
+    let toml = r#"
+        [rust]
+        debug-assertions = true
+        [variant.release.rust]
+        debug-assertions = false
+        lto = "thin"
+    "#;
+    let parse_variant = |args: &[&str]| {
+        let mut flags = vec!["check".to_owned(), "--config=/does/not/exist".to_owned()];
+        flags.extend(args.iter().map(|&arg| arg.to_owned()));
+        Config::parse_inner(Flags::parse(&flags), |&_| toml::from_str(toml))
+    };
+
+    let base = parse_variant(&[]);
+    assert!(base.rustc_debug_assertions);
+    assert_eq!(base.variant, None);
+
+    let config = parse_variant(&["--variant=release", "--set=rust.lto=fat"]);
+    assert!(!config.rustc_debug_assertions);
+    assert_eq!(config.variant.as_deref(), Some("release"));
+    assert_eq!(config.out, base.out.join("variants").join("release"));
+    assert_eq!(config.base_out, base.out);
+    assert_eq!(config.bootstrap_cache_path, Some(base.out.join("cache")));
+    assert_eq!(config.initial_rustc, base.initial_rustc);
+    let dump = config.dump(ConfigFormat::Toml);
+    assert!(dump.contains("debug-assertions = false  # variant release\n"), "{dump}");
+    assert!(dump.contains("lto = \"fat\"  # --set\n"), "{dump}");
+
+    // The name is a directory in `variants`.
+    for name in ["../x", "/abs", "a/b", "a/", ".", "..", ""] {
+        let variant = format!("--variant={name}");
+        assert!(Flags::try_parse_from(["x.py", "check", &variant]).is_err(), "{name}");
+    }
+}
+
+#[test]
+fn download_mirror() {
+    let config = parse("");
+    assert_eq!(
+        config.mirrored_url("https://example.org/dist/a.tar.xz"),
+        "https://example.org/dist/a.tar.xz"
+    );
+
+    let config = parse("build.download-mirror = \"/mirror\"");
+    assert_eq!(config.download_mirror, Some(PathBuf::from("/mirror")));
+    assert_eq!(
+        config.mirrored_url("https://static.rust-lang.org/dist/2024-10-17/rustc.tar.xz"),
+        "file:///mirror/dist/2024-10-17/rustc.tar.xz"
+    );
+    assert_eq!(
+        config.mirrored_url("https://ci-artifacts.rust-lang.org/rustc-builds/abc/rust-dev.tar.xz"),
+        "file:///mirror/rustc-builds/abc/rust-dev.tar.xz"
+    );
+    assert_eq!(config.mirrored_url("file:///elsewhere/a.tar.xz"), "file:///elsewhere/a.tar.xz");
+}
+
+#[test]
+fn download_manifest() {
+    let config = parse("llvm.download-ci-llvm = false\nrust.download-rustc = false");
+    let host = config.build.triple;
+    let date = &config.stage0_metadata.compiler.date;
+    let version = &config.stage0_metadata.compiler.version;
+    let manifest = config.download_manifest();
+    let names: Vec<_> = manifest.iter().map(|artifact| &*artifact.name).collect();
+    assert_eq!(
+        names,
+        [
+            format!("rust-std-{version}-{host}.tar.xz"),
+            format!("rustc-{version}-{host}.tar.xz"),
+            format!("cargo-{version}-{host}.tar.xz"),
+            format!("clippy-{version}-{host}.tar.xz"),
+        ]
+    );
+    for artifact in &manifest {
+        let url = format!("dist/{date}/{}", artifact.name);
+        assert_eq!(artifact.url, format!("https://static.rust-lang.org/{url}"));
+        assert_eq!(artifact.path, config.out.join("cache").join(date).join(&artifact.name));
+        assert_eq!(artifact.sha256.as_ref(), config.stage0_metadata.checksums_sha256.get(&url));
+    }
+
+    let config = parse(
+        "llvm.download-ci-llvm = false\nrust.download-rustc = false\n\
+         build.cargo-clippy = \"/bin/cargo-clippy\"",
+    );
+    assert!(config.download_manifest().iter().all(|artifact| !artifact.name.starts_with("clippy")));
+}
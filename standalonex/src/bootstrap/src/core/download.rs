@@ -1,17 +1,27 @@
+use std::collections::HashSet;
 use std::env;
-use std::ffi::OsString;
 use std::fs::{self, File};
 use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::sync::OnceLock;
+use std::panic::Location;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Instant;
 
 use build_helper::ci::CiEnv;
+use serde_derive::Serialize;
 use xz2::bufread::XzDecoder;
 
-use crate::core::config::BUILDER_CONFIG_FILENAME;
+use crate::core::build_steps::llvm::detect_llvm_sha;
+use crate::core::config::{BUILDER_CONFIG_FILENAME, RustfmtState};
+use crate::core::download_cache;
+use crate::utils::elf;
 use crate::utils::exec::{BootstrapCommand, command};
-use crate::utils::helpers::{check_run, exe, hex_encode, move_file, program_out_of_date};
+use crate::utils::executor::{CommandExecutor, ExternalExecutor, LocalExecutor};
+use crate::utils::helpers::{check_run, exe, move_file, program_out_of_date};
+use crate::utils::invocation_log::{self, INVOCATION_LOG_FILENAME, Invocation};
+use crate::utils::http;
 use crate::{Config, t};
 
 static SHOULD_FIX_BINS_AND_DYLIBS: OnceLock<bool> = OnceLock::new();
@@ -50,6 +60,7 @@ impl Config {
         if self.dry_run() {
             return;
         }
+        self.assert_writable(path);
         t!(fs::write(path, s));
     }
 
@@ -57,6 +68,7 @@ impl Config {
         if self.dry_run() {
             return;
         }
+        self.assert_writable(f);
         fs::remove_file(f).unwrap_or_else(|_| panic!("failed to remove {:?}", f));
     }
 
@@ -70,15 +82,38 @@ impl Config {
         tmp
     }
 
+    /// Returns the executor of the commands that really run, see `utils::executor`.
+    pub(crate) fn executor(&self) -> Arc<dyn CommandExecutor> {
+        match &self.command_executor {
+            Some(program) => Arc::new(ExternalExecutor::new(program.clone())),
+            None => Arc::new(LocalExecutor),
+        }
+    }
+
     /// Runs a command, printing out nice contextual information if it fails.
     /// Returns false if do not execute at all, otherwise returns its
     /// `status.success()`.
+    ///
+    /// These commands prepare the build, e.g. by checking out submodules, so they run for real
+    /// with `--emit=nix` too. They're logged with `--invocation-log` like the commands of the
+    /// build.
+    #[track_caller]
     pub(crate) fn check_run(&self, cmd: &mut BootstrapCommand) -> bool {
         if self.dry_run() && !cmd.run_always {
+            cmd.mark_as_executed();
             return true;
         }
         self.verbose(|| println!("running: {cmd:?}"));
-        check_run(cmd, self.is_verbose())
+        let invocation = self.invocation_log.then(|| {
+            Invocation::new(cmd, cmd.get_created_location(), Location::caller(), None)
+        });
+        let start = Instant::now();
+        let output = check_run(&*self.executor(), cmd, self.is_verbose());
+        if let Some(invocation) = invocation {
+            let log = self.out.join(INVOCATION_LOG_FILENAME);
+            invocation_log::append(&log, &invocation.finished(&output, start.elapsed()));
+        }
+        output.is_success()
     }
 
     /// Whether or not `fix_bin_or_dylib` needs to be run; can only be true
@@ -138,93 +173,217 @@ impl Config {
     /// Modifies the interpreter section of 'fname' to fix the dynamic linker,
     /// or the RPATH section, to fix the dynamic library search path
     ///
-    /// This is only required on NixOS and uses the PatchELF utility to
-    /// change the interpreter/RPATH of ELF executables.
-    ///
-    /// Please see <https://nixos.org/patchelf.html> for more information
+    /// This is only required on NixOS, see [`crate::utils::elf`] for how the
+    /// ELF files are rewritten.
     fn fix_bin_or_dylib(&self, fname: &Path) {
         assert_eq!(SHOULD_FIX_BINS_AND_DYLIBS.get(), Some(&true));
         println!("attempting to patch {}", fname.display());
 
-        // Only build `.nix-deps` once.
-        static NIX_DEPS_DIR: OnceLock<PathBuf> = OnceLock::new();
-        let mut nix_build_succeeded = true;
-        let nix_deps_dir = NIX_DEPS_DIR.get_or_init(|| {
-            // Run `nix-build` to "build" each dependency (which will likely reuse
-            // the existing `/nix/store` copy, or at most download a pre-built copy).
-            //
-            // Importantly, we create a gc-root called `.nix-deps` in the `build/`
-            // directory, but still reference the actual `/nix/store` path in the rpath
-            // as it makes it significantly more robust against changes to the location of
-            // the `.nix-deps` location.
-            //
-            // bintools: Needed for the path of `ld-linux.so` (via `nix-support/dynamic-linker`).
-            // zlib: Needed as a system dependency of `libLLVM-*.so`.
-            // patchelf: Needed for patching ELF binaries (see doc comment above).
-            let nix_deps_dir = self.out.join(".nix-deps");
-            const NIX_EXPR: &str = "
-            with (import <nixpkgs> {});
-            symlinkJoin {
-                name = \"rust-stage0-dependencies\";
-                paths = [
-                    zlib
-                    patchelf
-                    stdenv.cc.bintools
-                ];
+        let (interpreter, rpath) = self.nix_patch_paths();
+        let interpreter = if path_is_dylib(fname) { None } else { Some(interpreter.as_str()) };
+        if let Err(e) = elf::patch_file(fname, interpreter, Some(rpath)) {
+            eprintln!("ERROR: failed to patch {}: {e}", fname.display());
+            crate::exit!(1);
+        }
+    }
+
+    /// Returns the dynamic linker and the library directories to patch binaries with.
+    ///
+    /// They come from `build.patch-binaries-interpreter` and `build.patch-binaries-rpath`, or
+    /// else from the environment of a Nix shell or derivation: the dynamic linker of
+    /// `$NIX_BINTOOLS` (i.e. `stdenv.cc.bintools`), and the `-L` directories in `$NIX_LDFLAGS` if
+    /// one of them has zlib, which `libLLVM` needs. Whatever is still missing is taken from
+    /// `stdenv.cc.bintools` and `zlib` built with `nix-build` into `<build-dir>/.nix-deps`.
+    fn nix_patch_paths(&self) -> &'static (String, String) {
+        static PATHS: OnceLock<(String, String)> = OnceLock::new();
+        PATHS.get_or_init(|| {
+            let dynamic_linker = |bintools: &Path| {
+                let path = bintools.join("nix-support/dynamic-linker");
+                Some(fs::read_to_string(path).ok()?.trim_end().to_owned())
+            };
+            let mut interpreter = self.patch_binaries_interpreter.clone().or_else(|| {
+                dynamic_linker(Path::new(&env::var_os("NIX_BINTOOLS")?))
+            });
+            let mut rpath = self.patch_binaries_rpath.clone().or_else(|| {
+                let ldflags = env::var("NIX_LDFLAGS").ok()?;
+                let dirs: Vec<_> =
+                    ldflags.split_whitespace().filter_map(|flag| flag.strip_prefix("-L")).collect();
+                let has_zlib = dirs.iter().any(|dir| Path::new(dir).join("libz.so.1").exists());
+                has_zlib.then(|| dirs.join(":"))
+            });
+
+            if interpreter.is_none() || rpath.is_none() {
+                if let Some(nix_deps_dir) = self.build_nix_deps() {
+                    interpreter = interpreter.or_else(|| dynamic_linker(&nix_deps_dir));
+                    rpath = rpath.or_else(|| Some(nix_deps_dir.join("lib").display().to_string()));
+                }
             }
-            ";
-            nix_build_succeeded = try_run(
-                self,
-                Command::new("nix-build").args([
-                    Path::new("-E"),
-                    Path::new(NIX_EXPR),
-                    Path::new("-o"),
-                    &nix_deps_dir,
-                ]),
-            )
-            .is_ok();
-            nix_deps_dir
+
+            let Some(interpreter) = interpreter else {
+                eprintln!(
+                    "ERROR: don't know which dynamic linker to patch downloaded binaries with."
+                );
+                eprintln!(
+                    "HELP: set `build.patch-binaries-interpreter` in config.toml, or run bootstrap in a Nix shell"
+                );
+                crate::exit!(1);
+            };
+            let Some(rpath) = rpath else {
+                eprintln!(
+                    "ERROR: can't find zlib (`libz.so.1`), which the downloaded `libLLVM` needs."
+                );
+                eprintln!(
+                    "HELP: set `build.patch-binaries-rpath` in config.toml to its directory, or run bootstrap in a Nix shell with zlib"
+                );
+                crate::exit!(1);
+            };
+            (interpreter, rpath)
+        })
+    }
+
+    /// Builds the dependencies of patched binaries that the environment doesn't provide, and
+    /// returns the directory they are linked into.
+    fn build_nix_deps(&self) -> Option<PathBuf> {
+        // Run `nix-build` to "build" each dependency (which will likely reuse
+        // the existing `/nix/store` copy, or at most download a pre-built copy).
+        //
+        // Importantly, we create a gc-root called `.nix-deps` in the `build/`
+        // directory, but still reference the actual `/nix/store` path in the rpath
+        // as it makes it significantly more robust against changes to the location of
+        // the `.nix-deps` location.
+        //
+        // bintools: Needed for the path of `ld-linux.so` (via `nix-support/dynamic-linker`).
+        // zlib: Needed as a system dependency of `libLLVM-*.so`.
+        let nix_deps_dir = self.out.join(".nix-deps");
+        const NIX_EXPR: &str = "
+        with (import <nixpkgs> {});
+        symlinkJoin {
+            name = \"rust-stage0-dependencies\";
+            paths = [
+                zlib
+                stdenv.cc.bintools
+            ];
+        }
+        ";
+        try_run(
+            self,
+            Command::new("nix-build").args([
+                Path::new("-E"),
+                Path::new(NIX_EXPR),
+                Path::new("-o"),
+                &nix_deps_dir,
+            ]),
+        )
+        .ok()?;
+        fs::canonicalize(nix_deps_dir).ok()
+    }
+
+    /// Downloads each `(url, dest_path)` of `downloads`, the HTTP(S) ones in parallel unless
+    /// `build.use-curl` is set. Returns the SHA-256 checksums of the files bootstrap's HTTP client
+    /// computed while downloading them.
+    fn download_files(
+        &self,
+        downloads: &[(String, PathBuf)],
+        help_on_error: &str,
+    ) -> Vec<Option<String>> {
+        let downloads: Vec<_> = downloads
+            .iter()
+            .map(|(url, dest_path)| {
+                let url = self.mirrored_url(url);
+                self.verbose(|| println!("download {url}"));
+                // Use a temporary file in case we crash while downloading, to avoid a corrupt download in cache/.
+                let tempfile = self.tempdir().join(dest_path.file_name().unwrap());
+                (url, tempfile, dest_path)
+            })
+            .collect();
+        // Progress of parallel downloads would be garbled.
+        let progress = downloads.len() == 1 && !CiEnv::is_ci();
+
+        let checksums = thread::scope(|s| {
+            let transfers: Vec<_> = downloads
+                .iter()
+                .map(|(url, tempfile, _)| {
+                    let http = matches!(url.split_once("://"), Some(("http" | "https", _)));
+                    (http && !self.use_curl).then(|| {
+                        println!("downloading {url}");
+                        s.spawn(move || http::download(url, tempfile, progress))
+                    })
+                })
+                .collect();
+            downloads
+                .iter()
+                .zip(transfers)
+                .map(|((url, tempfile, _), transfer)| {
+                    if let Some(transfer) = transfer {
+                        match transfer.join().unwrap() {
+                            Ok(checksum) => return Some(checksum),
+                            Err(e) => {
+                                eprintln!("ERROR: failed to download {url}: {e}");
+                                if !help_on_error.is_empty() {
+                                    eprintln!("{help_on_error}");
+                                }
+                                crate::exit!(1);
+                            }
+                        }
+                    }
+                    // While bootstrap itself only supports http, https and file downloads,
+                    // downstream forks might need to download components from other protocols.
+                    // The match allows them adding more protocols without worrying about merge
+                    // conflicts if we change the HTTP implementation.
+                    match url.split_once("://") {
+                        Some(("http", _)) | Some(("https", _)) => {
+                            self.download_http_with_retries(tempfile, url, help_on_error)
+                        }
+                        Some(("file", path)) => {
+                            self.copy_local_file(tempfile, Path::new(path), help_on_error)
+                        }
+                        Some((other, _)) => panic!("unsupported protocol {other} in {url}"),
+                        None => panic!("no protocol in {url}"),
+                    }
+                    None
+                })
+                .collect()
         });
-        if !nix_build_succeeded {
-            return;
+
+        for (_, tempfile, dest_path) in &downloads {
+            t!(
+                move_file(tempfile, dest_path),
+                format!("failed to rename {tempfile:?} to {dest_path:?}")
+            );
         }
+        checksums
+    }
 
-        let mut patchelf = Command::new(nix_deps_dir.join("bin/patchelf"));
-        patchelf.args(&[
-            OsString::from("--add-rpath"),
-            OsString::from(t!(fs::canonicalize(nix_deps_dir)).join("lib")),
-        ]);
-        if !path_is_dylib(fname) {
-            // Finally, set the correct .interp for binaries
-            let dynamic_linker_path = nix_deps_dir.join("nix-support/dynamic-linker");
-            // FIXME: can we support utf8 here? `args` doesn't accept Vec<u8>, only OsString ...
-            let dynamic_linker = t!(String::from_utf8(t!(fs::read(dynamic_linker_path))));
-            patchelf.args(["--set-interpreter", dynamic_linker.trim_end()]);
-        }
-
-        let _ = try_run(self, patchelf.arg(fname));
-    }
-
-    fn download_file(&self, url: &str, dest_path: &Path, help_on_error: &str) {
-        self.verbose(|| println!("download {url}"));
-        // Use a temporary file in case we crash while downloading, to avoid a corrupt download in cache/.
-        let tempfile = self.tempdir().join(dest_path.file_name().unwrap());
-        // While bootstrap itself only supports http and https downloads, downstream forks might
-        // need to download components from other protocols. The match allows them adding more
-        // protocols without worrying about merge conflicts if we change the HTTP implementation.
-        match url.split_once("://").map(|(proto, _)| proto) {
-            Some("http") | Some("https") => {
-                self.download_http_with_retries(&tempfile, url, help_on_error)
+    /// Where downloaded tarballs are kept, see the `download_cache` module.
+    pub(crate) fn download_cache(&self) -> PathBuf {
+        self.bootstrap_cache_path.clone().unwrap_or_else(|| self.out.join("cache"))
+    }
+
+    /// With `build.download-mirror`, the `file://` URL of the mirrored copy of an HTTP(S) `url`.
+    /// The mirror has the layout of the servers, so the copy is at the path of the URL.
+    pub(crate) fn mirrored_url(&self, url: &str) -> String {
+        let Some(mirror) = &self.download_mirror else { return url.to_owned() };
+        match url.split_once("://") {
+            Some(("http" | "https", rest)) => {
+                let path = rest.split_once('/').map_or("", |(_host, path)| path);
+                format!("file://{}", mirror.join(path).display())
             }
-            Some(other) => panic!("unsupported protocol {other} in {url}"),
-            None => panic!("no protocol in {url}"),
+            _ => url.to_owned(),
         }
-        t!(
-            move_file(&tempfile, dest_path),
-            format!("failed to rename {tempfile:?} to {dest_path:?}")
-        );
     }
 
+    fn copy_local_file(&self, tempfile: &Path, path: &Path, help_on_error: &str) {
+        println!("copying {}", path.display());
+        if let Err(e) = fs::copy(path, tempfile) {
+            eprintln!("ERROR: failed to copy {}: {e}", path.display());
+            if !help_on_error.is_empty() {
+                eprintln!("{help_on_error}");
+            }
+            crate::exit!(1);
+        }
+    }
+
+    /// Downloads `url` with `curl`, or PowerShell on Windows if that fails, for `build.use-curl`.
     fn download_http_with_retries(&self, tempfile: &Path, url: &str, help_on_error: &str) {
         println!("downloading {url}");
         // Try curl. If that fails and we are on windows, fallback to PowerShell.
@@ -363,37 +522,24 @@ impl Config {
 
     /// Returns whether the SHA256 checksum of `path` matches `expected`.
     pub(crate) fn verify(&self, path: &Path, expected: &str) -> bool {
-        use sha2::Digest;
-
         self.verbose(|| println!("verifying {}", path.display()));
 
         if self.dry_run() {
             return false;
         }
 
-        let mut hasher = sha2::Sha256::new();
-
-        let file = t!(File::open(path));
-        let mut reader = BufReader::new(file);
-
-        loop {
-            let buffer = t!(reader.fill_buf());
-            let l = buffer.len();
-            // break if EOF
-            if l == 0 {
-                break;
-            }
-            hasher.update(buffer);
-            reader.consume(l);
-        }
+        let checksum = t!(download_cache::file_sha256(path));
+        self.checksum_matches(&checksum, expected)
+    }
 
-        let checksum = hex_encode(hasher.finalize().as_slice());
-        let verified = checksum == expected;
+    /// Whether the `found` checksum of a file is the `expected` one, see `verify`.
+    fn checksum_matches(&self, found: &str, expected: &str) -> bool {
+        let verified = found == expected;
 
         if !verified {
             println!(
                 "invalid checksum: \n\
-                found:    {checksum}\n\
+                found:    {found}\n\
                 expected: {expected}",
             );
         }
@@ -418,24 +564,67 @@ enum DownloadSource {
     Dist,
 }
 
+impl DownloadSource {
+    fn help_on_error(&self) -> &'static str {
+        match self {
+            DownloadSource::CI => {
+                "ERROR: failed to download pre-built rustc from CI
+
+NOTE: old builds get deleted after a certain time
+HELP: if trying to compile an old commit of rustc, disable `download-rustc` in config.toml:
+
+[rust]
+download-rustc = false
+"
+            }
+            DownloadSource::Dist => "",
+        }
+    }
+}
+
+/// The components the stage0 toolchain has besides `rustc` and `rust-std`.
+const BETA_EXTRA_COMPONENTS: &[&str] = &["cargo"];
+/// download-rustc doesn't need its own cargo, it can just use beta's. But it does need the
+/// `rustc_private` crates for tools.
+const CI_RUSTC_EXTRA_COMPONENTS: &[&str] = &["rustc-dev"];
+
+/// A file bootstrap downloads into its cache, see `Config::download_manifest`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Artifact {
+    /// The file name, which is also the `name` of a Nix `fetchurl`.
+    pub(crate) name: String,
+    /// Where it's downloaded from, before `build.download-mirror` applies.
+    pub(crate) url: String,
+    /// Where the download cache keeps it.
+    pub(crate) path: PathBuf,
+    /// Only `src/stage0` has checksums, so artifacts of CI builds don't have one.
+    pub(crate) sha256: Option<String>,
+}
+
 /// Functions that are only ever called once, but named for clarify and to avoid thousand-line functions.
 impl Config {
     pub(crate) fn download_clippy(&self) -> PathBuf {
+        if let Some(toolchain) = &self.nix_toolchain {
+            eprintln!(
+                "ERROR: `build.nix-toolchain` {} has no `bin/cargo-clippy`, add clippy to it",
+                toolchain.display()
+            );
+            crate::exit!(2);
+        }
         self.verbose(|| println!("downloading stage0 clippy artifacts"));
 
         let date = &self.stage0_metadata.compiler.date;
-        let version = &self.stage0_metadata.compiler.version;
         let host = self.build;
 
-        let bin_root = self.out.join(host).join("stage0");
+        let bin_root = self.base_out.join(host).join("stage0");
         let clippy_stamp = bin_root.join(".clippy-stamp");
         let cargo_clippy = bin_root.join("bin").join(exe("cargo-clippy", host));
         if cargo_clippy.exists() && !program_out_of_date(&clippy_stamp, date) {
             return cargo_clippy;
         }
 
-        let filename = format!("clippy-{version}-{host}.tar.xz");
-        self.download_component(DownloadSource::Dist, filename, "clippy-preview", date, "stage0");
+        let artifact = self.clippy_artifact();
+        self.download_component(DownloadSource::Dist, &artifact, "clippy-preview", "stage0");
         if self.should_fix_bins_and_dylibs() {
             self.fix_bin_or_dylib(&cargo_clippy);
             self.fix_bin_or_dylib(&cargo_clippy.with_file_name(exe("clippy-driver", host)));
@@ -460,27 +649,16 @@ impl Config {
         let channel = format!("{version}-{date}");
 
         let host = self.build;
-        let bin_root = self.out.join(host).join("rustfmt");
+        let bin_root = self.base_out.join(host).join("rustfmt");
         let rustfmt_path = bin_root.join("bin").join(exe("rustfmt", host));
         let rustfmt_stamp = bin_root.join(".rustfmt-stamp");
         if rustfmt_path.exists() && !program_out_of_date(&rustfmt_stamp, &channel) {
             return Some(rustfmt_path);
         }
 
-        self.download_component(
-            DownloadSource::Dist,
-            format!("rustfmt-{version}-{build}.tar.xz", build = host.triple),
-            "rustfmt-preview",
-            date,
-            "rustfmt",
-        );
-        self.download_component(
-            DownloadSource::Dist,
-            format!("rustc-{version}-{build}.tar.xz", build = host.triple),
-            "rustc",
-            date,
-            "rustfmt",
-        );
+        let [rustfmt, rustc] = self.rustfmt_artifacts()?;
+        self.download_component(DownloadSource::Dist, &rustfmt, "rustfmt-preview", "rustfmt");
+        self.download_component(DownloadSource::Dist, &rustc, "rustc", "rustfmt");
 
         if self.should_fix_bins_and_dylibs() {
             self.fix_bin_or_dylib(&bin_root.join("bin").join("rustfmt"));
@@ -522,16 +700,12 @@ impl Config {
         self.verbose(|| println!("using downloaded stage2 artifacts from CI (commit {commit})"));
 
         let version = self.artifact_version_part(commit);
-        // download-rustc doesn't need its own cargo, it can just use beta's. But it does need the
-        // `rustc_private` crates for tools.
-        let extra_components = ["rustc-dev"];
-
         self.download_toolchain(
             &version,
             "ci-rustc",
             &format!("{commit}-{}", self.llvm_assertions),
-            &extra_components,
-            Self::download_ci_component,
+            CI_RUSTC_EXTRA_COMPONENTS,
+            DownloadSource::CI,
         );
     }
 
@@ -540,23 +714,18 @@ impl Config {
 
     #[cfg(not(feature = "bootstrap-self-test"))]
     pub(crate) fn download_beta_toolchain(&self) {
+        use crate::core::config::flags::Subcommand;
+
+        // `x fetch` downloads it along with everything else, and `x cache` doesn't need it.
+        if let Subcommand::Fetch { .. } | Subcommand::Cache { .. } = self.cmd {
+            return;
+        }
         self.verbose(|| println!("downloading stage0 beta artifacts"));
 
         let date = &self.stage0_metadata.compiler.date;
         let version = &self.stage0_metadata.compiler.version;
-        let extra_components = ["cargo"];
-
-        let download_beta_component = |config: &Config, filename, prefix: &_, date: &_| {
-            config.download_component(DownloadSource::Dist, filename, prefix, date, "stage0")
-        };
-
-        self.download_toolchain(
-            version,
-            "stage0",
-            date,
-            &extra_components,
-            download_beta_component,
-        );
+        let mode = DownloadSource::Dist;
+        self.download_toolchain(version, "stage0", date, BETA_EXTRA_COMPONENTS, mode);
     }
 
     fn download_toolchain(
@@ -565,7 +734,7 @@ impl Config {
         sysroot: &str,
         stamp_key: &str,
         extra_components: &[&str],
-        download_component: fn(&Config, String, &str, &str),
+        mode: DownloadSource,
     ) {
         let host = self.build.triple;
         let bin_root = self.out.join(host).join(sysroot);
@@ -577,15 +746,15 @@ impl Config {
             if bin_root.exists() {
                 t!(fs::remove_dir_all(&bin_root));
             }
-            let filename = format!("rust-std-{version}-{host}.tar.xz");
-            let pattern = format!("rust-std-{host}");
-            download_component(self, filename, &pattern, stamp_key);
-            let filename = format!("rustc-{version}-{host}.tar.xz");
-            download_component(self, filename, "rustc", stamp_key);
-
-            for component in extra_components {
-                let filename = format!("{component}-{version}-{host}.tar.xz");
-                download_component(self, filename, component, stamp_key);
+            if !self.dry_run() {
+                let artifacts =
+                    self.toolchain_artifacts(version, stamp_key, extra_components, &mode);
+                // Fetched together, but unpacked one after the other into the same directory.
+                self.fetch_artifacts(&artifacts, mode.help_on_error());
+                let components = self.toolchain_components(extra_components);
+                for (artifact, (pattern, _)) in artifacts.iter().zip(&components) {
+                    self.unpack(&artifact.path, &bin_root, pattern);
+                }
             }
 
             if self.should_fix_bins_and_dylibs() {
@@ -607,112 +776,172 @@ impl Config {
         }
     }
 
-    /// Download a single component of a CI-built toolchain (not necessarily a published nightly).
-    // NOTE: intentionally takes an owned string to avoid downloading multiple times by accident
-    fn download_ci_component(&self, filename: String, prefix: &str, commit_with_assertions: &str) {
-        Self::download_component(
-            self,
-            DownloadSource::CI,
-            filename,
-            prefix,
-            commit_with_assertions,
-            "ci-rustc",
-        )
+    /// The components of a toolchain with `extra_components`, each with the directory its
+    /// tarball contains.
+    fn toolchain_components<'a>(&self, extra_components: &[&'a str]) -> Vec<(String, &'a str)> {
+        let rust_std = format!("rust-std-{}", self.build.triple);
+        let mut components = vec![(rust_std, "rust-std"), ("rustc".to_owned(), "rustc")];
+        components
+            .extend(extra_components.iter().map(|&component| (component.to_owned(), component)));
+        components
+    }
+
+    fn toolchain_artifacts(
+        &self,
+        version: &str,
+        stamp_key: &str,
+        extra_components: &[&str],
+        mode: &DownloadSource,
+    ) -> Vec<Artifact> {
+        let host = self.build.triple;
+        let filenames: Vec<_> = self
+            .toolchain_components(extra_components)
+            .iter()
+            .map(|(_, component)| format!("{component}-{version}-{host}.tar.xz"))
+            .collect();
+        self.component_artifacts(mode, stamp_key, &filenames)
+    }
+
+    fn clippy_artifact(&self) -> Artifact {
+        let date = &self.stage0_metadata.compiler.date;
+        let version = &self.stage0_metadata.compiler.version;
+        let filename = format!("clippy-{version}-{}.tar.xz", self.build.triple);
+        self.component_artifacts(&DownloadSource::Dist, date, &[filename]).pop().unwrap()
+    }
+
+    /// The `rustfmt` component and the `rustc` it needs, if `src/stage0` has a rustfmt.
+    fn rustfmt_artifacts(&self) -> Option<[Artifact; 2]> {
+        let metadata = self.stage0_metadata.rustfmt.as_ref()?;
+        let host = self.build.triple;
+        let filenames = [
+            format!("rustfmt-{}-{host}.tar.xz", metadata.version),
+            format!("rustc-{}-{host}.tar.xz", metadata.version),
+        ];
+        let artifacts = self.component_artifacts(&DownloadSource::Dist, &metadata.date, &filenames);
+        Some(artifacts.try_into().unwrap())
     }
 
+    /// Unpacks the stage0 `artifact` into `destination`, in the build directory all variants
+    /// share.
     fn download_component(
         &self,
         mode: DownloadSource,
-        filename: String,
+        artifact: &Artifact,
         prefix: &str,
-        key: &str,
         destination: &str,
     ) {
         if self.dry_run() {
             return;
         }
 
-        let cache_dst =
-            self.bootstrap_cache_path.as_ref().cloned().unwrap_or_else(|| self.out.join("cache"));
+        self.fetch_artifacts(std::slice::from_ref(artifact), mode.help_on_error());
+        self.unpack(&artifact.path, &self.base_out.join(self.build).join(destination), prefix);
+    }
 
-        let cache_dir = cache_dst.join(key);
-        if !cache_dir.exists() {
-            t!(fs::create_dir_all(&cache_dir));
-        }
+    /// The `filenames` of the release `key` of `mode`, with where they're cached.
+    fn component_artifacts(
+        &self,
+        mode: &DownloadSource,
+        key: &str,
+        filenames: &[String],
+    ) -> Vec<Artifact> {
+        let cache_dir = self.download_cache().join(key);
 
-        let bin_root = self.out.join(self.build).join(destination);
-        let tarball = cache_dir.join(&filename);
-        let (base_url, url, should_verify) = match mode {
+        let (base_url, should_verify) = match mode {
             DownloadSource::CI => {
                 let dist_server = if self.llvm_assertions {
                     self.stage0_metadata.config.artifacts_with_llvm_assertions_server.clone()
                 } else {
                     self.stage0_metadata.config.artifacts_server.clone()
                 };
-                let url = format!(
-                    "{}/{filename}",
-                    key.strip_suffix(&format!("-{}", self.llvm_assertions)).unwrap()
-                );
-                (dist_server, url, false)
+                (dist_server, false)
             }
             DownloadSource::Dist => {
                 let dist_server = env::var("RUSTUP_DIST_SERVER")
                     .unwrap_or(self.stage0_metadata.config.dist_server.to_string());
-                // NOTE: make `dist` part of the URL because that's how it's stored in src/stage0
-                (dist_server, format!("dist/{key}/{filename}"), true)
+                (dist_server, true)
             }
         };
 
-        // For the beta compiler, put special effort into ensuring the checksums are valid.
-        let checksum = if should_verify {
-            let error = format!(
-                "src/stage0 doesn't contain a checksum for {url}. \
-                Pre-built artifacts might not be available for this \
-                target at this time, see https://doc.rust-lang.org/nightly\
-                /rustc/platform-support.html for more information."
-            );
-            let sha256 = self.stage0_metadata.checksums_sha256.get(&url).expect(&error);
-            if tarball.exists() {
-                if self.verify(&tarball, sha256) {
-                    self.unpack(&tarball, &bin_root, prefix);
-                    return;
-                } else {
-                    self.verbose(|| {
-                        println!(
-                            "ignoring cached file {} due to failed verification",
-                            tarball.display()
-                        )
-                    });
-                    self.remove(&tarball);
-                }
-            }
-            Some(sha256)
-        } else if tarball.exists() {
-            self.unpack(&tarball, &bin_root, prefix);
-            return;
-        } else {
-            None
-        };
+        filenames
+            .iter()
+            .map(|filename| {
+                let url = match mode {
+                    DownloadSource::CI => format!(
+                        "{}/{filename}",
+                        key.strip_suffix(&format!("-{}", self.llvm_assertions)).unwrap()
+                    ),
+                    // NOTE: make `dist` part of the URL because that's how it's stored in
+                    // src/stage0
+                    DownloadSource::Dist => format!("dist/{key}/{filename}"),
+                };
 
-        let mut help_on_error = "";
-        if destination == "ci-rustc" {
-            help_on_error = "ERROR: failed to download pre-built rustc from CI
+                // For the beta compiler, put special effort into ensuring the checksums are valid.
+                let sha256 = should_verify.then(|| {
+                    let error = format!(
+                        "src/stage0 doesn't contain a checksum for {url}. \
+                        Pre-built artifacts might not be available for this \
+                        target at this time, see https://doc.rust-lang.org/nightly\
+                        /rustc/platform-support.html for more information."
+                    );
+                    self.stage0_metadata.checksums_sha256.get(&url).expect(&error).clone()
+                });
+                Artifact {
+                    name: filename.clone(),
+                    url: format!("{base_url}/{url}"),
+                    path: cache_dir.join(filename),
+                    sha256,
+                }
+            })
+            .collect()
+    }
 
-NOTE: old builds get deleted after a certain time
-HELP: if trying to compile an old commit of rustc, disable `download-rustc` in config.toml:
+    /// Makes sure the download cache has the `artifacts` and that they have the expected
+    /// checksums. Missing ones are downloaded together.
+    fn fetch_artifacts(&self, artifacts: &[Artifact], help_on_error: &str) {
+        let mut downloads = vec![];
+        let mut checksums = vec![];
+        for artifact in artifacts {
+            let tarball = &artifact.path;
+            let cache_dir = tarball.parent().unwrap();
+            if !cache_dir.exists() {
+                t!(fs::create_dir_all(cache_dir));
+            }
 
-[rust]
-download-rustc = false
-";
-        }
-        self.download_file(&format!("{base_url}/{url}"), &tarball, help_on_error);
-        if let Some(sha256) = checksum {
-            if !self.verify(&tarball, sha256) {
-                panic!("failed to verify {}", tarball.display());
+            if tarball.exists() {
+                let valid = match &artifact.sha256 {
+                    Some(sha256) => self.verify(tarball, sha256),
+                    None => true,
+                };
+                if valid {
+                    download_cache::mark_used(tarball);
+                    continue;
+                }
+                self.verbose(|| {
+                    println!("ignoring cached file {} due to failed verification", tarball.display())
+                });
+                self.remove(tarball);
             }
+            downloads.push((artifact.url.clone(), tarball.clone()));
+            checksums.push(artifact.sha256.as_deref());
         }
 
-        self.unpack(&tarball, &bin_root, prefix);
+        let found = self.download_files(&downloads, help_on_error);
+        for (((_, tarball), checksum), found) in downloads.iter().zip(checksums).zip(found) {
+            if let Some(sha256) = checksum {
+                let verified = match found {
+                    Some(found) => self.checksum_matches(&found, sha256),
+                    None => self.verify(tarball, sha256),
+                };
+                if !verified {
+                    panic!("failed to verify {}", tarball.display());
+                }
+            } else {
+                // For `x cache verify`.
+                let found = found.unwrap_or_else(|| t!(download_cache::file_sha256(tarball)));
+                download_cache::record_checksum(tarball, &found);
+            }
+        }
     }
 
     #[cfg(feature = "bootstrap-self-test")]
@@ -722,7 +951,6 @@ download-rustc = false
     pub(crate) fn maybe_download_ci_llvm(&self) {
         use build_helper::exit;
 
-        use crate::core::build_steps::llvm::detect_llvm_sha;
         use crate::core::config::check_incompatible_options_for_ci_llvm;
 
         if !self.llvm_from_ci {
@@ -792,16 +1020,27 @@ download-rustc = false
 
     #[cfg(not(feature = "bootstrap-self-test"))]
     fn download_ci_llvm(&self, llvm_sha: &str) {
+        let help_on_error = "ERROR: failed to download llvm from ci
+
+    HELP: There could be two reasons behind this:
+        1) The host triple is not supported for `download-ci-llvm`.
+        2) Old builds get deleted after a certain time.
+    HELP: In either case, disable `download-ci-llvm` in your config.toml:
+
+    [llvm]
+    download-ci-llvm = false
+    ";
+        let artifact = self.ci_llvm_artifact(llvm_sha);
+        self.fetch_artifacts(std::slice::from_ref(&artifact), help_on_error);
+        let llvm_root = self.ci_llvm_root();
+        self.unpack(&artifact.path, &llvm_root, "rust-dev");
+    }
+
+    fn ci_llvm_artifact(&self, llvm_sha: &str) -> Artifact {
         let llvm_assertions = self.llvm_assertions;
 
         let cache_prefix = format!("llvm-{llvm_sha}-{llvm_assertions}");
-        let cache_dst =
-            self.bootstrap_cache_path.as_ref().cloned().unwrap_or_else(|| self.out.join("cache"));
-
-        let rustc_cache = cache_dst.join(cache_prefix);
-        if !rustc_cache.exists() {
-            t!(fs::create_dir_all(&rustc_cache));
-        }
+        let rustc_cache = self.download_cache().join(cache_prefix);
         let base = if llvm_assertions {
             &self.stage0_metadata.config.artifacts_with_llvm_assertions_server
         } else {
@@ -809,22 +1048,66 @@ download-rustc = false
         };
         let version = self.artifact_version_part(llvm_sha);
         let filename = format!("rust-dev-{}-{}.tar.xz", version, self.build.triple);
-        let tarball = rustc_cache.join(&filename);
-        if !tarball.exists() {
-            let help_on_error = "ERROR: failed to download llvm from ci
+        Artifact {
+            url: format!("{base}/{llvm_sha}/{filename}"),
+            path: rustc_cache.join(&filename),
+            name: filename,
+            sha256: None,
+        }
+    }
 
-    HELP: There could be two reasons behind this:
-        1) The host triple is not supported for `download-ci-llvm`.
-        2) Old builds get deleted after a certain time.
-    HELP: In either case, disable `download-ci-llvm` in your config.toml:
+    /// Everything a build with this configuration downloads, for `x fetch`. Clippy and rustfmt
+    /// are only downloaded once a step needs them, but are listed unless local ones are
+    /// configured.
+    pub(crate) fn download_manifest(&self) -> Vec<Artifact> {
+        let configured = |key: &str| self.sources.source(&["build".to_owned(), key.to_owned()]);
+        let mut artifacts = vec![];
+        if self.nix_toolchain.is_none()
+            && (configured("rustc").is_none() || configured("cargo").is_none())
+        {
+            let date = &self.stage0_metadata.compiler.date;
+            let version = &self.stage0_metadata.compiler.version;
+            artifacts.extend(self.toolchain_artifacts(
+                version,
+                date,
+                BETA_EXTRA_COMPONENTS,
+                &DownloadSource::Dist,
+            ));
+        }
+        if let Some(commit) = &self.download_rustc_commit {
+            artifacts.extend(self.toolchain_artifacts(
+                &self.artifact_version_part(commit),
+                &format!("{commit}-{}", self.llvm_assertions),
+                CI_RUSTC_EXTRA_COMPONENTS,
+                &DownloadSource::CI,
+            ));
+        }
+        if self.llvm_from_ci {
+            let llvm_sha = detect_llvm_sha(self, self.rust_info.is_managed_git_subrepository());
+            artifacts.push(self.ci_llvm_artifact(&llvm_sha));
+        }
+        if let RustfmtState::LazyEvaluated = *self.initial_rustfmt.borrow() {
+            artifacts.extend(self.rustfmt_artifacts().into_iter().flatten());
+        }
+        if self.initial_cargo_clippy.is_none() && self.nix_toolchain.is_none() {
+            artifacts.push(self.clippy_artifact());
+        }
+        // rustfmt and the stage0 toolchain share the `rustc` component if they're from the
+        // same release.
+        let mut seen = HashSet::new();
+        artifacts.retain(|artifact| seen.insert(artifact.path.clone()));
+        artifacts
+    }
 
-    [llvm]
-    download-ci-llvm = false
-    ";
-            self.download_file(&format!("{base}/{llvm_sha}/{filename}"), &tarball, help_on_error);
+    /// `x fetch`: downloads the `download_manifest` into the download cache, or with `list`,
+    /// prints it as JSON.
+    pub fn fetch(&self, list: bool) {
+        let artifacts = self.download_manifest();
+        if list {
+            println!("{}", t!(serde_json::to_string_pretty(&artifacts)));
+        } else if !self.dry_run() {
+            self.fetch_artifacts(&artifacts, "");
         }
-        let llvm_root = self.ci_llvm_root();
-        self.unpack(&tarball, &llvm_root, "rust-dev");
     }
 }
 
@@ -0,0 +1,268 @@
+//! The download cache, where bootstrap keeps the tarballs it downloads, and `x cache` to inspect
+//! and clean it up. It's `build.bootstrap-cache-path`, or `<build-dir>/cache` by default.
+//!
+//! Each entry is a directory named after the release it was downloaded for: the date of a stage0
+//! release, `<commit>-<llvm-assertions>` for a CI rustc, or `llvm-<commit>-<llvm-assertions>` for
+//! CI LLVM. Tarballs that `src/stage0` has no checksum for get theirs recorded in
+//! `<tarball>.sha256` when they're downloaded, and a pinned entry contains an empty `.pinned` file.
+//!
+//! The age of an entry is the time since one of its tarballs was last downloaded or unpacked.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use build_helper::exit;
+use sha2::{Digest, Sha256};
+
+use crate::Config;
+use crate::core::config::flags::CacheCommand;
+use crate::utils::helpers::{hex_encode, set_file_times, t};
+
+#[cfg(test)]
+mod tests;
+
+const PINNED: &str = ".pinned";
+const CHECKSUM_EXTENSION: &str = "sha256";
+
+/// An entry of the download cache, see the module documentation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CacheEntry {
+    pub(crate) name: String,
+    /// The total size of its tarballs.
+    pub(crate) size: u64,
+    pub(crate) used: SystemTime,
+    pub(crate) pinned: bool,
+}
+
+/// The entries of the download cache `dir`, most recently used first.
+pub(crate) fn entries(dir: &Path) -> Vec<CacheEntry> {
+    let Ok(read_dir) = fs::read_dir(dir) else { return vec![] };
+    let mut entries = vec![];
+    for entry in read_dir {
+        let entry = t!(entry);
+        if !t!(entry.file_type()).is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        let mut size = 0;
+        let mut used = SystemTime::UNIX_EPOCH;
+        for tarball in tarballs(&path) {
+            let metadata = t!(tarball.metadata());
+            size += metadata.len();
+            used = used.max(metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+        }
+        entries.push(CacheEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            size,
+            used,
+            pinned: path.join(PINNED).exists(),
+        });
+    }
+    entries.sort_by(|a, b| b.used.cmp(&a.used).then_with(|| a.name.cmp(&b.name)));
+    entries
+}
+
+/// The tarballs in the entry `dir`, i.e. its files but the recorded checksums and the pin.
+fn tarballs(dir: &Path) -> Vec<PathBuf> {
+    let mut tarballs: Vec<_> = t!(fs::read_dir(dir))
+        .map(|entry| t!(entry).path())
+        .filter(|path| {
+            path.is_file()
+                && path.file_name().is_some_and(|name| name != PINNED)
+                && path.extension().is_none_or(|extension| extension != CHECKSUM_EXTENSION)
+        })
+        .collect();
+    tarballs.sort();
+    tarballs
+}
+
+/// The entries `x cache gc` removes: the unpinned ones last used more than `max_age` before
+/// `now`, and then the least recently used ones until the whole cache fits in `max_size`.
+pub(crate) fn evictions(
+    entries: &[CacheEntry],
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+    now: SystemTime,
+) -> Vec<&CacheEntry> {
+    let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+    let mut candidates: Vec<_> = entries.iter().filter(|entry| !entry.pinned).collect();
+    candidates.sort_by_key(|entry| entry.used);
+    candidates
+        .into_iter()
+        .filter(|entry| {
+            let age = now.duration_since(entry.used).unwrap_or_default();
+            let evict = max_age.is_some_and(|max_age| age > max_age)
+                || max_size.is_some_and(|max_size| total > max_size);
+            if evict {
+                total -= entry.size;
+            }
+            evict
+        })
+        .collect()
+}
+
+/// The SHA-256 checksum of the file `path`.
+pub(crate) fn file_sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex_encode(hasher.finalize()))
+}
+
+/// Records the `checksum` of a downloaded `tarball` that `src/stage0` has none for.
+pub(crate) fn record_checksum(tarball: &Path, checksum: &str) {
+    t!(fs::write(checksum_path(tarball), format!("{checksum}\n")));
+}
+
+/// Marks the cached `tarball` as used, which resets the age of its entry.
+pub(crate) fn mark_used(tarball: &Path) {
+    let times = fs::FileTimes::new().set_modified(SystemTime::now());
+    // Only affects `x cache gc`, so a read-only cache is fine.
+    let _ = set_file_times(tarball, times);
+}
+
+fn checksum_path(tarball: &Path) -> PathBuf {
+    let mut path = tarball.as_os_str().to_owned();
+    path.push(".");
+    path.push(CHECKSUM_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Parses a size for `x cache gc --max-size`: a number of bytes, optionally followed by `K`, `M`
+/// or `G` for KiB, MiB or GiB.
+pub(crate) fn parse_size(size: &str) -> Result<u64, String> {
+    let number = size.trim_end_matches(['B', 'b']);
+    let (number, shift) = match number.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&number[..i], 10),
+        Some((i, 'M' | 'm')) => (&number[..i], 20),
+        Some((i, 'G' | 'g')) => (&number[..i], 30),
+        _ => (number, 0),
+    };
+    let number: u64 = number.trim().parse().map_err(|_| format!("invalid size `{size}`"))?;
+    number.checked_mul(1 << shift).ok_or_else(|| format!("size `{size}` is too large"))
+}
+
+fn format_size(size: u64) -> String {
+    match size {
+        0..1024 => format!("{size} B"),
+        1024..0x10_0000 => format!("{:.1} KiB", size as f64 / 1024.0),
+        0x10_0000..0x4000_0000 => format!("{:.1} MiB", size as f64 / 1024.0 / 1024.0),
+        _ => format!("{:.1} GiB", size as f64 / 1024.0 / 1024.0 / 1024.0),
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    let minutes = age.as_secs() / 60;
+    match minutes {
+        0..60 => format!("{minutes} min"),
+        60..1440 => format!("{} h", minutes / 60),
+        _ => format!("{} days", minutes / 1440),
+    }
+}
+
+impl Config {
+    /// Runs `x cache`. Returns `false` if `x cache verify` found a corrupt tarball.
+    pub fn cache(&self, cmd: &CacheCommand) -> bool {
+        let dir = self.download_cache();
+        match cmd {
+            CacheCommand::Ls => self.cache_ls(&dir),
+            CacheCommand::Verify => return self.cache_verify(&dir),
+            CacheCommand::Gc { max_age, max_size } => {
+                let max_age = max_age.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+                self.cache_gc(&dir, max_age, *max_size);
+            }
+            CacheCommand::Pin { remove, entry } => self.cache_pin(&dir, entry, *remove),
+        }
+        true
+    }
+
+    fn cache_ls(&self, dir: &Path) {
+        let entries = entries(dir);
+        if entries.is_empty() {
+            println!("The download cache {} is empty.", dir.display());
+            return;
+        }
+        let now = SystemTime::now();
+        let width = entries.iter().map(|entry| entry.name.len()).max().unwrap();
+        for entry in &entries {
+            let age = now.duration_since(entry.used).unwrap_or_default();
+            println!(
+                "{:width$}  {:>10}  {:>8}{}",
+                entry.name,
+                format_size(entry.size),
+                format_age(age),
+                if entry.pinned { "  pinned" } else { "" },
+            );
+        }
+        let total = entries.iter().map(|entry| entry.size).sum();
+        println!("{} entries, {} in {}", entries.len(), format_size(total), dir.display());
+    }
+
+    fn cache_verify(&self, dir: &Path) -> bool {
+        let mut ok = true;
+        for entry in entries(dir) {
+            for tarball in tarballs(&dir.join(&entry.name)) {
+                let filename = tarball.file_name().unwrap().to_string_lossy();
+                let name = format!("{}/{filename}", entry.name);
+                let url = format!("dist/{}/{filename}", entry.name);
+                let expected = match self.stage0_metadata.checksums_sha256.get(&url) {
+                    Some(checksum) => checksum.clone(),
+                    None => match fs::read_to_string(checksum_path(&tarball)) {
+                        Ok(checksum) => checksum.trim().to_owned(),
+                        Err(_) => {
+                            println!("no checksum: {name}");
+                            continue;
+                        }
+                    },
+                };
+                if t!(file_sha256(&tarball)) == expected {
+                    self.verbose(|| println!("ok: {name}"));
+                } else {
+                    println!("CORRUPT: {name}");
+                    ok = false;
+                }
+            }
+        }
+        if ok {
+            println!("All checksums of the download cache {} match.", dir.display());
+        } else {
+            println!("HELP: remove the corrupt tarballs, they're downloaded again when needed");
+        }
+        ok
+    }
+
+    fn cache_gc(&self, dir: &Path, max_age: Option<Duration>, max_size: Option<u64>) {
+        let entries = entries(dir);
+        let evictions = evictions(&entries, max_age, max_size, SystemTime::now());
+        let freed: u64 = evictions.iter().map(|entry| entry.size).sum();
+        for entry in &evictions {
+            println!("removing {} ({})", entry.name, format_size(entry.size));
+            if !self.dry_run() {
+                t!(fs::remove_dir_all(dir.join(&entry.name)));
+            }
+        }
+        println!("Removed {} entries, freeing {}.", evictions.len(), format_size(freed));
+    }
+
+    fn cache_pin(&self, dir: &Path, name: &str, remove: bool) {
+        let entry = dir.join(name);
+        if name.contains(['/', '\\']) || !entry.is_dir() {
+            eprintln!("ERROR: `{name}` isn't an entry of the download cache {}", dir.display());
+            eprintln!("HELP: `x cache ls` lists the entries");
+            exit!(2);
+        }
+        if self.dry_run() {
+            return;
+        }
+        if remove {
+            if let Err(e) = fs::remove_file(entry.join(PINNED)) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    panic!("failed to unpin {name}: {e}");
+                }
+            }
+        } else {
+            t!(File::create(entry.join(PINNED)));
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use crate::core::config::tests::test_dir;
+use crate::core::download_cache::{
+    CacheEntry, entries, evictions, file_sha256, mark_used, parse_size, record_checksum,
+};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn entry(name: &str, size: u64, age_days: u32, pinned: bool) -> CacheEntry {
+    let now = SystemTime::UNIX_EPOCH + DAY * 1000;
+    CacheEntry { name: name.to_owned(), size, used: now - DAY * age_days, pinned }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("512"), Ok(512));
+    assert_eq!(parse_size("512B"), Ok(512));
+    assert_eq!(parse_size("4K"), Ok(4096));
+    assert_eq!(parse_size("3 MiB").ok(), None);
+    assert_eq!(parse_size("3M"), Ok(3 << 20));
+    assert_eq!(parse_size("10gb"), Ok(10 << 30));
+    assert!(parse_size("G").is_err());
+    assert!(parse_size("99999999999G").is_err());
+}
+
+#[test]
+fn test_evictions() {
+    let now = SystemTime::UNIX_EPOCH + DAY * 1000;
+    let entries = [
+        entry("new", 100, 1, false),
+        entry("pinned", 100, 90, true),
+        entry("old", 100, 60, false),
+        entry("older", 100, 70, false),
+        entry("middle", 100, 10, false),
+    ];
+    let names = |max_age, max_size| -> Vec<_> {
+        evictions(&entries, max_age, max_size, now).iter().map(|entry| &*entry.name).collect()
+    };
+    assert_eq!(names(Some(DAY * 30), None), ["older", "old"]);
+    assert_eq!(names(None, Some(300)), ["older", "old"]);
+    assert_eq!(names(None, Some(250)), ["older", "old", "middle"]);
+    assert_eq!(names(Some(DAY * 65), Some(400)), ["older"]);
+    // Pinned entries count towards the size, but stay.
+    assert_eq!(names(None, Some(0)), ["older", "old", "middle", "new"]);
+    assert!(names(None, Some(1000)).is_empty());
+}
+
+#[test]
+fn test_entries() {
+    let dir = test_dir("download-cache");
+    fs::create_dir_all(dir.join("2024-11-28")).unwrap();
+    fs::create_dir_all(dir.join("llvm-abc-false")).unwrap();
+    let rustc = dir.join("2024-11-28/rustc.tar.xz");
+    let llvm = dir.join("llvm-abc-false/rust-dev.tar.xz");
+    fs::write(&rustc, "rustc").unwrap();
+    fs::write(&llvm, "rust-dev").unwrap();
+    record_checksum(&llvm, &file_sha256(&llvm).unwrap());
+    fs::write(dir.join("llvm-abc-false/.pinned"), "").unwrap();
+    let old = fs::FileTimes::new().set_modified(SystemTime::now() - DAY);
+    crate::utils::helpers::set_file_times(&rustc, old).unwrap();
+
+    let names = |entries: Vec<CacheEntry>| -> Vec<_> {
+        entries.into_iter().map(|entry| (entry.name, entry.size, entry.pinned)).collect()
+    };
+    let expected = [("llvm-abc-false".to_owned(), 8, true), ("2024-11-28".to_owned(), 5, false)];
+    assert_eq!(names(entries(&dir)), expected);
+    mark_used(&rustc);
+    assert_eq!(names(entries(&dir))[0].0, "2024-11-28");
+
+    fs::remove_dir_all(dir).unwrap();
+}
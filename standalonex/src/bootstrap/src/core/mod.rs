@@ -2,5 +2,6 @@ pub(crate) mod build_steps;
 pub(crate) mod builder;
 pub(crate) mod config;
 pub(crate) mod download;
+pub(crate) mod download_cache;
 pub(crate) mod metadata;
 pub(crate) mod sanity;
@@ -21,9 +21,8 @@ use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fmt::Display;
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::OnceLock;
-use std::time::SystemTime;
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime};
 use std::{env, io, str};
 
 use build_helper::ci::gha;
@@ -35,18 +34,23 @@ use utils::helpers::hex_encode;
 
 use crate::core::builder;
 use crate::core::builder::{Builder, Kind};
+use crate::core::config::flags::EmitMode;
 use crate::core::config::{DryRun, LldMode, LlvmLibunwind, Target, TargetSelection, flags};
 use crate::utils::exec::{BehaviorOnFailure, BootstrapCommand, CommandOutput, OutputMode, command};
+use crate::utils::executor::{CommandExecutor, DryRunExecutor, NixExecutor};
 use crate::utils::helpers::{
     self, dir_is_empty, exe, libdir, mtime, output, set_file_times, symlink_dir,
 };
+use crate::utils::invocation_log::{INVOCATION_LOG_FILENAME, Invocation};
+use crate::utils::step_cache::StepCache;
 
 mod core;
 mod utils;
 
+pub use core::build_steps::setup::migrate_config_toml;
 pub use core::builder::PathSet;
 pub use core::config::Config;
-pub use core::config::flags::{Flags, Subcommand};
+pub use core::config::flags::{CacheCommand, ConfigCommand, Flags, Subcommand};
 
 pub use utils::change_tracker::{
     CONFIG_CHANGE_HISTORY, find_recent_config_change_ids, human_readable_changes,
@@ -174,6 +178,12 @@ pub struct Build {
     is_sudo: bool,
     delayed_failures: RefCell<Vec<String>>,
     prerelease_version: Cell<Option<u32>>,
+    /// Runs the commands, unless bootstrap is in dry-run mode.
+    executor: Arc<dyn CommandExecutor>,
+    /// Records the commands instead of running them with `--emit=nix`.
+    nix_executor: Option<NixExecutor>,
+    step_graph: crate::utils::step_graph::StepGraph,
+    step_cache: Option<StepCache>,
 
     #[cfg(feature = "build-metrics")]
     metrics: crate::utils::metrics::BuildMetrics,
@@ -276,6 +286,28 @@ forward! {
     initial_rustfmt() -> Option<PathBuf>,
 }
 
+/// A command being run by `Build::run_with` or `Build::run_all`.
+struct CommandRun {
+    created_at: std::panic::Location<'static>,
+    executed_at: &'static std::panic::Location<'static>,
+    /// The log the command goes to, with its entry so far.
+    invocation: Option<(PathBuf, Invocation)>,
+}
+
+impl CommandRun {
+    fn print(&self, command: &BootstrapCommand) {
+        let (created_at, executed_at) = (self.created_at, self.executed_at);
+        println!("running: {command:?} (created at {created_at}, executed at {executed_at})");
+    }
+
+    /// Logs that the command only pretended to run.
+    fn skip(self) {
+        if let Some((log, invocation)) = self.invocation {
+            utils::invocation_log::append(&log, &invocation.skipped());
+        }
+    }
+}
+
 impl Build {
     /// Creates a new set of build configuration from the `flags` on the command
     /// line and the filesystem `config`.
@@ -312,24 +344,32 @@ impl Build {
         let in_tree_llvm_info = config.in_tree_llvm_info.clone();
         let in_tree_gcc_info = config.in_tree_gcc_info.clone();
 
+        let executor = config.executor();
+        let nix_executor =
+            (config.emit == EmitMode::Nix).then(|| NixExecutor::new(out.join("nix")));
+
         let initial_target_libdir_str = if config.dry_run() {
             "/dummy/lib/path/to/lib/".to_string()
+        } else if let Some(sysroot) = &config.nix_toolchain {
+            let triple = config.build.rustc_target_arg();
+            sysroot.join("lib/rustlib").join(triple).join("lib").display().to_string()
         } else {
-            output(
-                Command::new(&config.initial_rustc)
-                    .arg("--target")
-                    .arg(config.build.rustc_target_arg())
-                    .arg("--print")
-                    .arg("target-libdir"),
-            )
+            let mut rustc = command(&config.initial_rustc);
+            rustc.arg("--target").arg(config.build.rustc_target_arg());
+            rustc.args(["--print", "target-libdir"]);
+            utils::executor::query(&*executor, rustc)
         };
         let initial_target_dir = Path::new(&initial_target_libdir_str).parent().unwrap();
         let initial_lld = initial_target_dir.join("bin").join("rust-lld");
 
         let initial_sysroot = if config.dry_run() {
             "/dummy".to_string()
+        } else if let Some(sysroot) = &config.nix_toolchain {
+            sysroot.display().to_string()
         } else {
-            output(Command::new(&config.initial_rustc).arg("--print").arg("sysroot"))
+            let mut rustc = command(&config.initial_rustc);
+            rustc.args(["--print", "sysroot"]);
+            utils::executor::query(&*executor, rustc)
         }
         .trim()
         .to_string();
@@ -379,6 +419,8 @@ impl Build {
             config.description = Some("built from a source tarball".to_owned());
         }
 
+        let step_cache = config.step_cache.clone().map(StepCache::new);
+
         let mut build = Build {
             initial_rustc: config.initial_rustc.clone(),
             initial_cargo: config.initial_cargo.clone(),
@@ -417,15 +459,26 @@ impl Build {
             is_sudo,
             delayed_failures: RefCell::new(Vec::new()),
             prerelease_version: Cell::new(None),
+            executor,
+            nix_executor,
+            step_graph: Default::default(),
+            step_cache,
 
             #[cfg(feature = "build-metrics")]
             metrics: crate::utils::metrics::BuildMetrics::init(),
         };
 
+        if let Some(log) = build.invocation_log_path() {
+            utils::invocation_log::reset(&log);
+        }
+
         // If local-rust is the same major.minor as the current version, then force a
         // local-rebuild
-        let local_version_verbose =
-            output(Command::new(&build.initial_rustc).arg("--version").arg("--verbose"));
+        let local_version_verbose = command(&build.initial_rustc)
+            .run_always()
+            .args(["--version", "--verbose"])
+            .run_capture_stdout(&build)
+            .stdout();
         let local_release = local_version_verbose
             .lines()
             .filter_map(|x| x.strip_prefix("release:"))
@@ -592,10 +645,20 @@ impl Build {
             Subcommand::Perf { .. } => {
                 return core::build_steps::perf::perf(&builder::Builder::new(self));
             }
+            Subcommand::Dist { verify_reproducible: true } => {
+                return utils::reproducible::verify(&builder::Builder::new(self));
+            }
+            Subcommand::Replay { log, graph } => {
+                return core::build_steps::replay::replay(
+                    &builder::Builder::new(self),
+                    log,
+                    graph.as_deref(),
+                );
+            }
             _ => (),
         }
 
-        if !self.config.dry_run() {
+        if !self.dry_run() {
             {
                 // We first do a dry-run. This is a sanity-check to ensure that
                 // steps don't do anything expensive in the dry-run.
@@ -611,6 +674,11 @@ impl Build {
             builder.execute_cli();
         }
 
+        // Written before checking for postponed failures, which exit without returning.
+        if let Some(graph_out) = &self.config.graph_out {
+            self.step_graph.persist(graph_out);
+        }
+
         // Check for postponed failures from `test --no-fail-fast`.
         let failures = self.delayed_failures.borrow();
         if failures.len() > 0 {
@@ -625,6 +693,23 @@ impl Build {
         self.metrics.persist(self);
     }
 
+    /// Where to log the commands that are run, if `--invocation-log` was passed.
+    fn invocation_log_path(&self) -> Option<PathBuf> {
+        self.config.invocation_log.then(|| self.out.join(INVOCATION_LOG_FILENAME))
+    }
+
+    /// Returns the executor that should run `command`, see `utils::executor`.
+    fn executor(&self, command: &BootstrapCommand) -> &dyn CommandExecutor {
+        if command.run_always {
+            return &*self.executor;
+        }
+        match (&self.config.dry_run, &self.nix_executor) {
+            (DryRun::Disabled, Some(nix_executor)) => nix_executor,
+            (DryRun::Disabled, None) => &*self.executor,
+            (DryRun::SelfCheck | DryRun::UserSelected, _) => &DryRunExecutor,
+        }
+    }
+
     /// Clear out `dir` if `input` is newer.
     ///
     /// After this executes, it will also ensure that `dir` exists.
@@ -715,11 +800,16 @@ impl Build {
     }
 
     fn tools_dir(&self, compiler: Compiler) -> PathBuf {
-        let out = self.out.join(compiler.host).join(format!("stage{}-tools-bin", compiler.stage));
+        let out = self.tools_dir_path(compiler);
         t!(fs::create_dir_all(&out));
         out
     }
 
+    /// Like `tools_dir`, but without creating the directory.
+    fn tools_dir_path(&self, compiler: Compiler) -> PathBuf {
+        self.out.join(compiler.host).join(format!("stage{}-tools-bin", compiler.stage))
+    }
+
     /// Returns the root directory for all output generated in a particular
     /// stage when running with a particular host compiler.
     ///
@@ -825,7 +915,8 @@ impl Build {
     fn llvm_bin(&self, target: TargetSelection) -> PathBuf {
         let target_config = self.config.target_config.get(&target);
         if let Some(s) = target_config.and_then(|c| c.llvm_config.as_ref()) {
-            let llvm_bindir = output(Command::new(s).arg("--bindir"));
+            let llvm_bindir =
+                command(s).run_always().arg("--bindir").run_capture_stdout(self).stdout();
             PathBuf::from(llvm_bindir.trim())
         } else {
             self.llvm_out(self.config.build).join("bin")
@@ -903,9 +994,9 @@ impl Build {
     fn rustc_snapshot_sysroot(&self) -> &Path {
         static SYSROOT_CACHE: OnceLock<PathBuf> = OnceLock::new();
         SYSROOT_CACHE.get_or_init(|| {
-            let mut rustc = Command::new(&self.initial_rustc);
-            rustc.args(["--print", "sysroot"]);
-            output(&mut rustc).trim().into()
+            let mut rustc = command(&self.initial_rustc);
+            rustc.run_always().args(["--print", "sysroot"]);
+            rustc.run_capture_stdout(self).stdout().trim().into()
         })
     }
 
@@ -919,32 +1010,123 @@ impl Build {
         stdout: OutputMode,
         stderr: OutputMode,
     ) -> CommandOutput {
-        command.mark_as_executed();
-        if self.config.dry_run() && !command.run_always {
-            return CommandOutput::default();
+        self.run_with(command, stdout, stderr, |executor, command| {
+            executor.execute(command, stdout, stderr)
+        })
+    }
+
+    /// Like `run`, but calls `on_line` for each line the command prints to stdout instead of
+    /// printing it. Both stdout and stderr count as printed for the failure behavior.
+    #[track_caller]
+    fn stream(
+        &self,
+        command: &mut BootstrapCommand,
+        on_line: &mut dyn FnMut(&str),
+    ) -> CommandOutput {
+        self.run_with(command, OutputMode::Print, OutputMode::Print, |executor, command| {
+            executor.stream(command, on_line)
+        })
+    }
+
+    /// Runs all of `commands` on the executor selected for the first one, up to `jobs` at once
+    /// if the executor supports it, while printing their output. Like `run`, each one is logged
+    /// and its failure handled.
+    #[track_caller]
+    fn run_all(&self, commands: &mut [BootstrapCommand], jobs: usize) -> Vec<CommandOutput> {
+        let Some(first) = commands.first() else { return vec![] };
+        let executor = self.executor(first);
+        let executed_at = std::panic::Location::caller();
+        let runs: Vec<_> =
+            commands.iter_mut().map(|command| self.start_run(command, executed_at)).collect();
+        let (print, dry_run) = (OutputMode::Print, executor.is_dry_run());
+        if !dry_run {
+            self.verbose(|| {
+                for (command, run) in commands.iter().zip(&runs) {
+                    run.print(command);
+                }
+            });
         }
+        let outputs = executor.execute_all(commands, jobs);
+        commands
+            .iter()
+            .zip(runs)
+            .zip(outputs)
+            .map(|((command, run), (output, duration))| {
+                if dry_run {
+                    run.skip();
+                    t!(output)
+                } else {
+                    self.finish_run(command, run, output, duration, print, print)
+                }
+            })
+            .collect()
+    }
+
+    /// Starts logging the run of `command`, which is executed at `executed_at`.
+    fn start_run(
+        &self,
+        command: &mut BootstrapCommand,
+        executed_at: &'static std::panic::Location<'static>,
+    ) -> CommandRun {
+        command.mark_as_executed();
 
         let created_at = command.get_created_location();
-        let executed_at = std::panic::Location::caller();
+        // Don't log the commands of the self-check dry run, they'd be duplicates of the real ones.
+        let invocation = self
+            .invocation_log_path()
+            .filter(|_| !matches!(self.config.dry_run, DryRun::SelfCheck))
+            .map(|log| {
+                let step = self.step_graph.current_step();
+                (log, Invocation::new(command, created_at, executed_at, step))
+            });
+        CommandRun { created_at, executed_at, invocation }
+    }
+
+    /// Runs `command` on the executor selected for it with `execute`, logging and handling its
+    /// failure.
+    #[track_caller]
+    fn run_with<F>(
+        &self,
+        command: &mut BootstrapCommand,
+        stdout: OutputMode,
+        stderr: OutputMode,
+        execute: F,
+    ) -> CommandOutput
+    where
+        F: FnOnce(&dyn CommandExecutor, &mut BootstrapCommand) -> io::Result<CommandOutput>,
+    {
+        let run = self.start_run(command, std::panic::Location::caller());
+        let executor = self.executor(command);
+        if executor.is_dry_run() {
+            run.skip();
+            return t!(execute(executor, command));
+        }
 
-        self.verbose(|| {
-            println!("running: {command:?} (created at {created_at}, executed at {executed_at})")
-        });
+        self.verbose(|| run.print(command));
 
-        let cmd = command.as_command_mut();
-        cmd.stdout(stdout.stdio());
-        cmd.stderr(stderr.stdio());
+        let start = Instant::now();
+        let output = execute(executor, command);
+        self.finish_run(command, run, output, start.elapsed(), stdout, stderr)
+    }
 
-        let output = cmd.output();
+    /// Logs how the `run` of `command` went, and handles its failure.
+    fn finish_run(
+        &self,
+        command: &BootstrapCommand,
+        run: CommandRun,
+        output: io::Result<CommandOutput>,
+        duration: std::time::Duration,
+        stdout: OutputMode,
+        stderr: OutputMode,
+    ) -> CommandOutput {
+        let CommandRun { created_at, executed_at, invocation } = run;
 
         use std::fmt::Write;
 
         let mut message = String::new();
         let output: CommandOutput = match output {
             // Command has succeeded
-            Ok(output) if output.status.success() => {
-                CommandOutput::from_output(output, stdout, stderr)
-            }
+            Ok(output) if output.is_success() => output,
             // Command has started, but then it failed
             Ok(output) => {
                 writeln!(
@@ -954,12 +1136,10 @@ Command {command:?} did not execute successfully.
 Expected success, got {}
 Created at: {created_at}
 Executed at: {executed_at}"#,
-                    output.status,
+                    output.status().expect("executor returned a command that did not start"),
                 )
                 .unwrap();
 
-                let output: CommandOutput = CommandOutput::from_output(output, stdout, stderr);
-
                 // If the output mode is OutputMode::Capture, we can now print the output.
                 // If it is OutputMode::Print, then the output has already been printed to
                 // stdout/stderr, and we thus don't have anything captured to print anyway.
@@ -983,6 +1163,10 @@ Executed at: {executed_at}"#,
             }
         };
 
+        if let Some((log, invocation)) = invocation {
+            utils::invocation_log::append(&log, &invocation.finished(&output, duration));
+        }
+
         let fail = |message: &str, output: CommandOutput| -> ! {
             if self.is_verbose() {
                 println!("{message}");
@@ -1033,6 +1217,14 @@ Executed at: {executed_at}"#,
         output
     }
 
+    /// Whether the commands of the build only pretend to run, so that nothing they would produce
+    /// exists: in dry runs, and with `--emit=nix`, which records them instead. Unlike
+    /// [`Config::dry_run`], this doesn't hold for `--emit=nix` while configuring the build, so
+    /// that the recorded commands refer to the real build directory and stage 0 toolchain.
+    pub fn dry_run(&self) -> bool {
+        self.config.build_dry_run()
+    }
+
     /// Check if verbosity is greater than the `level`
     pub fn is_verbose_than(&self, level: usize) -> bool {
         self.verbosity > level
@@ -1192,7 +1384,7 @@ Executed at: {executed_at}"#,
 
     /// Returns the path to the C compiler for the target specified.
     fn cc(&self, target: TargetSelection) -> PathBuf {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return PathBuf::new();
         }
         self.cc.borrow()[&target].path().into()
@@ -1201,7 +1393,7 @@ Executed at: {executed_at}"#,
     /// Returns a list of flags to pass to the C compiler for the target
     /// specified.
     fn cflags(&self, target: TargetSelection, which: GitRepo, c: CLang) -> Vec<String> {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return Vec::new();
         }
         let base = match c {
@@ -1247,7 +1439,7 @@ Executed at: {executed_at}"#,
 
     /// Returns the path to the `ar` archive utility for the target specified.
     fn ar(&self, target: TargetSelection) -> Option<PathBuf> {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return None;
         }
         self.ar.borrow().get(&target).cloned()
@@ -1255,7 +1447,7 @@ Executed at: {executed_at}"#,
 
     /// Returns the path to the `ranlib` utility for the target specified.
     fn ranlib(&self, target: TargetSelection) -> Option<PathBuf> {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return None;
         }
         self.ranlib.borrow().get(&target).cloned()
@@ -1263,7 +1455,7 @@ Executed at: {executed_at}"#,
 
     /// Returns the path to the C++ compiler for the target specified.
     fn cxx(&self, target: TargetSelection) -> Result<PathBuf, String> {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return Ok(PathBuf::new());
         }
         match self.cxx.borrow().get(&target) {
@@ -1274,7 +1466,7 @@ Executed at: {executed_at}"#,
 
     /// Returns the path to the linker for the given target if it needs to be overridden.
     fn linker(&self, target: TargetSelection) -> Option<PathBuf> {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return Some(PathBuf::new());
         }
         if let Some(linker) = self.config.target_config.get(&target).and_then(|c| c.linker.clone())
@@ -1660,7 +1852,7 @@ Executed at: {executed_at}"#,
     }
 
     fn read_stamp_file(&self, stamp: &Path) -> Vec<(PathBuf, DependencyType)> {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return Vec::new();
         }
 
@@ -1672,24 +1864,7 @@ Executed at: {executed_at}"#,
             crate::exit!(1);
         }
 
-        let mut paths = Vec::new();
-        let contents = t!(fs::read(stamp), &stamp);
-        // This is the method we use for extracting paths from the stamp file passed to us. See
-        // run_cargo for more information (in compile.rs).
-        for part in contents.split(|b| *b == 0) {
-            if part.is_empty() {
-                continue;
-            }
-            let dependency_type = match part[0] as char {
-                'h' => DependencyType::Host,
-                's' => DependencyType::TargetSelfContained,
-                't' => DependencyType::Target,
-                _ => unreachable!(),
-            };
-            let path = PathBuf::from(t!(str::from_utf8(&part[1..])));
-            paths.push((path, dependency_type));
-        }
-        paths
+        parse_stamp_file(&t!(fs::read(stamp), &stamp))
     }
 
     /// Copies a file from `src` to `dst`.
@@ -1709,10 +1884,11 @@ Executed at: {executed_at}"#,
     }
 
     fn copy_link_internal(&self, src: &Path, dst: &Path, dereference_symlinks: bool) {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return;
         }
         self.verbose_than(1, || println!("Copy/Link {src:?} to {dst:?}"));
+        self.config.assert_writable(dst);
         if src == dst {
             return;
         }
@@ -1757,7 +1933,7 @@ Executed at: {executed_at}"#,
     /// when this function is called.
     /// Will attempt to use hard links if possible and fall back to copying.
     pub fn cp_link_r(&self, src: &Path, dst: &Path) {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return;
         }
         for f in self.read_dir(src) {
@@ -1817,11 +1993,12 @@ Executed at: {executed_at}"#,
     }
 
     fn install(&self, src: &Path, dstdir: &Path, perms: u32) {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return;
         }
         let dst = dstdir.join(src.file_name().unwrap());
         self.verbose_than(1, || println!("Install {src:?} to {dst:?}"));
+        self.config.assert_writable(&dst);
         t!(fs::create_dir_all(dstdir));
         if !src.exists() {
             panic!("ERROR: File \"{}\" not found!", src.display());
@@ -1831,30 +2008,32 @@ Executed at: {executed_at}"#,
     }
 
     fn read(&self, path: &Path) -> String {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return String::new();
         }
         t!(fs::read_to_string(path))
     }
 
     fn create_dir(&self, dir: &Path) {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return;
         }
+        self.config.assert_writable(dir);
         t!(fs::create_dir_all(dir))
     }
 
     fn remove_dir(&self, dir: &Path) {
-        if self.config.dry_run() {
+        if self.dry_run() {
             return;
         }
+        self.config.assert_writable(dir);
         t!(fs::remove_dir_all(dir))
     }
 
     fn read_dir(&self, dir: &Path) -> impl Iterator<Item = fs::DirEntry> {
         let iter = match fs::read_dir(dir) {
             Ok(v) => v,
-            Err(_) if self.config.dry_run() => return vec![].into_iter(),
+            Err(_) if self.dry_run() => return vec![].into_iter(),
             Err(err) => panic!("could not read dir {dir:?}: {err:?}"),
         };
         iter.map(|e| t!(e)).collect::<Vec<_>>().into_iter()
@@ -1865,7 +2044,11 @@ Executed at: {executed_at}"#,
         use std::os::unix::fs::symlink as symlink_file;
         #[cfg(windows)]
         use std::os::windows::fs::symlink_file;
-        if !self.config.dry_run() { symlink_file(src.as_ref(), link.as_ref()) } else { Ok(()) }
+        if self.dry_run() {
+            return Ok(());
+        }
+        self.config.assert_writable(link.as_ref());
+        symlink_file(src.as_ref(), link.as_ref())
     }
 
     /// Returns if config.ninja is enabled, and checks for ninja existence,
@@ -1957,6 +2140,25 @@ impl Compiler {
     }
 }
 
+/// Extracts the paths from the contents of a stamp file written by `run_cargo` (in compile.rs).
+fn parse_stamp_file(contents: &[u8]) -> Vec<(PathBuf, DependencyType)> {
+    let mut paths = Vec::new();
+    for part in contents.split(|b| *b == 0) {
+        if part.is_empty() {
+            continue;
+        }
+        let dependency_type = match part[0] as char {
+            'h' => DependencyType::Host,
+            's' => DependencyType::TargetSelfContained,
+            't' => DependencyType::Target,
+            _ => unreachable!(),
+        };
+        let path = PathBuf::from(t!(str::from_utf8(&part[1..])));
+        paths.push((path, dependency_type));
+    }
+    paths
+}
+
 fn envify(s: &str) -> String {
     s.chars()
         .map(|c| match c {
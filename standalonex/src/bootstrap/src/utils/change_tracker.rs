@@ -74,11 +74,170 @@ pub fn human_readable_changes(changes: &[ChangeInfo]) -> String {
     message
 }
 
+/// An edit `x setup --migrate` makes to `config.toml` to follow a change in
+/// [`CONFIG_CHANGE_HISTORY`].
+#[derive(Clone, Debug)]
+pub struct ConfigMigration {
+    /// The `change_id` of the [`ChangeInfo`] describing the change.
+    pub change_id: usize,
+    pub kind: MigrationKind,
+}
+
+#[derive(Clone, Debug)]
+pub enum MigrationKind {
+    /// Removes the key, e.g. `dist.missing-tools`.
+    Remove(&'static str),
+    /// Replaces the string value `from` of the key with `to`.
+    ReplaceValue { key: &'static str, from: &'static str, to: &'static str },
+}
+
+/// A line of `config.toml` changed by [`migrate_config`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigEdit {
+    /// The index of the line in the original file.
+    pub line: usize,
+    /// The line before the edit, `None` if it's inserted.
+    pub old: Option<String>,
+    /// The line after the edit, `None` if it's removed.
+    pub new: Option<String>,
+    /// The change that requires the edit, `None` for the update of `change-id`.
+    pub change_id: Option<usize>,
+}
+
+/// Applies the migrations for the changes after `current_id` to the `config.toml` in `contents`,
+/// and sets its `change-id` to the latest one. Returns the new contents, and the edits that
+/// turned the old ones into them.
+pub fn migrate_config(contents: &str, current_id: Option<usize>) -> (String, Vec<ConfigEdit>) {
+    let changes = match current_id {
+        Some(id) => find_recent_config_change_ids(id),
+        None => CONFIG_CHANGE_HISTORY.to_vec(),
+    };
+    let migrations: Vec<_> = CONFIG_MIGRATIONS
+        .iter()
+        .filter(|m| changes.iter().any(|change| change.change_id == m.change_id))
+        .collect();
+    let latest_id = CONFIG_CHANGE_HISTORY.last().unwrap().change_id;
+    let change_id = format!("change-id = {latest_id}");
+
+    let mut migrated = String::new();
+    let mut edits = vec![];
+    let mut section = String::new();
+    let mut has_change_id = false;
+    // The change removing a multi-line array, and how many of its brackets are still open.
+    let mut removing = None;
+    for (i, line) in contents.lines().enumerate() {
+        let edit = |new: Option<&str>, change_id| ConfigEdit {
+            line: i,
+            old: Some(line.to_owned()),
+            new: new.map(str::to_owned),
+            change_id,
+        };
+        if let Some((change_id, open)) = removing {
+            let open = open + brackets(line);
+            removing = (open > 0).then_some((change_id, open));
+            edits.push(edit(None, change_id));
+            continue;
+        }
+
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && !trimmed.starts_with("[[") {
+            section = trimmed[1..].split(']').next().unwrap().trim().to_owned();
+        }
+        let Some((key, value)) =
+            trimmed.split_once('=').filter(|_| !trimmed.starts_with(['#', '[']))
+        else {
+            migrated.push_str(line);
+            migrated.push('\n');
+            continue;
+        };
+        let key: Vec<_> = key.split('.').map(|k| k.trim().trim_matches('"')).collect();
+        let key =
+            if section.is_empty() { key.join(".") } else { format!("{section}.{}", key.join(".")) };
+        let value = value.split('#').next().unwrap().trim();
+
+        let new = if key == "change-id" {
+            has_change_id = true;
+            (current_id != Some(latest_id)).then(|| (Some(change_id.clone()), None))
+        } else {
+            migrations.iter().find_map(|migration| match migration.kind {
+                MigrationKind::Remove(remove) if remove == key => {
+                    let open = brackets(value);
+                    removing = (open > 0).then_some((Some(migration.change_id), open));
+                    Some((None, Some(migration.change_id)))
+                }
+                MigrationKind::ReplaceValue { key: k, from, to }
+                    if k == key && value.trim_matches(['"', '\'']) == from =>
+                {
+                    let new = line.replacen(value, &format!("\"{to}\""), 1);
+                    Some((Some(new), Some(migration.change_id)))
+                }
+                _ => None,
+            })
+        };
+        match new {
+            Some((new, change_id)) => {
+                if let Some(new) = &new {
+                    migrated.push_str(new);
+                    migrated.push('\n');
+                }
+                edits.push(edit(new.as_deref(), change_id));
+            }
+            None => {
+                migrated.push_str(line);
+                migrated.push('\n');
+            }
+        }
+    }
+
+    if !has_change_id {
+        migrated.insert_str(0, &format!("{change_id}\n"));
+        edits.insert(0, ConfigEdit { line: 0, old: None, new: Some(change_id), change_id: None });
+    }
+    (migrated, edits)
+}
+
+/// How many more `[` than `]` there are in `value`, i.e. whether an array continues on the next
+/// lines.
+fn brackets(value: &str) -> i32 {
+    value.split('#').next().unwrap().chars().fold(0, |n, c| match c {
+        '[' => n + 1,
+        ']' => n - 1,
+        _ => n,
+    })
+}
+
+/// The migrations `x setup --migrate` knows about, in the order of [`CONFIG_CHANGE_HISTORY`].
+pub const CONFIG_MIGRATIONS: &[ConfigMigration] = &[
+    ConfigMigration {
+        change_id: 117813,
+        kind: MigrationKind::ReplaceValue {
+            key: "llvm.download-ci-llvm",
+            from: "if-available",
+            to: "if-unchanged",
+        },
+    },
+    ConfigMigration { change_id: 118703, kind: MigrationKind::Remove("rust.run-dsymutil") },
+    ConfigMigration { change_id: 118703, kind: MigrationKind::Remove("dist.gpg-password-file") },
+    ConfigMigration {
+        change_id: 121278,
+        kind: MigrationKind::ReplaceValue { key: "profile", from: "codegen", to: "compiler" },
+    },
+    ConfigMigration {
+        change_id: 121278,
+        kind: MigrationKind::ReplaceValue { key: "profile", from: "llvm", to: "compiler" },
+    },
+    ConfigMigration { change_id: 123711, kind: MigrationKind::Remove("changelog-seen") },
+    ConfigMigration { change_id: 125535, kind: MigrationKind::Remove("dist.missing-tools") },
+    ConfigMigration { change_id: 129925, kind: MigrationKind::Remove("rust.split-debuginfo") },
+    ConfigMigration { change_id: 132282, kind: MigrationKind::Remove("rust.parallel-compiler") },
+];
+
 /// Keeps track of major changes made to the bootstrap configuration.
 ///
 /// If you make any major changes (such as adding new values or changing default values),
 /// please ensure adding `ChangeInfo` to the end(because the list must be sorted by the merge date)
-/// of this list.
+/// of this list. If existing `config.toml` files can be updated for it automatically, also add
+/// the edit to [`CONFIG_MIGRATIONS`].
 pub const CONFIG_CHANGE_HISTORY: &[ChangeInfo] = &[
     ChangeInfo {
         change_id: 115898,
@@ -1,3 +1,4 @@
+use crate::utils::change_tracker::{ConfigEdit, migrate_config};
 use crate::{CONFIG_CHANGE_HISTORY, find_recent_config_change_ids};
 
 #[test]
@@ -8,3 +9,42 @@ fn test_find_recent_config_change_ids() {
     // There is no change-id equal to or less than 0, result should include the entire change history.
     assert_eq!(find_recent_config_change_ids(0).len(), CONFIG_CHANGE_HISTORY.len());
 }
+
+#[test]
+fn test_migrate_config() {
+    let latest = CONFIG_CHANGE_HISTORY.last().unwrap().change_id;
+    let config = r#"profile = "codegen" # old
+change-id = 121203
+
+[dist]
+missing-tools = [
+    "a",
+]
+[rust]
+parallel-compiler = true
+"#;
+    let (migrated, edits) = migrate_config(config, Some(121203));
+    assert_eq!(
+        migrated,
+        format!("profile = \"compiler\" # old\nchange-id = {latest}\n\n[dist]\n[rust]\n")
+    );
+    assert_eq!(edits.len(), 6);
+    assert_eq!(
+        edits[0],
+        ConfigEdit {
+            line: 0,
+            old: Some(r#"profile = "codegen" # old"#.to_owned()),
+            new: Some(r#"profile = "compiler" # old"#.to_owned()),
+            change_id: Some(121278),
+        }
+    );
+    assert!(edits[2..5].iter().all(|edit| edit.change_id == Some(125535) && edit.new.is_none()));
+
+    // Only the changes after `change-id` are migrated, and it's added if it's missing.
+    let config = format!("profile = \"codegen\"\nchange-id = {latest}\n");
+    let (migrated, edits) = migrate_config(&config, Some(latest));
+    assert!(edits.is_empty());
+    assert_eq!(migrated, config);
+    let (migrated, _) = migrate_config("changelog-seen = 2\n", None);
+    assert_eq!(migrated, format!("change-id = {latest}\n"));
+}
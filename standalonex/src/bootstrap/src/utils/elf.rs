@@ -0,0 +1,272 @@
+//! Rewrites the interpreter and RPATH of the ELF binaries bootstrap downloads, so that they can
+//! run on NixOS, where neither the dynamic linker nor the system libraries are at their usual
+//! locations.
+//!
+//! Instead of resizing sections in place, all the data that changes is appended to the file, in
+//! a new `PT_LOAD` segment: a copy of the program headers, the new interpreter, and a copy of
+//! `.dynstr` and `.dynamic` with the new RPATH. The program headers, section headers and dynamic
+//! entries that refer to the old data are then pointed at the new copies. The old data is left
+//! where it was, unused.
+//!
+//! Only 64-bit ELF files are supported, which covers all the hosts Nix runs on. 32-bit ones are
+//! rejected.
+
+use std::fs;
+use std::path::Path;
+
+use object::elf::{
+    self, Dyn64, FileHeader64, PF_R, PF_W, PT_DYNAMIC, PT_INTERP, PT_LOAD, PT_PHDR,
+    ProgramHeader64, SectionHeader64,
+};
+use object::pod::{bytes_of_slice, from_bytes_mut, slice_from_bytes_mut};
+use object::read::elf::{Dyn, FileHeader, ProgramHeader};
+use object::{Endianness, U32, U64};
+
+#[cfg(test)]
+mod tests;
+
+/// Smallest page size the new segment is aligned to, if the existing ones use a smaller alignment.
+const MIN_PAGE_SIZE: u64 = 0x1000;
+
+/// Sets the interpreter of `path` (if it has one) and adds the directories of `rpath` that aren't
+/// already in its RPATH, rewriting the file only if something changed.
+pub fn patch_file(
+    path: &Path,
+    interpreter: Option<&str>,
+    rpath: Option<&str>,
+) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    if let Some(patched) = patch(&data, interpreter, rpath)? {
+        fs::write(path, patched).map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Returns the patched contents of the ELF file `data`, or `None` if it didn't need patching.
+pub fn patch(
+    data: &[u8],
+    interpreter: Option<&str>,
+    rpath: Option<&str>,
+) -> Result<Option<Vec<u8>>, String> {
+    // `e_ident[EI_CLASS]`, which comes right after the magic number.
+    if data.starts_with(&elf::ELFMAG) && data.get(elf::ELFMAG.len()) == Some(&elf::ELFCLASS32) {
+        return Err("32-bit ELF files can't be patched, only 64-bit ones are supported".to_owned());
+    }
+    let header = FileHeader64::<Endianness>::parse(data).map_err(|e| e.to_string())?;
+    let endian = header.endian().map_err(|e| e.to_string())?;
+    let phdrs = header.program_headers(endian, data).map_err(|e| e.to_string())?;
+    let loads: Vec<_> = phdrs.iter().filter(|p| p.p_type(endian) == PT_LOAD).collect();
+    let first_load = loads.first().ok_or("the file has no PT_LOAD segment")?;
+
+    // The new interpreter, if the file has one and it's different.
+    let interp_phdr = phdrs.iter().find(|p| p.p_type(endian) == PT_INTERP);
+    let new_interp = match (interp_phdr, interpreter) {
+        (Some(phdr), Some(interpreter)) => {
+            let current = phdr.interpreter(endian, data).map_err(|e| e.to_string())?;
+            (current != Some(interpreter.as_bytes())).then_some(interpreter)
+        }
+        _ => None,
+    };
+
+    // The new `.dynstr` and `.dynamic`, if the RPATH changes.
+    let dynamic = phdrs
+        .iter()
+        .find_map(|p| p.dynamic(endian, data).transpose())
+        .transpose()
+        .map_err(|e| e.to_string())?;
+    let mut new_dynamic = None;
+    if let (Some(dynamic), Some(rpath)) = (dynamic, rpath) {
+        let dynamic_len = dynamic
+            .iter()
+            .position(|d| d.d_tag(endian) == u64::from(elf::DT_NULL))
+            .unwrap_or(dynamic.len());
+        let dynamic = &dynamic[..dynamic_len];
+        let find = |tag: u32| dynamic.iter().position(|d| d.d_tag(endian) == u64::from(tag));
+        let value = |tag| find(tag).map(|i| dynamic[i].d_val(endian));
+        let (Some(strtab), Some(strsz)) = (value(elf::DT_STRTAB), value(elf::DT_STRSZ)) else {
+            return Err("the dynamic section has no string table".to_owned());
+        };
+        let strtab_offset = file_offset(&loads, endian, strtab, strsz)?;
+        let dynstr = &data[strtab_offset as usize..][..strsz as usize];
+
+        // Extend `DT_RUNPATH`, or `DT_RPATH` for old binaries, the way `patchelf --add-rpath` does.
+        let rpath_entry = find(elf::DT_RUNPATH).or_else(|| find(elf::DT_RPATH));
+        let current = match rpath_entry {
+            Some(i) => {
+                let offset = dynamic[i].d_val(endian) as usize;
+                let string = dynstr.get(offset..).ok_or("RPATH is out of bounds")?;
+                &string[..string.iter().position(|&b| b == 0).ok_or("RPATH is not terminated")?]
+            }
+            None => &[][..],
+        };
+        let missing: Vec<&[u8]> = rpath
+            .as_bytes()
+            .split(|&b| b == b':')
+            .filter(|&dir| !dir.is_empty() && !current.split(|&b| b == b':').any(|c| c == dir))
+            .collect();
+        if !missing.is_empty() {
+            let mut dynstr = dynstr.to_vec();
+            let rpath_offset = dynstr.len() as u64;
+            for dir in current.split(|&b| b == b':').filter(|dir| !dir.is_empty()).chain(missing) {
+                if dynstr.len() as u64 != rpath_offset {
+                    dynstr.push(b':');
+                }
+                dynstr.extend_from_slice(dir);
+            }
+            dynstr.push(0);
+
+            let mut entries = dynamic.to_vec();
+            match rpath_entry {
+                Some(i) => entries[i].d_val = U64::new(endian, rpath_offset),
+                None => entries.push(Dyn64 {
+                    d_tag: U64::new(endian, elf::DT_RUNPATH.into()),
+                    d_val: U64::new(endian, rpath_offset),
+                }),
+            }
+            entries.push(Dyn64 {
+                d_tag: U64::new(endian, elf::DT_NULL.into()),
+                d_val: U64::new(endian, 0),
+            });
+            new_dynamic = Some((dynstr, entries));
+        }
+    }
+
+    if new_interp.is_none() && new_dynamic.is_none() {
+        return Ok(None);
+    }
+
+    // Place the new segment after everything else, both in the file and in memory. Its virtual
+    // address is at the same distance from its file offset as for the first segment, because
+    // older kernels compute the address of the program headers from the first segment.
+    let page_size = loads.iter().map(|p| p.p_align(endian)).fold(MIN_PAGE_SIZE, u64::max);
+    let delta = first_load
+        .p_vaddr(endian)
+        .checked_sub(first_load.p_offset(endian))
+        .ok_or("the first PT_LOAD segment is mapped below its file offset")?;
+    let memory_end = loads.iter().map(|p| p.p_vaddr(endian) + p.p_memsz(endian)).max().unwrap();
+    let offset = align(data.len() as u64, page_size).max(align(memory_end, page_size) - delta);
+    let vaddr = offset + delta;
+
+    // The contents of the new segment, in order: program headers, interpreter, `.dynstr`,
+    // `.dynamic`. `(offset, size)` of each part, relative to the segment.
+    let mut segment = vec![0; (phdrs.len() + 1) * size_of::<ProgramHeader64<Endianness>>()];
+    let mut push = |bytes: &[u8], alignment: usize| {
+        segment.resize(segment.len().next_multiple_of(alignment), 0);
+        let start = segment.len() as u64;
+        segment.extend_from_slice(bytes);
+        (start, bytes.len() as u64)
+    };
+    let interp_part = new_interp.map(|interp| push(&[interp.as_bytes(), &[0]].concat(), 1));
+    let dynamic_parts = new_dynamic.map(|(dynstr, mut entries)| {
+        let (dynstr_start, dynstr_size) = push(&dynstr, 1);
+        for entry in &mut entries {
+            match entry.d_tag(endian) as u32 {
+                elf::DT_STRTAB => entry.d_val.set(endian, vaddr + dynstr_start),
+                elf::DT_STRSZ => entry.d_val.set(endian, dynstr_size),
+                _ => {}
+            }
+        }
+        ((dynstr_start, dynstr_size), push(bytes_of_slice(&entries), 8))
+    });
+    let segment_len = segment.len() as u64;
+
+    let mut new_phdrs = phdrs.to_vec();
+    let set_location = |phdr: &mut ProgramHeader64<Endianness>, (start, size): (u64, u64)| {
+        phdr.p_offset = U64::new(endian, offset + start);
+        phdr.p_vaddr = U64::new(endian, vaddr + start);
+        phdr.p_paddr = U64::new(endian, vaddr + start);
+        phdr.p_filesz = U64::new(endian, size);
+        phdr.p_memsz = U64::new(endian, size);
+    };
+    let phdrs_part = (0, ((phdrs.len() + 1) * size_of::<ProgramHeader64<Endianness>>()) as u64);
+    for phdr in &mut new_phdrs {
+        match phdr.p_type(endian) {
+            PT_PHDR => set_location(phdr, phdrs_part),
+            PT_INTERP => {
+                if let Some(part) = interp_part {
+                    set_location(phdr, part);
+                }
+            }
+            PT_DYNAMIC => {
+                if let Some((_, part)) = dynamic_parts {
+                    set_location(phdr, part);
+                }
+            }
+            _ => {}
+        }
+    }
+    // The dynamic linker writes to `.dynamic`, so the segment has to be writable.
+    let mut load = ProgramHeader64 {
+        p_type: U32::new(endian, PT_LOAD),
+        p_flags: U32::new(endian, PF_R | PF_W),
+        p_offset: U64::default(),
+        p_vaddr: U64::default(),
+        p_paddr: U64::default(),
+        p_filesz: U64::default(),
+        p_memsz: U64::default(),
+        p_align: U64::new(endian, page_size),
+    };
+    set_location(&mut load, (0, segment_len));
+    new_phdrs.push(load);
+    segment[..phdrs_part.1 as usize].copy_from_slice(bytes_of_slice(&new_phdrs));
+
+    let mut out = data.to_vec();
+    out.resize(offset as usize, 0);
+    out.extend_from_slice(&segment);
+
+    // Point the file header at the new program headers.
+    let (header, _) = from_bytes_mut::<FileHeader64<Endianness>>(&mut out).unwrap();
+    header.e_phoff.set(endian, offset);
+    header.e_phnum.set(endian, new_phdrs.len() as u16);
+
+    // Keep the section headers consistent for tools like `readelf`, which the loader ignores.
+    let shoff = header.e_shoff(endian) as usize;
+    let shnum = header.e_shnum(endian) as usize;
+    if shoff != 0 {
+        let section_names = {
+            let header = FileHeader64::<Endianness>::parse(data).unwrap();
+            let sections = header.sections(endian, data).map_err(|e| e.to_string())?;
+            sections
+                .iter()
+                .map(|s| sections.section_name(endian, s).unwrap_or_default().to_vec())
+                .collect::<Vec<_>>()
+        };
+        let (shdrs, _) =
+            slice_from_bytes_mut::<SectionHeader64<Endianness>>(&mut out[shoff..], shnum)
+                .map_err(|_| "section headers are out of bounds")?;
+        for (shdr, name) in shdrs.iter_mut().zip(section_names) {
+            let part = match &name[..] {
+                b".interp" => interp_part,
+                b".dynstr" => dynamic_parts.map(|(dynstr, _)| dynstr),
+                b".dynamic" => dynamic_parts.map(|(_, dynamic)| dynamic),
+                _ => None,
+            };
+            if let Some((start, size)) = part {
+                shdr.sh_offset.set(endian, offset + start);
+                shdr.sh_addr.set(endian, vaddr + start);
+                shdr.sh_size.set(endian, size);
+            }
+        }
+    }
+    Ok(Some(out))
+}
+
+/// Maps the virtual address range `addr..addr + size` to a file offset.
+fn file_offset(
+    loads: &[&ProgramHeader64<Endianness>],
+    endian: Endianness,
+    addr: u64,
+    size: u64,
+) -> Result<u64, String> {
+    loads
+        .iter()
+        .find(|p| {
+            p.p_vaddr(endian) <= addr && addr + size <= p.p_vaddr(endian) + p.p_filesz(endian)
+        })
+        .map(|p| addr - p.p_vaddr(endian) + p.p_offset(endian))
+        .ok_or_else(|| format!("address {addr:#x} is not in any PT_LOAD segment"))
+}
+
+fn align(value: u64, alignment: u64) -> u64 {
+    value.next_multiple_of(alignment)
+}
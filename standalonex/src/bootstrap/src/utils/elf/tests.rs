@@ -0,0 +1,82 @@
+use std::fs;
+
+use object::elf::FileHeader64;
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::{Endianness, elf};
+
+use crate::core::config::tests::test_dir;
+use crate::utils::elf::{patch, patch_file};
+
+/// Returns the interpreter and RPATH of an ELF file.
+fn read(data: &[u8]) -> (Option<String>, Option<String>) {
+    let header = FileHeader64::<Endianness>::parse(data).unwrap();
+    let endian = header.endian().unwrap();
+    let phdrs = header.program_headers(endian, data).unwrap();
+    let interpreter = phdrs
+        .iter()
+        .find_map(|p| p.interpreter(endian, data).unwrap())
+        .map(|i| String::from_utf8(i.to_vec()).unwrap());
+
+    let sections = header.sections(endian, data).unwrap();
+    let (dynamic, index) = sections.dynamic(endian, data).unwrap().unwrap();
+    let strings = sections.strings(endian, data, index).unwrap();
+    let rpath = dynamic
+        .iter()
+        .find(|d| matches!(d.d_tag.get(endian) as u32, elf::DT_RUNPATH | elf::DT_RPATH))
+        .map(|d| {
+            let rpath = strings.get(d.d_val.get(endian) as u32).unwrap();
+            String::from_utf8(rpath.to_vec()).unwrap()
+        });
+    (interpreter, rpath)
+}
+
+#[test]
+fn test_not_elf() {
+    assert!(patch(b"#!/bin/sh\n", Some("/lib/ld.so"), None).is_err());
+}
+
+#[test]
+fn test_elf32() {
+    let mut data = elf::ELFMAG.to_vec();
+    data.extend([elf::ELFCLASS32, elf::ELFDATA2LSB, elf::EV_CURRENT]);
+    data.resize(52, 0);
+    let error = patch(&data, Some("/lib/ld.so"), None).unwrap_err();
+    assert!(error.contains("32-bit"), "{error}");
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+#[test]
+fn test_patch_file() {
+    use std::process::Command;
+
+    let dir = test_dir("elf-patch");
+    let bin = dir.join("true");
+    fs::copy("/bin/true", &bin).unwrap();
+    let data = fs::read(&bin).unwrap();
+    let (Some(interpreter), rpath) = read(&data) else {
+        panic!("/bin/true is not dynamically linked")
+    };
+    assert_eq!(patch(&data, Some(&interpreter), None).unwrap(), None);
+
+    // An equivalent path that's longer than the original, so it can't be patched in place.
+    let new_interpreter = format!("/{}", interpreter.replace('/', "//"));
+    patch_file(&bin, Some(&new_interpreter), Some("/nix/store/zlib/lib")).unwrap();
+    let patched = fs::read(&bin).unwrap();
+    let expected_rpath = match rpath {
+        Some(rpath) if !rpath.is_empty() => format!("{rpath}:/nix/store/zlib/lib"),
+        _ => "/nix/store/zlib/lib".to_owned(),
+    };
+    assert_eq!(read(&patched), (Some(new_interpreter.clone()), Some(expected_rpath.clone())));
+    assert!(Command::new(&bin).status().unwrap().success());
+
+    // Patching again is a no-op.
+    assert_eq!(patch(&patched, Some(&new_interpreter), Some("/nix/store/zlib/lib")).unwrap(), None);
+
+    // Only the directories that are missing are added.
+    let patched =
+        patch(&patched, None, Some("/nix/store/openssl/lib:/nix/store/zlib/lib")).unwrap().unwrap();
+    let (_, rpath) = read(&patched);
+    assert_eq!(rpath, Some(format!("{expected_rpath}:/nix/store/openssl/lib")));
+
+    fs::remove_dir_all(dir).unwrap();
+}
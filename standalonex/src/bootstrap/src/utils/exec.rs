@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fmt::{Debug, Formatter};
 use std::path::Path;
@@ -19,6 +20,16 @@ pub enum BehaviorOnFailure {
     Ignore,
 }
 
+impl BehaviorOnFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BehaviorOnFailure::Exit => "exit",
+            BehaviorOnFailure::DelayFail => "delay-fail",
+            BehaviorOnFailure::Ignore => "ignore",
+        }
+    }
+}
+
 /// How should the output of a specific stream of the command (stdout/stderr) be handled
 /// (whether it should be captured or printed).
 #[derive(Debug, Copy, Clone)]
@@ -97,10 +108,35 @@ impl BootstrapCommand {
         self
     }
 
+    pub fn get_program(&self) -> &OsStr {
+        self.command.get_program()
+    }
+
+    pub fn get_current_dir(&self) -> Option<&Path> {
+        self.command.get_current_dir()
+    }
+
     pub fn get_envs(&self) -> CommandEnvs<'_> {
         self.command.get_envs()
     }
 
+    /// Returns the environment variables explicitly set on this command, and the ones explicitly
+    /// removed from it.
+    pub fn get_env_delta(&self) -> (BTreeMap<String, String>, Vec<String>) {
+        let mut env = BTreeMap::new();
+        let mut env_remove = vec![];
+        for (key, value) in self.get_envs() {
+            let key = key.to_string_lossy().into_owned();
+            match value {
+                Some(value) => {
+                    env.insert(key, value.to_string_lossy().into_owned());
+                }
+                None => env_remove.push(key),
+            }
+        }
+        (env, env_remove)
+    }
+
     pub fn get_args(&self) -> CommandArgs<'_> {
         self.command.get_args()
     }
@@ -154,6 +190,13 @@ impl BootstrapCommand {
         builder.run(self, OutputMode::Capture, OutputMode::Print)
     }
 
+    /// Run the command, while printing stderr and passing each line of stdout to `on_line` as
+    /// it is printed. Returns true if the command has succeeded.
+    #[track_caller]
+    pub fn stream(&mut self, builder: &Build, on_line: &mut dyn FnMut(&str)) -> bool {
+        builder.stream(self, on_line).is_success()
+    }
+
     /// Provides access to the stdlib Command inside.
     /// FIXME: This function should be eventually removed from bootstrap.
     pub fn as_command_mut(&mut self) -> &mut Command {
@@ -0,0 +1,426 @@
+//! The different ways `Build::run` can execute a [`BootstrapCommand`].
+//!
+//! Which executor runs a command depends on the [`DryRun`] mode bootstrap is in:
+//! - [`DryRun::Disabled`]: [`NixExecutor`] with `--emit=nix`. Otherwise [`LocalExecutor`] by
+//!   default, or [`ExternalExecutor`] if `build.command-executor` is set.
+//! - [`DryRun::SelfCheck`] and [`DryRun::UserSelected`]: [`DryRunExecutor`].
+//!
+//! Commands marked with [`BootstrapCommand::run_always`] always go to the executor of real runs.
+//!
+//! [`DryRun`]: crate::core::config::DryRun
+//! [`DryRun::Disabled`]: crate::core::config::DryRun::Disabled
+//! [`DryRun::SelfCheck`]: crate::core::config::DryRun::SelfCheck
+//! [`DryRun::UserSelected`]: crate::core::config::DryRun::UserSelected
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::core::builder::StepIo;
+use crate::utils::exec::{BootstrapCommand, CommandOutput, OutputMode};
+use crate::utils::nix;
+
+#[cfg(test)]
+mod tests;
+
+pub(crate) trait CommandExecutor {
+    /// Runs `command`, or returns the error that prevented it from starting.
+    fn execute(
+        &self,
+        command: &mut BootstrapCommand,
+        stdout: OutputMode,
+        stderr: OutputMode,
+    ) -> io::Result<CommandOutput>;
+
+    /// Runs `command` while printing its stderr, calling `on_line` for each line of its stdout.
+    ///
+    /// By default, the lines are only passed on once the command has finished.
+    fn stream(
+        &self,
+        command: &mut BootstrapCommand,
+        on_line: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandOutput> {
+        let output = self.execute(command, OutputMode::Capture, OutputMode::Print)?;
+        for line in output.stdout_if_present().unwrap_or_default().lines() {
+            on_line(line);
+        }
+        Ok(output)
+    }
+
+    /// Runs `commands` while printing their output, up to `jobs` of them at once, returning how
+    /// each went and how long it took.
+    ///
+    /// By default, they run one after the other.
+    fn execute_all(
+        &self,
+        commands: &mut [BootstrapCommand],
+        _jobs: usize,
+    ) -> Vec<(io::Result<CommandOutput>, Duration)> {
+        commands
+            .iter_mut()
+            .map(|command| {
+                let start = Instant::now();
+                let output = self.execute(command, OutputMode::Print, OutputMode::Print);
+                (output, start.elapsed())
+            })
+            .collect()
+    }
+
+    /// Whether commands only pretend to run. Their output is then neither checked nor logged as
+    /// having run.
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+}
+
+/// Runs commands as child processes of bootstrap.
+pub(crate) struct LocalExecutor;
+
+impl CommandExecutor for LocalExecutor {
+    fn execute(
+        &self,
+        command: &mut BootstrapCommand,
+        stdout: OutputMode,
+        stderr: OutputMode,
+    ) -> io::Result<CommandOutput> {
+        let cmd = command.as_command_mut();
+        cmd.stdout(stdout.stdio());
+        cmd.stderr(stderr.stdio());
+        cmd.output().map(|output| CommandOutput::from_output(output, stdout, stderr))
+    }
+
+    /// Passes each line on as soon as the command prints it.
+    fn stream(
+        &self,
+        command: &mut BootstrapCommand,
+        on_line: &mut dyn FnMut(&str),
+    ) -> io::Result<CommandOutput> {
+        let cmd = command.as_command_mut();
+        let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::inherit()).spawn()?;
+        for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+            on_line(&line?);
+        }
+        let output = Output { status: child.wait()?, stdout: vec![], stderr: vec![] };
+        Ok(CommandOutput::from_output(output, OutputMode::Print, OutputMode::Print))
+    }
+
+    /// Keeps up to `jobs` commands running, waiting for the oldest one to start the next.
+    fn execute_all(
+        &self,
+        commands: &mut [BootstrapCommand],
+        jobs: usize,
+    ) -> Vec<(io::Result<CommandOutput>, Duration)> {
+        let wait = |child: Child, start: Instant| {
+            let output = child.wait_with_output();
+            let output = output.map(|output| {
+                CommandOutput::from_output(output, OutputMode::Print, OutputMode::Print)
+            });
+            (output, start.elapsed())
+        };
+
+        let mut results: Vec<_> = commands.iter().map(|_| None).collect();
+        let mut running = VecDeque::new();
+        for (i, command) in commands.iter_mut().enumerate() {
+            if running.len() >= jobs.max(1) {
+                let (oldest, child, start) = running.pop_front().unwrap();
+                results[oldest] = Some(wait(child, start));
+            }
+            let start = Instant::now();
+            match command.as_command_mut().spawn() {
+                Ok(child) => running.push_back((i, child, start)),
+                Err(e) => results[i] = Some((Err(e), start.elapsed())),
+            }
+        }
+        for (i, child, start) in running {
+            results[i] = Some(wait(child, start));
+        }
+        results.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+/// Doesn't run anything, every command succeeds with an empty output.
+pub(crate) struct DryRunExecutor;
+
+impl CommandExecutor for DryRunExecutor {
+    fn execute(
+        &self,
+        _command: &mut BootstrapCommand,
+        _stdout: OutputMode,
+        _stderr: OutputMode,
+    ) -> io::Result<CommandOutput> {
+        Ok(CommandOutput::default())
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+}
+
+/// Doesn't run anything either, but writes each command out as a Nix derivation into `nix_dir`
+/// (`--emit=nix`). So is each step that declares its inputs and outputs with
+/// [`Step::declared_io`], as a derivation that runs the commands of the step and depends on the
+/// derivations of the steps it ensured.
+///
+/// Steps that don't declare anything are folded into the step that ensured them.
+///
+/// [`Step::declared_io`]: crate::core::builder::Step::declared_io
+#[derive(Clone)]
+pub(crate) struct NixExecutor {
+    nix_dir: PathBuf,
+    /// Number of derivations written so far.
+    emitted: Cell<usize>,
+    /// The steps that are currently running, innermost last.
+    running: RefCell<Vec<RunningStep>>,
+    /// The derivations that stand for each finished step, by type name and `Debug` repr.
+    finished: RefCell<HashMap<(String, String), Vec<String>>>,
+}
+
+/// What a running step did so far, see [`NixExecutor`].
+#[derive(Clone, Default)]
+struct RunningStep {
+    /// The commands it ran, rendered by [`nix::shell_command`].
+    commands: Vec<String>,
+    /// The derivations of the steps it ensured.
+    dependencies: Vec<String>,
+}
+
+impl NixExecutor {
+    pub(crate) fn new(nix_dir: PathBuf) -> Self {
+        NixExecutor {
+            nix_dir,
+            emitted: Cell::new(0),
+            running: Default::default(),
+            finished: Default::default(),
+        }
+    }
+
+    /// Starts collecting the commands and dependencies of a step that is about to run.
+    pub(crate) fn enter_step(&self) {
+        self.running.borrow_mut().push(RunningStep::default());
+    }
+
+    /// Finishes the innermost running step. If it declares any inputs or outputs it gets a
+    /// derivation of its own, otherwise its commands and dependencies are passed on to the step
+    /// that ensured it.
+    pub(crate) fn exit_step(
+        &self,
+        step_type: &str,
+        step: &dyn Debug,
+        io: impl FnOnce() -> StepIo,
+    ) -> io::Result<()> {
+        let step_repr = format!("{step:?}");
+        let Some(finished) = self.running.borrow_mut().pop() else {
+            return Err(io::Error::other(format!("{step_repr} finished without having started")));
+        };
+        let io = io();
+        let derivations = if io.is_empty() {
+            if let Some(parent) = self.running.borrow_mut().last_mut() {
+                parent.commands.extend(finished.commands);
+            }
+            finished.dependencies
+        } else {
+            let short_type = step_type.rsplit("::").next().unwrap_or(step_type);
+            let name = self.next_name(short_type);
+            let expr = nix::step_derivation(
+                &name,
+                &step_repr,
+                &io,
+                &finished.commands,
+                &finished.dependencies,
+            );
+            fs::create_dir_all(&self.nix_dir)?;
+            fs::write(self.nix_dir.join(format!("{name}.nix")), expr)?;
+            vec![name]
+        };
+        self.add_dependencies(&derivations);
+        self.finished.borrow_mut().insert((step_type.to_owned(), step_repr), derivations);
+        Ok(())
+    }
+
+    /// Records that the innermost running step ensured a step that already finished.
+    pub(crate) fn reuse_step(&self, step_type: &str, step: &dyn Debug) {
+        let key = (step_type.to_owned(), format!("{step:?}"));
+        if let Some(derivations) = self.finished.borrow().get(&key) {
+            self.add_dependencies(derivations);
+        }
+    }
+
+    fn add_dependencies(&self, derivations: &[String]) {
+        if let Some(parent) = self.running.borrow_mut().last_mut() {
+            for derivation in derivations {
+                if !parent.dependencies.contains(derivation) {
+                    parent.dependencies.push(derivation.clone());
+                }
+            }
+        }
+    }
+
+    fn next_name(&self, program: &str) -> String {
+        let index = self.emitted.get();
+        self.emitted.set(index + 1);
+        nix::derivation_name(index, program)
+    }
+}
+
+impl CommandExecutor for NixExecutor {
+    fn execute(
+        &self,
+        command: &mut BootstrapCommand,
+        _stdout: OutputMode,
+        _stderr: OutputMode,
+    ) -> io::Result<CommandOutput> {
+        let index = self.emitted.get();
+        let name = self.next_name(&command.get_program().to_string_lossy());
+        fs::create_dir_all(&self.nix_dir)?;
+        fs::write(self.nix_dir.join(format!("{name}.nix")), nix::derivation(command, index))?;
+        if let Some(step) = self.running.borrow_mut().last_mut() {
+            step.commands.push(nix::shell_command(command));
+        }
+        Ok(CommandOutput::default())
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+}
+
+/// Forwards every command to an external program, e.g. a sandboxed or remote runner.
+///
+/// The program is started once per command. It receives an [`ExecuteRequest`] as JSON on stdin,
+/// and has to print an [`ExecuteResponse`] as JSON on stdout.
+pub(crate) struct ExternalExecutor {
+    program: PathBuf,
+}
+
+impl ExternalExecutor {
+    pub(crate) fn new(program: PathBuf) -> Self {
+        ExternalExecutor { program }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct ExecuteRequest {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Environment variables to set on top of the environment of bootstrap.
+    pub env: BTreeMap<String, String>,
+    /// Environment variables to remove from the environment of bootstrap.
+    pub env_remove: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    /// Whether bootstrap needs the stdout of the command, or only wants it printed.
+    pub capture_stdout: bool,
+    /// Whether bootstrap needs the stderr of the command, or only wants it printed.
+    pub capture_stderr: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct ExecuteResponse {
+    /// Why the command could not be started. If set, all the other fields are ignored.
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub exit_code: i32,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+}
+
+impl ExecuteRequest {
+    pub(crate) fn new(command: &BootstrapCommand, stdout: OutputMode, stderr: OutputMode) -> Self {
+        let (env, env_remove) = command.get_env_delta();
+        ExecuteRequest {
+            program: command.get_program().to_string_lossy().into_owned(),
+            args: command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+            env,
+            env_remove,
+            cwd: command.get_current_dir().map(Path::to_path_buf),
+            capture_stdout: stdout.captures(),
+            capture_stderr: stderr.captures(),
+        }
+    }
+}
+
+impl CommandExecutor for ExternalExecutor {
+    fn execute(
+        &self,
+        command: &mut BootstrapCommand,
+        stdout: OutputMode,
+        stderr: OutputMode,
+    ) -> io::Result<CommandOutput> {
+        let request = serde_json::to_vec(&ExecuteRequest::new(command, stdout, stderr))?;
+
+        let mut child = Command::new(&self.program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("failed to start {}: {e}", self.program.display()))
+            })?;
+        child.stdin.take().unwrap().write_all(&request)?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "{} exited with {}",
+                self.program.display(),
+                output.status
+            )));
+        }
+
+        let response: ExecuteResponse = serde_json::from_slice(&output.stdout)?;
+        if let Some(error) = response.error {
+            return Err(io::Error::other(error));
+        }
+        // Print what the command would have printed itself if it had run locally.
+        if !stdout.captures() {
+            print!("{}", response.stdout);
+        }
+        if !stderr.captures() {
+            eprint!("{}", response.stderr);
+        }
+        let output = Output {
+            status: exit_status(response.exit_code),
+            stdout: response.stdout.into_bytes(),
+            stderr: response.stderr.into_bytes(),
+        };
+        Ok(CommandOutput::from_output(output, stdout, stderr))
+    }
+}
+
+#[cfg(unix)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    // This is a raw wait status, where the exit code lives in the second byte.
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
+
+#[cfg(windows)]
+fn exit_status(code: i32) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(code as u32)
+}
+
+/// Runs `command` with `executor` and returns its stdout, panicking if it fails.
+///
+/// This is only for the few commands bootstrap runs before `Build` exists, everything else goes
+/// through `Build::run`.
+#[track_caller]
+pub(crate) fn query(executor: &dyn CommandExecutor, mut command: BootstrapCommand) -> String {
+    command.mark_as_executed();
+    match executor.execute(&mut command, OutputMode::Capture, OutputMode::Print) {
+        Ok(output) if output.is_success() => output.stdout(),
+        Ok(output) => panic!(
+            "command did not execute successfully: {command:?}\nexpected success, got: {}",
+            output.status().unwrap()
+        ),
+        Err(e) => panic!("failed to execute command: {command:?}\nERROR: {e}"),
+    }
+}
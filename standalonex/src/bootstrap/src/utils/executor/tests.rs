@@ -0,0 +1,142 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::core::builder::StepIo;
+use crate::core::config::tests::test_dir;
+use crate::utils::exec::{OutputMode, command};
+use crate::utils::executor::{CommandExecutor, DryRunExecutor, ExecuteRequest, NixExecutor};
+
+#[test]
+fn test_execute_request() {
+    let mut cmd = command("rustc");
+    cmd.arg("-vV").env("RUSTC_BOOTSTRAP", "1").env_remove("RUSTFLAGS");
+    cmd.mark_as_executed();
+
+    let request = ExecuteRequest::new(&cmd, OutputMode::Capture, OutputMode::Print);
+    assert_eq!(request.program, "rustc");
+    assert_eq!(request.args, ["-vV"]);
+    assert_eq!(request.env.get("RUSTC_BOOTSTRAP").map(String::as_str), Some("1"));
+    assert_eq!(request.env_remove, ["RUSTFLAGS"]);
+    assert_eq!(request.cwd, None);
+    assert!(request.capture_stdout && !request.capture_stderr);
+}
+
+#[test]
+fn test_dry_run_executor() {
+    let mut cmd = command("/bin/false");
+    let output =
+        DryRunExecutor.execute(&mut cmd, OutputMode::Capture, OutputMode::Capture).unwrap();
+    cmd.mark_as_executed();
+    assert!(DryRunExecutor.is_dry_run());
+    assert!(output.is_success());
+}
+
+#[test]
+fn test_nix_executor() {
+    let dir = test_dir("nix-executor");
+    let mut cmd = command("/bin/false");
+
+    let executor = NixExecutor::new(dir.join("nix"));
+    assert!(executor.is_dry_run());
+    executor.execute(&mut cmd, OutputMode::Print, OutputMode::Print).unwrap();
+    executor.execute(&mut cmd, OutputMode::Print, OutputMode::Print).unwrap();
+    cmd.mark_as_executed();
+    assert!(dir.join("nix/0000-false.nix").exists());
+    assert!(dir.join("nix/0001-false.nix").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_nix_executor_steps() {
+    let dir = test_dir("nix-executor-steps");
+    let mut cmd = command("true");
+    let io = || StepIo { outputs: vec![PathBuf::from("/build/out")], ..Default::default() };
+
+    let executor = NixExecutor::new(dir.join("nix"));
+    executor.enter_step();
+    // A step without declared inputs or outputs is folded into its parent.
+    executor.enter_step();
+    executor.execute(&mut cmd, OutputMode::Print, OutputMode::Print).unwrap();
+    executor.exit_step("Inner", &"Inner", StepIo::default).unwrap();
+    executor.enter_step();
+    executor.execute(&mut cmd, OutputMode::Print, OutputMode::Print).unwrap();
+    executor.exit_step("compile::Leaf", &"Leaf", io).unwrap();
+    executor.reuse_step("compile::Leaf", &"Leaf");
+    executor.exit_step("compile::Outer", &"Outer", io).unwrap();
+    cmd.mark_as_executed();
+
+    // Each command still gets a derivation of its own.
+    assert!(dir.join("nix/0000-true.nix").exists());
+    assert!(dir.join("nix/0001-true.nix").exists());
+    let leaf = fs::read_to_string(dir.join("nix/0002-Leaf.nix")).unwrap();
+    assert!(leaf.contains(r#""set -e\n('true')\necho > \"$out\"\n""#));
+    let outer = fs::read_to_string(dir.join("nix/0003-Outer.nix")).unwrap();
+    assert!(outer.contains(r#""set -e\n('true')\necho > \"$out\"\n""#));
+    assert!(outer.contains("bootstrapDependencies = [\n    (import ./0002-Leaf.nix)\n  ];"));
+    // A step can't finish without having started.
+    assert!(executor.exit_step("Outer", &"Outer", io).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_local_executor_stream() {
+    use crate::utils::executor::LocalExecutor;
+
+    let mut cmd = command("sh");
+    cmd.args(["-c", "echo first; echo second; exit 2"]);
+    let mut lines = vec![];
+    let output = LocalExecutor.stream(&mut cmd, &mut |line| lines.push(line.to_owned())).unwrap();
+    assert_eq!(lines, ["first", "second"]);
+    assert_eq!(output.status().and_then(|status| status.code()), Some(2));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_external_executor() {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::utils::executor::ExternalExecutor;
+
+    let dir = test_dir("external-executor");
+    let script = dir.join("executor.sh");
+    fs::write(
+        &script,
+        r#"#!/bin/sh
+request=$(cat)
+case "$request" in
+    *'"program":"missing"'*) echo '{"error": "no such program"}' ;;
+    *) echo '{"exit_code": 3, "stdout": "out", "stderr": "err"}' ;;
+esac
+"#,
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let executor = ExternalExecutor::new(script);
+
+    let mut cmd = command("cargo");
+    cmd.mark_as_executed();
+    let output = executor.execute(&mut cmd, OutputMode::Capture, OutputMode::Capture).unwrap();
+    assert!(!executor.is_dry_run());
+    assert_eq!(output.status().and_then(|status| status.code()), Some(3));
+    assert_eq!(output.stdout(), "out");
+    assert_eq!(output.stderr(), "err");
+
+    let mut cmd = command("missing");
+    cmd.mark_as_executed();
+    let Err(error) = executor.execute(&mut cmd, OutputMode::Print, OutputMode::Print) else {
+        panic!("the executor should have failed to start `missing`");
+    };
+    assert_eq!(error.to_string(), "no such program");
+
+    // Without a streaming protocol, the lines are passed on once the command has finished.
+    let mut cmd = command("cargo");
+    cmd.mark_as_executed();
+    let mut lines = vec![];
+    executor.stream(&mut cmd, &mut |line| lines.push(line.to_owned())).unwrap();
+    assert_eq!(lines, ["out"]);
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -47,7 +47,8 @@ macro_rules! t {
 }
 pub use t;
 
-use crate::utils::exec::{BootstrapCommand, command};
+use crate::utils::exec::{BootstrapCommand, CommandOutput, OutputMode, command};
+use crate::utils::executor::CommandExecutor;
 
 pub fn exe(name: &str, target: TargetSelection) -> String {
     crate::utils::shared_helpers::exe(name, &target.triple)
@@ -128,7 +129,7 @@ pub struct TimeIt(bool, Instant);
 
 /// Returns an RAII structure that prints out how long it took to drop.
 pub fn timeit(builder: &Builder<'_>) -> TimeIt {
-    TimeIt(builder.config.dry_run(), Instant::now())
+    TimeIt(builder.dry_run(), Instant::now())
 }
 
 impl Drop for TimeIt {
@@ -151,7 +152,7 @@ pub(crate) fn program_out_of_date(stamp: &Path, key: &str) -> bool {
 /// Symlinks two directories, using junctions on Windows and normal symlinks on
 /// Unix.
 pub fn symlink_dir(config: &Config, original: &Path, link: &Path) -> io::Result<()> {
-    if config.dry_run() {
+    if config.build_dry_run() {
         return Ok(());
     }
     let _ = fs::remove_dir_all(link);
@@ -264,21 +265,27 @@ pub fn is_valid_test_suite_arg<'a, P: AsRef<Path>>(
 }
 
 // FIXME: get rid of this function
-pub fn check_run(cmd: &mut BootstrapCommand, print_cmd_on_fail: bool) -> bool {
-    let status = match cmd.as_command_mut().status() {
-        Ok(status) => status,
+pub(crate) fn check_run(
+    executor: &dyn CommandExecutor,
+    cmd: &mut BootstrapCommand,
+    print_cmd_on_fail: bool,
+) -> CommandOutput {
+    cmd.mark_as_executed();
+    let output = match executor.execute(cmd, OutputMode::Print, OutputMode::Print) {
+        Ok(output) => output,
         Err(e) => {
             println!("failed to execute command: {cmd:?}\nERROR: {e}");
-            return false;
+            return CommandOutput::did_not_start(OutputMode::Print, OutputMode::Print);
         }
     };
-    if !status.success() && print_cmd_on_fail {
+    if !output.is_success() && print_cmd_on_fail {
         println!(
             "\n\ncommand did not execute successfully: {cmd:?}\n\
-             expected success, got: {status}\n\n"
+             expected success, got: {}\n\n",
+            output.status().unwrap()
         );
     }
-    status.success()
+    output
 }
 
 pub fn make(host: &str) -> PathBuf {
@@ -542,6 +549,11 @@ pub fn check_cfg_arg(name: &str, values: Option<&[&str]>) -> String {
     format!("--check-cfg=cfg({name}{next})")
 }
 
+/// Quotes `s` for a POSIX shell.
+pub fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'"'"'"#))
+}
+
 /// Prepares `BootstrapCommand` that runs git inside the source directory if given.
 ///
 /// Whenever a git invocation is needed, this function should be preferred over
@@ -0,0 +1,127 @@
+//! Bootstrap's HTTP client for downloads, used unless `build.use-curl` is set.
+//!
+//! Like the `curl` invocation it replaces, it follows redirects, gives up on stalled transfers,
+//! retries failed ones and resumes them where they stopped. The SHA-256 checksum of the file is
+//! computed while it's written, so it doesn't have to be read again to be verified.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::utils::helpers::hex_encode;
+
+/// Retries after the first attempt, like `curl --retry 3`.
+const RETRIES: u32 = 3;
+/// How long connecting, or waiting for more data, may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Downloads `url` into `dest`, continuing the transfer of an earlier attempt if `dest` exists.
+/// Returns the SHA-256 checksum of `dest`. With `progress`, prints how much was received.
+pub(crate) fn download(url: &str, dest: &Path, progress: bool) -> Result<String, String> {
+    let agent = ureq::AgentBuilder::new().timeout_connect(TIMEOUT).timeout_read(TIMEOUT).build();
+    let mut attempt = 0;
+    loop {
+        match try_download(&agent, url, dest, progress) {
+            Ok(checksum) => return Ok(checksum),
+            Err(Failure { message, transient: true }) if attempt < RETRIES => {
+                attempt += 1;
+                eprintln!("{message}, retrying ({attempt}/{RETRIES})");
+                thread::sleep(Duration::from_secs(1 << attempt));
+            }
+            Err(Failure { message, .. }) => return Err(message),
+        }
+    }
+}
+
+struct Failure {
+    message: String,
+    /// Whether another attempt may succeed.
+    transient: bool,
+}
+
+fn try_download(
+    agent: &ureq::Agent,
+    url: &str,
+    dest: &Path,
+    progress: bool,
+) -> Result<String, Failure> {
+    let io_error = |e: io::Error| Failure {
+        message: format!("failed to write {}: {e}", dest.display()),
+        transient: false,
+    };
+
+    let resume_from = dest.metadata().map_or(0, |metadata| metadata.len());
+    let mut request = agent.get(url);
+    if resume_from > 0 {
+        request = request.set("Range", &format!("bytes={resume_from}-"));
+    }
+    let response = match request.call() {
+        Ok(response) => response,
+        // The earlier attempt got all of it.
+        Err(ureq::Error::Status(416, _)) if resume_from > 0 => {
+            let mut hasher = Sha256::new();
+            io::copy(&mut File::open(dest).map_err(io_error)?, &mut hasher).map_err(io_error)?;
+            return Ok(hex_encode(hasher.finalize()));
+        }
+        Err(ureq::Error::Status(status, _)) => {
+            let transient = matches!(status, 408 | 429 | 500..);
+            return Err(Failure { message: format!("{url}: HTTP status {status}"), transient });
+        }
+        Err(e) => return Err(Failure { message: format!("{url}: {e}"), transient: true }),
+    };
+
+    let mut hasher = Sha256::new();
+    // Servers that don't support ranges send the whole file again.
+    let (mut file, mut received) = if response.status() == 206 {
+        let mut file = OpenOptions::new().read(true).append(true).open(dest).map_err(io_error)?;
+        io::copy(&mut file, &mut hasher).map_err(io_error)?;
+        (file, resume_from)
+    } else {
+        (File::create(dest).map_err(io_error)?, 0)
+    };
+    let total = response.header("Content-Length").and_then(|len| len.parse::<u64>().ok());
+    let total = total.map(|len| len + received);
+
+    let mut reader = response.into_reader();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut reported = Instant::now();
+    loop {
+        let len = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) => return Err(Failure { message: format!("{url}: {e}"), transient: true }),
+        };
+        hasher.update(&buffer[..len]);
+        file.write_all(&buffer[..len]).map_err(io_error)?;
+        received += len as u64;
+        if progress && reported.elapsed() > Duration::from_millis(200) {
+            report_progress(received, total);
+            reported = Instant::now();
+        }
+    }
+    if progress {
+        report_progress(received, total);
+        eprintln!();
+    }
+    Ok(hex_encode(hasher.finalize()))
+}
+
+fn report_progress(received: u64, total: Option<u64>) {
+    const MIB: f64 = (1 << 20) as f64;
+    let received_mib = received as f64 / MIB;
+    match total {
+        Some(total) if total > 0 => eprint!(
+            "\r{received_mib:.1} / {:.1} MiB ({:.0}%)",
+            total as f64 / MIB,
+            received as f64 * 100.0 / total as f64
+        ),
+        _ => eprint!("\r{received_mib:.1} MiB"),
+    }
+}
+
+#[cfg(test)]
+mod tests;
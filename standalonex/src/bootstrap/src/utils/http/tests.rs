@@ -0,0 +1,51 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::{fs, thread};
+
+use sha2::{Digest, Sha256};
+
+use crate::core::config::tests::test_dir;
+use crate::utils::helpers::hex_encode;
+use crate::utils::http::download;
+
+#[test]
+fn download_resumes() {
+    let body: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/dist/file.tar.xz", listener.local_addr().unwrap());
+
+    let served = body.clone();
+    let server = thread::spawn(move || {
+        let mut ranges = vec![];
+        for (attempt, stream) in listener.incoming().take(2).enumerate() {
+            let mut stream = stream.unwrap();
+            let mut range = None;
+            for line in BufReader::new(&stream).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.to_lowercase().strip_prefix("range: bytes=") {
+                    range = Some(value.trim_end_matches('-').parse::<usize>().unwrap());
+                }
+            }
+            ranges.push(range);
+            let start = range.unwrap_or(0);
+            let status = if range.is_some() { "206 Partial Content" } else { "200 OK" };
+            let rest = &served[start..];
+            write!(stream, "HTTP/1.1 {status}\r\nContent-Length: {}\r\n\r\n", rest.len()).unwrap();
+            // The first attempt breaks off halfway.
+            let sent = if attempt == 0 { &rest[..rest.len() / 2] } else { rest };
+            stream.write_all(sent).unwrap();
+        }
+        ranges
+    });
+
+    let dir = test_dir("http");
+    let dest = dir.join("file.tar.xz");
+    let checksum = download(&url, &dest, false).unwrap();
+    assert_eq!(server.join().unwrap(), [None, Some(body.len() / 2)]);
+    assert_eq!(fs::read(&dest).unwrap(), body);
+    assert_eq!(checksum, hex_encode(Sha256::digest(&body)));
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,129 @@
+//! A machine-readable log of every command that goes through `Build::run`.
+//!
+//! When `--invocation-log` is passed, one JSON object per line is appended to
+//! `<build-dir>/invocations.jsonl` for each command, whether it was run or skipped because of
+//! `--dry-run`. The format is meant to be consumed by external tooling, so only add fields to
+//! [`Invocation`], don't change the meaning of existing ones.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::panic::Location;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::utils::exec::{BootstrapCommand, CommandOutput};
+use crate::utils::helpers::t;
+use crate::utils::step_graph::StepNode;
+
+#[cfg(test)]
+mod tests;
+
+/// Name of the log file inside the build directory.
+pub(crate) const INVOCATION_LOG_FILENAME: &str = "invocations.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Environment variables set explicitly by bootstrap, on top of its own environment.
+    pub env: BTreeMap<String, String>,
+    /// Environment variables removed explicitly by bootstrap.
+    pub env_remove: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    /// `exit`, `delay-fail` or `ignore`.
+    pub failure_behavior: String,
+    /// Where the command was created, as `file:line:column`.
+    pub created_at: String,
+    /// Where the command was executed, as `file:line:column`.
+    pub executed_at: String,
+    /// Id of the step that was running when the command was executed, as used in the graph
+    /// written by `--graph-out`.
+    pub step_id: Option<usize>,
+    /// Type name of the step that was running when the command was executed.
+    pub step_type: Option<String>,
+    /// `Debug` representation of the step that was running when the command was executed.
+    pub step: Option<String>,
+    /// Whether the command was skipped because of `--dry-run`.
+    pub dry_run_skipped: bool,
+    /// Whether the command could be started at all.
+    pub started: bool,
+    pub success: bool,
+    /// `None` if the command didn't start, was skipped, or was killed by a signal.
+    pub exit_code: Option<i32>,
+    pub duration_secs: f64,
+}
+
+impl Invocation {
+    pub(crate) fn new(
+        command: &BootstrapCommand,
+        created_at: Location<'_>,
+        executed_at: &Location<'_>,
+        step: Option<StepNode>,
+    ) -> Self {
+        let (env, env_remove) = command.get_env_delta();
+
+        Invocation {
+            program: command.get_program().to_string_lossy().into_owned(),
+            args: command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+            env,
+            env_remove,
+            cwd: command.get_current_dir().map(Path::to_path_buf),
+            failure_behavior: command.failure_behavior.as_str().to_owned(),
+            created_at: created_at.to_string(),
+            executed_at: executed_at.to_string(),
+            step_id: step.as_ref().map(|step| step.id),
+            step_type: step.as_ref().map(|step| step.type_.clone()),
+            step: step.map(|step| step.debug_repr),
+            dry_run_skipped: false,
+            started: false,
+            success: false,
+            exit_code: None,
+            duration_secs: 0.0,
+        }
+    }
+
+    /// Fills in the outcome of a command that was actually executed.
+    pub(crate) fn finished(mut self, output: &CommandOutput, duration: Duration) -> Self {
+        self.started = output.status().is_some();
+        self.success = output.is_success();
+        self.exit_code = output.status().and_then(|status| status.code());
+        self.duration_secs = duration.as_secs_f64();
+        self
+    }
+
+    /// Marks the command as skipped because of `--dry-run`.
+    pub(crate) fn skipped(mut self) -> Self {
+        self.dry_run_skipped = true;
+        self
+    }
+}
+
+/// Truncates the log, so that it only contains the commands of the current invocation.
+pub(crate) fn reset(path: &Path) {
+    if let Some(parent) = path.parent() {
+        t!(fs::create_dir_all(parent));
+    }
+    t!(fs::write(path, ""));
+}
+
+/// Appends `invocation` to the log at `path`.
+pub(crate) fn append(path: &Path, invocation: &Invocation) {
+    let mut line = t!(serde_json::to_string(invocation));
+    line.push('\n');
+    let mut file = t!(OpenOptions::new().create(true).append(true).open(path));
+    t!(file.write_all(line.as_bytes()));
+}
+
+/// Reads back all the invocations recorded at `path`.
+pub(crate) fn read(path: &Path) -> Vec<Invocation> {
+    let contents = t!(fs::read_to_string(path), format!("failed to read {}", path.display()));
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| t!(serde_json::from_str(line), format!("{}:{}", path.display(), i + 1)))
+        .collect()
+}
@@ -0,0 +1,57 @@
+use std::panic::Location;
+use std::time::Duration;
+
+use crate::utils::exec::{CommandOutput, command};
+use crate::utils::invocation_log::Invocation;
+use crate::utils::step_graph::StepNode;
+
+#[test]
+fn test_invocation_record() {
+    let mut cmd = command("cargo");
+    cmd.args(["build", "--release"])
+        .env("RUSTC", "rustc")
+        .env_remove("RUSTFLAGS")
+        .current_dir("/src");
+    let mut cmd = cmd.delay_failure();
+    cmd.mark_as_executed();
+
+    let created_at = cmd.get_created_location();
+    let step = StepNode {
+        id: 3,
+        type_: "core::build_steps::tool::Cargo".to_owned(),
+        debug_repr: "Cargo { stage: 1 }".to_owned(),
+        kind: "build".to_owned(),
+        stage: Some(1),
+        target: None,
+    };
+    let invocation = Invocation::new(&cmd, created_at, Location::caller(), Some(step))
+        .finished(&CommandOutput::default(), Duration::from_millis(1500));
+
+    assert_eq!(invocation.program, "cargo");
+    assert_eq!(invocation.args, ["build", "--release"]);
+    assert_eq!(invocation.env.get("RUSTC").map(String::as_str), Some("rustc"));
+    assert_eq!(invocation.env_remove, ["RUSTFLAGS"]);
+    assert_eq!(invocation.cwd.as_deref(), Some("/src".as_ref()));
+    assert_eq!(invocation.failure_behavior, "delay-fail");
+    assert!(invocation.created_at.contains("invocation_log/tests.rs"));
+    assert_eq!(invocation.step_id, Some(3));
+    assert_eq!(invocation.step.as_deref(), Some("Cargo { stage: 1 }"));
+    assert!(invocation.started && invocation.success && !invocation.dry_run_skipped);
+    assert_eq!(invocation.exit_code, Some(0));
+    assert_eq!(invocation.duration_secs, 1.5);
+
+    let line = serde_json::to_string(&invocation).unwrap();
+    assert!(!line.contains('\n'));
+    assert_eq!(serde_json::from_str::<Invocation>(&line).unwrap(), invocation);
+}
+
+#[test]
+fn test_skipped_invocation() {
+    let mut cmd = command("git");
+    cmd.mark_as_executed();
+    let invocation =
+        Invocation::new(&cmd, cmd.get_created_location(), Location::caller(), None).skipped();
+    assert!(invocation.dry_run_skipped && !invocation.started && !invocation.success);
+    assert_eq!(invocation.exit_code, None);
+    assert_eq!(invocation.step_type, None);
+}
@@ -6,11 +6,19 @@ pub(crate) mod cache;
 pub(crate) mod cc_detect;
 pub(crate) mod change_tracker;
 pub(crate) mod channel;
+pub(crate) mod elf;
 pub(crate) mod exec;
+pub(crate) mod executor;
 pub(crate) mod helpers;
+pub(crate) mod http;
+pub(crate) mod invocation_log;
 pub(crate) mod job;
 #[cfg(feature = "build-metrics")]
 pub(crate) mod metrics;
+pub(crate) mod nix;
 pub(crate) mod render_tests;
+pub(crate) mod reproducible;
 pub(crate) mod shared_helpers;
+pub(crate) mod step_cache;
+pub(crate) mod step_graph;
 pub(crate) mod tarball;
@@ -0,0 +1,161 @@
+//! Rendering of bootstrap commands as Nix derivations.
+//!
+//! With `--emit=nix`, `Build::run` doesn't spawn anything. Instead every command is written out
+//! as a standalone `derivation { ... }` expression, so the build can be driven by Nix itself.
+//! Steps that implement `Step::declared_io` additionally get a derivation of their own, which
+//! runs the commands they ran, in order, as a shell script.
+
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::core::builder::StepIo;
+use crate::utils::exec::BootstrapCommand;
+use crate::utils::helpers::sh_quote;
+
+#[cfg(test)]
+mod tests;
+
+/// Renders `command` as a Nix derivation expression.
+///
+/// `index` is the position of the command in the build; it keeps the derivation names unique
+/// and ordered. The program becomes the `builder`, and only the environment that bootstrap set
+/// explicitly is recorded, since derivations don't inherit the environment of the caller.
+pub(crate) fn derivation(command: &BootstrapCommand, index: usize) -> String {
+    let program = command.get_program().to_string_lossy();
+    let mut out = String::new();
+
+    writeln!(out, "derivation {{").unwrap();
+    writeln!(out, "  name = {};", nix_string(&derivation_name(index, &program))).unwrap();
+    writeln!(out, "  system = builtins.currentSystem;").unwrap();
+    writeln!(out, "  builder = {};", nix_string(&program)).unwrap();
+
+    write!(out, "  args = [").unwrap();
+    for arg in command.get_args() {
+        write!(out, "\n    {}", nix_string(&arg.to_string_lossy())).unwrap();
+    }
+    writeln!(out, "\n  ];").unwrap();
+
+    if let Some(cwd) = command.get_current_dir() {
+        writeln!(out, "  bootstrapCwd = {};", nix_string(&cwd.to_string_lossy())).unwrap();
+    }
+    let failure_behavior = command.failure_behavior.as_str();
+    writeln!(out, "  bootstrapFailureBehavior = {};", nix_string(failure_behavior)).unwrap();
+
+    let mut removed = vec![];
+    for (key, value) in command.get_envs() {
+        let key = key.to_string_lossy();
+        match value {
+            Some(value) => {
+                writeln!(out, "  {} = {};", nix_string(&key), nix_string(&value.to_string_lossy()))
+                    .unwrap();
+            }
+            // Nothing to remove from a clean environment, but keep the intent around.
+            None => removed.push(nix_string(&key)),
+        }
+    }
+    if !removed.is_empty() {
+        writeln!(out, "  bootstrapEnvRemove = [ {} ];", removed.join(" ")).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// Renders a step as a Nix derivation expression named `name`.
+///
+/// `commands` are the commands the step ran, as rendered by [`shell_command`], and `dependencies`
+/// the names of the derivations of the steps it ensured, which are expected next to this one.
+/// The outputs of the step are outside of the Nix store, so `$out` only records that it ran.
+pub(crate) fn step_derivation(
+    name: &str,
+    step_repr: &str,
+    io: &StepIo,
+    commands: &[String],
+    dependencies: &[String],
+) -> String {
+    let paths = |paths: &[PathBuf]| -> String {
+        paths.iter().map(|p| format!("\n    {}", nix_string(&p.to_string_lossy()))).collect()
+    };
+    let dependencies: String =
+        dependencies.iter().map(|d| format!("\n    (import ./{d}.nix)")).collect();
+    let mut script = String::from("set -e\n");
+    for command in commands {
+        script.push_str(command);
+        script.push('\n');
+    }
+    script.push_str("echo > \"$out\"\n");
+    let mut out = String::new();
+
+    writeln!(out, "derivation {{").unwrap();
+    writeln!(out, "  name = {};", nix_string(name)).unwrap();
+    writeln!(out, "  system = builtins.currentSystem;").unwrap();
+    writeln!(out, "  builder = \"/bin/sh\";").unwrap();
+    writeln!(out, "  args = [\n    \"-c\"\n    {}\n  ];", nix_string(&script)).unwrap();
+    writeln!(out, "  bootstrapStep = {};", nix_string(step_repr)).unwrap();
+    writeln!(out, "  bootstrapInputs = [{}\n  ];", paths(&io.inputs)).unwrap();
+    writeln!(out, "  bootstrapOutputs = [{}\n  ];", paths(&io.outputs)).unwrap();
+    writeln!(out, "  bootstrapTools = [{}\n  ];", paths(&io.tools)).unwrap();
+    writeln!(out, "  bootstrapDependencies = [{dependencies}\n  ];").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+/// Renders `command` as a line of a shell script, in a subshell so that its working directory
+/// and environment don't leak into the next line.
+///
+/// The failure behavior isn't rendered: commands that are allowed to fail mostly have their
+/// failure handled by bootstrap itself, e.g. by exiting after `stream_cargo`, so a step's script
+/// stops at the first command that fails.
+pub(crate) fn shell_command(command: &BootstrapCommand) -> String {
+    let mut words = vec![];
+    for (key, value) in command.get_envs() {
+        let key = key.to_string_lossy();
+        match value {
+            Some(value) => words.push(sh_quote(&format!("{key}={}", value.to_string_lossy()))),
+            None => words.extend(["-u".to_owned(), sh_quote(&key)]),
+        }
+    }
+    if !words.is_empty() {
+        words.insert(0, "env".to_owned());
+    }
+    words.push(sh_quote(&command.get_program().to_string_lossy()));
+    words.extend(command.get_args().map(|arg| sh_quote(&arg.to_string_lossy())));
+
+    let line = words.join(" ");
+    match command.get_current_dir() {
+        Some(cwd) => format!("(cd {} && {line})", sh_quote(&cwd.to_string_lossy())),
+        None => format!("({line})"),
+    }
+}
+
+/// Builds a derivation name out of the program's file name, e.g. `0042-rustc`.
+///
+/// Nix only accepts a limited set of characters in names, everything else becomes `-`.
+pub(crate) fn derivation_name(index: usize, program: &str) -> String {
+    let stem = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let stem: String = stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "+-._?=".contains(c) { c } else { '-' })
+        .collect();
+    format!("{index:04}-{stem}")
+}
+
+/// Quotes `s` as a Nix string literal.
+pub(crate) fn nix_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // `${` would start an interpolation.
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,104 @@
+use std::path::PathBuf;
+
+use crate::core::builder::StepIo;
+use crate::utils::exec::command;
+use crate::utils::nix::{derivation, derivation_name, nix_string, shell_command, step_derivation};
+
+#[test]
+fn test_nix_string() {
+    assert_eq!(nix_string("rustc"), r#""rustc""#);
+    assert_eq!(nix_string(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+    assert_eq!(nix_string("${HOME} $PATH"), r#""\${HOME} $PATH""#);
+    assert_eq!(nix_string("a\nb\t"), r#""a\nb\t""#);
+}
+
+#[test]
+fn test_derivation_name() {
+    assert_eq!(derivation_name(3, "/nix/store/abc-rust/bin/rustc"), "0003-rustc");
+    assert_eq!(derivation_name(12, "cargo clippy"), "0012-cargo-clippy");
+}
+
+#[test]
+fn test_derivation() {
+    let mut cmd = command("/usr/bin/rustc");
+    cmd.args(["--edition", "2021", "lib.rs"])
+        .env("RUSTFLAGS", "-Cdebuginfo=2")
+        .env_remove("CARGO")
+        .current_dir("/tmp/build");
+    let cmd = cmd.allow_failure();
+    let expr = derivation(&cmd, 7);
+    let mut cmd = cmd;
+    cmd.mark_as_executed();
+
+    assert_eq!(
+        expr,
+        r#"derivation {
+  name = "0007-rustc";
+  system = builtins.currentSystem;
+  builder = "/usr/bin/rustc";
+  args = [
+    "--edition"
+    "2021"
+    "lib.rs"
+  ];
+  bootstrapCwd = "/tmp/build";
+  bootstrapFailureBehavior = "ignore";
+  "RUSTFLAGS" = "-Cdebuginfo=2";
+  bootstrapEnvRemove = [ "CARGO" ];
+}
+"#
+    );
+}
+
+#[test]
+fn test_step_derivation() {
+    let io = StepIo {
+        inputs: vec![PathBuf::from("/src/library")],
+        outputs: vec![PathBuf::from("/build/stage0-std"), PathBuf::from("/build/.libstd.stamp")],
+        tools: vec![],
+    };
+    let commands = ["('cargo' 'build')".to_owned()];
+    let deps = ["0004-Sysroot".to_owned()];
+
+    assert_eq!(
+        step_derivation("0005-Std", "Std { stage: 0 }", &io, &commands, &deps),
+        r#"derivation {
+  name = "0005-Std";
+  system = builtins.currentSystem;
+  builder = "/bin/sh";
+  args = [
+    "-c"
+    "set -e\n('cargo' 'build')\necho > \"$out\"\n"
+  ];
+  bootstrapStep = "Std { stage: 0 }";
+  bootstrapInputs = [
+    "/src/library"
+  ];
+  bootstrapOutputs = [
+    "/build/stage0-std"
+    "/build/.libstd.stamp"
+  ];
+  bootstrapTools = [
+  ];
+  bootstrapDependencies = [
+    (import ./0004-Sysroot.nix)
+  ];
+}
+"#
+    );
+}
+
+#[test]
+fn test_shell_command() {
+    let mut cmd = command("/usr/bin/cargo");
+    cmd.args(["build", "--features", "a b"]);
+    assert_eq!(shell_command(&cmd), "('/usr/bin/cargo' 'build' '--features' 'a b')");
+
+    cmd.env("RUSTFLAGS", "-Cdebuginfo=2").env_remove("CARGO").current_dir("/tmp/it's");
+    cmd.mark_as_executed();
+    assert_eq!(
+        shell_command(&cmd),
+        "(cd '/tmp/it'\"'\"'s' && env -u 'CARGO' 'RUSTFLAGS=-Cdebuginfo=2' '/usr/bin/cargo' \
+         'build' '--features' 'a b')"
+    );
+}
@@ -6,14 +6,13 @@
 //! and rustc) libtest doesn't include the rendered human-readable output as a JSON field. We had
 //! to reimplement all the rendering logic in this module because of that.
 
-use std::io::{BufRead, BufReader, Read, Write};
-use std::process::{ChildStdout, Stdio};
+use std::io::Write;
 use std::time::Duration;
 
 use termcolor::{Color, ColorSpec, WriteColor};
 
 use crate::core::builder::Builder;
-use crate::utils::exec::BootstrapCommand;
+use crate::utils::exec::{BehaviorOnFailure, BootstrapCommand};
 
 const TERSE_TESTS_PER_LINE: usize = 88;
 
@@ -34,11 +33,6 @@ pub(crate) fn try_run_tests(
     cmd: &mut BootstrapCommand,
     stream: bool,
 ) -> bool {
-    if builder.config.dry_run() {
-        cmd.mark_as_executed();
-        return true;
-    }
-
     if !run_tests(builder, cmd, stream) {
         if builder.fail_fast {
             crate::exit!(1);
@@ -52,37 +46,23 @@ pub(crate) fn try_run_tests(
     }
 }
 
+/// Runs the tests through the executor, so that they are recorded instead of run in dry runs
+/// and with `--emit=nix`, like any other command.
 fn run_tests(builder: &Builder<'_>, cmd: &mut BootstrapCommand, stream: bool) -> bool {
-    let cmd = cmd.as_command_mut();
-    cmd.stdout(Stdio::piped());
-
-    builder.verbose(|| println!("running: {cmd:?}"));
+    // `try_run_tests` decides what a failure means.
+    cmd.failure_behavior = BehaviorOnFailure::Ignore;
 
-    let mut process = cmd.spawn().unwrap();
-
-    // This runs until the stdout of the child is closed, which means the child exited. We don't
-    // run this on another thread since the builder is not Sync.
-    let renderer = Renderer::new(process.stdout.take().unwrap(), builder);
     if stream {
-        renderer.stream_all();
-    } else {
-        renderer.render_all();
+        // Printed as is, as soon as the test runner prints it.
+        return cmd.run(builder);
     }
-
-    let result = process.wait_with_output().unwrap();
-    if !result.status.success() && builder.is_verbose() {
-        println!(
-            "\n\ncommand did not execute successfully: {cmd:?}\n\
-             expected success, got: {}",
-            result.status
-        );
-    }
-
-    result.status.success()
+    let mut renderer = Renderer::new(builder);
+    let success = cmd.stream(builder, &mut |line| renderer.render_line(line));
+    renderer.finish();
+    success
 }
 
 struct Renderer<'a> {
-    stdout: BufReader<ChildStdout>,
     failures: Vec<TestOutcome>,
     benches: Vec<BenchOutcome>,
     builder: &'a Builder<'a>,
@@ -95,9 +75,8 @@ struct Renderer<'a> {
 }
 
 impl<'a> Renderer<'a> {
-    fn new(stdout: ChildStdout, builder: &'a Builder<'a>) -> Self {
+    fn new(builder: &'a Builder<'a>) -> Self {
         Self {
-            stdout: BufReader::new(stdout),
             benches: Vec::new(),
             failures: Vec::new(),
             builder,
@@ -108,30 +87,17 @@ impl<'a> Renderer<'a> {
         }
     }
 
-    fn render_all(mut self) {
-        let mut line = Vec::new();
-        loop {
-            line.clear();
-            match self.stdout.read_until(b'\n', &mut line) {
-                Ok(_) => {}
-                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(err) => panic!("failed to read output of test runner: {err}"),
-            }
-            if line.is_empty() {
-                break;
-            }
-
-            match serde_json::from_slice(&line) {
-                Ok(parsed) => self.render_message(parsed),
-                Err(_err) => {
-                    // Handle non-JSON output, for example when --nocapture is passed.
-                    let mut stdout = std::io::stdout();
-                    stdout.write_all(&line).unwrap();
-                    let _ = stdout.flush();
-                }
+    fn render_line(&mut self, line: &str) {
+        match serde_json::from_str(line) {
+            Ok(parsed) => self.render_message(parsed),
+            Err(_err) => {
+                // Handle non-JSON output, for example when --nocapture is passed.
+                println!("{line}");
             }
         }
+    }
 
+    fn finish(self) {
         if self.up_to_date_tests > 0 {
             let n = self.up_to_date_tests;
             let s = if n > 1 { "s" } else { "" };
@@ -139,23 +105,6 @@ impl<'a> Renderer<'a> {
         }
     }
 
-    /// Renders the stdout characters one by one
-    fn stream_all(mut self) {
-        let mut buffer = [0; 1];
-        loop {
-            match self.stdout.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(_) => {
-                    let mut stdout = std::io::stdout();
-                    stdout.write_all(&buffer).unwrap();
-                    let _ = stdout.flush();
-                }
-                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(err) => panic!("failed to read output of test runner: {err}"),
-            }
-        }
-    }
-
     fn render_test_outcome(&mut self, outcome: Outcome<'_>, test: &TestOutcome) {
         self.executed_tests += 1;
 
@@ -0,0 +1,304 @@
+//! `x dist --verify-reproducible`, which checks that the dist tarballs don't depend on where and
+//! in which environment they're built.
+//!
+//! Bootstrap runs itself twice for the same `x dist`, each time with a build directory of its
+//! own in `<build-dir>/reproducible` and different environment noise, and compares the tarballs
+//! of the two builds member by member. Both builds share the download cache, and only produce
+//! `.tar.xz` tarballs: the archives are the same for every compression format, so comparing one
+//! of them is enough.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use build_helper::exit;
+use object::read::archive::ArchiveFile;
+use object::{Object, ObjectSection};
+
+use crate::core::builder::Builder;
+use crate::utils::exec::command;
+use crate::utils::helpers::t;
+
+#[cfg(test)]
+mod tests;
+
+/// The two builds, with the environment noise of each.
+const BUILDS: [(&str, &[(&str, &str)]); 2] = [
+    ("first", &[("TZ", "UTC"), ("LC_ALL", "C"), ("BOOTSTRAP_REPRODUCIBLE_NOISE", "1")]),
+    (
+        "second",
+        &[
+            ("TZ", "Pacific/Kiritimati"),
+            ("LC_ALL", "C.UTF-8"),
+            ("BOOTSTRAP_REPRODUCIBLE_NOISE", "the second build"),
+        ],
+    ),
+];
+
+/// Runs `x dist --verify-reproducible`, exiting with an error if the builds differ.
+pub fn verify(builder: &Builder<'_>) {
+    let config = &builder.config;
+    let root = config.out.join("reproducible");
+    let mut dist_dirs = vec![];
+    for (name, noise) in BUILDS {
+        let build_dir = root.join(name);
+        let tmp = build_dir.join("tmp-env");
+        t!(fs::create_dir_all(&tmp));
+        builder.info(&format!("Building the dist tarballs in {}", build_dir.display()));
+
+        let options = [
+            "--build-dir".to_owned(),
+            build_dir.display().to_string(),
+            "--set".to_owned(),
+            format!("build.bootstrap-cache-path={:?}", config.download_cache()),
+            "--set".to_owned(),
+            r#"dist.compression-formats=["xz"]"#.to_owned(),
+        ];
+        let mut cmd = command(t!(env::current_exe()));
+        cmd.args(child_args(env::args().skip(1), options)).env("TMPDIR", &tmp);
+        for (var, value) in noise {
+            cmd.env(var, value);
+        }
+        cmd.run(builder);
+
+        let mut out = build_dir;
+        if let Some(variant) = &config.variant {
+            out = out.join("variants").join(variant);
+        }
+        dist_dirs.push(out.join("dist"));
+    }
+    if builder.dry_run() {
+        return;
+    }
+
+    let (first, second) = (&dist_dirs[0], &dist_dirs[1]);
+    let tarballs = |dir: &Path| -> Vec<String> {
+        let mut names: Vec<_> = t!(fs::read_dir(dir))
+            .map(|entry| t!(entry).file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".tar.xz"))
+            .collect();
+        names.sort();
+        names
+    };
+    let (first_tarballs, second_tarballs) = (tarballs(first), tarballs(second));
+    let mut names = first_tarballs.clone();
+    names.extend(second_tarballs.iter().cloned());
+    names.sort();
+    names.dedup();
+    if names.is_empty() {
+        eprintln!("ERROR: `x dist` didn't produce any tarballs to compare");
+        exit!(2);
+    }
+
+    let mut differing = 0;
+    for name in &names {
+        let differences = if !first_tarballs.contains(name) {
+            vec!["only produced by the second build".to_owned()]
+        } else if !second_tarballs.contains(name) {
+            vec!["only produced by the first build".to_owned()]
+        } else {
+            t!(compare(&first.join(name), &second.join(name)))
+        };
+        if differences.is_empty() {
+            builder.verbose(|| println!("reproducible: {name}"));
+            continue;
+        }
+        differing += 1;
+        println!("NOT REPRODUCIBLE: {name}");
+        for difference in differences {
+            println!("    {difference}");
+        }
+    }
+    if differing > 0 {
+        eprintln!("ERROR: {differing} of {} tarballs differ between the builds", names.len());
+        eprintln!("HELP: both builds are kept in {}", root.display());
+        exit!(1);
+    }
+    println!("All {} tarballs are reproducible.", names.len());
+}
+
+/// The arguments bootstrap was run with, for one of the builds: without
+/// `--verify-reproducible` and `--build-dir`, and with `options` instead.
+pub(crate) fn child_args(
+    args: impl IntoIterator<Item = String>,
+    options: impl IntoIterator<Item = String>,
+) -> Vec<String> {
+    let mut child_args = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                child_args.extend(options);
+                child_args.push(arg);
+                child_args.extend(args);
+                return child_args;
+            }
+            "--verify-reproducible" => {}
+            "--build-dir" => {
+                args.next();
+            }
+            _ if arg.starts_with("--build-dir=") => {}
+            _ => child_args.push(arg),
+        }
+    }
+    child_args.extend(options);
+    child_args
+}
+
+/// A member of a tarball.
+#[derive(Debug, PartialEq, Eq)]
+struct Member {
+    kind: u8,
+    mode: u32,
+    mtime: u64,
+    link: Option<PathBuf>,
+    contents: Vec<u8>,
+}
+
+fn read_member(mut entry: tar::Entry<'_, impl Read>) -> io::Result<(String, Member)> {
+    let header = entry.header();
+    let (kind, mode, mtime) = (header.entry_type().as_byte(), header.mode()?, header.mtime()?);
+    let link = entry.link_name()?.map(|link| link.into_owned());
+    let path = entry.path()?.to_string_lossy().into_owned();
+    let mut contents = vec![];
+    entry.read_to_end(&mut contents)?;
+    Ok((path, Member { kind, mode, mtime, link, contents }))
+}
+
+fn archive(tarball: &Path) -> io::Result<tar::Archive<impl Read>> {
+    let file = BufReader::new(File::open(tarball)?);
+    Ok(tar::Archive::new(xz2::read::XzDecoder::new(file)))
+}
+
+/// How the `.tar.xz` tarballs `first` and `second` differ, one line per difference.
+///
+/// Both tarballs are read once, side by side. The builds normally pack the same members in the
+/// same order, so each member is compared as soon as it has been read from both; a member
+/// without a counterpart at the same position waits for it until the end.
+pub(crate) fn compare(first: &Path, second: &Path) -> io::Result<Vec<String>> {
+    let (mut first_archive, mut second_archive) = (archive(first)?, archive(second)?);
+    let mut first_entries = first_archive.entries()?;
+    let mut second_entries = second_archive.entries()?;
+    let (mut first_pending, mut second_pending) = (BTreeMap::new(), BTreeMap::new());
+    let mut differences = vec![];
+    loop {
+        let a = first_entries.next().map(|entry| read_member(entry?)).transpose()?;
+        let b = second_entries.next().map(|entry| read_member(entry?)).transpose()?;
+        match (a, b) {
+            (None, None) => break,
+            (Some((path, a)), Some((b_path, b))) if path == b_path => {
+                differences.extend(member_differences(&path, &a, &b));
+            }
+            (a, b) => {
+                if let Some((path, a)) = a {
+                    match second_pending.remove(&path) {
+                        Some(b) => differences.extend(member_differences(&path, &a, &b)),
+                        None => drop(first_pending.insert(path, a)),
+                    }
+                }
+                if let Some((path, b)) = b {
+                    match first_pending.remove(&path) {
+                        Some(a) => differences.extend(member_differences(&path, &a, &b)),
+                        None => drop(second_pending.insert(path, b)),
+                    }
+                }
+            }
+        }
+    }
+    for path in first_pending.keys() {
+        differences.push(format!("{path}: only in the first build"));
+    }
+    for path in second_pending.keys() {
+        differences.push(format!("{path}: only in the second build"));
+    }
+    Ok(differences)
+}
+
+/// How the member `path` differs between the builds.
+fn member_differences(path: &str, a: &Member, b: &Member) -> Vec<String> {
+    if a.kind != b.kind {
+        return vec![format!("{path}: a different kind of entry")];
+    }
+    let mut differences = vec![];
+    if a.mode != b.mode {
+        differences.push(format!("{path}: permissions {:o} != {:o}", a.mode, b.mode));
+    }
+    if a.mtime != b.mtime {
+        differences.push(format!("{path}: mtime {} != {}", a.mtime, b.mtime));
+    }
+    if a.link != b.link {
+        differences.push(format!("{path}: links to different paths"));
+    }
+    if a.contents != b.contents {
+        let contents = content_differences(&a.contents, &b.contents);
+        differences.extend(contents.into_iter().map(|d| format!("{path}: {d}")));
+    }
+    differences
+}
+
+/// How the contents `a` and `b` of a member differ. For object files, that's the sections that
+/// differ, and for archives like rlibs and `.a` files, the members that differ.
+fn content_differences(a: &[u8], b: &[u8]) -> Vec<String> {
+    if let (Ok(a_archive), Ok(b_archive)) = (ArchiveFile::parse(a), ArchiveFile::parse(b)) {
+        return archive_differences((&a_archive, a), (&b_archive, b));
+    }
+    let (Ok(a), Ok(b)) = (object::File::parse(a), object::File::parse(b)) else {
+        return vec!["contents differ".to_owned()];
+    };
+    let sections = |file: &object::File<'_>| -> BTreeMap<String, Vec<u8>> {
+        file.sections()
+            .filter_map(|section| {
+                let name = section.name().ok()?.to_owned();
+                Some((name, section.data().unwrap_or_default().to_vec()))
+            })
+            .collect()
+    };
+    let (a, b) = (sections(&a), sections(&b));
+    let mut differences = vec![];
+    for name in a.keys().chain(b.keys().filter(|name| !a.contains_key(*name))) {
+        match (a.get(name), b.get(name)) {
+            (Some(a), Some(b)) if a == b => {}
+            (Some(_), Some(_)) => differences.push(format!("section {name} differs")),
+            (Some(_), None) => differences.push(format!("section {name} only in the first build")),
+            _ => differences.push(format!("section {name} only in the second build")),
+        }
+    }
+    if differences.is_empty() {
+        // The headers differ, or what's between the sections.
+        differences.push("contents differ outside of sections".to_owned());
+    }
+    differences
+}
+
+/// How the archives `a` and `b`, each with its data, differ.
+fn archive_differences(a: (&ArchiveFile<'_>, &[u8]), b: (&ArchiveFile<'_>, &[u8])) -> Vec<String> {
+    let members = |(archive, data): (&ArchiveFile<'_>, &[u8])| -> BTreeMap<String, Vec<u8>> {
+        archive
+            .members()
+            .filter_map(|member| {
+                let member = member.ok()?;
+                let name = String::from_utf8_lossy(member.name()).into_owned();
+                Some((name, member.data(data).unwrap_or_default().to_vec()))
+            })
+            .collect()
+    };
+    let (a, b) = (members(a), members(b));
+    let mut differences = vec![];
+    for name in a.keys().chain(b.keys().filter(|name| !a.contains_key(*name))) {
+        match (a.get(name), b.get(name)) {
+            (Some(a), Some(b)) if a == b => {}
+            (Some(a), Some(b)) => differences.extend(
+                content_differences(a, b).into_iter().map(|d| format!("member {name}: {d}")),
+            ),
+            (Some(_), None) => differences.push(format!("member {name} only in the first build")),
+            _ => differences.push(format!("member {name} only in the second build")),
+        }
+    }
+    if differences.is_empty() {
+        // The member headers differ, or the symbol table.
+        differences.push("contents differ outside of members".to_owned());
+    }
+    differences
+}
@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::core::config::tests::test_dir;
+use crate::utils::reproducible::{child_args, compare, content_differences};
+use crate::utils::tarball::write_tarballs;
+
+fn args(args: &str) -> Vec<String> {
+    let options = ["--build-dir", "/build/reproducible/first"].map(str::to_owned);
+    child_args(args.split(' ').map(str::to_owned), options)
+}
+
+#[test]
+fn test_child_args() {
+    assert_eq!(
+        args("dist --verify-reproducible rustc"),
+        ["dist", "rustc", "--build-dir", "/build/reproducible/first"]
+    );
+    assert_eq!(
+        args("dist --build-dir /b --verify-reproducible --build-dir=/c -- --build-dir"),
+        ["dist", "--build-dir", "/build/reproducible/first", "--", "--build-dir"]
+    );
+}
+
+/// Packs `files` into `<dir>/<name>.tar.xz`.
+fn tarball(dir: &Path, name: &str, files: &[(&str, &str)], mtime: u64) -> PathBuf {
+    let work = dir.join(name);
+    for (file, contents) in files {
+        let path = work.join("pkg").join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    let output = dir.join(name);
+    write_tarballs(&work, "pkg", &output, &["xz".to_owned()], "fast", Some(mtime)).unwrap();
+    output.with_extension("tar.xz")
+}
+
+#[test]
+fn test_compare() {
+    let dir = test_dir("compare-tarballs");
+    let files = [("bin/tool", "a"), ("version", "1.0")];
+    let first = tarball(&dir, "first", &files, 1);
+    let same = tarball(&dir, "same", &files, 1);
+    assert_eq!(compare(&first, &same).unwrap(), Vec::<String>::new());
+
+    let second = tarball(&dir, "second", &[("bin/tool", "b"), ("extra", "")], 2);
+    assert_eq!(
+        compare(&first, &second).unwrap(),
+        [
+            "pkg: mtime 1 != 2",
+            "pkg/bin: mtime 1 != 2",
+            "pkg/bin/tool: mtime 1 != 2",
+            "pkg/bin/tool: contents differ",
+            "pkg/version: only in the first build",
+            "pkg/extra: only in the second build",
+        ]
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_content_differences() {
+    use object::{Object, ObjectSection};
+
+    assert_eq!(content_differences(b"a", b"b"), ["contents differ"]);
+
+    let a = fs::read(std::env::current_exe().unwrap()).unwrap();
+    let (offset, _) = object::File::parse(&*a)
+        .unwrap()
+        .section_by_name(".rodata")
+        .and_then(|section| section.file_range())
+        .unwrap();
+    let mut b = a.clone();
+    b[offset as usize] ^= 1;
+    assert_eq!(content_differences(&a, &b), ["section .rodata differs"]);
+}
+
+/// An `ar` archive of `members`, like an rlib or a `.a` file.
+fn ar(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut archive = b"!<arch>\n".to_vec();
+    for (name, data) in members {
+        let header =
+            format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 644, data.len());
+        archive.extend(header.as_bytes());
+        archive.extend(*data);
+        if data.len() % 2 == 1 {
+            archive.push(b'\n');
+        }
+    }
+    archive
+}
+
+#[test]
+fn test_archive_differences() {
+    let a = ar(&[("a.o", b"a"), ("b.o", b"b"), ("c.o", b"c")]);
+    let b = ar(&[("a.o", b"a"), ("b.o", b"x"), ("d.o", b"d")]);
+    assert_eq!(
+        content_differences(&a, &b),
+        [
+            "member b.o: contents differ",
+            "member c.o only in the first build",
+            "member d.o only in the second build",
+        ]
+    );
+}
@@ -0,0 +1,329 @@
+//! An on-disk cache of [`Step`] outputs that survives across invocations of bootstrap, enabled
+//! with `build.step-cache = "<dir>"`.
+//!
+//! Unlike the in-memory [`Cache`](crate::utils::cache::Cache) used by `Builder::ensure`, entries
+//! are content-addressed: they are keyed by a hash of everything the step reads (its sources, the
+//! parts of the configuration it depends on and the stage0 toolchain), rather than by the step
+//! itself. A step opts in by implementing [`Step::persistent_output`]. On a hit, the files the step
+//! produced are copied back into the build directory and [`Step::restored`] is called instead of
+//! `Step::run`.
+//!
+//! Each entry is stored in `<dir>/<key>/`, as `output.json` next to a `files/` directory with a
+//! copy of each file listed in `output.json`. Paths inside the build directory are stored
+//! relative to it, so entries can be shared between checkouts in different locations.
+
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::core::build_steps::compile::Sysroot;
+use crate::core::builder::{Builder, Step};
+use crate::core::config::TargetSelection;
+use crate::utils::helpers::{hex_encode, t};
+use crate::{Compiler, envify};
+
+#[cfg(test)]
+mod tests;
+
+/// Prefix used in place of the build directory in stored outputs.
+const OUT_DIR: &str = "$OUT";
+
+/// Configuration keys left out of [`StepInputs::whole_config`]: they only affect how bootstrap
+/// runs or where it puts things, or are covered by [`StepInputs::toolchain`].
+const IGNORED_CONFIG: &[&str] = &[
+    "build.build-dir",
+    "build.cargo",
+    "build.jobs",
+    "build.rustc",
+    "build.step-cache",
+    "build.verbose",
+];
+
+/// Hashes everything a step reads, see [`StepInputs::key`].
+#[derive(Clone)]
+pub(crate) struct StepInputs {
+    hasher: Sha256,
+}
+
+impl StepInputs {
+    /// Starts from the type and `Debug` representation of `step`, which covers its fields.
+    pub(crate) fn new<S: Step>(step: &S) -> Self {
+        let mut inputs = StepInputs { hasher: Sha256::new() };
+        inputs.field(std::any::type_name::<S>(), format!("{step:?}").as_bytes());
+        inputs
+    }
+
+    /// Adds the contents of a file, or of all the files in a directory. Missing paths are hashed
+    /// as such, so creating them later changes the key.
+    pub(crate) fn source(&mut self, path: &Path) -> &mut Self {
+        self.field("source", path.as_os_str().as_encoded_bytes());
+        self.hash_path(path, path);
+        self
+    }
+
+    /// Adds a configuration value the step depends on.
+    pub(crate) fn config(&mut self, key: &str, value: impl Debug) -> &mut Self {
+        self.field(key, format!("{value:?}").as_bytes());
+        self
+    }
+
+    /// Adds the stage0 toolchain, i.e. the compiler and cargo bootstrap builds with.
+    pub(crate) fn toolchain(&mut self, builder: &Builder<'_>) -> &mut Self {
+        let compiler = &builder.config.stage0_metadata.compiler;
+        self.config("stage0", (&compiler.version, &compiler.date));
+        // `build.rustc` and `build.cargo` may point to a local toolchain, which the stage0
+        // version doesn't describe.
+        for program in [&builder.config.initial_rustc, &builder.config.initial_cargo] {
+            self.field("program", &fs::read(program).unwrap_or_default());
+        }
+        self
+    }
+
+    /// Adds `compiler`: the stage0 toolchain and, for a compiler built earlier in this build, its
+    /// rustc and the libraries next to it.
+    pub(crate) fn compiler(&mut self, builder: &Builder<'_>, compiler: Compiler) -> &mut Self {
+        self.toolchain(builder);
+        if compiler.is_snapshot(builder) {
+            return self;
+        }
+        self.field("program", &fs::read(Sysroot::rustc(builder, compiler)).unwrap_or_default());
+        // Only the files, `rustlib` below it has the libraries this compiler built.
+        let libdir = builder.rustc_libdir(compiler);
+        let mut libs: Vec<_> = match fs::read_dir(&libdir) {
+            Ok(entries) => entries.map(|e| t!(e).path()).filter(|path| path.is_file()).collect(),
+            Err(_) => vec![],
+        };
+        libs.sort();
+        for lib in libs {
+            self.hash_path(&libdir, &lib);
+        }
+        self
+    }
+
+    /// Adds the flags cargo and rustc get from the environment when building for `target`, and
+    /// the linker of `target`.
+    pub(crate) fn flags(&mut self, builder: &Builder<'_>, target: TargetSelection) -> &mut Self {
+        let target_rustflags = format!("CARGO_TARGET_{}_RUSTFLAGS", envify(&target.triple));
+        for var in [
+            "RUSTFLAGS",
+            "RUSTFLAGS_BOOTSTRAP",
+            "RUSTFLAGS_NOT_BOOTSTRAP",
+            "CARGOFLAGS",
+            "CARGOFLAGS_BOOTSTRAP",
+            "CARGOFLAGS_NOT_BOOTSTRAP",
+            &target_rustflags,
+        ] {
+            self.config(var, env::var_os(var));
+        }
+        self.config("linker", builder.linker(target))
+    }
+
+    /// Adds the whole resolved configuration (see `x config dump`), for steps whose output
+    /// depends on too much of it to list. [`IGNORED_CONFIG`] is left out.
+    pub(crate) fn whole_config(&mut self, builder: &Builder<'_>) -> &mut Self {
+        for (key, value) in builder.config.resolved_config() {
+            let key = key.join(".");
+            if !IGNORED_CONFIG.contains(&key.as_str()) {
+                self.config(&key, value);
+            }
+        }
+        self
+    }
+
+    /// The key of the cache entry, as a hex-encoded SHA-256.
+    pub(crate) fn key(&self) -> String {
+        hex_encode(self.hasher.clone().finalize().as_slice())
+    }
+
+    fn field(&mut self, name: &str, value: &[u8]) {
+        // Length-prefix everything so that different inputs can't produce the same byte stream.
+        for part in [name.as_bytes(), value] {
+            self.hasher.update((part.len() as u64).to_le_bytes());
+            self.hasher.update(part);
+        }
+    }
+
+    fn hash_path(&mut self, root: &Path, path: &Path) {
+        let relative = path.strip_prefix(root).unwrap_or(path).as_os_str().as_encoded_bytes();
+        let Ok(metadata) = path.symlink_metadata() else {
+            self.field("missing", relative);
+            return;
+        };
+        if metadata.is_dir() {
+            self.field("dir", relative);
+            let mut entries: Vec<_> = t!(fs::read_dir(path)).map(|e| t!(e).path()).collect();
+            entries.sort();
+            for entry in entries {
+                self.hash_path(root, &entry);
+            }
+        } else if metadata.is_symlink() {
+            self.field("symlink", relative);
+            self.field("target", t!(fs::read_link(path)).as_os_str().as_encoded_bytes());
+        } else {
+            self.field("file", relative);
+            self.field("contents", &t!(fs::read(path)));
+        }
+    }
+}
+
+/// Lists the files that make up the output of a step.
+type OutputFiles<T> = Box<dyn Fn(&T) -> Vec<PathBuf>>;
+
+/// Describes how the output of a step is stored in the [`StepCache`].
+pub(crate) struct PersistentOutput<T> {
+    inputs: StepInputs,
+    encode: fn(&T) -> serde_json::Result<Value>,
+    decode: fn(Value) -> serde_json::Result<T>,
+    /// The files that make up the output, which are stored along with it.
+    files: OutputFiles<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> PersistentOutput<T> {
+    /// Stores the output of a step that produces the files returned by `files`, which is called
+    /// once the step has run.
+    pub(crate) fn new(inputs: StepInputs, files: impl Fn(&T) -> Vec<PathBuf> + 'static) -> Self {
+        PersistentOutput {
+            inputs,
+            encode: |output| serde_json::to_value(output),
+            decode: serde_json::from_value,
+            files: Box::new(files),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    output: Value,
+    /// Where each file in `files/` goes, `files/0` being the first one.
+    files: Vec<String>,
+}
+
+#[derive(Clone)]
+pub(crate) struct StepCache {
+    dir: PathBuf,
+}
+
+impl StepCache {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        StepCache { dir }
+    }
+
+    /// Restores the output of `step` from the cache, or runs it and stores its output.
+    pub(crate) fn run<S: Step>(&self, builder: &Builder<'_>, step: S) -> S::Output {
+        let Some(persistent) = step.persistent_output(builder) else {
+            return step.run(builder);
+        };
+        let key = persistent.inputs.key();
+        let entry_dir = self.dir.join(&key);
+
+        match self.load(&entry_dir, &builder.out, &persistent) {
+            Ok(Some(output)) => {
+                builder.verbose(|| println!("restored {step:?} from step cache entry {key}"));
+                step.restored(builder, &output);
+                return output;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("WARNING: ignoring broken step cache entry {}: {e}", entry_dir.display())
+            }
+        }
+
+        let output = step.run(builder);
+        if let Err(e) = self.store(&entry_dir, &builder.out, &persistent, &output) {
+            eprintln!("WARNING: failed to store step cache entry {}: {e}", entry_dir.display());
+        }
+        output
+    }
+
+    fn load<T>(
+        &self,
+        entry_dir: &Path,
+        out: &Path,
+        persistent: &PersistentOutput<T>,
+    ) -> io::Result<Option<T>> {
+        let contents = match fs::read(entry_dir.join("output.json")) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let entry: Entry = serde_json::from_slice(&contents)?;
+        for (i, file) in entry.files.iter().enumerate() {
+            let dst = expand_out_dir(file, out);
+            if let Some(parent) = dst.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let _ = fs::remove_file(&dst);
+            fs::copy(entry_dir.join("files").join(i.to_string()), &dst)?;
+        }
+        Ok(Some((persistent.decode)(map_strings(entry.output, &|s| {
+            expand_out_dir(&s, out).to_string_lossy().into_owned()
+        }))?))
+    }
+
+    fn store<T>(
+        &self,
+        entry_dir: &Path,
+        out: &Path,
+        persistent: &PersistentOutput<T>,
+        output: &T,
+    ) -> io::Result<()> {
+        let mut files = vec![];
+        for file in (persistent.files)(output) {
+            // Files outside of the build directory can't be restored in another checkout.
+            let Some(relative) = strip_out_dir(&file, out) else { return Ok(()) };
+            files.push((file, relative));
+        }
+        let output = map_strings((persistent.encode)(output)?, &|s| {
+            strip_out_dir(Path::new(&s), out).unwrap_or(s)
+        });
+
+        // Write the entry next to its final location first, so that an interrupted build never
+        // leaves a partial entry behind.
+        let tmp_dir = entry_dir.with_extension(format!("tmp-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&tmp_dir);
+        fs::create_dir_all(tmp_dir.join("files"))?;
+        for (i, (file, _)) in files.iter().enumerate() {
+            fs::copy(file, tmp_dir.join("files").join(i.to_string()))?;
+        }
+        let entry =
+            Entry { output, files: files.into_iter().map(|(_, relative)| relative).collect() };
+        fs::write(tmp_dir.join("output.json"), serde_json::to_vec_pretty(&entry)?)?;
+
+        let _ = fs::remove_dir_all(entry_dir);
+        fs::rename(&tmp_dir, entry_dir)
+    }
+}
+
+/// Replaces the build directory at the start of `path` with [`OUT_DIR`].
+fn strip_out_dir(path: &Path, out: &Path) -> Option<String> {
+    let relative = path.strip_prefix(out).ok()?;
+    Some(format!("{OUT_DIR}/{}", relative.to_str()?.replace('\\', "/")))
+}
+
+/// Inverse of [`strip_out_dir`]; other paths are returned unchanged.
+fn expand_out_dir(path: &str, out: &Path) -> PathBuf {
+    match path.strip_prefix(OUT_DIR).and_then(|rest| rest.strip_prefix('/')) {
+        Some(relative) => out.join(relative),
+        None if path == OUT_DIR => out.to_path_buf(),
+        None => PathBuf::from(path),
+    }
+}
+
+fn map_strings(value: Value, f: &dyn Fn(String) -> String) -> Value {
+    match value {
+        Value::String(s) => Value::String(f(s)),
+        Value::Array(values) => {
+            Value::Array(values.into_iter().map(|v| map_strings(v, f)).collect())
+        }
+        Value::Object(map) => {
+            Value::Object(map.into_iter().map(|(k, v)| (k, map_strings(v, f))).collect())
+        }
+        value => value,
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::core::config::tests::test_dir;
+use crate::utils::step_cache::{
+    PersistentOutput, StepCache, StepInputs, expand_out_dir, map_strings, strip_out_dir,
+};
+
+fn inputs() -> StepInputs {
+    StepInputs { hasher: Sha256::new() }
+}
+
+#[test]
+fn test_out_dir() {
+    let out = Path::new("/checkout/build");
+    let stripped = strip_out_dir(&out.join("host/stage0-tools-bin/tidy"), out).unwrap();
+    assert_eq!(stripped, "$OUT/host/stage0-tools-bin/tidy");
+    assert_eq!(
+        expand_out_dir(&stripped, Path::new("/other/build")),
+        Path::new("/other/build/host/stage0-tools-bin/tidy")
+    );
+    assert_eq!(strip_out_dir(Path::new("/usr/bin/tidy"), out), None);
+    assert_eq!(expand_out_dir("/usr/bin/tidy", out), Path::new("/usr/bin/tidy"));
+
+    let value = map_strings(json!({ "paths": ["a", "b"], "stage": 1 }), &|s| s.to_uppercase());
+    assert_eq!(value, json!({ "paths": ["A", "B"], "stage": 1 }));
+}
+
+#[test]
+fn test_step_inputs() {
+    let dir = test_dir("step-inputs");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+
+    let key = |config: &str| inputs().config("channel", config).source(&dir).key();
+    let original = key("dev");
+    assert_eq!(original, key("dev"));
+    assert_ne!(original, key("nightly"));
+
+    fs::write(dir.join("src/main.rs"), "fn main() { }").unwrap();
+    assert_ne!(original, key("dev"));
+    fs::write(dir.join("src/main.rs"), "fn main() {}").unwrap();
+    assert_eq!(original, key("dev"));
+    fs::write(dir.join("src/lib.rs"), "").unwrap();
+    assert_ne!(original, key("dev"));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_store_and_load() {
+    let dir = test_dir("step-cache");
+    let cache = StepCache::new(dir.join("cache"));
+    let persistent: PersistentOutput<PathBuf> =
+        PersistentOutput::new(inputs(), |bin: &PathBuf| vec![bin.clone()]);
+    let entry = dir.join("cache").join(persistent.inputs.key());
+
+    let out = dir.join("build");
+    let bin = out.join("bin/tool");
+    fs::create_dir_all(bin.parent().unwrap()).unwrap();
+    fs::write(&bin, "binary").unwrap();
+    assert_eq!(cache.load(&entry, &out, &persistent).unwrap(), None);
+    cache.store(&entry, &out, &persistent, &bin).unwrap();
+
+    // A clean checkout somewhere else.
+    let other = dir.join("other/build");
+    let restored = cache.load(&entry, &other, &persistent).unwrap().unwrap();
+    assert_eq!(restored, other.join("bin/tool"));
+    assert_eq!(fs::read_to_string(restored).unwrap(), "binary");
+
+    // Outputs outside of the build directory can't be restored, so they aren't stored.
+    let outside = dir.join("outside");
+    fs::write(&outside, "binary").unwrap();
+    let entry = dir.join("cache/outside");
+    cache.store(&entry, &out, &persistent, &outside).unwrap();
+    assert!(!entry.exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
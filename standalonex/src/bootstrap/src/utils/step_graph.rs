@@ -0,0 +1,193 @@
+//! Records the dependency graph between [`Step`]s as seen by `Builder::ensure`, and writes it
+//! out as JSON and Graphviz DOT when `--graph-out` is passed.
+//!
+//! Every call to `ensure` adds an edge from the step currently running (if any) to the step
+//! being ensured, including calls that are answered from the step cache. This is what allows
+//! figuring out why a given step was pulled into the build.
+//!
+//! The graph is also recorded with `--invocation-log`, which logs the step running each command,
+//! and not at all otherwise.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use serde_derive::Serialize;
+
+use crate::core::builder::{Builder, Step};
+use crate::core::config::DryRun;
+use crate::utils::helpers::t;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Default, Clone)]
+pub(crate) struct StepGraph {
+    state: RefCell<StepGraphState>,
+}
+
+#[derive(Default, Clone)]
+struct StepGraphState {
+    nodes: Vec<StepNode>,
+    /// Maps `(type name, Debug repr)` to the index of the node in `nodes`.
+    ids: HashMap<(String, String), usize>,
+    /// `(parent, child)` pairs.
+    edges: BTreeSet<(usize, usize)>,
+    /// Nodes of the steps that are currently running, innermost last.
+    running: Vec<usize>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(crate) struct StepNode {
+    pub id: usize,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub debug_repr: String,
+    pub kind: String,
+    pub stage: Option<u32>,
+    pub target: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JsonEdge {
+    parent: usize,
+    child: usize,
+}
+
+#[derive(Serialize)]
+struct JsonGraph<'a> {
+    nodes: &'a [StepNode],
+    edges: Vec<JsonEdge>,
+}
+
+impl StepGraph {
+    /// Records that `step` was requested by the currently running step, and marks it as running.
+    pub(crate) fn enter_step<S: Step>(&self, step: &S, builder: &Builder<'_>) {
+        if let Some(id) = self.record(step, builder) {
+            self.state.borrow_mut().running.push(id);
+        }
+    }
+
+    /// Marks the innermost running step as finished.
+    pub(crate) fn exit_step(&self, builder: &Builder<'_>) {
+        if Self::is_recording(builder) {
+            self.state.borrow_mut().running.pop().expect("step graph stack empty");
+        }
+    }
+
+    /// Records that `step` was requested by the currently running step, but its output was
+    /// already cached.
+    pub(crate) fn record_cached<S: Step>(&self, step: &S, builder: &Builder<'_>) {
+        self.record(step, builder);
+    }
+
+    /// Returns the node of the innermost running step.
+    pub(crate) fn current_step(&self) -> Option<StepNode> {
+        let state = self.state.borrow();
+        Some(state.nodes[*state.running.last()?].clone())
+    }
+
+    fn is_recording(builder: &Builder<'_>) -> bool {
+        // The self-check dry run would only record the same graph a second time.
+        let config = &builder.config;
+        (config.graph_out.is_some() || config.invocation_log)
+            && !matches!(config.dry_run, DryRun::SelfCheck)
+    }
+
+    fn record<S: Step>(&self, step: &S, builder: &Builder<'_>) -> Option<usize> {
+        if !Self::is_recording(builder) {
+            return None;
+        }
+        Some(self.add(
+            std::any::type_name::<S>(),
+            &format!("{step:?}"),
+            builder.kind.as_str(),
+            step.stage(),
+            step.target().map(|target| target.to_string()),
+        ))
+    }
+
+    fn add(
+        &self,
+        type_: &str,
+        debug_repr: &str,
+        kind: &str,
+        stage: Option<u32>,
+        target: Option<String>,
+    ) -> usize {
+        let mut state = self.state.borrow_mut();
+        let key = (type_.to_owned(), debug_repr.to_owned());
+        let id = match state.ids.get(&key) {
+            Some(&id) => id,
+            None => {
+                let id = state.nodes.len();
+                state.nodes.push(StepNode {
+                    id,
+                    type_: type_.strip_prefix("bootstrap::").unwrap_or(type_).to_owned(),
+                    debug_repr: debug_repr.to_owned(),
+                    kind: kind.to_owned(),
+                    stage,
+                    target,
+                });
+                state.ids.insert(key, id);
+                id
+            }
+        };
+        if let Some(&parent) = state.running.last() {
+            state.edges.insert((parent, id));
+        }
+        id
+    }
+
+    fn to_json(&self) -> String {
+        let state = self.state.borrow();
+        let graph = JsonGraph {
+            nodes: &state.nodes,
+            edges: state.edges.iter().map(|&(parent, child)| JsonEdge { parent, child }).collect(),
+        };
+        t!(serde_json::to_string_pretty(&graph))
+    }
+
+    fn to_dot(&self) -> String {
+        let state = self.state.borrow();
+        let mut out = String::from("digraph bootstrap {\n    node [shape=box];\n");
+        for node in &state.nodes {
+            let name = node.type_.strip_prefix("core::build_steps::").unwrap_or(&node.type_);
+            let mut label = name.to_owned();
+            if let Some(stage) = node.stage {
+                write!(label, "\nstage {stage}").unwrap();
+            }
+            if let Some(target) = &node.target {
+                write!(label, "\n{target}").unwrap();
+            }
+            writeln!(
+                out,
+                "    n{} [label={}, tooltip={}];",
+                node.id,
+                dot_string(&label),
+                dot_string(&node.debug_repr)
+            )
+            .unwrap();
+        }
+        for (parent, child) in &state.edges {
+            writeln!(out, "    n{parent} -> n{child};").unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Writes the graph to `path` with a `json` and a `dot` extension.
+    pub(crate) fn persist(&self, path: &Path) {
+        if let Some(parent) = path.parent() {
+            t!(fs::create_dir_all(parent));
+        }
+        t!(fs::write(path.with_extension("json"), self.to_json()));
+        t!(fs::write(path.with_extension("dot"), self.to_dot()));
+    }
+}
+
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
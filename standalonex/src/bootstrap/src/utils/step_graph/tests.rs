@@ -0,0 +1,33 @@
+use crate::utils::step_graph::StepGraph;
+
+#[test]
+fn test_edges() {
+    let graph = StepGraph::default();
+    let root = graph.add("bootstrap::core::build_steps::dist::Rustc", "Rustc", "dist", None, None);
+    graph.state.borrow_mut().running.push(root);
+    let add_std = || {
+        let target = Some("x86_64-unknown-linux-gnu".to_owned());
+        graph.add("bootstrap::core::build_steps::compile::Std", "Std", "dist", Some(1), target)
+    };
+    let std = add_std();
+    // Ensuring the same step again, e.g. when it was cached, must not add a new node.
+    assert_eq!(add_std(), std);
+    graph.state.borrow_mut().running.pop();
+
+    let state = graph.state.borrow();
+    assert_eq!(state.nodes.len(), 2);
+    assert_eq!(state.nodes[std].type_, "core::build_steps::compile::Std");
+    assert_eq!(state.nodes[std].stage, Some(1));
+    assert_eq!(state.edges.iter().copied().collect::<Vec<_>>(), vec![(root, std)]);
+    drop(state);
+
+    let dot = graph.to_dot();
+    let label = r#"label="compile::Std\nstage 1\nx86_64-unknown-linux-gnu""#;
+    assert!(dot.contains(&format!(r#"n1 [{label}, tooltip="Std"];"#)));
+    assert!(dot.contains("n0 -> n1;"));
+
+    let json: serde_json::Value = serde_json::from_str(&graph.to_json()).unwrap();
+    assert_eq!(json["nodes"][1]["type"], "core::build_steps::compile::Std");
+    assert_eq!(json["edges"][0]["parent"], 0);
+    assert_eq!(json["edges"][0]["child"], 1);
+}
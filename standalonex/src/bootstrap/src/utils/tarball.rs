@@ -4,16 +4,24 @@
 //! capture a snapshot of each boostrap stage.
 //! In uplifting, a tarball from Stage N captures essential components
 //! to assemble Stage N + 1 compiler.
+//!
+//! The tarballs are in the format of `rust-installer`, which rustup and the `install.sh` in
+//! each of them understand, but they're generated here rather than by running that tool.
 
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
 
 use crate::core::build_steps::dist::distdir;
 use crate::core::builder::{Builder, Kind};
 use crate::core::config::BUILDER_CONFIG_FILENAME;
-use crate::utils::exec::BootstrapCommand;
-use crate::utils::helpers::{move_file, t};
+use crate::utils::helpers::{move_file, sh_quote, t};
 use crate::utils::{channel, helpers};
 
+#[cfg(test)]
+mod tests;
+
 #[derive(Copy, Clone)]
 pub(crate) enum OverlayKind {
     Rust,
@@ -111,7 +119,6 @@ pub(crate) struct Tarball<'a> {
 
     temp_dir: PathBuf,
     image_dir: PathBuf,
-    overlay_dir: PathBuf,
     bulk_dirs: Vec<PathBuf>,
 
     include_target_in_component_name: bool,
@@ -138,7 +145,6 @@ impl<'a> Tarball<'a> {
         let _ = std::fs::remove_dir_all(&temp_dir);
 
         let image_dir = temp_dir.join("image");
-        let overlay_dir = temp_dir.join("overlay");
 
         Self {
             builder,
@@ -151,7 +157,6 @@ impl<'a> Tarball<'a> {
 
             temp_dir,
             image_dir,
-            overlay_dir,
             bulk_dirs: Vec::new(),
 
             include_target_in_component_name: false,
@@ -181,7 +186,7 @@ impl<'a> Tarball<'a> {
     }
 
     pub(crate) fn image_dir(&self) -> &Path {
-        t!(std::fs::create_dir_all(&self.image_dir));
+        self.builder.create_dir(&self.image_dir);
         &self.image_dir
     }
 
@@ -194,7 +199,7 @@ impl<'a> Tarball<'a> {
             self.image_dir.join(destdir.as_ref())
         };
 
-        t!(std::fs::create_dir_all(&destdir));
+        self.builder.create_dir(&destdir);
         self.builder.install(src.as_ref(), &destdir, perms);
     }
 
@@ -205,7 +210,7 @@ impl<'a> Tarball<'a> {
         new_name: &str,
     ) {
         let destdir = self.image_dir.join(destdir.as_ref());
-        t!(std::fs::create_dir_all(&destdir));
+        self.builder.create_dir(&destdir);
         self.builder.copy_link(src.as_ref(), &destdir.join(new_name));
     }
 
@@ -218,7 +223,7 @@ impl<'a> Tarball<'a> {
     pub(crate) fn add_dir(&self, src: impl AsRef<Path>, dest: impl AsRef<Path>) {
         let dest = self.image_dir.join(dest.as_ref());
 
-        t!(std::fs::create_dir_all(&dest));
+        self.builder.create_dir(&dest);
         self.builder.cp_link_r(src.as_ref(), &dest);
     }
 
@@ -241,53 +246,41 @@ impl<'a> Tarball<'a> {
             );
         }
 
-        self.run(|this, cmd| {
-            cmd.arg("generate")
-                .arg("--image-dir")
-                .arg(&this.image_dir)
-                .arg(format!("--component-name={component_name}"));
-
-            if let Some((dir, dirs)) = this.bulk_dirs.split_first() {
-                let mut arg = dir.as_os_str().to_os_string();
-                for dir in dirs {
-                    arg.push(",");
-                    arg.push(dir);
-                }
-                cmd.arg("--bulk-dirs").arg(&arg);
-            }
-
-            this.non_bare_args(cmd);
+        self.run(|this, package_dir| {
+            let component_dir = package_dir.join(&component_name);
+            this.builder.create_dir(&component_dir);
+            this.builder.cp_link_r(&this.image_dir, &component_dir);
+            let manifest = manifest_in(&this.image_dir, &this.bulk_dirs);
+            this.builder.create(&component_dir.join("manifest.in"), &manifest);
+            this.builder.create(&package_dir.join("components"), &format!("{component_name}\n"));
+            this.add_installer(package_dir);
         })
     }
 
     pub(crate) fn combine(self, tarballs: &[GeneratedTarball]) -> GeneratedTarball {
-        let mut input_tarballs = tarballs[0].path.as_os_str().to_os_string();
-        for tarball in &tarballs[1..] {
-            input_tarballs.push(",");
-            input_tarballs.push(&tarball.path);
-        }
-
-        self.run(|this, cmd| {
-            cmd.arg("combine").arg("--input-tarballs").arg(input_tarballs);
-            this.non_bare_args(cmd);
+        self.run(|this, package_dir| {
+            let mut components = String::new();
+            for tarball in tarballs {
+                let input = tarball.decompressed_output();
+                for component in t!(fs::read_to_string(input.join("components"))).lines() {
+                    let component_dir = package_dir.join(component);
+                    this.builder.create_dir(&component_dir);
+                    this.builder.cp_link_r(&input.join(component), &component_dir);
+                    components.push_str(component);
+                    components.push('\n');
+                }
+            }
+            this.builder.create(&package_dir.join("components"), &components);
+            this.add_installer(package_dir);
         })
     }
 
     pub(crate) fn bare(self) -> GeneratedTarball {
         // Bare tarballs should have the top level directory match the package
-        // name, not "image". We rename the image directory just before passing
-        // into rust-installer.
-        let dest = self.temp_dir.join(self.package_name());
-        t!(move_file(&self.image_dir, &dest));
-
-        self.run(|this, cmd| {
-            let distdir = distdir(this.builder);
-            t!(std::fs::create_dir_all(&distdir));
-            cmd.arg("tarball")
-                .arg("--input")
-                .arg(&dest)
-                .arg("--output")
-                .arg(distdir.join(this.package_name()));
+        // name, not "image", and nothing but the image in it.
+        self.run(|this, package_dir| {
+            this.builder.config.assert_writable(package_dir);
+            t!(move_file(&this.image_dir, package_dir))
         })
     }
 
@@ -299,65 +292,86 @@ impl<'a> Tarball<'a> {
         }
     }
 
-    fn non_bare_args(&self, cmd: &mut BootstrapCommand) {
-        cmd.arg("--rel-manifest-dir=rustlib")
-            .arg("--legacy-manifest-dirs=rustlib,cargo")
-            .arg(format!("--product-name={}", self.product_name))
-            .arg(format!("--success-message={} installed.", self.component))
-            .arg(format!("--package-name={}", self.package_name()))
-            .arg("--non-installed-overlay")
-            .arg(&self.overlay_dir)
-            .arg("--output-dir")
-            .arg(distdir(self.builder));
-    }
+    /// Adds what the components of `package_dir` need to be installed with its `install.sh`,
+    /// and the files that aren't installed: the version, commit info, config and legal files.
+    fn add_installer(&self, package_dir: &Path) {
+        self.builder
+            .create(&package_dir.join("rust-installer-version"), &format!("{INSTALLER_VERSION}\n"));
 
-    fn run(self, build_cli: impl FnOnce(&Tarball<'a>, &mut BootstrapCommand)) -> GeneratedTarball {
-        t!(std::fs::create_dir_all(&self.overlay_dir));
-        self.builder.create(&self.overlay_dir.join("version"), &self.overlay.version(self.builder));
+        self.builder.create(&package_dir.join("version"), &self.overlay.version(self.builder));
         if let Some(info) = self.builder.rust_info().info() {
-            channel::write_commit_hash_file(&self.overlay_dir, &info.sha);
-            channel::write_commit_info_file(&self.overlay_dir, info);
+            channel::write_commit_hash_file(package_dir, &info.sha);
+            channel::write_commit_info_file(package_dir, info);
         }
-
         // Add config file if present.
         if let Some(config) = &self.builder.config.config {
-            self.add_renamed_file(config, &self.overlay_dir, BUILDER_CONFIG_FILENAME);
+            self.builder.copy_link(config, &package_dir.join(BUILDER_CONFIG_FILENAME));
         }
-
         for file in self.overlay.legal_and_readme() {
-            self.builder.install(&self.builder.src.join(file), &self.overlay_dir, 0o644);
+            self.builder.install(&self.builder.src.join(file), package_dir, 0o644);
         }
 
-        let mut cmd = self.builder.tool_cmd(crate::core::build_steps::tool::Tool::RustInstaller);
+        let template = self.builder.src.join("src/tools/rust-installer/install-template.sh");
+        let script = t!(fs::read_to_string(&template))
+            .replace("%%TEMPLATE_PRODUCT_NAME%%", &sh_quote(&self.product_name.replace('-', " ")))
+            .replace("%%TEMPLATE_REL_MANIFEST_DIR%%", "rustlib")
+            .replace(
+                "%%TEMPLATE_SUCCESS_MESSAGE%%",
+                &sh_quote(&format!("{} installed.", self.component).replace('-', " ")),
+            )
+            .replace("%%TEMPLATE_LEGACY_MANIFEST_DIRS%%", &sh_quote("rustlib,cargo"))
+            .replace("%%TEMPLATE_RUST_INSTALLER_VERSION%%", &sh_quote(INSTALLER_VERSION));
+        let install_sh = package_dir.join("install.sh");
+        self.builder.create(&install_sh, &script);
+        crate::chmod(&install_sh, 0o755);
+    }
 
+    fn run(self, fill_package_dir: impl FnOnce(&Tarball<'a>, &Path)) -> GeneratedTarball {
         let package_name = self.package_name();
         self.builder.info(&format!("Dist {package_name}"));
         let _time = crate::utils::helpers::timeit(self.builder);
 
-        build_cli(&self, &mut cmd);
-        cmd.arg("--work-dir").arg(&self.temp_dir);
-        if let Some(formats) = &self.builder.config.dist_compression_formats {
-            assert!(!formats.is_empty(), "dist.compression-formats can't be empty");
-            cmd.arg("--compression-formats").arg(formats.join(","));
+        let decompressed_output = self.temp_dir.join(&package_name);
+        let formats = match &self.builder.config.dist_compression_formats {
+            Some(formats) => {
+                assert!(!formats.is_empty(), "dist.compression-formats can't be empty");
+                formats.clone()
+            }
+            None => vec!["gz".to_owned(), "xz".to_owned()],
+        };
+        // Use the first compression format as the one the tarball is known by.
+        let path = distdir(self.builder).join(format!("{package_name}.tar.{}", formats[0]));
+        let generated =
+            GeneratedTarball { path, decompressed_output, work: self.temp_dir.clone() };
+        if self.builder.dry_run() {
+            return generated;
+        }
+
+        fill_package_dir(&self, &generated.decompressed_output);
+
+        // Ensure there are no symbolic links in the tarball. In particular,
+        // rustup-toolchain-install-master and most versions of Windows can't handle symbolic links.
+        if !self.permit_symlinks {
+            for entry in walkdir::WalkDir::new(&generated.decompressed_output) {
+                let entry = t!(entry);
+                if entry.path_is_symlink() {
+                    panic!("generated a symlink in a tarball: {}", entry.path().display());
+                }
+            }
         }
 
         // For `x install` tarball files aren't needed, so we can speed up the process by not producing them.
-        let compression_profile = if self.builder.kind == Kind::Install {
+        if self.builder.kind == Kind::Install {
             self.builder.verbose(|| {
                 println!("Forcing dist.compression-profile = 'no-op' for `x install`.")
             });
-            // "no-op" indicates that the rust-installer won't produce compressed tarball sources.
-            "no-op"
-        } else {
-            assert!(
-                self.builder.config.dist_compression_profile != "no-op",
-                "dist.compression-profile = 'no-op' can only be used for `x install`"
-            );
-
-            &self.builder.config.dist_compression_profile
-        };
-
-        cmd.args(["--compression-profile", compression_profile]);
+            return generated;
+        }
+        let profile = &self.builder.config.dist_compression_profile;
+        assert!(
+            profile != "no-op",
+            "dist.compression-profile = 'no-op' can only be used for `x install`"
+        );
 
         // We want to use a pinned modification time for files in the archive
         // to achieve better reproducibility. However, using the same mtime for all
@@ -368,13 +382,12 @@ impl<'a> Tarball<'a> {
         // (achieving reproducibility), but it will also change between different commits and
         // Rust versions, so that it won't break mtime-based caches.
         //
-        // Note that this only overrides the mtime of files, not directories, due to the
-        // limitations of the tarballer tool. Directories will have their mtime set to 2006.
+        // Outside of git, everything in the archive has its mtime set to 2006.
 
         // Get the UTC timestamp of the last git commit, if we're under git.
         // We need to use UTC, so that anyone who tries to rebuild from the same commit
         // gets the same timestamp.
-        if self.builder.rust_info().is_managed_git_subrepository() {
+        let mtime = if self.builder.rust_info().is_managed_git_subrepository() {
             // %ct means committer date
             let timestamp = helpers::git(Some(&self.builder.src))
                 .arg("log")
@@ -382,39 +395,217 @@ impl<'a> Tarball<'a> {
                 .arg("--format=%ct")
                 .run_capture_stdout(self.builder)
                 .stdout();
-            cmd.args(["--override-file-mtime", timestamp.trim()]);
+            Some(t!(timestamp.trim().parse()))
+        } else {
+            None
+        };
+
+        let distdir = distdir(self.builder);
+        self.builder.create_dir(&distdir);
+        t!(write_tarballs(
+            &self.temp_dir,
+            &package_name,
+            &distdir.join(&package_name),
+            &formats,
+            profile,
+            mtime,
+        ));
+
+        generated
+    }
+}
+
+/// The version of the rust-installer format of the tarballs, which `install.sh` checks.
+const INSTALLER_VERSION: &str = "3";
+
+/// The `manifest.in` of a component with the contents of `image_dir`, which lists what
+/// `install.sh` installs: each of the `bulk_dirs` as a whole, and the files outside of them.
+pub(crate) fn manifest_in(image_dir: &Path, bulk_dirs: &[PathBuf]) -> String {
+    let mut lines = BTreeSet::new();
+    for dir in bulk_dirs {
+        lines.insert(format!("dir:{}", slash_path(dir)));
+    }
+    for entry in walkdir::WalkDir::new(image_dir) {
+        let entry = t!(entry);
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let path = entry.path().strip_prefix(image_dir).unwrap();
+        if !bulk_dirs.iter().any(|dir| path.starts_with(dir)) {
+            lines.insert(format!("file:{}", slash_path(path)));
         }
+    }
+    lines.into_iter().map(|line| line + "\n").collect()
+}
 
-        cmd.run(self.builder);
+fn slash_path(path: &Path) -> String {
+    let components: Vec<_> = path.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+    components.join("/")
+}
 
-        // Ensure there are no symbolic links in the tarball. In particular,
-        // rustup-toolchain-install-master and most versions of Windows can't handle symbolic links.
-        let decompressed_output = self.temp_dir.join(&package_name);
-        if !self.builder.config.dry_run() && !self.permit_symlinks {
-            for entry in walkdir::WalkDir::new(&decompressed_output) {
-                let entry = t!(entry);
-                if entry.path_is_symlink() {
-                    panic!("generated a symlink in a tarball: {}", entry.path().display());
-                }
+/// Writes `work_dir/package_name` into `<output>.tar.<format>` for each of the `formats`, with
+/// the given `dist.compression-profile`. Unless `mtime` overrides it, everything in the archive
+/// has the same fixed modification time.
+///
+/// Directories come first, so that they're created before what's in them when unpacking. The
+/// files follow ordered by their reversed path, which puts files of the same type next to each
+/// other, and makes the tarballs compress better.
+pub(crate) fn write_tarballs(
+    work_dir: &Path,
+    package_name: &str,
+    output: &Path,
+    formats: &[String],
+    profile: &str,
+    mtime: Option<u64>,
+) -> io::Result<()> {
+    let mut dirs = vec![];
+    let mut files = vec![];
+    for entry in walkdir::WalkDir::new(work_dir.join(package_name)).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(work_dir).unwrap().to_path_buf();
+        if entry.file_type().is_dir() {
+            dirs.push(path);
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort_by_cached_key(|path| {
+        let mut bytes = path.as_os_str().as_encoded_bytes().to_vec();
+        bytes.reverse();
+        bytes
+    });
+
+    let mut encoders = vec![];
+    for format in formats {
+        let mut path = output.as_os_str().to_owned();
+        path.push(format!(".tar.{format}"));
+        let file = BufWriter::new(File::create(path)?);
+        encoders.push(Encoder::new(file, format, profile)?);
+    }
+
+    let mut builder = tar::Builder::new(Tee(encoders));
+    builder.mode(tar::HeaderMode::Deterministic);
+    for path in dirs.iter().chain(&files) {
+        let src = work_dir.join(path);
+        let metadata = fs::symlink_metadata(&src)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, tar::HeaderMode::Deterministic);
+        if let Some(mtime) = mtime {
+            header.set_mtime(mtime);
+        }
+        if cfg!(windows) && has_executable_extension(path) {
+            // Windows has no executable permission to preserve.
+            header.set_mode(0o755);
+        }
+        if metadata.is_symlink() {
+            builder.append_link(&mut header, path, fs::read_link(&src)?)?;
+        } else if metadata.is_dir() {
+            builder.append_data(&mut header, path, io::empty())?;
+        } else {
+            builder.append_data(&mut header, path, File::open(&src)?)?;
+        }
+    }
+    for encoder in builder.into_inner()?.0 {
+        encoder.finish()?.flush()?;
+    }
+    Ok(())
+}
+
+fn has_executable_extension(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        ["exe", "dll", "py", "sh"].iter().any(|executable| extension == *executable)
+    })
+}
+
+/// A compressor for one of the `dist.compression-formats`.
+enum Encoder {
+    Gz(flate2::write::GzEncoder<BufWriter<File>>),
+    Xz(xz2::write::XzEncoder<BufWriter<File>>),
+}
+
+impl Encoder {
+    fn new(file: BufWriter<File>, format: &str, profile: &str) -> io::Result<Self> {
+        let encoder = match format {
+            "gz" => {
+                let compression = match profile {
+                    "fast" => flate2::Compression::fast(),
+                    "balanced" => flate2::Compression::default(),
+                    "best" => flate2::Compression::best(),
+                    _ => panic!("unknown dist.compression-profile `{profile}`"),
+                };
+                Encoder::Gz(flate2::write::GzEncoder::new(file, compression))
+            }
+            "xz" => {
+                let mut builder = xz2::stream::MtStreamBuilder::new();
+                builder.threads(if cfg!(target_pointer_width = "32") { 1 } else { 6 });
+                match profile {
+                    "fast" => builder.preset(1),
+                    "balanced" => builder.preset(6),
+                    "best" => {
+                        // Preset 9 with a larger dictionary and the most thorough match finder,
+                        // which is worth it for release tarballs.
+                        let mut lzma_ops = xz2::stream::LzmaOptions::new_preset(9)?;
+                        lzma_ops
+                            .dict_size(64 * 1024 * 1024)
+                            .match_finder(xz2::stream::MatchFinder::BinaryTree4)
+                            .mode(xz2::stream::Mode::Normal)
+                            .nice_len(273)
+                            .depth(1000)
+                            .position_bits(2)
+                            .literal_position_bits(0)
+                            .literal_context_bits(3);
+                        let mut filters = xz2::stream::Filters::new();
+                        filters.lzma2(&lzma_ops);
+                        builder.filters(filters)
+                    }
+                    _ => panic!("unknown dist.compression-profile `{profile}`"),
+                };
+                Encoder::Xz(xz2::write::XzEncoder::new_stream(file, builder.encoder()?))
             }
+            _ => panic!("unknown dist.compression-formats entry `{format}`"),
+        };
+        Ok(encoder)
+    }
+
+    fn finish(self) -> io::Result<BufWriter<File>> {
+        match self {
+            Encoder::Gz(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
         }
+    }
+}
 
-        // Use either the first compression format defined, or "gz" as the default.
-        let ext = self
-            .builder
-            .config
-            .dist_compression_formats
-            .as_ref()
-            .and_then(|formats| formats.first())
-            .map(|s| s.as_str())
-            .unwrap_or("gz");
-
-        GeneratedTarball {
-            path: distdir(self.builder).join(format!("{package_name}.tar.{ext}")),
-            decompressed_output,
-            work: self.temp_dir,
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gz(encoder) => encoder.write(buf),
+            Encoder::Xz(encoder) => encoder.write(buf),
         }
     }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gz(encoder) => encoder.flush(),
+            Encoder::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writes everything into each of the encoders, to compress the tarball once per format in one
+/// pass over the files.
+struct Tee(Vec<Encoder>);
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for encoder in &mut self.0 {
+            encoder.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.iter_mut().try_for_each(|encoder| encoder.flush())
+    }
 }
 
 #[derive(Debug, Clone)]
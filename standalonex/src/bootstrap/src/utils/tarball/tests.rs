@@ -0,0 +1,81 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+
+use crate::core::config::tests::test_dir;
+use crate::utils::tarball::{manifest_in, write_tarballs};
+
+#[test]
+fn test_manifest_in() {
+    let image = test_dir("manifest-in");
+    for file in ["bin/rustc", "lib/rustlib/x86_64-unknown-linux-gnu/lib/libstd.rlib", "README.md"] {
+        fs::create_dir_all(image.join(file).parent().unwrap()).unwrap();
+        fs::write(image.join(file), "").unwrap();
+    }
+    fs::create_dir_all(image.join("share/empty")).unwrap();
+
+    let bulk_dirs = [PathBuf::from("lib/rustlib/x86_64-unknown-linux-gnu/lib")];
+    assert_eq!(
+        manifest_in(&image, &bulk_dirs),
+        "dir:lib/rustlib/x86_64-unknown-linux-gnu/lib\nfile:README.md\nfile:bin/rustc\n"
+    );
+}
+
+/// Returns the path and contents of each entry of a tarball.
+fn read_tarball(decoder: impl Read) -> Vec<(String, String)> {
+    let mut entries = vec![];
+    for entry in tar::Archive::new(decoder).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        assert_eq!(entry.header().mtime().unwrap(), 1_700_000_000);
+        let path = entry.path().unwrap().to_string_lossy().into_owned();
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        entries.push((path, contents));
+    }
+    entries
+}
+
+#[test]
+fn test_write_tarballs() {
+    let dir = test_dir("write-tarballs");
+    let work = dir.join("work");
+    let package = work.join("rustc-nightly");
+    for file in ["rustc/bin/rustc", "rustc/manifest.in", "rustc/share/doc/README.md", "version"] {
+        fs::create_dir_all(package.join(file).parent().unwrap()).unwrap();
+        fs::write(package.join(file), file).unwrap();
+    }
+
+    let output = dir.join("rustc-nightly");
+    let formats = ["gz".to_owned(), "xz".to_owned()];
+    write_tarballs(&work, "rustc-nightly", &output, &formats, "fast", Some(1_700_000_000)).unwrap();
+
+    let xz = fs::read(output.with_extension("tar.xz")).unwrap();
+    let entries = read_tarball(xz2::read::XzDecoder::new(&xz[..]));
+    let paths: Vec<_> = entries.iter().map(|(path, _)| path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "rustc-nightly",
+            "rustc-nightly/rustc",
+            "rustc-nightly/rustc/bin",
+            "rustc-nightly/rustc/share",
+            "rustc-nightly/rustc/share/doc",
+            // Ordered by reversed path.
+            "rustc-nightly/rustc/bin/rustc",
+            "rustc-nightly/rustc/share/doc/README.md",
+            "rustc-nightly/rustc/manifest.in",
+            "rustc-nightly/version",
+        ]
+    );
+    assert!(entries.iter().all(|(path, contents)| path.ends_with(contents.as_str())));
+
+    // The gzip tarball has the same contents.
+    let gz = fs::read(output.with_extension("tar.gz")).unwrap();
+    assert_eq!(read_tarball(flate2::read::GzDecoder::new(&gz[..])), entries);
+
+    // Compressing the same files again gives the same tarballs.
+    let mut gz_again = vec![];
+    write_tarballs(&work, "rustc-nightly", &output, &formats, "fast", Some(1_700_000_000)).unwrap();
+    File::open(output.with_extension("tar.gz")).unwrap().read_to_end(&mut gz_again).unwrap();
+    assert!(gz == gz_again);
+}